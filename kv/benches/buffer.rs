@@ -0,0 +1,45 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kv::{CompactionPolicy, KvStore, KvStoreOptions};
+use tempfile::TempDir;
+
+const VALUES: usize = 256;
+const VALUE_SIZE: usize = 32 * 1024;
+
+// Buffered sets of values a few times the default buffer size. A larger
+// buffer writes them out in fewer, bigger system calls.
+fn buffer_capacity_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("buffer_capacity");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes((VALUES * VALUE_SIZE) as u64));
+    let value = "v".repeat(VALUE_SIZE);
+    for capacity in [8 * 1024, 256 * 1024] {
+        group.bench_function(BenchmarkId::from_parameter(capacity), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let options = KvStoreOptions {
+                        buffer_capacity: capacity,
+                        sync_sets: false,
+                        compaction_policy: CompactionPolicy::Bytes(u64::MAX),
+                        ..KvStoreOptions::default()
+                    };
+                    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+                    (temp_dir, store)
+                },
+                // Hand the store back so removing it is not timed.
+                |(temp_dir, store)| {
+                    for i in 0..VALUES {
+                        store.set(format!("key{}", i), value.clone()).unwrap();
+                    }
+                    store.flush().unwrap();
+                    (temp_dir, store)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, buffer_capacity_bench);
+criterion_main!(benches);
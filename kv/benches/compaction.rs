@@ -0,0 +1,66 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use kv::{CompactionPolicy, KvStore, KvStoreOptions};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use tempfile::TempDir;
+
+const SEED: u64 = 42;
+const KEYS: usize = 256;
+const VALUE_SIZE: usize = 16 * 1024;
+// Every reopen starts a new log file, so each round lands in a file of
+// its own.
+const ROUNDS: usize = 4;
+
+// A store whose live values are spread over `ROUNDS` log files, each
+// round overwriting half the keys. With `shuffled`, every round writes its
+// keys in a random order, so key order and log order disagree.
+fn spread_store(shuffled: bool) -> (TempDir, KvStore) {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        compaction_policy: CompactionPolicy::Bytes(u64::MAX),
+        sync_sets: false,
+        ..KvStoreOptions::default()
+    };
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut keys: Vec<usize> = (0..KEYS).collect();
+    for round in 0..ROUNDS {
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap();
+        if shuffled {
+            keys.shuffle(&mut rng);
+        }
+        let written = if round == 0 { KEYS } else { KEYS / 2 };
+        for &i in &keys[..written] {
+            store
+                .set(format!("key{}", i), "v".repeat(VALUE_SIZE))
+                .unwrap();
+        }
+        store.flush().unwrap();
+    }
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    (temp_dir, store)
+}
+
+// Compaction copies live records in log order, so its reads stay
+// sequential however the keys were laid out.
+fn compaction_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compaction");
+    group.sample_size(10);
+    for shuffled in [false, true] {
+        group.bench_function(BenchmarkId::new("shuffled", shuffled), |b| {
+            b.iter_batched(
+                || spread_store(shuffled),
+                // Hand the store back so removing it is not timed.
+                |(temp_dir, store)| {
+                    store.compact().unwrap();
+                    (temp_dir, store)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, compaction_bench);
+criterion_main!(benches);
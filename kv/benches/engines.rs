@@ -0,0 +1,239 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kv::{KvStore, KvStoreOptions, KvsEngine, SledKvsEngine};
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::path::Path;
+use tempfile::TempDir;
+
+const SEED: u64 = 42;
+const KEYS: usize = 200;
+// 2000 writes of 1 KiB over 200 keys leave well over the 1 MiB compaction
+// threshold of stale data, so write-heavy runs include compaction.
+const WRITES: usize = 2000;
+const VALUE_SIZE: usize = 1024;
+const OPS: usize = 1000;
+const BULK: usize = 10_000;
+// The engine comparison writes this many random keys, with values of up
+// to `MAX_RANDOM_VALUE` bytes.
+const RANDOM_KEYS: usize = 100;
+const MAX_RANDOM_VALUE: usize = 100_000;
+
+fn key(i: usize) -> String {
+    format!("key{}", i)
+}
+
+// Every engine under comparison, by name, opened on a directory.
+type OpenEngine = fn(&Path) -> Box<dyn KvsEngine>;
+const ENGINES: [(&str, OpenEngine); 2] = [
+    ("kvs", |dir| Box::new(KvStore::open(dir).unwrap())),
+    ("sled", |dir| Box::new(SledKvsEngine::open(dir).unwrap())),
+];
+
+// `RANDOM_KEYS` random keys, each with a value of 1 to `MAX_RANDOM_VALUE`
+// bytes, the same for every run.
+fn random_pairs() -> Vec<(String, String)> {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    (0..RANDOM_KEYS)
+        .map(|_| {
+            let key_len = rng.gen_range(1..=64);
+            let value_len = rng.gen_range(1..=MAX_RANDOM_VALUE);
+            (
+                random_string(&mut rng, key_len),
+                random_string(&mut rng, value_len),
+            )
+        })
+        .collect()
+}
+
+fn random_string(rng: &mut StdRng, len: usize) -> String {
+    (0..len)
+        .map(|_| char::from(rng.sample(Alphanumeric)))
+        .collect()
+}
+
+// A store in a fresh directory with every key set once.
+fn populated_kvs() -> (TempDir, KvStore) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..KEYS {
+        store.set(key(i), "v".repeat(VALUE_SIZE)).unwrap();
+    }
+    (temp_dir, store)
+}
+
+fn write_heavy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_heavy");
+    group.throughput(Throughput::Elements(WRITES as u64));
+    group.sample_size(10);
+    group.bench_function(BenchmarkId::from_parameter("kvs"), |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let store = KvStore::open(temp_dir.path()).unwrap();
+                (temp_dir, store, StdRng::seed_from_u64(SEED))
+            },
+            |(_temp_dir, store, mut rng)| {
+                for _ in 0..WRITES {
+                    let i = rng.gen_range(0..KEYS);
+                    store.set(key(i), "v".repeat(VALUE_SIZE)).unwrap();
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+fn read_heavy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_heavy");
+    group.throughput(Throughput::Elements(OPS as u64));
+    let (_temp_dir, store) = populated_kvs();
+    let mut rng = StdRng::seed_from_u64(SEED);
+    group.bench_function(BenchmarkId::from_parameter("kvs"), |b| {
+        b.iter(|| {
+            for _ in 0..OPS {
+                let i = rng.gen_range(0..KEYS);
+                store.get(key(i)).unwrap().unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+// Half reads, half writes over the same keys.
+fn mixed(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mixed");
+    group.throughput(Throughput::Elements(OPS as u64));
+    let (_temp_dir, store) = populated_kvs();
+    let mut rng = StdRng::seed_from_u64(SEED);
+    group.bench_function(BenchmarkId::from_parameter("kvs"), |b| {
+        b.iter(|| {
+            for _ in 0..OPS {
+                let i = rng.gen_range(0..KEYS);
+                if rng.gen_bool(0.5) {
+                    store.get(key(i)).unwrap().unwrap();
+                } else {
+                    store.set(key(i), "v".repeat(VALUE_SIZE)).unwrap();
+                }
+            }
+        })
+    });
+    group.finish();
+}
+
+// Loading many fresh keys, one flush per `set` against a single one for
+// the whole `set_many` batch, or none until the end with `sync_sets` off.
+fn bulk_load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_load");
+    group.throughput(Throughput::Elements(BULK as u64));
+    group.sample_size(10);
+    let pairs = || (0..BULK).map(|i| (key(i), "v".repeat(100)));
+    let fresh_store = || {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        (temp_dir, store)
+    };
+    group.bench_function(BenchmarkId::new("kvs", "set"), |b| {
+        b.iter_batched(
+            fresh_store,
+            |(_temp_dir, store)| {
+                for (key, value) in pairs() {
+                    store.set(key, value).unwrap();
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function(BenchmarkId::new("kvs", "set_many"), |b| {
+        b.iter_batched(
+            fresh_store,
+            |(_temp_dir, store)| store.set_many(pairs()).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function(BenchmarkId::new("kvs", "buffered_set"), |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let options = KvStoreOptions {
+                    sync_sets: false,
+                    ..KvStoreOptions::default()
+                };
+                let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+                (temp_dir, store)
+            },
+            |(_temp_dir, store)| {
+                for (key, value) in pairs() {
+                    store.set(key, value).unwrap();
+                }
+                store.flush().unwrap();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+// Write the random pairs into a fresh directory with each engine.
+fn engine_write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engine_write");
+    group.throughput(Throughput::Elements(RANDOM_KEYS as u64));
+    group.sample_size(10);
+    let pairs = random_pairs();
+    for (name, open) in ENGINES {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let engine = open(temp_dir.path());
+                    (temp_dir, engine)
+                },
+                |(temp_dir, engine)| {
+                    for (key, value) in &pairs {
+                        engine.set(key.clone(), value.clone()).unwrap();
+                    }
+                    (temp_dir, engine)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+// Read the random pairs back from each engine in a shuffled order.
+fn engine_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engine_read");
+    group.throughput(Throughput::Elements(RANDOM_KEYS as u64));
+    let pairs = random_pairs();
+    let mut keys: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    keys.shuffle(&mut StdRng::seed_from_u64(SEED));
+    for (name, open) in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+        let engine = open(temp_dir.path());
+        for (key, value) in &pairs {
+            engine.set(key.clone(), value.clone()).unwrap();
+        }
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                for key in &keys {
+                    engine.get(key.clone()).unwrap().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    write_heavy,
+    read_heavy,
+    mixed,
+    bulk_load,
+    engine_write,
+    engine_read
+);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kv::{KvStore, KvStoreOptions};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use tempfile::TempDir;
+
+const KEYS: usize = 10_000;
+const FETCHED: usize = 1000;
+
+// Fetch 1000 random keys spread over several files, one `get` at a time or
+// in one `get_many`.
+fn get_many_bench(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        max_file_size: Some(256 * 1024),
+        sync_sets: false,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    for i in 0..KEYS {
+        store
+            .set(format!("key{}", i), format!("{:0100}", i))
+            .unwrap();
+    }
+    store.flush().unwrap();
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut keys: Vec<String> = (0..KEYS).map(|i| format!("key{}", i)).collect();
+    keys.shuffle(&mut rng);
+    keys.truncate(FETCHED);
+
+    let mut group = c.benchmark_group("get_many");
+    group.throughput(Throughput::Elements(FETCHED as u64));
+    group.bench_function(BenchmarkId::from_parameter("get"), |b| {
+        b.iter(|| {
+            for key in &keys {
+                store.get(key.clone()).unwrap();
+            }
+        })
+    });
+    group.bench_function(BenchmarkId::from_parameter("get_many"), |b| {
+        b.iter(|| store.get_many(&keys).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, get_many_bench);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use kv::{CompactionPolicy, FsyncPolicy, GroupCommit, KvStore, KvStoreOptions};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const WRITERS: usize = 8;
+const WRITES: usize = 250;
+
+fn open(fsync: FsyncPolicy) -> (TempDir, KvStore) {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        sync_sets: fsync == FsyncPolicy::Always,
+        fsync,
+        compaction_policy: CompactionPolicy::Bytes(u64::MAX),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    (temp_dir, store)
+}
+
+// `WRITERS` threads each making `WRITES` sets that must be on disk before
+// the next one, either syncing every set or committing them in groups.
+fn write(store: &KvStore, group_commit: Option<&GroupCommit>) {
+    thread::scope(|scope| {
+        for writer in 0..WRITERS {
+            let store = store.clone();
+            scope.spawn(move || {
+                for i in 0..WRITES {
+                    store
+                        .set(format!("key{}-{}", writer, i), "value".to_owned())
+                        .unwrap();
+                    if let Some(group_commit) = group_commit {
+                        group_commit.commit(&store).unwrap();
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn group_commit_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_writers");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((WRITERS * WRITES) as u64));
+    group.bench_function("sync_per_write", |b| {
+        b.iter_batched(
+            || open(FsyncPolicy::Always),
+            |(temp_dir, store)| {
+                write(&store, None);
+                (temp_dir, store)
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("group_commit", |b| {
+        let group_commit = GroupCommit::new(Duration::from_micros(200), WRITERS);
+        b.iter_batched(
+            || open(FsyncPolicy::Never),
+            |(temp_dir, store)| {
+                write(&store, Some(&group_commit));
+                (temp_dir, store)
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, group_commit_bench);
+criterion_main!(benches);
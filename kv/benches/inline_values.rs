@@ -0,0 +1,47 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kv::{KvStore, KvStoreOptions};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tempfile::TempDir;
+
+const KEYS: usize = 1000;
+const GETS: usize = 1000;
+
+// Random gets of 16-byte values, read from the log files or straight from
+// the index with `inline_value_size`.
+fn inline_values_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("inline_values");
+    group.throughput(Throughput::Elements(GETS as u64));
+    for inline_value_size in [None, Some(64)] {
+        let temp_dir = TempDir::new().unwrap();
+        let options = KvStoreOptions {
+            inline_value_size,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..KEYS {
+            store
+                .set(format!("key{}", i), format!("{:016}", i))
+                .unwrap();
+        }
+        let mut rng = StdRng::seed_from_u64(0);
+        let keys: Vec<String> = (0..GETS)
+            .map(|_| format!("key{}", rng.gen_range(0..KEYS)))
+            .collect();
+        let name = match inline_value_size {
+            Some(_) => "inline",
+            None => "log",
+        };
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                for key in &keys {
+                    store.get(key.clone()).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, inline_values_bench);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kv::{CompactionPolicy, KvStore, KvStoreOptions};
+use tempfile::TempDir;
+
+const FILES: u64 = 64;
+const FILE_SIZE: u64 = 256 * 1024;
+
+// Opening a store spread over many full log files, with the files read on
+// one thread or split between several.
+fn recovery_bench(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        max_file_size: Some(FILE_SIZE),
+        sync_sets: false,
+        compaction_policy: CompactionPolicy::Bytes(u64::MAX),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    let value = "v".repeat(100);
+    let mut i = 0;
+    while store.stats().num_log_files < FILES {
+        store
+            .set(format!("key{}", i % 10_000), value.clone())
+            .unwrap();
+        i += 1;
+    }
+    drop(store);
+
+    let mut group = c.benchmark_group("recovery");
+    group.sample_size(10);
+    for workers in [1, 4] {
+        group.bench_function(BenchmarkId::from_parameter(workers), |b| {
+            b.iter(|| {
+                // Leave the directory as it is so every open does the same
+                // work.
+                let options = KvStoreOptions {
+                    recovery_workers: workers,
+                    defer_active_file: true,
+                    ..KvStoreOptions::default()
+                };
+                KvStore::open_with_options(temp_dir.path(), options).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, recovery_bench);
+criterion_main!(benches);
@@ -0,0 +1,38 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kv::{KvStore, KvStoreOptions};
+use tempfile::TempDir;
+
+// Replay a store spread over many log files with and without prefetching.
+fn replay_bench(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        // Roll segments without ever dropping one to get many files.
+        ring_capacity: Some(u64::MAX),
+        ring_segment_size: 64 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap();
+    for i in 0..10000 {
+        store.set(format!("key{}", i), "v".repeat(1000)).unwrap();
+    }
+    drop(store);
+
+    let mut group = c.benchmark_group("replay");
+    for prefetch in [false, true] {
+        let store = KvStore::open_with_options(
+            temp_dir.path(),
+            KvStoreOptions {
+                scan_prefetch: prefetch,
+                ..options.clone()
+            },
+        )
+        .unwrap();
+        group.bench_function(BenchmarkId::new("prefetch", prefetch), |b| {
+            b.iter(|| store.replay().unwrap().count())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, replay_bench);
+criterion_main!(benches);
@@ -0,0 +1,84 @@
+use crate::{KvError, KvsEngine, Result};
+use std::future::Future;
+use std::io;
+use std::panic;
+use std::sync::Arc;
+
+/// A storage backend for async code: the operations of `KvsEngine`,
+/// completing as futures instead of blocking the calling thread.
+///
+/// Every method takes `&self` and returns a `Send` future, so a handle can
+/// be cloned into any number of tasks on a multi-threaded runtime.
+pub trait AsyncKvsEngine: Clone + Send + Sync + 'static {
+    /// Set `key` to `value`, overwriting any previous value.
+    fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send;
+    /// Get the value of `key`, or `None` if it is absent.
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send;
+    /// Remove `key`, failing with `KvError::KeyNotFound` if it is absent.
+    fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send;
+    /// Write out anything still buffered so it survives a restart.
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Serves a blocking `KvsEngine` to async code by running each operation
+/// on tokio's blocking thread pool, so that disk I/O never stalls the
+/// runtime's worker threads.
+///
+/// Operations must be awaited from within a tokio runtime. Clones share
+/// the engine, and with it a `KvStore`'s open log readers.
+pub struct SpawnBlocking<E> {
+    engine: Arc<E>,
+}
+
+impl<E: KvsEngine + Sync> SpawnBlocking<E> {
+    /// Serve `engine` to async code.
+    pub fn new(engine: E) -> SpawnBlocking<E> {
+        SpawnBlocking {
+            engine: Arc::new(engine),
+        }
+    }
+
+    /// The engine behind the adapter, for the blocking calls it has and
+    /// `KvsEngine` lacks.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    // Run `op` on the engine from the blocking pool. A panicking operation
+    // panics the awaiting task in turn.
+    async fn run<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&E) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let engine = self.engine.clone();
+        match tokio::task::spawn_blocking(move || op(&engine)).await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+            // The runtime shut down before the operation could run.
+            Err(e) => Err(KvError::Io(io::Error::other(e))),
+        }
+    }
+}
+
+impl<E> Clone for SpawnBlocking<E> {
+    fn clone(&self) -> Self {
+        SpawnBlocking {
+            engine: Arc::clone(&self.engine),
+        }
+    }
+}
+
+impl<E: KvsEngine + Sync> AsyncKvsEngine for SpawnBlocking<E> {
+    fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send {
+        self.run(move |engine| engine.set(key, value))
+    }
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send {
+        self.run(move |engine| engine.get(key))
+    }
+    fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send {
+        self.run(move |engine| engine.remove(key))
+    }
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send {
+        self.run(|engine| engine.flush())
+    }
+}
@@ -0,0 +1,65 @@
+use crate::Result;
+use std::io::{self, Read, Write};
+
+// An export is a sequence of pairs, each written as the key and then the
+// value, both behind a 4-byte little-endian length. It ends at the end of
+// the input.
+
+/// Write every pair of `pairs` to `writer` in the export format.
+pub(crate) fn export(
+    pairs: impl Iterator<Item = Result<(String, String)>>,
+    mut writer: impl Write,
+) -> Result<()> {
+    for pair in pairs {
+        let (key, value) = pair?;
+        write_field(&mut writer, &key)?;
+        write_field(&mut writer, &value)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Hand every pair exported to `reader` to `set`, returning how many there
+/// were. Input ending part way through a pair is an error.
+pub(crate) fn import(
+    mut reader: impl Read,
+    mut set: impl FnMut(String, String) -> Result<()>,
+) -> Result<usize> {
+    let mut imported = 0;
+    while let Some(key) = read_field(&mut reader, true)? {
+        let value = read_field(&mut reader, false)?.expect("value is required");
+        set(key, value)?;
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+fn write_field(writer: &mut impl Write, field: &str) -> Result<()> {
+    let len = u32::try_from(field.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "field over 4 GiB"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(field.as_bytes())?;
+    Ok(())
+}
+
+// Read one field, or `None` if the input ends cleanly before it and
+// `at_pair_start` allows that.
+fn read_field(reader: &mut impl Read, at_pair_start: bool) -> Result<Option<String>> {
+    let mut header = [0; 4];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 && at_pair_start => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let len = u32::from_le_bytes(header) as u64;
+    let mut field = Vec::new();
+    if reader.take(len).read_to_end(&mut field)? < len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(String::from_utf8(field)?))
+}
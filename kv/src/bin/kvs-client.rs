@@ -0,0 +1,262 @@
+use kv::protocol::{Request, Response};
+use kv::{KvError, Result};
+use serde_json::Deserializer;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::iter;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::process::exit;
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+
+// How long to wait before the first retry of a connection. Each later
+// retry waits twice as long as the one before, up to `MAX_RETRY_BACKOFF`.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "kvs-client",
+            version=env!("CARGO_PKG_VERSION"),
+            author=env!("CARGO_PKG_AUTHORS"),
+            about=env!("CARGO_PKG_DESCRIPTION"))]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+    #[structopt(
+        long,
+        global = true,
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000",
+        help = "The address of the server"
+    )]
+    addr: SocketAddr,
+    #[structopt(
+        long,
+        global = true,
+        value_name = "MS",
+        help = "Give up on connecting to the server, or on a request it takes, after this long"
+    )]
+    timeout: Option<u64>,
+    #[structopt(
+        long,
+        global = true,
+        value_name = "N",
+        default_value = "0",
+        help = "Try connecting this many more times if the server is not there, waiting twice as long before each attempt"
+    )]
+    retries: u32,
+}
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(name = "get", about = "Get the string value of a given string key")]
+    Get {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+    },
+    #[structopt(name = "set", about = "Set the value of a string key to a string")]
+    Set {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(name = "VALUE", help = "The string value of the key")]
+        value: String,
+    },
+    #[structopt(name = "rm", about = "Remove a given string key")]
+    Remove {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+    },
+    #[structopt(name = "stats", about = "Print the statistics of the server's store")]
+    Stats,
+    #[structopt(name = "compact", about = "Compact the server's store")]
+    Compact,
+    #[structopt(name = "status", about = "Print what the server is serving")]
+    Status,
+    #[structopt(
+        name = "batch",
+        about = "Run commands read from stdin, one per line, over a single connection"
+    )]
+    Batch,
+}
+impl Command {
+    // The request this command sends, `None` for `batch`.
+    fn into_request(self) -> Option<Request> {
+        Some(match self {
+            Command::Get { key } => Request::Get { key },
+            Command::Set { key, value } => Request::Set { key, value },
+            Command::Remove { key } => Request::Remove { key },
+            Command::Stats => Request::Stats,
+            Command::Compact => Request::Compact,
+            Command::Status => Request::Status,
+            Command::Batch => return None,
+        })
+    }
+}
+fn main() -> Result<()> {
+    env_logger::init();
+    let opt = Opt::from_args();
+    let server = Server {
+        addr: opt.addr,
+        timeout: opt.timeout.map(Duration::from_millis),
+        retries: opt.retries,
+    };
+    let succeeded = match opt.command.into_request() {
+        Some(request) => send(&server, &request).and_then(report),
+        None => batch(&server),
+    };
+    match succeeded {
+        Ok(true) => Ok(()),
+        Ok(false) => exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+// Print `response` the way `kvs` prints the result of the same command,
+// returning whether the request succeeded.
+fn report(response: Response) -> Result<bool> {
+    match response {
+        Response::Value(Some(value)) => println!("{}", value),
+        Response::Value(None) => println!("Key not found"),
+        Response::Ok => {}
+        Response::Stats(stats) => println!("{}", serde_json::to_string_pretty(&stats)?),
+        Response::Compacted(reclaimed) => println!("Reclaimed {} bytes", reclaimed),
+        Response::Status(status) => println!("{}", serde_json::to_string_pretty(&status)?),
+        Response::Err(e) if e == KvError::KeyNotFound.to_string() => {
+            println!("{}", e);
+            return Ok(false);
+        }
+        Response::Err(e) => {
+            eprintln!("{}", e);
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+// Pipeline the commands on stdin to `server`: requests go out as they are
+// read while a second thread prints the responses in order, so neither
+// side waits on the other. Returns whether everything succeeded.
+fn batch(server: &Server) -> Result<bool> {
+    let stream = server.connect()?;
+    let reader = BufReader::new(stream.try_clone()?);
+    let responses = thread::spawn(move || -> Result<(bool, usize)> {
+        let mut succeeded = true;
+        let mut received = 0;
+        for response in Deserializer::from_reader(reader).into_iter() {
+            succeeded &= report(response.map_err(from_json)?)?;
+            received += 1;
+        }
+        Ok((succeeded, received))
+    });
+
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let mut sent = 0;
+    let mut parsed = true;
+    for (n, line) in io::stdin().lock().lines().enumerate() {
+        let line = line?;
+        let args = line.split_whitespace();
+        if args.clone().next().is_none() {
+            continue;
+        }
+        let command = Command::from_iter_safe(iter::once("kvs-client").chain(args));
+        match command.map(Command::into_request) {
+            Ok(Some(request)) => {
+                serde_json::to_writer(&mut writer, &request)?;
+                writer.flush().map_err(timed_out)?;
+                sent += 1;
+            }
+            Ok(None) => {
+                eprintln!("line {}: batch cannot be nested", n + 1);
+                parsed = false;
+            }
+            Err(e) => {
+                eprintln!("line {}: {}", n + 1, e.message);
+                parsed = false;
+            }
+        }
+    }
+    // Let the server see the end of the requests so it closes its side
+    // once the last response is out.
+    drop(writer);
+    stream.shutdown(Shutdown::Write)?;
+
+    let (succeeded, received) = responses.join().expect("response thread panicked")?;
+    if received < sent {
+        return Err(KvError::Protocol {
+            reason: "connection closed before a response".to_owned(),
+        });
+    }
+    Ok(parsed && succeeded)
+}
+// Send `request` to `server` and wait for its response.
+fn send(server: &Server, request: &Request) -> Result<Response> {
+    let stream = server.connect()?;
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    serde_json::to_writer(&mut writer, request)?;
+    writer.flush().map_err(timed_out)?;
+    let mut responses = Deserializer::from_reader(reader).into_iter();
+    match responses.next() {
+        Some(response) => response.map_err(from_json),
+        None => Err(KvError::Protocol {
+            reason: "connection closed before a response".to_owned(),
+        }),
+    }
+}
+// Where the server is and how to reach it.
+struct Server {
+    addr: SocketAddr,
+    // The longest every step on the connection may take.
+    timeout: Option<Duration>,
+    // Connection attempts to make after the first one fails.
+    retries: u32,
+}
+impl Server {
+    // Connect, retrying with exponential backoff while the server cannot
+    // be reached.
+    fn connect(&self) -> Result<TcpStream> {
+        let mut backoff = RETRY_BACKOFF;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.try_connect() {
+                Ok(stream) => return Ok(stream),
+                Err(_) if attempts <= self.retries => {
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+                }
+                Err(e) if self.retries == 0 => return Err(timed_out(e)),
+                Err(e) => {
+                    let reason = format!(
+                        "Could not connect to {} after {} attempts: {}",
+                        self.addr, attempts, e
+                    );
+                    return Err(KvError::Io(io::Error::new(e.kind(), reason)));
+                }
+            }
+        }
+    }
+    fn try_connect(&self) -> io::Result<TcpStream> {
+        let stream = match self.timeout {
+            Some(timeout) => TcpStream::connect_timeout(&self.addr, timeout),
+            None => TcpStream::connect(self.addr),
+        }?;
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+        Ok(stream)
+    }
+}
+// Report a response that could not be read, telling timeouts apart.
+fn from_json(err: serde_json::Error) -> KvError {
+    match err.is_io() {
+        true => timed_out(err.into()),
+        false => err.into(),
+    }
+}
+// Report an expired socket timeout as `KvError::Timeout`.
+fn timed_out(err: io::Error) -> KvError {
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => KvError::Timeout,
+        _ => KvError::Io(err),
+    }
+}
@@ -0,0 +1,428 @@
+use kv::metrics::ServerMetrics;
+use kv::protocol::{Request, Response, ServerStatus};
+use kv::{
+    GroupCommit, KvError, KvStore, KvStoreOptions, KvsEngine, Result, SharedQueueThreadPool,
+    SledKvsEngine, ThreadPool,
+};
+use log::{debug, error, info};
+use serde::Deserialize;
+use serde_json::Deserializer;
+use std::collections::HashMap;
+use std::env::current_dir;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+// How often the shutdown watcher checks whether a signal arrived.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// How long a metrics scrape may take to send its request.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Set once SIGINT or SIGTERM arrives.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "kvs-server",
+            version=env!("CARGO_PKG_VERSION"),
+            author=env!("CARGO_PKG_AUTHORS"),
+            about=env!("CARGO_PKG_DESCRIPTION"))]
+struct Opt {
+    #[structopt(
+        long,
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000",
+        help = "The address to listen on"
+    )]
+    addr: SocketAddr,
+    #[structopt(
+        long,
+        value_name = "ENGINE-NAME",
+        possible_values = &["kvs", "sled"],
+        help = "The storage engine to serve, by default the one the directory was last served with"
+    )]
+    engine: Option<String>,
+    #[structopt(
+        long,
+        value_name = "PROTOCOL",
+        default_value = "kvs",
+        possible_values = &["kvs", "resp"],
+        help = "The wire protocol to speak, `resp` for Redis clients"
+    )]
+    protocol: String,
+    #[structopt(
+        long,
+        value_name = "N",
+        help = "The number of connections to serve at once, by default one per CPU"
+    )]
+    threads: Option<u32>,
+    #[structopt(
+        long,
+        value_name = "PATH",
+        parse(from_os_str),
+        help = "The directory holding the data, by default the current one"
+    )]
+    log_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "IP:PORT",
+        help = "Also serve Prometheus metrics over HTTP at /metrics on this address"
+    )]
+    metrics_addr: Option<SocketAddr>,
+    #[structopt(
+        long,
+        value_name = "MS",
+        help = "Sync writes arriving within this many milliseconds of each other to disk \
+                together, answering each once its batch is synced"
+    )]
+    group_commit: Option<u64>,
+    #[structopt(
+        long,
+        value_name = "N",
+        default_value = "128",
+        help = "Sync a group commit batch early once it holds this many writes"
+    )]
+    group_commit_batch: usize,
+}
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let opt = Opt::from_args();
+    let dir = match &opt.log_dir {
+        Some(dir) => dir.clone(),
+        None => current_dir()?,
+    };
+    let engine_name = match &opt.engine {
+        Some(engine) => engine.clone(),
+        None => kv::recorded_engine(&dir)?.unwrap_or_else(|| "kvs".to_owned()),
+    };
+    info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    info!("Storage engine: {}", engine_name);
+    if opt.protocol == "resp" && engine_name != "kvs" {
+        error!("The resp protocol needs the kvs engine");
+        exit(1);
+    }
+    if opt.group_commit.is_some() && (engine_name != "kvs" || opt.protocol == "resp") {
+        error!("Group commit needs the kvs engine and protocol");
+        exit(1);
+    }
+    // With group commit the writes are synced in batches instead.
+    let options = KvStoreOptions {
+        sync_sets: opt.group_commit.is_none(),
+        sync_removes: opt.group_commit.is_none(),
+        ..KvStoreOptions::default()
+    };
+
+    // Only `KvStore` keeps statistics and speaks RESP, so keep hold of it.
+    let opened = match engine_name.as_str() {
+        "sled" => {
+            SledKvsEngine::open(&dir).map(|engine| (Box::new(engine) as Box<dyn KvsEngine>, None))
+        }
+        _ => KvStore::open_with_options(&dir, options)
+            .map(|store| (Box::new(store.clone()) as Box<dyn KvsEngine>, Some(store))),
+    };
+    let (engine, store) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("{}", e);
+            exit(1);
+        }
+    };
+    let threads = match opt.threads {
+        Some(threads) => threads,
+        None => thread::available_parallelism().map_or(1, |n| n.get() as u32),
+    };
+    let pool = SharedQueueThreadPool::new(threads)?;
+    let info = Arc::new(ServerInfo {
+        engine: engine_name,
+        started: Instant::now(),
+    });
+    let metrics = Arc::new(ServerMetrics::default());
+    let group_commit = opt.group_commit.map(|window| {
+        info!("Group commit window: {}ms", window);
+        Arc::new(GroupCommit::new(
+            Duration::from_millis(window),
+            opt.group_commit_batch,
+        ))
+    });
+    if let Some(metrics_addr) = opt.metrics_addr {
+        serve_metrics(
+            TcpListener::bind(metrics_addr)?,
+            metrics.clone(),
+            store.clone(),
+        );
+        info!("Serving metrics on http://{}/metrics", metrics_addr);
+    }
+    let listener = TcpListener::bind(opt.addr)?;
+    watch_for_shutdown(listener.local_addr()?);
+    info!("Listening on {}", opt.addr);
+    let connections = Arc::new(Connections::default());
+    for stream in listener.incoming() {
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            break;
+        }
+        let engine = engine.clone();
+        let store = store.clone();
+        let metrics = metrics.clone();
+        let group_commit = group_commit.clone();
+        let info = info.clone();
+        let resp = opt.protocol == "resp";
+        // Track the connection from here, so shutdown also waits for the
+        // ones still queued on the pool.
+        let accepted = stream.map_err(KvError::from).and_then(|stream| {
+            debug!("Accepted connection from {}", stream.peer_addr()?);
+            Ok((Connections::open(&connections, &stream)?, stream))
+        });
+        pool.spawn(move || {
+            let result = accepted.and_then(|(_open, stream)| match &store {
+                Some(store) if resp => kv::resp::serve(
+                    store,
+                    BufReader::new(stream.try_clone()?),
+                    BufWriter::new(stream),
+                ),
+                _ => serve(
+                    engine.as_ref(),
+                    store.as_ref(),
+                    &info,
+                    &metrics,
+                    group_commit.as_deref(),
+                    stream,
+                ),
+            });
+            if let Err(e) = result {
+                error!("Connection failed: {}", e);
+            }
+        });
+    }
+    // Let every connection finish the requests it has sent, then make sure
+    // the last writes are on disk.
+    info!("Shutting down");
+    connections.close_all();
+    drop(pool);
+    engine.flush()?;
+    info!("Stopped");
+    Ok(())
+}
+// Make SIGINT and SIGTERM stop the server, by waking the accept loop on
+// `addr` once one of them arrives.
+fn watch_for_shutdown(addr: SocketAddr) {
+    #[cfg(unix)]
+    {
+        extern "C" fn on_signal(_: libc::c_int) {
+            SHUTDOWN.store(true, Ordering::SeqCst);
+        }
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            // SAFETY: the handler only stores to an atomic, which is
+            // async-signal-safe.
+            unsafe {
+                libc::signal(
+                    signal,
+                    on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+                );
+            }
+        }
+    }
+    thread::spawn(move || {
+        while !SHUTDOWN.load(Ordering::SeqCst) {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        // Failing to connect means the accept loop is gone anyway.
+        let _ = TcpStream::connect(addr);
+    });
+}
+// What `Request::Status` reports besides the store's state.
+struct ServerInfo {
+    engine: String,
+    started: Instant,
+}
+// The connections being served, so shutdown can wait for them.
+#[derive(Default)]
+struct Connections {
+    open: Mutex<OpenStreams>,
+    closed: Condvar,
+}
+#[derive(Default)]
+struct OpenStreams {
+    next_id: u64,
+    streams: HashMap<u64, TcpStream>,
+}
+impl Connections {
+    // Track `stream` until the returned guard drops.
+    fn open(connections: &Arc<Connections>, stream: &TcpStream) -> Result<OpenConnection> {
+        let mut open = connections.open.lock().unwrap();
+        let id = open.next_id;
+        open.next_id += 1;
+        open.streams.insert(id, stream.try_clone()?);
+        Ok(OpenConnection {
+            connections: connections.clone(),
+            id,
+        })
+    }
+    // Stop reading from every connection, so each ends after the requests
+    // it has already sent, and wait until all are done.
+    fn close_all(&self) {
+        let mut open = self.open.lock().unwrap();
+        for stream in open.streams.values() {
+            // The peer may have gone already.
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        while !open.streams.is_empty() {
+            open = self.closed.wait(open).unwrap();
+        }
+    }
+}
+struct OpenConnection {
+    connections: Arc<Connections>,
+    id: u64,
+}
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        open.streams.remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
+// Answer each request on the connection until the client closes it, counting
+// them in `metrics`. Stats and compaction need `store`, which is there when
+// the engine is a `KvStore`. With `group_commit`, a write is only answered
+// once its batch is synced to disk.
+//
+// A failed request, or a message that is not a request at all, is answered
+// with `Response::Err` and the connection carries on. Only JSON that does
+// not parse ends it, as there is no telling where the next message starts.
+fn serve(
+    engine: &dyn KvsEngine,
+    store: Option<&KvStore>,
+    info: &ServerInfo,
+    metrics: &ServerMetrics,
+    group_commit: Option<&GroupCommit>,
+    stream: TcpStream,
+) -> Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    for message in Deserializer::from_reader(reader).into_iter::<serde_json::Value>() {
+        let request = match message.map(Request::deserialize) {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) => {
+                debug!("Invalid request: {}", e);
+                send(&mut writer, &invalid_request(&e))?;
+                continue;
+            }
+            Err(e) if e.is_syntax() => {
+                send(&mut writer, &invalid_request(&e))?;
+                return Err(e.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let response = match &request {
+            Request::Get { key } => {
+                debug!("Get {}", key);
+                engine.get(key.clone()).map(Response::Value)
+            }
+            Request::Set { key, value } => {
+                debug!("Set {}", key);
+                engine
+                    .set(key.clone(), value.clone())
+                    .map(|()| Response::Ok)
+            }
+            Request::Remove { key } => {
+                debug!("Remove {}", key);
+                engine.remove(key.clone()).map(|()| Response::Ok)
+            }
+            Request::Stats => Ok(match store {
+                Some(store) => Response::Stats(store.stats()),
+                None => Response::Err("The sled engine keeps no statistics".to_owned()),
+            }),
+            Request::Compact => {
+                debug!("Compact");
+                match store {
+                    Some(store) => store
+                        .compact()
+                        .map(|result| Response::Compacted(result.reclaimed_bytes)),
+                    None => Ok(Response::Err(
+                        "The sled engine cannot be compacted".to_owned(),
+                    )),
+                }
+            }
+            Request::Status => {
+                let stats = store.map(KvStore::stats);
+                Ok(Response::Status(ServerStatus {
+                    engine: info.engine.clone(),
+                    uptime_secs: info.started.elapsed().as_secs(),
+                    live_keys: stats.as_ref().map(|stats| stats.live_keys),
+                    uncompacted_bytes: stats.as_ref().map(|stats| stats.uncompacted_bytes),
+                }))
+            }
+        };
+        let response = match (response, group_commit) {
+            (Ok(Response::Ok), Some(group_commit)) => {
+                group_commit.commit(engine).map(|()| Response::Ok)
+            }
+            (response, _) => response,
+        };
+        let response = response.unwrap_or_else(|e| Response::Err(e.to_string()));
+        metrics.record(&request, &response);
+        send(&mut writer, &response)?;
+    }
+    Ok(())
+}
+// The answer to a message that could not be read as a request.
+fn invalid_request(err: &serde_json::Error) -> Response {
+    let err = KvError::Protocol {
+        reason: format!("invalid request: {}", err),
+    };
+    Response::Err(err.to_string())
+}
+fn send(writer: &mut impl Write, response: &Response) -> Result<()> {
+    serde_json::to_writer(&mut *writer, response)?;
+    writer.flush()?;
+    Ok(())
+}
+// Serve the metrics over HTTP on `listener`, answering a GET of `/metrics`
+// and nothing else. One scrape is answered at a time.
+fn serve_metrics(listener: TcpListener, metrics: Arc<ServerMetrics>, store: Option<KvStore>) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(KvError::from)
+                .and_then(|stream| answer_scrape(stream, &metrics, store.as_ref()));
+            if let Err(e) = result {
+                error!("Metrics request failed: {}", e);
+            }
+        }
+    });
+}
+fn answer_scrape(
+    mut stream: TcpStream,
+    metrics: &ServerMetrics,
+    store: Option<&KvStore>,
+) -> Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers make no difference to the answer.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render(store)),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
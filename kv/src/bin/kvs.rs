@@ -1,6 +1,8 @@
 // extern crate clap;
 // use clap::{App, Arg, SubCommand};
 use kv::{KvStore, Result};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
 use std::{env::current_dir, process::exit};
 use structopt::StructOpt;
 
@@ -10,6 +12,14 @@ use structopt::StructOpt;
             author=env!("CARGO_PKG_AUTHORS"),
             about=env!("CARGO_PKG_DESCRIPTION"))]
 struct Opt {
+    #[structopt(
+        long,
+        global = true,
+        value_name = "PATH",
+        parse(from_os_str),
+        help = "The directory holding the store, by default the current one"
+    )]
+    log_dir: Option<PathBuf>,
     #[structopt(subcommand)]
     command: Command,
 }
@@ -19,6 +29,14 @@ enum Command {
     Get {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
+        #[structopt(
+            long,
+            value_name = "FORMAT",
+            default_value = "plain",
+            possible_values = &["plain", "json"],
+            help = "Print the bare value, or a JSON object with the key and its value or null"
+        )]
+        format: String,
     },
     #[structopt(name = "set", about = "Set the value of a string key to a string")]
     Set {
@@ -31,28 +49,47 @@ enum Command {
     Remove {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
+        #[structopt(long = "if-exists", help = "Succeed silently if the key is not there")]
+        if_exists: bool,
     },
+    #[structopt(name = "compact", about = "Compact the logs, dropping stale records")]
+    Compact,
+    #[structopt(
+        name = "list",
+        alias = "scan",
+        about = "Print every key and its value, tab-separated, in key order"
+    )]
+    List,
 }
 fn main() -> Result<()> {
+    env_logger::init();
     let opt = Opt::from_args();
+    let dir = match opt.log_dir {
+        Some(dir) => dir,
+        None => current_dir()?,
+    };
     match opt.command {
-        Command::Get { key } => {
-            let mut store = KvStore::open(current_dir()?)?;
+        Command::Get { key, format } => {
+            let store = KvStore::open(&dir)?;
 
-            if let Some(value) = store.get(key)? {
-                println!("{}", value);
-            } else {
-                println!("Key not found");
+            match store.get(key.clone()) {
+                Ok(value) if format == "json" => {
+                    println!("{}", serde_json::json!({ "key": key, "value": value }))
+                }
+                Ok(Some(value)) => println!("{}", value),
+                Ok(None) => println!("Key not found"),
+                Err(e) => return Err(e),
             }
         }
         Command::Set { key, value } => {
-            let mut store = KvStore::open(current_dir()?)?;
+            let store = KvStore::open(&dir)?;
             store.set(key, value)?;
         }
-        Command::Remove { key } => {
-            let mut store = KvStore::open(current_dir()?)?;
+        Command::Remove { key, if_exists } => {
+            let store = KvStore::open(&dir)?;
             match store.remove(key) {
                 Ok(()) => {}
+                Err(kv::KvError::KeyNotFound) if if_exists => {}
                 Err(kv::KvError::KeyNotFound) => {
                     println!("Key not found");
                     exit(1);
@@ -60,6 +97,20 @@ fn main() -> Result<()> {
                 Err(e) => return Err(e),
             };
         }
+        Command::Compact => {
+            let store = KvStore::open(&dir)?;
+            let result = store.compact()?;
+            println!("Reclaimed {} bytes", result.reclaimed_bytes);
+        }
+        Command::List => {
+            let store = KvStore::open(&dir)?;
+            let mut out = BufWriter::new(io::stdout().lock());
+            for pair in store.scan()? {
+                let (key, value) = pair?;
+                writeln!(out, "{}\t{}", key, value)?;
+            }
+            out.flush()?;
+        }
     }
     Ok(())
 }
@@ -0,0 +1,53 @@
+use crate::kv::{IndexEntry, Record};
+use crate::Result;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// Size of the fixed-width trailer closing a file with a checkpoint. It is
+// padded with JSON whitespace so log readers simply skip it.
+const TRAILER_LEN: u64 = 64;
+
+/// Append a checkpoint of `entries`, one for every live record in the file,
+/// at offset `pos` of `writer`. Returns the bytes written.
+pub(crate) fn write(writer: &mut impl Write, pos: u64, entries: Vec<IndexEntry>) -> Result<u64> {
+    let mut footer = serde_json::to_vec(&Record::Checkpoint { entries })?;
+    let trailer = serde_json::to_vec(&Record::CheckpointAt(pos))?;
+    footer.extend_from_slice(&trailer);
+    footer.resize(
+        footer.len() + TRAILER_LEN as usize - trailer.len() - 1,
+        b' ',
+    );
+    footer.push(b'\n');
+    writer.write_all(&footer)?;
+    Ok(footer.len() as u64)
+}
+
+/// Find where the checkpoint of a log file starts, if it ends with one.
+/// Everything before that offset is ordinary records.
+pub(crate) fn find(reader: &mut (impl Read + Seek)) -> Result<Option<u64>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    if len < TRAILER_LEN {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(len - TRAILER_LEN))?;
+    let mut trailer = vec![0; TRAILER_LEN as usize];
+    reader.read_exact(&mut trailer)?;
+    // Anything other than a well-formed trailer means there is none.
+    match serde_json::from_slice(&trailer) {
+        Ok(Record::CheckpointAt(pos)) if pos < len - TRAILER_LEN => Ok(Some(pos)),
+        _ => Ok(None),
+    }
+}
+
+/// Load the entries held by the checkpoint of a log file.
+pub(crate) fn read(reader: &mut (impl Read + Seek)) -> Result<Option<Vec<IndexEntry>>> {
+    let pos = match find(reader)? {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Record>();
+    match stream.next() {
+        Some(Ok(Record::Checkpoint { entries })) => Ok(Some(entries)),
+        _ => Ok(None),
+    }
+}
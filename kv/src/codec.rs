@@ -0,0 +1,198 @@
+use crate::kv::Record;
+use crate::{KvError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::io::{self, Read, Write};
+
+/// How records are encoded in the log files.
+///
+/// A store directory notes the format it was created with next to its
+/// engine, and is always read in that format, whatever the options it is
+/// opened with say. Directories from before formats were noted, and stores
+/// opened with `open_file`, have to be opened with the format they were
+/// written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordFormat {
+    /// One JSON value per record, readable with ordinary tools.
+    #[default]
+    Json,
+    /// `bincode` records behind a 4-byte little-endian length. Large values
+    /// take less room and are cheaper to decode.
+    Bincode,
+    /// MessagePack records behind a 4-byte little-endian length, more
+    /// compact than JSON while still self-describing.
+    MessagePack,
+}
+
+impl RecordFormat {
+    /// Write `record`, either a `Record` or a `CommandRecord`, to `writer`.
+    pub(crate) fn write(self, writer: impl Write, record: &impl Serialize) -> Result<()> {
+        match self {
+            RecordFormat::Json => JsonCodec.write(writer, record),
+            RecordFormat::Bincode => BincodeCodec.write(writer, record),
+            RecordFormat::MessagePack => MessagePackCodec.write(writer, record),
+        }
+    }
+    /// Read the next record from `reader` along with the bytes it took, or
+    /// `None` once the input is used up.
+    pub(crate) fn read(self, reader: impl Read) -> Option<Decoded> {
+        match self {
+            RecordFormat::Json => JsonCodec.read(reader),
+            RecordFormat::Bincode => BincodeCodec.read(reader),
+            RecordFormat::MessagePack => MessagePackCodec.read(reader),
+        }
+    }
+    /// The name of the format, as manifests and engine markers spell it.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            RecordFormat::Json => "json",
+            RecordFormat::Bincode => "bincode",
+            RecordFormat::MessagePack => "messagepack",
+        }
+    }
+    pub(crate) fn from_name(name: &str) -> Option<RecordFormat> {
+        [
+            RecordFormat::Json,
+            RecordFormat::Bincode,
+            RecordFormat::MessagePack,
+        ]
+        .into_iter()
+        .find(|format| format.name() == name)
+    }
+}
+
+pub(crate) type Decoded = std::result::Result<(Record, u64), DecodeError>;
+
+/// A record that could not be decoded.
+#[derive(Debug)]
+pub(crate) struct DecodeError {
+    /// Whether the input ended part way through the record.
+    pub(crate) truncated: bool,
+    error: KvError,
+}
+
+impl From<DecodeError> for KvError {
+    fn from(err: DecodeError) -> KvError {
+        err.error
+    }
+}
+
+// One encoding of log records.
+trait RecordCodec {
+    fn write(&self, writer: impl Write, record: &impl Serialize) -> Result<()>;
+    fn read(&self, reader: impl Read) -> Option<Decoded>;
+}
+
+struct JsonCodec;
+
+impl RecordCodec for JsonCodec {
+    fn write(&self, writer: impl Write, record: &impl Serialize) -> Result<()> {
+        Ok(serde_json::to_writer(writer, record)?)
+    }
+    fn read(&self, reader: impl Read) -> Option<Decoded> {
+        // Records are objects or strings, which end on a byte of their own,
+        // so a fresh deserializer never reads past the one it returns.
+        let mut stream = Deserializer::from_reader(reader).into_iter::<Record>();
+        let record = stream.next()?;
+        Some(match record {
+            Ok(record) => Ok((record, stream.byte_offset() as u64)),
+            Err(e) => Err(DecodeError {
+                truncated: e.is_eof(),
+                error: e.into(),
+            }),
+        })
+    }
+}
+
+// Bytes in the length header of a bincode or MessagePack record.
+const FRAME_HEADER_LEN: u64 = 4;
+
+struct BincodeCodec;
+
+impl RecordCodec for BincodeCodec {
+    fn write(&self, writer: impl Write, record: &impl Serialize) -> Result<()> {
+        write_frame(writer, &bincode::serialize(record)?)
+    }
+    fn read(&self, reader: impl Read) -> Option<Decoded> {
+        Some(read_frame(reader)?.and_then(|(payload, len)| {
+            bincode::deserialize(&payload)
+                .map(|record| (record, len))
+                .map_err(|e| invalid(e.into()))
+        }))
+    }
+}
+
+struct MessagePackCodec;
+
+impl RecordCodec for MessagePackCodec {
+    // Structs are written as maps, so fields left out of older records
+    // still take their defaults.
+    fn write(&self, writer: impl Write, record: &impl Serialize) -> Result<()> {
+        write_frame(writer, &rmp_serde::to_vec_named(record)?)
+    }
+    fn read(&self, reader: impl Read) -> Option<Decoded> {
+        Some(read_frame(reader)?.and_then(|(payload, len)| {
+            rmp_serde::from_slice(&payload)
+                .map(|record| (record, len))
+                .map_err(|e| invalid(e.into()))
+        }))
+    }
+}
+
+// Write `payload` behind its length.
+fn write_frame(mut writer: impl Write, payload: &[u8]) -> Result<()> {
+    let len = u32::try_from(payload.len()).map_err(|_| Box::new(bincode::ErrorKind::SizeLimit))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+// Read the payload of the next frame along with the bytes the frame took,
+// or `None` once the input is used up.
+fn read_frame(mut reader: impl Read) -> Option<std::result::Result<(Vec<u8>, u64), DecodeError>> {
+    let mut header = [0; FRAME_HEADER_LEN as usize];
+    match read_full(&mut reader, &mut header) {
+        Ok(0) => return None,
+        Ok(n) if n < header.len() => return Some(Err(truncated())),
+        Ok(_) => {}
+        Err(e) => return Some(Err(invalid(e.into()))),
+    }
+    let len = u32::from_le_bytes(header) as u64;
+    // Grow the buffer as bytes arrive rather than trusting the header
+    // with an allocation up front.
+    let mut payload = Vec::new();
+    match reader.take(len).read_to_end(&mut payload) {
+        Ok(n) if (n as u64) < len => Some(Err(truncated())),
+        Ok(_) => Some(Ok((payload, FRAME_HEADER_LEN + len))),
+        Err(e) => Some(Err(invalid(e.into()))),
+    }
+}
+
+// Fill as much of `buf` as `reader` has left, returning how much that was.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn truncated() -> DecodeError {
+    DecodeError {
+        truncated: true,
+        error: io::Error::from(io::ErrorKind::UnexpectedEof).into(),
+    }
+}
+
+fn invalid(error: KvError) -> DecodeError {
+    DecodeError {
+        truncated: false,
+        error,
+    }
+}
@@ -0,0 +1,135 @@
+use crate::kv::generate_id;
+use crate::{FileOp, KvError, KvStore, RecordFormat, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// The file naming the engine whose data a directory holds, followed for a
+// `KvStore` by the format of its records on a second line.
+const ENGINE_MARKER: &str = "engine";
+
+/// A pluggable key/value storage backend.
+///
+/// Every method takes `&self`, so one engine can be shared by handing each
+/// user a clone. The trait is object-safe: a server can pick its engine at
+/// startup and hold it as a `Box<dyn KvsEngine>`, which `clone_engine`
+/// makes cloneable.
+pub trait KvsEngine: Send + 'static {
+    /// Set `key` to `value`, overwriting any previous value.
+    fn set(&self, key: String, value: String) -> Result<()>;
+    /// Get the value of `key`, or `None` if it is absent.
+    fn get(&self, key: String) -> Result<Option<String>>;
+    /// Remove `key`, failing with `KvError::KeyNotFound` if it is absent.
+    fn remove(&self, key: String) -> Result<()>;
+    /// Write out anything still buffered so it survives a restart.
+    fn flush(&self) -> Result<()>;
+    /// Write out anything still buffered and sync it to disk, so it also
+    /// survives the machine going down. Engines whose `flush` already does
+    /// that can leave this as it is.
+    fn sync(&self) -> Result<()> {
+        self.flush()
+    }
+    /// A new handle to the same engine.
+    fn clone_engine(&self) -> Box<dyn KvsEngine>;
+}
+
+impl Clone for Box<dyn KvsEngine> {
+    fn clone(&self) -> Self {
+        self.clone_engine()
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+    fn flush(&self) -> Result<()> {
+        KvStore::flush(self)
+    }
+    fn sync(&self) -> Result<()> {
+        KvStore::sync(self)
+    }
+    fn clone_engine(&self) -> Box<dyn KvsEngine> {
+        Box::new(self.clone())
+    }
+}
+
+/// The name of the engine whose data `dir` holds, as recorded the first
+/// time an engine opened it. `None` for a directory no engine has used.
+pub fn recorded_engine(dir: &Path) -> Result<Option<String>> {
+    Ok(read_marker(dir)?.map(|(engine, _)| engine))
+}
+
+// The record format the `KvStore` data in `dir` is in, if its marker
+// notes one.
+pub(crate) fn recorded_format(dir: &Path) -> Result<Option<RecordFormat>> {
+    match read_marker(dir)? {
+        Some((_, Some(name))) => match RecordFormat::from_name(&name) {
+            Some(format) => Ok(Some(format)),
+            None => Err(KvError::UnknownRecordFormat { name }),
+        },
+        _ => Ok(None),
+    }
+}
+
+// Claim `dir` for `KvStore` data and, if it holds no logs and notes no
+// format yet, note on its marker that its records are written in `format`.
+pub(crate) fn claim_kvs_dir(dir: &Path, format: RecordFormat) -> Result<()> {
+    claim_dir(dir, "kvs")?;
+    if recorded_format(dir)?.is_none() && generate_id(dir)?.is_empty() {
+        write_marker(dir, &format!("kvs\n{}", format.name()))?;
+    }
+    Ok(())
+}
+
+// The engine named by the marker of `dir` and the detail on its second
+// line, if there is one.
+fn read_marker(dir: &Path) -> Result<Option<(String, Option<String>)>> {
+    let marker = match fs::read_to_string(marker_path(dir)) {
+        Ok(marker) => marker,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = marker.lines().map(str::trim);
+    let engine = lines.next().unwrap_or_default().to_owned();
+    let detail = lines
+        .next()
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned);
+    Ok(Some((engine, detail)))
+}
+
+// Record that `dir` holds data of `engine`, failing if another engine's
+// data is there already.
+pub(crate) fn claim_dir(dir: &Path, engine: &str) -> Result<()> {
+    let found = match recorded_engine(dir)? {
+        Some(found) => found,
+        // Logs predating the marker are `KvStore` data.
+        None if !generate_id(dir)?.is_empty() => "kvs".to_owned(),
+        None => return write_marker(dir, engine),
+    };
+    if found != engine {
+        return Err(KvError::EngineMismatch {
+            requested: engine.to_owned(),
+            found,
+        });
+    }
+    if !marker_path(dir).exists() {
+        write_marker(dir, engine)?;
+    }
+    Ok(())
+}
+fn write_marker(dir: &Path, engine: &str) -> Result<()> {
+    let path = marker_path(dir);
+    fs::write(&path, engine).map_err(KvError::file(FileOp::Write, path))
+}
+
+fn marker_path(dir: &Path) -> PathBuf {
+    dir.join(ENGINE_MARKER)
+}
@@ -1,19 +1,117 @@
+// `failure_derive` expands to impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
 use failure::Fail;
+use std::fmt;
 use std::io;
+use std::path::PathBuf;
 
 #[derive(Fail, Debug)]
 pub enum KvError {
     #[fail(display = "{}", _0)]
     Io(#[cause] io::Error),
 
-    #[fail(display = "{}",_0)]
+    #[fail(display = "{}", _0)]
+    File(#[cause] FileError),
+
+    #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
 
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
+
+    #[fail(display = "{}", _0)]
+    MessagePackEncode(#[cause] rmp_serde::encode::Error),
+
+    #[fail(display = "{}", _0)]
+    MessagePackDecode(#[cause] rmp_serde::decode::Error),
+
     #[fail(display = "Key not found")]
     KeyNotFound,
 
     #[fail(display = "Unexpected commmand type")]
     UnexpectedCommandType,
+
+    #[fail(display = "No reader for log file {}", _0)]
+    ReaderNotFound(u64),
+
+    #[fail(
+        display = "Position {} with length {} is out of range in log file {}",
+        pos, len, file_id
+    )]
+    InvalidPosition { file_id: u64, pos: u64, len: u64 },
+
+    #[fail(display = "Recovery exceeded its time budget")]
+    RecoveryTimeout,
+
+    #[fail(display = "Compaction is disabled on a filtered store")]
+    FilteredStore,
+
+    #[fail(display = "Compaction is disabled in audit mode")]
+    AuditMode,
+
+    #[fail(display = "Multiple log files share id {}", id)]
+    DuplicateFileId { id: u64 },
+
+    #[fail(display = "Log file {} is missing or empty", id)]
+    MissingFile { id: u64 },
+
+    #[fail(display = "Store is read-only")]
+    ReadOnly,
+
+    #[fail(display = "Invalid key: {:?}", key)]
+    InvalidKey { key: String },
+
+    #[fail(display = "Files to coalesce must be adjacent, inactive log files")]
+    InvalidCoalesce,
+
+    #[fail(display = "Log file changed while a record was being read")]
+    ConcurrentModification,
+
+    #[fail(
+        display = "Index needs {} bytes, over the memory budget of {}",
+        needed, budget
+    )]
+    MemoryBudgetExceeded { needed: usize, budget: usize },
+
+    #[fail(display = "Protocol error: {}", reason)]
+    Protocol { reason: String },
+
+    #[fail(display = "Request timed out")]
+    Timeout,
+
+    #[fail(display = "{}", _0)]
+    Sled(#[cause] sled::Error),
+
+    #[fail(display = "Value is not valid UTF-8: {}", _0)]
+    Utf8(#[cause] std::string::FromUtf8Error),
+
+    #[fail(
+        display = "Directory holds data of the {} engine, not {}",
+        found, requested
+    )]
+    EngineMismatch { requested: String, found: String },
+
+    #[fail(display = "Record does not match its checksum")]
+    CorruptRecord,
+
+    #[fail(display = "Key of {} bytes is over the limit of {}", size, max)]
+    KeyTooLarge { size: usize, max: usize },
+
+    #[fail(display = "Value of {} bytes is over the limit of {}", size, max)]
+    ValueTooLarge { size: usize, max: usize },
+
+    #[fail(display = "Directory holds {} shards, not {}", found, requested)]
+    ShardCountMismatch { requested: usize, found: usize },
+
+    #[fail(display = "Directory is in use by another writable store")]
+    Locked,
+
+    #[fail(display = "Directory records its logs in the unknown format {}", name)]
+    UnknownRecordFormat { name: String },
+
+    #[fail(display = "Not supported by a store that indexes key hashes")]
+    HashedKeys,
 }
 
 impl From<io::Error> for KvError {
@@ -26,5 +124,88 @@ impl From<serde_json::Error> for KvError {
         KvError::Serde(err)
     }
 }
+impl From<bincode::Error> for KvError {
+    fn from(err: bincode::Error) -> KvError {
+        KvError::Bincode(err)
+    }
+}
+impl From<rmp_serde::encode::Error> for KvError {
+    fn from(err: rmp_serde::encode::Error) -> KvError {
+        KvError::MessagePackEncode(err)
+    }
+}
+impl From<rmp_serde::decode::Error> for KvError {
+    fn from(err: rmp_serde::decode::Error) -> KvError {
+        KvError::MessagePackDecode(err)
+    }
+}
+impl From<sled::Error> for KvError {
+    fn from(err: sled::Error) -> KvError {
+        KvError::Sled(err)
+    }
+}
+impl From<std::string::FromUtf8Error> for KvError {
+    fn from(err: std::string::FromUtf8Error) -> KvError {
+        KvError::Utf8(err)
+    }
+}
+
+/// An I/O error on a store file, naming the file and what was being done
+/// to it.
+#[derive(Debug)]
+pub struct FileError {
+    pub op: FileOp,
+    pub path: PathBuf,
+    pub source: io::Error,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Failed to {} {}: {}",
+            self.op,
+            self.path.display(),
+            self.source
+        )
+    }
+}
+
+impl Fail for FileError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        Some(&self.source)
+    }
+}
+
+/// The file operation a `FileError` failed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOp {
+    Create,
+    Open,
+    Read,
+    Seek,
+    Write,
+    Lock,
+}
+
+impl fmt::Display for FileOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FileOp::Create => "create",
+            FileOp::Open => "open",
+            FileOp::Read => "read",
+            FileOp::Seek => "seek",
+            FileOp::Write => "write",
+            FileOp::Lock => "lock",
+        })
+    }
+}
+
+impl KvError {
+    /// Wrap an error from `op` on the file at `path`.
+    pub(crate) fn file(op: FileOp, path: PathBuf) -> impl FnOnce(io::Error) -> KvError {
+        move |source| KvError::File(FileError { op, path, source })
+    }
+}
 
-pub type Result<T> = std::result::Result<T,KvError>;
+pub type Result<T> = std::result::Result<T, KvError>;
@@ -0,0 +1,110 @@
+use crate::{KvError, KvsEngine, Result};
+use std::io;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Syncs the writes of many threads to disk together, so they share one
+/// `KvsEngine::sync` rather than paying for one each.
+///
+/// A writer makes its write with the engine's own flushing and syncing
+/// turned off, for a `KvStore` by opening it without `sync_sets` and
+/// `sync_removes` and with `FsyncPolicy::Never`, then calls `commit`. The
+/// first writer to commit waits up to `window` for others to join, or
+/// until `max_batch` writes are waiting, then syncs for all of them.
+pub struct GroupCommit {
+    window: Duration,
+    max_batch: u64,
+    state: Mutex<CommitState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct CommitState {
+    // Writes committed so far, each numbering the write it covers.
+    written: u64,
+    // Every write up to this one has been synced, or failed to be.
+    synced: u64,
+    // Whether a writer is gathering or syncing a batch.
+    syncing: bool,
+    // Failed syncs whose writers have not all heard yet.
+    failures: Vec<Failure>,
+}
+
+struct Failure {
+    // The writes the sync covered.
+    from: u64,
+    upto: u64,
+    // Writers still to be told.
+    unreported: u64,
+    reason: String,
+}
+
+impl GroupCommit {
+    /// Batch the writes committed within `window` of each other, up to
+    /// `max_batch` at a time.
+    pub fn new(window: Duration, max_batch: usize) -> GroupCommit {
+        GroupCommit {
+            window,
+            max_batch: max_batch.max(1) as u64,
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        }
+    }
+    /// Return once the write just made to `engine` has been synced.
+    ///
+    /// If the sync fails, every write it covered gets the error.
+    pub fn commit(&self, engine: &dyn KvsEngine) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        let ticket = state.written;
+        // A gathering writer may be waiting for the batch to fill.
+        self.changed.notify_all();
+        while state.synced < ticket {
+            if state.syncing {
+                state = self.changed.wait(state).unwrap();
+                continue;
+            }
+            state.syncing = true;
+            let deadline = Instant::now() + self.window;
+            loop {
+                let now = Instant::now();
+                if state.written - state.synced >= self.max_batch || now >= deadline {
+                    break;
+                }
+                state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+            }
+            let (from, upto) = (state.synced + 1, state.written);
+            drop(state);
+            let result = engine.sync();
+            state = self.state.lock().unwrap();
+            state.synced = upto;
+            state.syncing = false;
+            if let Err(e) = &result {
+                if upto > from {
+                    state.failures.push(Failure {
+                        from,
+                        upto,
+                        unreported: upto - from,
+                        reason: e.to_string(),
+                    });
+                }
+            }
+            self.changed.notify_all();
+            return result;
+        }
+        let failures = &mut state.failures;
+        let i = match failures
+            .iter()
+            .position(|failure| (failure.from..=failure.upto).contains(&ticket))
+        {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        failures[i].unreported -= 1;
+        let reason = match failures[i].unreported {
+            0 => failures.swap_remove(i).reason,
+            _ => failures[i].reason.clone(),
+        };
+        Err(KvError::Io(io::Error::other(reason)))
+    }
+}
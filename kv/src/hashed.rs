@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// A 64-bit hash of `key`, for `KvStoreOptions::hashed_keys`.
+///
+/// The index it keys is rebuilt from the logs on every open, so the hash
+/// only has to agree with itself while a store is open.
+pub fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// An index keyed by the hashes of keys rather than the keys themselves.
+// Keys sharing a hash share a bucket, and only the log records of its
+// entries tell them apart.
+pub(crate) struct HashedIndex<V> {
+    hash: fn(&str) -> u64,
+    buckets: HashMap<u64, Vec<V>>,
+}
+
+impl<V> HashedIndex<V> {
+    // Index `entries` by the hashes of their keys.
+    pub(crate) fn new(
+        hash: fn(&str) -> u64,
+        entries: impl IntoIterator<Item = (String, V)>,
+    ) -> HashedIndex<V> {
+        let mut index = HashedIndex {
+            hash,
+            buckets: HashMap::new(),
+        };
+        for (key, value) in entries {
+            let hash = index.hash(&key);
+            index.insert(hash, None, value);
+        }
+        index
+    }
+    pub(crate) fn hash(&self, key: &str) -> u64 {
+        (self.hash)(key)
+    }
+    // The entries of the keys hashing to `hash`.
+    pub(crate) fn bucket(&self, hash: u64) -> &[V] {
+        self.buckets.get(&hash).map_or(&[], Vec::as_slice)
+    }
+    pub(crate) fn bucket_mut(&mut self, hash: u64) -> &mut [V] {
+        self.buckets
+            .get_mut(&hash)
+            .map_or(&mut [], Vec::as_mut_slice)
+    }
+    // Put `value` in `slot` of the bucket of `hash`, returning the entry it
+    // replaces, or add it to the bucket if there is no slot.
+    pub(crate) fn insert(&mut self, hash: u64, slot: Option<usize>, value: V) -> Option<V> {
+        let bucket = self.buckets.entry(hash).or_default();
+        match slot {
+            Some(slot) => Some(std::mem::replace(&mut bucket[slot], value)),
+            None => {
+                bucket.push(value);
+                None
+            }
+        }
+    }
+    // Take the entry in `slot` of the bucket of `hash` out of the index.
+    // The last entry of the bucket takes its slot.
+    pub(crate) fn remove(&mut self, hash: u64, slot: usize) -> V {
+        let bucket = self.buckets.get_mut(&hash).expect("bucket is empty");
+        let value = bucket.swap_remove(slot);
+        if bucket.is_empty() {
+            self.buckets.remove(&hash);
+        }
+        value
+    }
+    // Keep only the entries for which `keep` returns true.
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&V) -> bool) {
+        self.buckets.retain(|_, bucket| {
+            bucket.retain(&mut keep);
+            !bucket.is_empty()
+        });
+    }
+    // Every entry, with the hash of its key.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u64, &V)> {
+        self.buckets
+            .iter()
+            .flat_map(|(&hash, bucket)| bucket.iter().map(move |value| (hash, value)))
+    }
+    pub(crate) fn values(&self) -> impl Iterator<Item = &V> {
+        self.buckets.values().flatten()
+    }
+    pub(crate) fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.buckets.values_mut().flatten()
+    }
+}
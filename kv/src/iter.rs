@@ -0,0 +1,50 @@
+use crate::{KvStore, Result};
+
+/// A cursor over the live key/value pairs of a store, in lexicographic key
+/// order.
+///
+/// Each step looks up the key after the last one returned and reads its
+/// value, so only that one pair is held in memory at a time. The store is
+/// not locked in between: writes to keys further along show up as the
+/// cursor reaches them, and compaction may run at any point.
+pub struct Iter<'a> {
+    store: &'a KvStore,
+    last: Option<String>,
+    done: bool,
+}
+
+impl<'a> Iter<'a> {
+    pub(crate) fn new(store: &'a KvStore) -> Iter<'a> {
+        Iter {
+            store,
+            last: None,
+            done: false,
+        }
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Result<(String, String)>> {
+        if self.done {
+            return None;
+        }
+        match self.store.next_entry(self.last.as_deref()) {
+            Ok(Some((key, value))) => {
+                self.last = Some(key.clone());
+                Some(Ok((key, value)))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            // Without the key the cursor cannot move past it, so a failed
+            // step ends the iteration.
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
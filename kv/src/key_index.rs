@@ -0,0 +1,48 @@
+use crate::kv::IndexEntry;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The keys of one log file and where their records live, written next to
+/// it as `<id>.keys` so `open` can skip reading the values.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct KeyIndex {
+    /// Offset in the log file up to which `entries` is complete. Records
+    /// after it were appended later and must still be replayed.
+    pub end: u64,
+    /// Every live record in the file.
+    pub entries: Vec<IndexEntry>,
+}
+
+fn key_index_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.keys", id))
+}
+
+impl KeyIndex {
+    /// Write the key index for log file `id`, replacing any old one.
+    pub(crate) fn write(&self, dir: &Path, id: u64) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.keys.tmp", id));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        fs::rename(&tmp_path, key_index_path(dir, id))?;
+        Ok(())
+    }
+
+    /// Load the key index for log file `id`. A missing or unreadable index
+    /// is not an error; the log is simply replayed in full.
+    pub(crate) fn read(dir: &Path, id: u64) -> Option<KeyIndex> {
+        let file = File::open(key_index_path(dir, id)).ok()?;
+        serde_json::from_reader(BufReader::new(file)).ok()
+    }
+
+    /// Delete the key index for log file `id`, if there is one.
+    pub(crate) fn remove(dir: &Path, id: u64) -> Result<()> {
+        match fs::remove_file(key_index_path(dir, id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
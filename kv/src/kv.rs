@@ -1,246 +1,4600 @@
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
-use std::collections::HashMap;
-use std::fs::{self, File, OpenOptions};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions, TryLockError};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, Range};
 
-use crate::{KvError, Result};
+use crate::backup;
+use crate::checkpoint;
+use crate::engine;
+use crate::hashed::HashedIndex;
+use crate::key_index::KeyIndex;
+use crate::manifest;
+use crate::pin::FilePins;
+use crate::saved_index::{SavedFile, SavedIndex};
+use crate::txn::{Txn, TxnFrame};
+use crate::{
+    Clock, CompactionAdvice, CompactionEstimate, CompactionPolicy, CompactionResult,
+    FileFragmentation, FileOp, FsyncPolicy, Iter, KvError, KvStoreOptions, KvStoreStats,
+    ProgressCallback, RecordFormat, Replay, Result, Scan, ShardedKvStore, Tail, ValueReader,
+    VerifyFailure, VerifyReport,
+};
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// Number of records replayed between recovery deadline checks.
+const DEADLINE_CHECK_INTERVAL: u64 = 128;
+// The file writable stores lock to keep each other out of a directory.
+const LOCK_FILE: &str = "lock";
 
+/// A log-structured key/value store.
+///
+/// The in-memory index is a `BTreeMap`, so range and prefix queries only
+/// visit the keys they return.
+///
+/// A `KvStore` is a handle: clones share the same store, and every method
+/// takes `&self` so one store can serve several threads. Writes are
+/// serialized, while gets only share the store with each other; each clone
+/// reads through file handles of its own, so give every reading thread a
+/// clone rather than sharing one handle.
+///
+/// Compaction never pulls a file out from under a read. It waits for the
+/// gets already running, which finish against the old files, and every
+/// later get looks its key up in the compacted index and reads the new
+/// file. Scans and replays pin the files they read until they are dropped.
+///
+/// Dropping the last handle flushes writes still buffered in memory, such
+/// as sets made with `sync_sets` off. A failure to flush then can only be
+/// logged, so call `flush` first where it has to be noticed.
 pub struct KvStore {
-    dir_path: PathBuf,
+    inner: Arc<RwLock<KvStoreInner>>,
+    readers: Mutex<HandleReaders>,
+    // A copy of the path the store was opened with, which never changes,
+    // readable without taking the lock.
+    path: Arc<Path>,
+    // Declared after `inner`, so that the last handle lets go of the store
+    // before waiting for the compaction and sweeper threads to see it gone.
+    compactor: Option<Arc<Compactor>>,
+    sweeper: Option<Arc<Sweeper>>,
+}
+impl Clone for KvStore {
+    fn clone(&self) -> Self {
+        KvStore {
+            inner: Arc::clone(&self.inner),
+            readers: Mutex::default(),
+            path: Arc::clone(&self.path),
+            compactor: self.compactor.clone(),
+            sweeper: self.sweeper.clone(),
+        }
+    }
+}
+// The state behind a `KvStore` handle.
+struct KvStoreInner {
+    files: LogFiles,
     current_id: u64,
-    index: HashMap<String, CommandPos>,
-    readers: HashMap<u64, BufReaderWithPos<File>>,
-    curren_writer: BufWriterWithPos<File>,
+    index: BTreeMap<String, CommandPos>,
+    // With `hashed_keys`, the index by key hash that takes the place of
+    // `index`, which is then left empty.
+    hashed: Option<HashedIndex<CommandPos>>,
+    readers: LogReaders,
+    // `None` until the active file is created, and forever for handles
+    // that may never write.
+    curren_writer: Option<BufWriterWithPos<File>>,
+    read_only: bool,
     uncompacted: u64,
+    options: KvStoreOptions,
+    last_compaction: SystemTime,
+    // Behind a mutex so that gets, which only share the store, can update it.
+    last_op: Mutex<SystemTime>,
+    last_sweep: SystemTime,
+    ops: OpCounters,
+    // Whether only a subset of keys was indexed at open.
+    filtered: bool,
+    // Reverse lookup from value to keys, when enabled.
+    value_index: Option<ValueIndex>,
+    // Log files still in use by scans and replays.
+    pins: Arc<FilePins>,
+    // A compaction left for the next operation by `defer_compaction_one_op`.
+    compaction_pending: bool,
+    // Estimated memory held by `index`.
+    index_bytes: usize,
+    // Bumped whenever a log file is deleted or replaced, so that handles
+    // know to reopen their readers.
+    reader_generation: u64,
+    // Sequence number for the next command written, one past the highest
+    // found in the logs at open.
+    next_seq: u64,
+    // Writes since the active file was last synced, and when that was.
+    unsynced_writes: u64,
+    last_sync: SystemTime,
+    // Wakes the compaction thread, with `background_compaction`.
+    compaction_signal: Option<Sender<()>>,
+    // Whether the compaction thread has been signalled and not yet done.
+    compacting: bool,
+    // The directory's lock file, held from open or the first write for as
+    // long as the store may write.
+    lock: Option<File>,
+}
+impl KvStore {
+    /// Open a 'KvStore' with given path.
+    ///
+    /// This wiil create a new file if the given one is not exist.
+    ///
+    /// Fails with `KvError::EngineMismatch` if the directory holds data of
+    /// another engine, and with `KvError::Locked` while another writable
+    /// store, in this process or another, has it open. Read-only opens take
+    /// no lock.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+    /// Open a 'KvStore' with given path and options.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let inner =
+            KvStoreInner::open_inner(LogFiles::Dir(path.into()), options, OpenMode::default())?;
+        Ok(KvStore::from_inner(inner))
+    }
+    /// Open a `KvStore` kept whole in the single file at `path`, rather
+    /// than in a directory of log files. The file is created if it does not
+    /// exist.
+    ///
+    /// Writes are appended to the file, and compaction copies the live
+    /// records to `<path>.tmp` and renames the copy over it. The only other
+    /// file is `<path>.lock`, which keeps out a second writable store the
+    /// way the lock in a store directory does.
+    ///
+    /// A `tail` stops seeing writes once a compaction replaces the file.
+    pub fn open_file(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_file_with_options(path, KvStoreOptions::default())
+    }
+    /// Open a single-file `KvStore` with given path and options.
+    ///
+    /// Options that need more than the one file are ignored: there is no
+    /// rolling over at `max_file_size`, ring mode, key index file or saved
+    /// index, and compaction always runs in place, inside the write that
+    /// triggers it.
+    pub fn open_file_with_options(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<KvStore> {
+        let options = KvStoreOptions {
+            max_file_size: None,
+            ring_capacity: None,
+            compact_in_place: true,
+            background_compaction: false,
+            compact_while_recovering: false,
+            key_index_file: false,
+            save_index: false,
+            ..options
+        };
+        let files = LogFiles::File(path.into());
+        let inner = KvStoreInner::open_inner(files, options, OpenMode::default())?;
+        Ok(KvStore::from_inner(inner))
+    }
+    /// Open every store listed in the manifest at `manifest`, in order, each
+    /// with the options it names.
+    ///
+    /// The manifest is a JSON array with an entry per store. Relative paths
+    /// are taken from the manifest's directory, and `options` may override
+    /// the plain fields of `KvStoreOptions`, such as thresholds and flags:
+    ///
+    /// ```json
+    /// [
+    ///     { "path": "shard0" },
+    ///     { "path": "/data/shard1", "options": { "compaction_policy": { "bytes": 4096 } } }
+    /// ]
+    /// ```
+    ///
+    /// Fails on the first store that cannot be opened.
+    pub fn open_many(manifest: &Path) -> Result<Vec<KvStore>> {
+        manifest::read(manifest)?
+            .into_iter()
+            .map(|(path, options)| Self::open_with_options(path, options))
+            .collect()
+    }
+    /// Open a store split into `shards` independent shards under `path`,
+    /// so writes to different keys can run in parallel. See
+    /// `ShardedKvStore`.
+    pub fn open_sharded(path: impl Into<PathBuf>, shards: usize) -> Result<ShardedKvStore> {
+        ShardedKvStore::open(path, shards)
+    }
+    /// Open a 'KvStore' that only indexes keys matching `predicate`.
+    ///
+    /// Other keys are invisible to the returned handle. Since it cannot see
+    /// every live entry, the handle never compacts.
+    pub fn open_filtered(
+        path: impl Into<PathBuf>,
+        predicate: impl Fn(&str) -> bool,
+    ) -> Result<KvStore> {
+        let mode = OpenMode {
+            filter: Some(&predicate),
+            ..OpenMode::default()
+        };
+        let inner =
+            KvStoreInner::open_inner(LogFiles::Dir(path.into()), KvStoreOptions::default(), mode)?;
+        Ok(KvStore::from_inner(inner))
+    }
+    /// Open a 'KvStore' over a snapshot directory without modifying it.
+    ///
+    /// No directory, active log file or cleanup is ever created or run, so
+    /// this works against read-only mounts. Every write fails with
+    /// `KvError::ReadOnly`.
+    pub fn open_snapshot(path: impl Into<PathBuf>) -> Result<KvStore> {
+        let mode = OpenMode {
+            snapshot: true,
+            ..OpenMode::default()
+        };
+        let inner =
+            KvStoreInner::open_inner(LogFiles::Dir(path.into()), KvStoreOptions::default(), mode)?;
+        Ok(KvStore::from_inner(inner))
+    }
+    /// Open a `KvStore` that only reads, for inspection and backup tools.
+    ///
+    /// The same as `open_snapshot`: no log file is created on open, and
+    /// `set`, `remove` and `compact` fail with `KvError::ReadOnly`.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_snapshot(path)
+    }
+    fn from_inner(mut inner: KvStoreInner) -> KvStore {
+        let background = inner.options.background_compaction
+            && inner.options.ring_capacity.is_none()
+            && inner.may_compact()
+            && !inner.read_only;
+        let signals = background.then(|| {
+            let (signal, signals) = mpsc::channel();
+            inner.compaction_signal = Some(signal);
+            signals
+        });
+        let sweep_interval = inner
+            .options
+            .ttl_sweep_interval
+            .filter(|_| !inner.read_only);
+        let path = inner.files.path().into();
+        let inner = Arc::new(RwLock::new(inner));
+        let compactor = signals.map(|signals| {
+            let store = Arc::downgrade(&inner);
+            Arc::new(Compactor {
+                thread: Some(thread::spawn(move || run_compactor(store, signals))),
+            })
+        });
+        let sweeper = sweep_interval.map(|interval| {
+            let store = Arc::downgrade(&inner);
+            let (stop, stops) = mpsc::channel();
+            Arc::new(Sweeper {
+                stop: Some(stop),
+                thread: Some(thread::spawn(move || run_sweeper(store, interval, stops))),
+            })
+        });
+        KvStore {
+            path,
+            inner,
+            readers: Mutex::default(),
+            compactor,
+            sweeper,
+        }
+    }
+    /// The directory the store keeps its files in, as given to `open`, or
+    /// its one file if it was opened with `open_file`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    fn lock(&self) -> RwLockWriteGuard<'_, KvStoreInner> {
+        self.inner.write().unwrap()
+    }
+    fn read(&self) -> RwLockReadGuard<'_, KvStoreInner> {
+        self.inner.read().unwrap()
+    }
+    // Run `read` under the shared lock, reading values through this
+    // handle's own readers. If the store has to write first, to run a
+    // deferred compaction or flush buffered records, it runs under the
+    // exclusive lock once that is done.
+    fn shared_read<T>(
+        &self,
+        read: impl FnOnce(&KvStoreInner, &mut HandleReaders) -> Result<T>,
+    ) -> Result<T> {
+        {
+            let inner = self.read();
+            if inner.readable_shared() {
+                return read(&inner, &mut self.readers.lock().unwrap());
+            }
+        }
+        let mut inner = self.lock();
+        inner.run_pending_compaction()?;
+        inner.flush()?;
+        read(&inner, &mut self.readers.lock().unwrap())
+    }
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.lock().set(key, value)
+    }
+    /// Set every key in `pairs` to its value, flushing to the log file once
+    /// at the end rather than after each write.
+    ///
+    /// The batch is not atomic: if the process dies part way through, a
+    /// later open recovers whichever of its writes reached the file. Use
+    /// `transaction` for writes that must land together.
+    pub fn set_many(&self, pairs: impl IntoIterator<Item = (String, String)>) -> Result<()> {
+        self.lock().set_many(pairs)
+    }
+    /// Run `f` to collect sets and removes into a `Txn`, then apply them all
+    /// at once if it returns `Ok`. An `Err` from `f` is returned with nothing
+    /// written.
+    ///
+    /// The writes are framed by begin and commit records in the log; a
+    /// crash before the commit record reaches the file loses the whole
+    /// transaction on the next open, never just part of it. A remove of a
+    /// missing key fails the transaction under `strict_removes` and is
+    /// skipped otherwise.
+    pub fn transaction<F: FnOnce(&mut Txn) -> Result<()>>(&self, f: F) -> Result<()> {
+        let mut txn = Txn::default();
+        f(&mut txn)?;
+        self.lock().transaction(txn)
+    }
+    /// Set `key` to `value` for `ttl`, after which it reads as absent. The
+    /// expiry is kept with one-second precision, rounded up.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.lock().set_with_ttl(key, value, ttl)
+    }
+    /// Flush buffered writes out to the active log file.
+    pub fn flush(&self) -> Result<()> {
+        self.lock().flush()
+    }
+    /// Flush buffered writes and sync the active log file to disk,
+    /// whatever the `fsync` policy.
+    pub fn sync(&self) -> Result<()> {
+        self.lock().sync()
+    }
+    /// Return every key currently holding `value`, in sorted order.
+    ///
+    /// Always empty unless the store was opened with `build_value_index`.
+    pub fn keys_with_value(&self, value: &str) -> Vec<String> {
+        self.read().keys_with_value(value)
+    }
+    /// Run time-based maintenance, returning whether a compaction ran.
+    ///
+    /// This is meant to be called periodically by a background ticker. With
+    /// `ttl_sweep_interval` set, it sweeps expired keys that often, which a
+    /// thread of the store's own also does between calls. With
+    /// `compact_when_idle` set, it compacts once no operation has happened
+    /// for that long and there is stale data.
+    pub fn tick(&self) -> Result<bool> {
+        self.lock().tick()
+    }
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        if let Some(result) = self
+            .read()
+            .get_shared(&key, &mut self.readers.lock().unwrap())
+        {
+            return result;
+        }
+        self.lock().get(key)
+    }
+    /// Get a reader over the value of `key`, or `None` if it is absent, for
+    /// passing a large value on without holding all of it in memory. Only
+    /// bincode and MessagePack logs are streamed: a value in a JSON log is
+    /// read whole before the reader is returned. See `ValueReader` for the
+    /// other exceptions.
+    pub fn get_reader(&self, key: &str) -> Result<Option<ValueReader>> {
+        self.lock().get_reader(key)
+    }
+    /// Get the values of `keys`, in the same order, reading them from the
+    /// log files in file and offset order rather than one seek per key.
+    pub fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        if self.is_hashed() {
+            return keys.iter().map(|key| self.get(key.clone())).collect();
+        }
+        self.shared_read(|inner, readers| inner.get_many(keys, readers))
+    }
+    /// Decode the command stored at a physical log location, regardless of
+    /// whether the index still refers to it.
+    pub fn read_at(&self, file_id: u64, pos: u64, len: u64) -> Result<Command> {
+        self.lock().read_at(file_id, pos, len)
+    }
+    /// Compute a SHA-256 digest over all live key/value pairs in sorted key
+    /// order. Stores with the same logical contents share a digest no matter
+    /// how their logs are laid out.
+    pub fn digest(&self) -> Result<[u8; 32]> {
+        self.shared_read(|inner, readers| inner.digest(readers))
+    }
+    /// Return the live pairs with keys in `start..end`, sorted by the
+    /// configured `key_order`.
+    pub fn scan_range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        self.shared_read(|inner, readers| inner.scan_range(start, end, readers))
+    }
+    /// Return the live pairs with keys in `start..end`, in lexicographic
+    /// order whatever the configured `key_order`. An empty or backwards
+    /// range returns nothing.
+    pub fn range(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        self.shared_read(|inner, readers| inner.range(start, end, readers))
+    }
+    /// Return the live pairs whose key starts with `prefix`, in
+    /// lexicographic order. An empty prefix returns every pair.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.shared_read(|inner, readers| inner.scan_prefix(prefix, readers))
+    }
+    /// List keys with a `Remove` record still in the logs that are not
+    /// currently live. These tombstones disappear on the next compaction.
+    pub fn tombstoned_keys(&self) -> Result<Vec<String>> {
+        self.lock().tombstoned_keys()
+    }
+    /// Iterate over every command in the logs in write order, including
+    /// stale and removed entries.
+    pub fn replay(&self) -> Result<Replay> {
+        self.lock().replay()
+    }
+    /// Follow the logs from their current end, yielding each command as it
+    /// is appended by this or any other handle on the directory.
+    ///
+    /// The returned iterator blocks while waiting for new commands.
+    pub fn tail(&self) -> Result<Tail> {
+        self.lock().tail()
+    }
+    /// Iterate over the live key/value pairs as of now, sorted by the
+    /// configured `key_order`.
+    ///
+    /// The scan is independent of the store afterwards: later writes are
+    /// not visible to it, and compaction leaves the files it reads alone
+    /// until it is dropped.
+    pub fn scan(&self) -> Result<Scan> {
+        self.shared_read(|inner, _| inner.scan())
+    }
+    /// Iterate over the live key/value pairs in lexicographic order,
+    /// reading each value from disk only as the iterator reaches it.
+    ///
+    /// Unlike `scan`, nothing is collected up front and the store stays
+    /// writable throughout, so the pairs are not a consistent snapshot.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(self)
+    }
+    // The first live pair with a key after `after`, or the first of all.
+    pub(crate) fn next_entry(&self, after: Option<&str>) -> Result<Option<(String, String)>> {
+        self.lock().next_entry(after)
+    }
+    /// Copy the raw bytes of every log file with id `from_file_id` or above
+    /// to `out`, in write order, returning the id to resume from next time.
+    ///
+    /// The active file is always included, so resuming from the returned id
+    /// re-sends it; replaying commands in order makes that harmless. A
+    /// compaction in between rewrites live data without its removes, so a
+    /// replica that missed them must be re-seeded instead.
+    pub fn stream_changes(&self, from_file_id: u64, out: &mut impl Write) -> Result<u64> {
+        self.lock().stream_changes(from_file_id, out)
+    }
+    /// Apply a stream of log commands, such as one produced by
+    /// `stream_changes`, returning how many were applied. Removes of keys
+    /// that are already absent are skipped, and so is a transaction the
+    /// stream ends before committing.
+    pub fn apply_changes(&self, changes: impl Read) -> Result<usize> {
+        self.lock().apply_changes(changes)
+    }
+    /// Write every live key/value pair to `writer`, as of now, in a framed
+    /// format that `import` of this or any other engine reads back. Keys
+    /// whose TTL has run out are left out.
+    pub fn export(&self, writer: impl Write) -> Result<()> {
+        backup::export(self.scan()?, writer)
+    }
+    /// Set every pair in an export read from `reader`, returning how many
+    /// were imported.
+    pub fn import(&self, reader: impl Read) -> Result<usize> {
+        self.lock().import(reader)
+    }
+    /// Set every live pair of the store at `other`, opened read-only, and
+    /// return how many were merged.
+    ///
+    /// Pairs from `other` overwrite the values this store holds for the
+    /// same keys, so of two merges setting a key the later call wins. TTLs
+    /// are not carried over.
+    pub fn merge_from(&self, other: &Path) -> Result<usize> {
+        let pairs = KvStore::open_read_only(other)?.scan()?;
+        self.lock().merge_from(pairs)
+    }
+    /// Scan the logs for live and stale bytes per file and advise whether to
+    /// compact.
+    pub fn compaction_advice(&self) -> Result<CompactionAdvice> {
+        self.lock().compaction_advice()
+    }
+    /// Estimate what a compaction would reclaim from the stale byte count
+    /// and the lengths of the live records, without reading the logs as
+    /// `compaction_advice` does. Like `stats` it never fails: if the log
+    /// sizes cannot be read, `current_total_bytes` is 0.
+    pub fn compaction_estimate(&self) -> CompactionEstimate {
+        self.read().compaction_estimate()
+    }
+    /// Remove log files other than the active one that hold no live record,
+    /// such as the empty or fully stale ones a crash during compaction can
+    /// leave behind, and return how many were removed.
+    ///
+    /// A file is kept if one of its removes still hides a value in an older
+    /// file, so no removed key comes back.
+    pub fn cleanup(&self) -> Result<usize> {
+        self.lock().cleanup()
+    }
+    /// The options the store was opened with.
+    pub fn options(&self) -> KvStoreOptions {
+        self.read().options().clone()
+    }
+    /// List the live keys, sorted by the configured `key_order`. No value is
+    /// read.
+    ///
+    /// Always empty if the store was opened with `hashed_keys`, whose index
+    /// does not hold the keys.
+    pub fn keys(&self) -> Vec<String> {
+        self.read().keys()
+    }
+    /// The number of live keys.
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+    /// Whether the store holds no live key.
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+    /// Compact once more than `bytes` stale bytes accumulate, replacing the
+    /// compaction policy. It applies from the next write on.
+    pub fn set_compaction_threshold(&self, bytes: u64) {
+        self.lock().set_compaction_threshold(bytes)
+    }
+    /// Report the store's internal state and operation counts.
+    pub fn stats(&self) -> KvStoreStats {
+        self.read().stats()
+    }
+    /// Sum the sizes of the store's log files on disk.
+    ///
+    /// Writes still buffered in memory only count once flushed. Unlike
+    /// `stats().disk_bytes`, a file that cannot be read fails the call.
+    pub fn disk_size(&self) -> Result<u64> {
+        self.read().log_size()
+    }
+    /// Exchange the values of `a` and `b`, failing with `KeyNotFound` if
+    /// either is absent or expired.
+    ///
+    /// Both writes go in one transaction, and each expiry moves with its
+    /// value.
+    pub fn swap(&self, a: String, b: String) -> Result<()> {
+        self.lock().swap(a, b)
+    }
+    /// Move the value of `from` to `to`, overwriting any value `to` had,
+    /// failing with `KeyNotFound` if `from` is absent.
+    ///
+    /// The move is written as one transaction, so a crash leaves the value
+    /// under exactly one of the two keys. An expiry moves with the value.
+    pub fn rename_key(&self, from: String, to: String) -> Result<()> {
+        self.lock().rename_key(from, to)
+    }
+    /// Append `suffix` to the value of `key`, treating an absent or expired
+    /// key as empty.
+    pub fn append(&self, key: String, suffix: &str) -> Result<()> {
+        self.lock().append(key, suffix)
+    }
+    /// Set `key` to `new` only if its value is `expected`, with `None`
+    /// meaning absent, and return whether it was set. No other write can
+    /// come between the check and the set.
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: String,
+    ) -> Result<bool> {
+        self.lock().compare_and_swap(key, expected, new)
+    }
+    /// Set `key` to `value` only if it is absent, and return whether it was
+    /// set. A present key is left alone without writing anything to the
+    /// log.
+    pub fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.lock().set_nx(key, value)
+    }
+    /// Replace the value of `key` with what `f` makes of the current one,
+    /// `None` standing for absent on both sides, so returning `None`
+    /// removes the key.
+    ///
+    /// `f` runs with the store locked, so no other write interleaves, but
+    /// it should be quick and must not use the store itself.
+    pub fn update<F: FnOnce(Option<String>) -> Option<String>>(
+        &self,
+        key: String,
+        f: F,
+    ) -> Result<()> {
+        self.lock().update(key, f)
+    }
+    /// Set `key` to `value`, returning the value it replaced.
+    pub fn set_and_get_old(&self, key: String, value: String) -> Result<Option<String>> {
+        self.lock().set_and_get_old(key, value)
+    }
+    /// Return the value of `key`, first setting it to what `f` returns if
+    /// the key is absent. `f` only runs in that case, with the store
+    /// locked, so it should be quick and must not use the store itself.
+    pub fn get_or_insert_with<F: FnOnce() -> String>(&self, key: String, f: F) -> Result<String> {
+        self.lock().get_or_insert_with(key, f)
+    }
+    /// Remove `key`, returning its value. Unlike `remove`, an absent key is
+    /// not an error: nothing is written and `None` comes back.
+    pub fn take(&self, key: String) -> Result<Option<String>> {
+        self.lock().take(key)
+    }
+    /// Check that every tracked log file still exists on disk and, apart
+    /// from the active file, is non-empty.
+    pub fn check_files(&self) -> Result<()> {
+        self.lock().check_files()
+    }
+    /// Read the record behind every entry in the index and check that it
+    /// decodes, holds a value for the same key, and takes up exactly the
+    /// bytes the index says. Entries that fail are listed in the report
+    /// rather than failing the whole check.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        self.lock().verify()
+    }
+    /// Concatenate the given log files, stale records included, into one
+    /// file and return its id.
+    ///
+    /// The files must not include the active one and must be adjacent in
+    /// write order, so the merged file can take the place of the newest of
+    /// them without reordering any record.
+    pub fn coalesce_files(&self, ids: &[u64]) -> Result<u64> {
+        self.lock().coalesce_files(ids)
+    }
+    /// Replace the whole contents of the store with `entries`, discarding
+    /// every key set before.
+    ///
+    /// The entries are written to a new file that only takes effect once it
+    /// is complete, so an interrupted call leaves the old data in place.
+    pub fn replace_all(&self, entries: impl Iterator<Item = (String, String)>) -> Result<()> {
+        self.lock().replace_all(entries)
+    }
+    /// Remove every key and delete the old log files, leaving the store as
+    /// if just created.
+    ///
+    /// Like `replace_all` with no entries, this first writes a file holding
+    /// only a `Clear` record, so a crash part way through deleting the old
+    /// files cannot bring back some of their keys.
+    pub fn clear(&self) -> Result<()> {
+        self.lock().replace_all(std::iter::empty())
+    }
+    /// Whether `key` currently has a value.
+    pub fn contains_key(&self, key: &str) -> bool {
+        if self.is_hashed() {
+            return self
+                .lock()
+                .live_hashed(key)
+                .is_ok_and(|found| found.is_some());
+        }
+        self.read().contains_key(key)
+    }
+    /// The sequence number of the write that gave `key` its value.
+    ///
+    /// Every write is numbered as it reaches the log, counting on from the
+    /// highest number found there at open, so a later write of any key has
+    /// a higher number. Records from before numbers were logged have none.
+    pub fn sequence(&self, key: &str) -> Option<u64> {
+        if self.is_hashed() {
+            let mut inner = self.lock();
+            let cmd_pos = inner.live_hashed(key).ok().flatten()?;
+            return Some(cmd_pos.seq).filter(|&seq| seq != 0);
+        }
+        self.read().sequence(key)
+    }
+    /// Return the byte length of the value stored for `key`.
+    pub fn value_len(&self, key: &str) -> Result<Option<usize>> {
+        if self.is_hashed() {
+            return Ok(self.get(key.to_owned())?.map(|value| value.len()));
+        }
+        self.shared_read(|inner, readers| inner.value_len(key, readers))
+    }
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.lock().remove(key)
+    }
+    /// Remove every key whose TTL has run out, returning how many there were.
+    ///
+    /// Expired keys otherwise only go away when they are next accessed.
+    pub fn sweep_expired(&self) -> Result<usize> {
+        self.lock().sweep_expired()
+    }
+    /// Rewrite the live records into a fresh log file and delete the stale
+    /// ones, returning the resulting file layout.
+    pub fn compact(&self) -> Result<CompactionResult> {
+        self.lock().compact()
+    }
+    /// Return the `(file_id, pos, len)` of the record backing a live key.
+    #[cfg(any(test, feature = "internals"))]
+    pub fn command_pos(&self, key: &str) -> Option<(u64, u64, u64)> {
+        let mut inner = self.lock();
+        if inner.hashed.is_some() {
+            let cmd_pos = inner.live_hashed(key).ok().flatten()?;
+            return Some((cmd_pos.file_id, cmd_pos.pos, cmd_pos.len));
+        }
+        inner.command_pos(key)
+    }
+    // Whether the store was opened with `hashed_keys`, whose point reads
+    // check keys against the log under the exclusive lock.
+    fn is_hashed(&self) -> bool {
+        self.read().hashed.is_some()
+    }
+}
+impl KvStoreInner {
+    fn open_inner(
+        files: LogFiles,
+        options: KvStoreOptions,
+        mode: OpenMode,
+    ) -> Result<KvStoreInner> {
+        let filter = mode.filter;
+        // A directory is read in the format its marker notes it was created
+        // with.
+        let recorded_format = match &files {
+            LogFiles::Dir(dir) if dir.is_dir() => engine::recorded_format(dir)?,
+            _ => None,
+        };
+        let options = KvStoreOptions {
+            record_format: recorded_format.unwrap_or(options.record_format),
+            ..options
+        };
+        // Each of these works from the full keys the index holds.
+        if options.hashed_keys.is_some()
+            && (options.build_value_index
+                || options.key_index_file
+                || options.index_checkpoint
+                || options.save_index
+                || options.ring_capacity.is_some())
+        {
+            return Err(KvError::HashedKeys);
+        }
+        let deferred = mode.snapshot || options.defer_active_file;
+        let mut lock = None;
+        if !deferred {
+            create_dir(files.dir())?;
+            lock = Some(lock_store(&files)?);
+            if let LogFiles::Dir(dir) = &files {
+                engine::claim_kvs_dir(dir, options.record_format)?;
+            }
+            remove_tmp_logs(&files)?;
+        }
+
+        let mut index = BTreeMap::new();
+        let mut readers = LogReaders::new(
+            files.clone(),
+            options.buffer_capacity,
+            options.max_open_readers,
+        );
+
+        // generate id for every log file in given directory.
+        let id_list = log_ids(&files)?;
+        let mut uncompacted = 0;
+        let mut recovered = 0;
+        let mut max_seq = 0;
+        let deadline = Deadline::new(options.clock.as_ref(), options.recovery_deadline);
+
+        // Compaction leaves gaps in the ids, but every file it writes is
+        // numbered above the ones it replaces, so one past the newest file
+        // is always free. A single file is the one log, and goes on being
+        // written to.
+        let mut current_id = match files {
+            LogFiles::Dir(_) => id_list.last().unwrap_or(&0) + 1,
+            LogFiles::File(_) => 1,
+        };
+        // Compacting on open otherwise means reading every log once to
+        // recover and again to compact.
+        let fused = options.compact_on_open
+            && options.compact_while_recovering
+            && filter.is_none()
+            && !options.audit_mode
+            && !deferred;
+        let mut compactions = 0;
+        if fused {
+            let recovery = Self::recover_compacting(
+                &files,
+                &id_list,
+                current_id,
+                &deadline,
+                options.record_format,
+                options.buffer_capacity,
+            )?;
+            index = recovery.index;
+            uncompacted = recovery.uncompacted;
+            recovered = recovery.records;
+            max_seq = recovery.max_seq;
+            let ids = match recovery.compacted {
+                true => vec![current_id],
+                false => id_list.clone(),
+            };
+            for id in ids {
+                readers.insert(
+                    id,
+                    BufReaderWithPos::new(open_log(&files, id)?, options.buffer_capacity)?,
+                );
+            }
+            if recovery.compacted {
+                info!("Compacted {} log files while recovering", id_list.len());
+                current_id += 1;
+                compactions = 1;
+            }
+        } else {
+            // Start from the saved index if it still fits the files, and
+            // replay each file it covers from where it left off.
+            let saved = match options.save_index {
+                true => SavedIndex::read(files.dir(), &id_list, |id| {
+                    Ok(fs::metadata(log_path(&files, id))?.len())
+                }),
+                false => None,
+            };
+            let mut resume = HashMap::new();
+            if let Some(saved) = saved {
+                let mut base = PartialIndex::new(false, options.inline_value_size);
+                for file in saved.files {
+                    resume.insert(file.id, file.end);
+                    uncompacted += Self::index_entries(file.id, file.entries, &mut base, filter);
+                }
+                uncompacted += saved.uncompacted;
+                max_seq = saved.max_seq.max(base.max_seq);
+                base.merge_into(&mut index);
+                debug!("Loaded the saved index of {} keys", index.len());
+            }
+            let resume = &resume;
+            let preloaded = !resume.is_empty();
+            // Recover the run of files `ids` on its own, tracking removes
+            // only if earlier files may hold the keys.
+            let recover_run = |ids: &[u64],
+                               filter: Option<&dyn Fn(&str) -> bool>,
+                               track_removes: bool|
+             -> Result<RecoveredRun> {
+                let mut run = RecoveredRun {
+                    index: PartialIndex::new(track_removes, options.inline_value_size),
+                    readers: Vec::with_capacity(ids.len()),
+                    uncompacted: 0,
+                    records: 0,
+                };
+                let index = &mut run.index;
+                for &id in ids {
+                    let mut reader =
+                        BufReaderWithPos::new(open_log(&files, id)?, options.buffer_capacity)?;
+                    let start = resume.get(&id).copied();
+                    let checkpoint = match options.index_checkpoint
+                        && options.record_format == RecordFormat::Json
+                        && start.is_none()
+                    {
+                        true => checkpoint::read(&mut reader)?,
+                        false => None,
+                    };
+                    if let Some(entries) = checkpoint {
+                        run.uncompacted += Self::index_entries(id, entries, index, filter);
+                    } else {
+                        reader
+                            .seek(SeekFrom::Start(start.unwrap_or(0)))
+                            .map_err(KvError::file(FileOp::Seek, log_path(&files, id)))?;
+                        if options.key_index_file && start.is_none() {
+                            run.uncompacted +=
+                                Self::load_key_index(files.dir(), id, &mut reader, index, filter)?;
+                        }
+                        let tail = Some(&id) == id_list.last();
+                        let (stale, records, torn_at) = Self::recover(
+                            id,
+                            &mut reader,
+                            index,
+                            &deadline,
+                            filter,
+                            tail,
+                            options.record_format,
+                        )?;
+                        run.uncompacted += stale;
+                        run.records += records;
+                        debug!("Recovered {} records from log file {}", records, id);
+                        // A crash part way through a write leaves half a
+                        // record or transaction at the end of the newest
+                        // file; cut it off so the next write does not land
+                        // behind it.
+                        if let (Some(end), false) = (torn_at, mode.snapshot) {
+                            truncate_log(&files, id, end)?;
+                        }
+                    }
+                    run.readers.push((id, reader));
+                }
+                Ok(run)
+            };
+            // The key filter need not be shareable between threads.
+            let workers = match filter {
+                Some(_) => 1,
+                None => options.recovery_workers.max(1),
+            };
+            let per_worker = id_list.len().div_ceil(workers).max(1);
+            let runs = if id_list.len() <= per_worker {
+                vec![recover_run(&id_list, filter, preloaded)]
+            } else {
+                thread::scope(|scope| {
+                    let handles: Vec<_> = id_list
+                        .chunks(per_worker)
+                        .enumerate()
+                        .map(|(i, ids)| {
+                            scope.spawn(move || recover_run(ids, None, i > 0 || preloaded))
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .map(|handle| handle.join().expect("recovery worker panicked"))
+                        .collect()
+                })
+            };
+            // Apply the runs in file order, so the newest write of a key
+            // wins as it would replaying the files one by one.
+            for run in runs {
+                let run = run?;
+                max_seq = max_seq.max(run.index.max_seq);
+                uncompacted += run.uncompacted + run.index.merge_into(&mut index);
+                recovered += run.records;
+                for (id, reader) in run.readers {
+                    readers.insert(id, reader);
+                }
+            }
+        }
+        let writer = if deferred {
+            None
+        } else {
+            Some(Self::new_log_file(
+                &files,
+                current_id,
+                options.buffer_capacity,
+                &mut readers,
+            )?)
+        };
+
+        let mut store = KvStoreInner {
+            files,
+            current_id,
+            index: BTreeMap::new(),
+            hashed: None,
+            readers,
+            curren_writer: writer,
+            read_only: mode.snapshot,
+            uncompacted,
+            last_compaction: options.clock.now(),
+            last_op: Mutex::new(options.clock.now()),
+            last_sweep: options.clock.now(),
+            last_sync: options.clock.now(),
+            options,
+            ops: OpCounters {
+                recovered,
+                compactions,
+                ..OpCounters::default()
+            },
+            filtered: filter.is_some(),
+            value_index: None,
+            pins: Arc::default(),
+            compaction_pending: false,
+            index_bytes: 0,
+            reader_generation: 0,
+            next_seq: max_seq + 1,
+            unsynced_writes: 0,
+            compaction_signal: None,
+            compacting: false,
+            lock,
+        };
+        info!(
+            "Opened {} with {} live keys from {} log files",
+            store.files.path().display(),
+            index.len(),
+            id_list.len()
+        );
+        store.install_index(index);
+        store.check_memory_budget(store.index_bytes)?;
+        if store.options.build_value_index {
+            store.rebuild_value_index()?;
+        }
+        if store.options.cleanup_on_open && store.may_compact() && !store.read_only {
+            store.cleanup()?;
+        }
+        // A log dominated by overwrites of a few hot keys holds more stale
+        // than live bytes; compact right away rather than on a later write.
+        if store.options.compact_on_open && store.may_compact() && !deferred {
+            let live: u64 = store.positions().map(|cmd_pos| cmd_pos.len).sum();
+            if store.uncompacted > live {
+                store.compact()?;
+            }
+        }
+        Ok(store)
+    }
+    // Recover the index from the log files `ids` while copying their live
+    // records into the new file `compaction_id`, reading every file once.
+    //
+    // Files are read newest first and each one's records backwards, so the
+    // first record met for a key is its latest. If the logs turn out to hold
+    // more stale bytes than live ones the copy replaces them; otherwise it is
+    // dropped and the index keeps pointing at the old files.
+    fn recover_compacting(
+        files: &LogFiles,
+        ids: &[u64],
+        compaction_id: u64,
+        deadline: &Deadline,
+        format: RecordFormat,
+        buffer_capacity: usize,
+    ) -> Result<Recovery> {
+        let tmp_path = tmp_log_path(files, compaction_id);
+        let mut writer = BufWriterWithPos::new(File::create(&tmp_path)?, buffer_capacity)?;
+        let mut index = BTreeMap::new();
+        let mut compacted = BTreeMap::new();
+        // Keys whose latest record is a remove.
+        let mut removed = HashSet::new();
+        let mut cleared = false;
+        let mut uncompacted = 0;
+        let mut records = 0u64;
+        let mut max_seq = 0;
+
+        for &id in ids.iter().rev() {
+            let path = log_path(files, id);
+            let bytes = fs::read(&path).map_err(KvError::file(FileOp::Read, path))?;
+            let tail = Some(&id) == ids.last();
+            let mut file_records = Vec::new();
+            let mut frame = TxnFrame::default();
+            let mut pos = 0;
+            while let Some(decoded) = format.read(&bytes[pos as usize..]) {
+                let (record, len) = match decoded {
+                    // As in `recover`, drop a torn record ending the newest
+                    // file.
+                    Err(e) if tail && e.truncated => break,
+                    decoded => decoded?,
+                };
+                records += 1;
+                if records.is_multiple_of(DEADLINE_CHECK_INTERVAL) {
+                    deadline.check()?;
+                }
+                frame.feed(pos, len, record, |pos, len, record| {
+                    file_records.push((pos, len, record));
+                    Ok(())
+                })?;
+                pos += len;
+            }
+            // Along with any transaction it left unfinished.
+            let torn_at = frame.finish().unwrap_or(pos);
+            if tail && torn_at < bytes.len() as u64 {
+                truncate_log(files, id, torn_at)?;
+            }
+            uncompacted += frame.stale;
+
+            for (pos, len, record) in file_records.into_iter().rev() {
+                if cleared {
+                    uncompacted += len;
+                    continue;
+                }
+                if let Record::Clear = record {
+                    cleared = true;
+                    uncompacted += len;
+                    continue;
+                }
+                let seq = record.seq();
+                max_seq = max_seq.max(seq);
+                let (key, expire_at) = match record.into_command()? {
+                    Some(Command::Set { key, .. }) => (key, None),
+                    Some(Command::SetEx {
+                        key,
+                        expire_at_unix_secs,
+                        ..
+                    }) => (key, Some(expire_at_unix_secs)),
+                    Some(Command::Remove { key }) => {
+                        if !index.contains_key(&key) {
+                            removed.insert(key);
+                        }
+                        uncompacted += len;
+                        continue;
+                    }
+                    None => continue,
+                };
+                if index.contains_key(&key) || removed.contains(&key) {
+                    uncompacted += len;
+                    continue;
+                }
+                let new_pos = writer.pos;
+                writer.write_all(&bytes[pos as usize..(pos + len) as usize])?;
+                let cmd_pos = CommandPos {
+                    file_id: id,
+                    pos,
+                    len,
+                    expire_at,
+                    value: None,
+                    seq,
+                };
+                compacted.insert(
+                    key.clone(),
+                    CommandPos {
+                        file_id: compaction_id,
+                        pos: new_pos,
+                        len,
+                        expire_at,
+                        value: None,
+                        seq,
+                    },
+                );
+                index.insert(key, cmd_pos);
+            }
+        }
+
+        let live: u64 = index.values().map(|cmd_pos| cmd_pos.len).sum();
+        if uncompacted <= live {
+            drop(writer);
+            fs::remove_file(&tmp_path)?;
+            return Ok(Recovery {
+                index,
+                uncompacted,
+                records,
+                max_seq,
+                compacted: false,
+            });
+        }
+        writer
+            .writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&tmp_path, log_path(files, compaction_id))?;
+        for &id in ids {
+            fs::remove_file(log_path(files, id))?;
+            KeyIndex::remove(files.dir(), id)?;
+        }
+        Ok(Recovery {
+            index: compacted,
+            uncompacted: 0,
+            records,
+            max_seq,
+            compacted: true,
+        })
+    }
+    // Index the records of log file `id` from the reader's position on,
+    // returning the stale bytes found, the number of records read and, for
+    // the `tail` file, where a final record cut short by a crash or a
+    // transaction it interrupted starts.
+    fn recover<R: Read + Seek>(
+        id: u64,
+        reader: &mut BufReaderWithPos<R>,
+        index: &mut PartialIndex,
+        deadline: &Deadline,
+        filter: Option<&dyn Fn(&str) -> bool>,
+        tail: bool,
+        format: RecordFormat,
+    ) -> Result<(u64, u64, Option<u64>)> {
+        // ready to read data
+        let mut pos = reader.stream_position()?;
+        let mut uncompacted = 0;
+        let mut records = 0u64;
+
+        let mut frame = TxnFrame::default();
+        while let Some(decoded) = format.read(&mut *reader) {
+            let (record, len) = match decoded {
+                // Running out of input mid-record can only mean the file
+                // ends there, so nothing valid follows.
+                Err(e) if tail && e.truncated => {
+                    let torn_at = frame.finish().unwrap_or(pos);
+                    return Ok((uncompacted + frame.stale, records, Some(torn_at)));
+                }
+                decoded => decoded?,
+            };
+            let new_pos = pos + len;
+            records += 1;
+            if records.is_multiple_of(DEADLINE_CHECK_INTERVAL) {
+                deadline.check()?;
+            }
+            frame.feed(pos, len, record, |pos, len, record| {
+                uncompacted += Self::index_record(id, pos, len, record, index, filter)?;
+                Ok(())
+            })?;
+            pos = new_pos;
+        }
+        // A transaction missing its commit marker never happened, so for
+        // the tail file it is cut off like a torn record.
+        let torn_at = frame.finish().filter(|_| tail);
+        Ok((uncompacted + frame.stale, records, torn_at))
+    }
+    // Apply the record at `pos` of log file `id` to `index`, returning the
+    // stale bytes it leaves.
+    fn index_record(
+        id: u64,
+        pos: u64,
+        len: u64,
+        record: Record,
+        index: &mut PartialIndex,
+        filter: Option<&dyn Fn(&str) -> bool>,
+    ) -> Result<u64> {
+        if let Record::Clear = record {
+            return Ok(index.clear() + len);
+        }
+        let seq = record.seq();
+        index.max_seq = index.max_seq.max(seq);
+        let cmd = match record.into_command()? {
+            Some(cmd) => cmd,
+            None => return Ok(0),
+        };
+        let key = match &cmd {
+            Command::Set { key, .. } | Command::SetEx { key, .. } | Command::Remove { key } => key,
+        };
+        if !filter.is_none_or(|filter| filter(key)) {
+            return Ok(0);
+        }
+        let stale = match cmd {
+            Command::Set { .. } | Command::SetEx { .. } => {
+                let (key, value, expire_at) = match cmd {
+                    Command::SetEx {
+                        key,
+                        value,
+                        expire_at_unix_secs,
+                    } => (key, value, Some(expire_at_unix_secs)),
+                    Command::Set { key, value } => (key, value, None),
+                    Command::Remove { .. } => unreachable!(),
+                };
+                let cmd_pos = CommandPos {
+                    file_id: id,
+                    pos,
+                    len,
+                    expire_at,
+                    value: inline_value(&value, index.inline_value_size),
+                    seq,
+                };
+                index.insert(key, cmd_pos).map_or(0, |old_cmd| old_cmd.len)
+            }
+            Command::Remove { key } => index.remove(key).map_or(0, |old_cmd| old_cmd.len) + len,
+        };
+        Ok(stale)
+    }
+    // Index the entries recorded in the key index of log file `id`, if it
+    // has a usable one, and position `reader` where replay must resume.
+    fn load_key_index(
+        dir_path: &Path,
+        id: u64,
+        reader: &mut BufReaderWithPos<File>,
+        index: &mut PartialIndex,
+        filter: Option<&dyn Fn(&str) -> bool>,
+    ) -> Result<u64> {
+        let key_index = match KeyIndex::read(dir_path, id) {
+            Some(key_index) if key_index.end <= reader.reader.get_ref().metadata()?.len() => {
+                key_index
+            }
+            _ => return Ok(0),
+        };
+        reader.seek(SeekFrom::Start(key_index.end))?;
+        Ok(Self::index_entries(id, key_index.entries, index, filter))
+    }
+    // Index entries of log file `id` saved by a key index or checkpoint,
+    // returning the stale bytes they supersede.
+    fn index_entries(
+        id: u64,
+        entries: Vec<IndexEntry>,
+        index: &mut PartialIndex,
+        filter: Option<&dyn Fn(&str) -> bool>,
+    ) -> u64 {
+        let mut uncompacted = 0;
+        for (key, pos, len, expire_at, seq) in entries {
+            index.max_seq = index.max_seq.max(seq);
+            if !filter.is_none_or(|filter| filter(&key)) {
+                continue;
+            }
+            let cmd_pos = CommandPos {
+                file_id: id,
+                pos,
+                len,
+                expire_at,
+                value: None,
+                seq,
+            };
+            if let Some(old_cmd) = index.insert(key, cmd_pos) {
+                uncompacted += old_cmd.len;
+            }
+        }
+        uncompacted
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_inner(key, value, None, self.options.sync_sets)
+    }
+    fn set_many(&mut self, pairs: impl IntoIterator<Item = (String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set_inner(key, value, None, false)?;
+        }
+        self.flush()
+    }
+    fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expire_at = self
+            .options
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            + ttl;
+        let secs = expire_at.as_secs() + u64::from(expire_at.subsec_nanos() > 0);
+        self.set_inner(key, value, Some(secs), self.options.sync_sets)
+    }
+    // Write a set of `key`, flushing it to the log file if `flush` is set.
+    fn set_inner(
+        &mut self,
+        key: String,
+        value: String,
+        expire_at: Option<u64>,
+        flush: bool,
+    ) -> Result<()> {
+        self.run_pending_compaction()?;
+        *self.last_op.get_mut().unwrap() = self.options.clock.now();
+        if let Some(validator) = self.options.key_validator {
+            if !validator(&key) {
+                return Err(KvError::InvalidKey { key });
+            }
+        }
+        self.check_sizes(&key, &value)?;
+        // Keyed by hash, the entry a set replaces has to be looked for in
+        // the log.
+        let hashed = match self.hashed {
+            Some(_) => Some(self.find_hashed(&key)?),
+            None => None,
+        };
+        let cached = cached_value(&value, self.options.inline_value_size);
+        let (old_size, new_size) = match &hashed {
+            Some((hash, found)) => (
+                found.as_ref().map_or(0, |&(slot, _)| {
+                    hashed_entry_size(self.hashed_at(*hash, slot).value.as_deref())
+                }),
+                hashed_entry_size(cached),
+            ),
+            None => (self.entry_size(&key), index_entry_size(&key, cached)),
+        };
+        self.check_memory_budget(self.index_bytes - old_size + new_size)?;
+        let old_value = match self.value_index {
+            Some(_) => self.read_value(&key)?,
+            None => None,
+        };
+        let cmd = match expire_at {
+            Some(expire_at_unix_secs) => CommandRef::SetEx {
+                key: &key,
+                value: &value,
+                expire_at_unix_secs,
+            },
+            None => CommandRef::Set {
+                key: &key,
+                value: &value,
+            },
+        };
+        let cmd_pos = self.write_command(cmd, flush)?;
+        self.maybe_sync()?;
+        match hashed {
+            Some((hash, found)) => {
+                self.hashed_set(hash, found.map(|(slot, _)| slot), &value, cmd_pos)
+            }
+            None => self.index_set(key, value, old_value, cmd_pos),
+        }
+        self.ops.sets += 1;
+
+        self.maybe_roll_over()?;
+        self.maybe_rotate_ring()?;
+        self.maybe_compact()?;
+        Ok(())
+    }
+    // Point `key` at its set of `value` just written to the active file.
+    fn index_set(
+        &mut self,
+        key: String,
+        value: String,
+        old_value: Option<String>,
+        mut cmd_pos: CommandPos,
+    ) {
+        cmd_pos.value = inline_value(&value, self.options.inline_value_size);
+        if let Some(value_index) = self.value_index.as_mut() {
+            if let Some(old_value) = old_value {
+                value_index.remove(&old_value, &key);
+            }
+            value_index.insert(value, &key);
+        }
+        self.index_bytes -= self.entry_size(&key);
+        self.index_bytes += index_entry_size(&key, cmd_pos.value.as_deref());
+        if let Some(old_cmd) = self.index.insert(key, cmd_pos) {
+            self.uncompacted += old_cmd.len;
+        }
+    }
+    // Point the entry in `slot` of the bucket of `hash`, or a new one there,
+    // at its set of `value` just written to the active file.
+    fn hashed_set(&mut self, hash: u64, slot: Option<usize>, value: &str, mut cmd_pos: CommandPos) {
+        cmd_pos.value = inline_value(value, self.options.inline_value_size);
+        self.index_bytes += hashed_entry_size(cmd_pos.value.as_deref());
+        let hashed = self.hashed.as_mut().expect("index is hashed");
+        if let Some(old_cmd) = hashed.insert(hash, slot, cmd_pos) {
+            self.index_bytes -= hashed_entry_size(old_cmd.value.as_deref());
+            self.uncompacted += old_cmd.len;
+        }
+    }
+    // With `hashed_keys`, the hash of `key` and, if it has an entry, the
+    // slot of the entry in the bucket of that hash and its value, found by
+    // reading back the record of each entry sharing the hash.
+    fn find_hashed(&mut self, key: &str) -> Result<(u64, Option<(usize, String)>)> {
+        let hashed = self.hashed.as_ref().expect("index is hashed");
+        let hash = hashed.hash(key);
+        for slot in 0..hashed.bucket(hash).len() {
+            if self.hashed_at(hash, slot).file_id == self.current_id {
+                self.flush()?;
+            }
+            let cmd_pos = &self.hashed.as_ref().expect("index is hashed").bucket(hash)[slot];
+            let reader = self.readers.get_mut(cmd_pos.file_id)?;
+            let format = self.options.record_format;
+            let (found, value) = read_pair_at(format, reader, cmd_pos.pos, cmd_pos.len)?;
+            if found == key {
+                return Ok((hash, Some((slot, value))));
+            }
+        }
+        Ok((hash, None))
+    }
+    // The entry in `slot` of the bucket of `hash`.
+    fn hashed_at(&self, hash: u64, slot: usize) -> &CommandPos {
+        &self.hashed.as_ref().expect("index is hashed").bucket(hash)[slot]
+    }
+    // With `hashed_keys`, the index entry of `key`, unless it is absent or
+    // has expired.
+    fn live_hashed(&mut self, key: &str) -> Result<Option<&CommandPos>> {
+        let (hash, found) = self.find_hashed(key)?;
+        Ok(found
+            .map(|(slot, _)| self.hashed_at(hash, slot))
+            .filter(|cmd_pos| !self.is_expired(cmd_pos)))
+    }
+    fn transaction(&mut self, txn: Txn) -> Result<()> {
+        self.full_keys()?;
+        self.run_pending_compaction()?;
+        *self.last_op.get_mut().unwrap() = self.options.clock.now();
+        for cmd in &txn.commands {
+            let (Command::Set { key, .. } | Command::SetEx { key, .. } | Command::Remove { key }) =
+                cmd;
+            self.expire_if_due(key)?;
+        }
+        // Check every write before any reaches the log, so that a refused
+        // one leaves no trace.
+        // The index entry size of each key written so far, 0 once removed.
+        let mut sizes: HashMap<&str, usize> = HashMap::new();
+        let mut index_bytes = self.index_bytes;
+        let mut commands = Vec::new();
+        for cmd in &txn.commands {
+            let key = match cmd {
+                Command::Set { key, .. } | Command::SetEx { key, .. } | Command::Remove { key } => {
+                    key
+                }
+            };
+            let old_size = sizes
+                .get(key.as_str())
+                .copied()
+                .unwrap_or_else(|| self.entry_size(key));
+            let new_size = match cmd {
+                Command::Remove { .. } if old_size == 0 => {
+                    if self.options.strict_removes {
+                        return Err(KvError::KeyNotFound);
+                    }
+                    continue;
+                }
+                Command::Remove { .. } => 0,
+                Command::Set { value, .. } | Command::SetEx { value, .. } => {
+                    if let Some(validator) = self.options.key_validator {
+                        if !validator(key) {
+                            return Err(KvError::InvalidKey { key: key.clone() });
+                        }
+                    }
+                    self.check_sizes(key, value)?;
+                    index_entry_size(key, cached_value(value, self.options.inline_value_size))
+                }
+            };
+            index_bytes = index_bytes - old_size + new_size;
+            sizes.insert(key, new_size);
+            commands.push(cmd);
+        }
+        self.check_memory_budget(index_bytes)?;
+        if commands.is_empty() {
+            return Ok(());
+        }
+
+        let format = self.options.record_format;
+        let writer = self.writer()?;
+        let begin = writer.pos;
+        format.write(&mut *writer, &Record::TxnBegin)?;
+        let mut markers = writer.pos - begin;
+        let mut positions = Vec::with_capacity(commands.len());
+        for cmd in &commands {
+            positions.push(self.write_command(cmd.as_ref(), false)?);
+        }
+        let writer = self.writer()?;
+        let commit = writer.pos;
+        format.write(&mut *writer, &Record::TxnCommit)?;
+        markers += writer.pos - commit;
+        if let Err(e) = writer.flush() {
+            let path = log_path(&self.files, self.current_id);
+            return Err(KvError::file(FileOp::Write, path)(e));
+        }
+        self.ops.bytes_written += markers;
+        self.uncompacted += markers;
+        self.maybe_sync()?;
+
+        for (cmd, cmd_pos) in commands.into_iter().zip(positions) {
+            let (key, value) = match cmd {
+                Command::Set { key, value } | Command::SetEx { key, value, .. } => {
+                    (key, Some(value))
+                }
+                Command::Remove { key } => (key, None),
+            };
+            let old_value = match self.value_index {
+                Some(_) => self.read_value(key)?,
+                None => None,
+            };
+            match value {
+                Some(value) => {
+                    self.ops.sets += 1;
+                    self.index_set(key.clone(), value.clone(), old_value, cmd_pos);
+                }
+                None => {
+                    self.ops.removes += 1;
+                    self.index_remove(key.clone(), old_value, cmd_pos.len);
+                }
+            }
+        }
+        self.maybe_roll_over()?;
+        self.maybe_rotate_ring()?;
+        self.maybe_compact()?;
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<()> {
+        if let Some(writer) = self.curren_writer.as_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+    // Count a write just made and sync the active file if the `fsync`
+    // policy says it is time.
+    fn maybe_sync(&mut self) -> Result<()> {
+        self.unsynced_writes += 1;
+        let due = match self.options.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Batch { writes, interval } => {
+                self.unsynced_writes >= writes
+                    || interval
+                        .is_some_and(|interval| self.elapsed_since(self.last_sync) >= interval)
+            }
+            FsyncPolicy::Never => false,
+        };
+        match due {
+            true => self.sync(),
+            false => Ok(()),
+        }
+    }
+    // Flush the active file and sync it to disk.
+    fn sync(&mut self) -> Result<()> {
+        if let Some(writer) = self.curren_writer.as_mut() {
+            let path = log_path(&self.files, self.current_id);
+            writer
+                .flush()
+                .and_then(|()| writer.writer.get_ref().sync_all())
+                .map_err(KvError::file(FileOp::Write, path))?;
+            self.ops.syncs += 1;
+        }
+        self.unsynced_writes = 0;
+        self.last_sync = self.options.clock.now();
+        Ok(())
+    }
+    // The active log file's writer, creating the file if it was deferred.
+    fn writer(&mut self) -> Result<&mut BufWriterWithPos<File>> {
+        if self.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        if self.curren_writer.is_none() {
+            create_dir(self.files.dir())?;
+            if self.lock.is_none() {
+                self.lock = Some(lock_store(&self.files)?);
+            }
+            // A deferred open leaves claiming the directory and noting its
+            // record format to the first write.
+            if let LogFiles::Dir(dir) = &self.files {
+                engine::claim_kvs_dir(dir, self.options.record_format)?;
+            }
+            let writer = Self::new_log_file(
+                &self.files,
+                self.current_id,
+                self.options.buffer_capacity,
+                &mut self.readers,
+            )?;
+            self.curren_writer = Some(writer);
+        }
+        Ok(self.curren_writer.as_mut().unwrap())
+    }
+    // Write `cmd` to the active log file, returning the index entry for it.
+    // Unless `flush` is set the record may stay buffered in memory.
+    fn write_command(&mut self, cmd: CommandRef, flush: bool) -> Result<CommandPos> {
+        let format = self.options.record_format;
+        let compress = self.options.compress_values;
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let writer = self.writer()?;
+        let pos = writer.pos;
+        write_command_record(format, &mut *writer, cmd, seq, compress)?;
+        if flush {
+            if let Err(e) = writer.flush() {
+                let path = log_path(&self.files, self.current_id);
+                return Err(KvError::file(FileOp::Write, path)(e));
+            }
+        }
+        let len = writer.pos - pos;
+        self.ops.bytes_written += len;
+        let expire_at = match cmd {
+            CommandRef::SetEx {
+                expire_at_unix_secs,
+                ..
+            } => Some(expire_at_unix_secs),
+            _ => None,
+        };
+        Ok(CommandPos {
+            file_id: self.current_id,
+            pos,
+            len,
+            expire_at,
+            value: None,
+            seq,
+        })
+    }
+    // Move on to a new active file once the current one reaches
+    // `max_file_size`.
+    fn maybe_roll_over(&mut self) -> Result<()> {
+        match self.options.max_file_size {
+            Some(max) if self.active_len() >= max => self.roll_over(),
+            _ => Ok(()),
+        }
+    }
+    fn active_len(&self) -> u64 {
+        self.curren_writer.as_ref().map_or(0, |writer| writer.pos)
+    }
+    // Flush the active file and make a new one, numbered next, the active
+    // one. The old file stays as it is until compaction.
+    fn roll_over(&mut self) -> Result<()> {
+        // Writes the policy would still sync must not be left behind in a
+        // file it no longer syncs.
+        if self.unsynced_writes > 0 && self.options.fsync != FsyncPolicy::Never {
+            self.sync()?;
+        }
+        self.flush()?;
+        self.current_id += 1;
+        self.curren_writer = Some(Self::new_log_file(
+            &self.files,
+            self.current_id,
+            self.options.buffer_capacity,
+            &mut self.readers,
+        )?);
+        Ok(())
+    }
+    // In ring mode, roll over a full active segment and drop the oldest
+    // segments until the log fits in the configured capacity.
+    fn maybe_rotate_ring(&mut self) -> Result<()> {
+        let capacity = match self.options.ring_capacity {
+            Some(capacity) => capacity,
+            None => return Ok(()),
+        };
+        if self.active_len() >= self.options.ring_segment_size {
+            self.roll_over()?;
+        }
+        while !self.options.audit_mode && self.log_size()? > capacity {
+            let oldest = match self
+                .readers
+                .keys()
+                .filter(|&&id| id != self.current_id)
+                .min()
+            {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            self.drop_segment(oldest)?;
+        }
+        Ok(())
+    }
+    // Remove a whole log file, migrating its live entries to the active file
+    // when `ring_migrate_live` is set and forgetting them otherwise.
+    fn drop_segment(&mut self, id: u64) -> Result<()> {
+        self.forget_saved_index()?;
+        let writer = self.curren_writer.as_mut().ok_or(KvError::ReadOnly)?;
+        let mut reader = self.readers.take(id)?;
+        let file_len = reader.reader.get_ref().metadata()?.len();
+        let mut live = 0;
+        for cmd_pos in self
+            .index
+            .values_mut()
+            .filter(|cmd_pos| cmd_pos.file_id == id)
+        {
+            live += cmd_pos.len;
+            if self.options.ring_migrate_live {
+                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+                let pos = writer.pos;
+                let len = std::io::copy(&mut (&mut reader).take(cmd_pos.len), writer)?;
+                self.ops.bytes_written += len;
+                cmd_pos.file_id = self.current_id;
+                cmd_pos.pos = pos;
+                cmd_pos.len = len;
+            }
+        }
+        writer.flush()?;
+        if !self.options.ring_migrate_live {
+            self.index.retain(|_, cmd_pos| cmd_pos.file_id != id);
+            self.index_bytes = index_size(&self.index);
+        }
+        self.uncompacted = self.uncompacted.saturating_sub(file_len - live);
+        drop(reader);
+        self.remove_log(id)?;
+        if self.value_index.is_some() && !self.options.ring_migrate_live {
+            self.rebuild_value_index()?;
+        }
+        Ok(())
+    }
+    // Populate the reverse value index from every live entry.
+    fn rebuild_value_index(&mut self) -> Result<()> {
+        let mut value_index = ValueIndex::default();
+        let keys: Vec<String> = self.index.keys().cloned().collect();
+        for key in keys {
+            let value = self.read_value(&key)?.ok_or(KvError::KeyNotFound)?;
+            value_index.insert(value, &key);
+        }
+        self.value_index = Some(value_index);
+        Ok(())
+    }
+    fn keys_with_value(&self, value: &str) -> Vec<String> {
+        self.value_index
+            .as_ref()
+            .and_then(|value_index| value_index.keys_by_value.get(value))
+            .map(|keys| {
+                keys.iter()
+                    .filter(|key| self.contains_key(key))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    // Total size of all log files tracked by the store.
+    fn log_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for &id in self.readers.keys() {
+            size += self.readers.file_len(id)?;
+        }
+        Ok(size)
+    }
+    // Compact if stale data crossed the threshold or has lingered too long.
+    fn maybe_compact(&mut self) -> Result<()> {
+        // Idle-only compaction is left entirely to `tick`.
+        if self.options.compact_when_idle.is_some() {
+            return Ok(());
+        }
+        let overdue = match self.options.compact_after {
+            Some(interval) => {
+                self.uncompacted > 0 && self.elapsed_since(self.last_compaction) >= interval
+            }
+            None => false,
+        };
+        if !self.may_compact() {
+            return Ok(());
+        }
+        // Only look at the file sizes once there is something stale.
+        let triggered = self.uncompacted > 0
+            && self
+                .options
+                .compaction_policy
+                .triggered(self.uncompacted, self.log_size()?);
+        if triggered || overdue {
+            if self.compacting {
+                return Ok(());
+            }
+            if let Some(signal) = &self.compaction_signal {
+                if signal.send(()).is_ok() {
+                    self.compacting = true;
+                    return Ok(());
+                }
+            }
+            if self.options.defer_compaction_one_op && !self.compaction_pending {
+                self.compaction_pending = true;
+                return Ok(());
+            }
+            self.compact()?;
+        }
+        Ok(())
+    }
+    // Make `index` the store's, keyed by hash with `hashed_keys`.
+    fn install_index(&mut self, index: BTreeMap<String, CommandPos>) {
+        match self.options.hashed_keys {
+            Some(hash) => {
+                let hashed = HashedIndex::new(hash, index);
+                self.index_bytes = hashed
+                    .values()
+                    .map(|cmd_pos| hashed_entry_size(cmd_pos.value.as_deref()))
+                    .sum();
+                self.hashed = Some(hashed);
+            }
+            None => {
+                self.index_bytes = index_size(&index);
+                self.index = index;
+            }
+        }
+    }
+    // Every index entry, keyed by its key or its key's hash.
+    fn positions(&self) -> impl Iterator<Item = &CommandPos> {
+        self.index
+            .values()
+            .chain(self.hashed.iter().flat_map(HashedIndex::values))
+    }
+    // Fail with `KvError::HashedKeys` unless the index holds the full keys.
+    fn full_keys(&self) -> Result<()> {
+        match self.hashed {
+            Some(_) => Err(KvError::HashedKeys),
+            None => Ok(()),
+        }
+    }
+    // Whether the index has an entry for `key`, expired or not.
+    fn indexed(&mut self, key: &str) -> Result<bool> {
+        match self.hashed {
+            Some(_) => Ok(self.find_hashed(key)?.1.is_some()),
+            None => Ok(self.index.contains_key(key)),
+        }
+    }
+    // Estimated memory the index entry of `key` takes up, or 0 if it has
+    // none.
+    fn entry_size(&self, key: &str) -> usize {
+        self.index
+            .get(key)
+            .map_or(0, |cmd_pos| index_entry_size(key, cmd_pos.value.as_deref()))
+    }
+    // Fail if an index of `needed` bytes would not fit the memory budget.
+    fn check_memory_budget(&self, needed: usize) -> Result<()> {
+        match self.options.memory_budget {
+            Some(budget) if needed > budget => {
+                Err(KvError::MemoryBudgetExceeded { needed, budget })
+            }
+            _ => Ok(()),
+        }
+    }
+    // Fail if `key` or `value` is over its configured size limit.
+    fn check_sizes(&self, key: &str, value: &str) -> Result<()> {
+        if let Some(max) = self.options.max_key_size.filter(|&max| key.len() > max) {
+            return Err(KvError::KeyTooLarge {
+                size: key.len(),
+                max,
+            });
+        }
+        if let Some(max) = self.options.max_value_size.filter(|&max| value.len() > max) {
+            return Err(KvError::ValueTooLarge {
+                size: value.len(),
+                max,
+            });
+        }
+        Ok(())
+    }
+    // Run a compaction deferred by the previous operation.
+    fn run_pending_compaction(&mut self) -> Result<()> {
+        if self.compaction_pending {
+            self.compact()?;
+        }
+        Ok(())
+    }
+    fn tick(&mut self) -> Result<bool> {
+        if let FsyncPolicy::Batch {
+            interval: Some(interval),
+            ..
+        } = self.options.fsync
+        {
+            if self.unsynced_writes > 0 && self.elapsed_since(self.last_sync) >= interval {
+                self.sync()?;
+            }
+        }
+        self.sweep_if_due()?;
+        let window = match self.options.compact_when_idle {
+            Some(window) => window,
+            None => return Ok(false),
+        };
+        if self.uncompacted == 0
+            || !self.may_compact()
+            || self.read_only
+            || self.elapsed_since(*self.last_op.lock().unwrap()) < window
+        {
+            return Ok(false);
+        }
+        self.compact()?;
+        Ok(true)
+    }
+    // Whether automatic compaction may discard data in this store.
+    fn may_compact(&self) -> bool {
+        !self.filtered && !self.options.audit_mode
+    }
+    // Time elapsed on the store's clock since `earlier`.
+    fn elapsed_since(&self, earlier: SystemTime) -> Duration {
+        self.options
+            .clock
+            .now()
+            .duration_since(earlier)
+            .unwrap_or_default()
+    }
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.run_pending_compaction()?;
+        *self.ops.gets.get_mut() += 1;
+        *self.last_op.get_mut().unwrap() = self.options.clock.now();
+        if self.hashed.is_some() {
+            return self.get_hashed(&key);
+        }
+        if self.expire_if_due(&key)? {
+            return Ok(None);
+        }
+        self.read_value(&key)
+    }
+    // Serve a get with `hashed_keys` from a single read of each record
+    // sharing the hash of `key`.
+    fn get_hashed(&mut self, key: &str) -> Result<Option<String>> {
+        let (hash, found) = self.find_hashed(key)?;
+        let (slot, value) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        if !self.is_expired(self.hashed_at(hash, slot)) {
+            return Ok(Some(value));
+        }
+        if !self.read_only {
+            self.remove_hashed(key, hash, slot)?;
+        }
+        Ok(None)
+    }
+    fn get_reader(&mut self, key: &str) -> Result<Option<ValueReader>> {
+        self.run_pending_compaction()?;
+        *self.ops.gets.get_mut() += 1;
+        *self.last_op.get_mut().unwrap() = self.options.clock.now();
+        if self.expire_if_due(key)? {
+            return Ok(None);
+        }
+        if self.hashed.is_some() {
+            return Ok(self.read_value(key)?.map(ValueReader::from_value));
+        }
+        let streamable = match self.index.get(key) {
+            Some(cmd_pos) => cmd_pos.value.is_none(),
+            None => return Ok(None),
+        };
+        let from_log = match self.options.record_format {
+            RecordFormat::Bincode => ValueReader::from_bincode_log,
+            RecordFormat::MessagePack => ValueReader::from_messagepack_log,
+            // JSON escapes would have to be decoded on the fly.
+            RecordFormat::Json => return Ok(self.read_value(key)?.map(ValueReader::from_value)),
+        };
+        if streamable {
+            if self.index[key].file_id == self.current_id {
+                self.flush()?;
+            }
+            let cmd_pos = &self.index[key];
+            let file = open_log(&self.files, cmd_pos.file_id)?;
+            let reader = from_log(file, cmd_pos.pos, key, self.options.buffer_capacity)?;
+            if reader.is_some() {
+                return Ok(reader);
+            }
+        }
+        Ok(self.read_value(key)?.map(ValueReader::from_value))
+    }
+    fn get_many(
+        &self,
+        keys: &[String],
+        readers: &mut HandleReaders,
+    ) -> Result<Vec<Option<String>>> {
+        self.count_gets(keys.len() as u64);
+        let mut values = vec![None; keys.len()];
+        let mut reads = Vec::with_capacity(keys.len());
+        for (i, key) in keys.iter().enumerate() {
+            if let Some(cmd_pos) = self.live_entry(key) {
+                match &cmd_pos.value {
+                    Some(value) => values[i] = Some(value.to_string()),
+                    None => reads.push((cmd_pos.file_id, cmd_pos.pos, i)),
+                }
+            }
+        }
+        reads.sort_unstable();
+        for (_, _, i) in reads {
+            values[i] = Some(self.read_shared(&self.index[&keys[i]], readers)?);
+        }
+        Ok(values)
+    }
+    // Read the value the index holds for `key` from its log file, whether
+    // or not its TTL has run out, as keeping the value index up to date
+    // needs. Reads made for callers skip expired keys before getting here.
+    fn read_value(&mut self, key: &str) -> Result<Option<String>> {
+        if self.hashed.is_some() {
+            return Ok(self.find_hashed(key)?.1.map(|(_, value)| value));
+        }
+        if let Some(value) = self
+            .index
+            .get(key)
+            .and_then(|cmd_pos| cmd_pos.value.as_ref())
+        {
+            return Ok(Some(value.to_string()));
+        }
+        if self
+            .index
+            .get(key)
+            .is_some_and(|cmd_pos| cmd_pos.file_id == self.current_id)
+        {
+            self.flush()?;
+        }
+        if let Some(cmd_pos) = self.index.get(key) {
+            let reader = self.readers.get_mut(cmd_pos.file_id)?;
+            read_value_at(self.options.record_format, reader, cmd_pos).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+    // Serve a get without writing to the store, reading through a handle's
+    // own `readers`. Returns `None` if the get has to write after all: to
+    // run a deferred compaction, drop an expired key or flush the record.
+    // With `hashed_keys` it always does, as the keys sharing a hash are told
+    // apart through the store's own readers.
+    fn get_shared(&self, key: &str, readers: &mut HandleReaders) -> Option<Result<Option<String>>> {
+        if self.compaction_pending || self.hashed.is_some() {
+            return None;
+        }
+        let cmd_pos = self.index.get(key);
+        if let Some(cmd_pos) = cmd_pos {
+            let buffered = cmd_pos.file_id == self.current_id
+                && self
+                    .curren_writer
+                    .as_ref()
+                    .is_some_and(|writer| !writer.writer.buffer().is_empty());
+            if self.is_expired(cmd_pos) || (buffered && cmd_pos.value.is_none()) {
+                return None;
+            }
+        }
+        self.count_gets(1);
+        match cmd_pos {
+            Some(cmd_pos) => Some(self.read_shared(cmd_pos, readers).map(Some)),
+            None => Some(Ok(None)),
+        }
+    }
+    // Whether reads can be served under the shared lock: no compaction is
+    // waiting to run and every record is flushed out to its file.
+    fn readable_shared(&self) -> bool {
+        !self.compaction_pending
+            && self
+                .curren_writer
+                .as_ref()
+                .is_none_or(|writer| writer.writer.buffer().is_empty())
+    }
+    // Read the value of the flushed set record at `cmd_pos` through a
+    // handle's own `readers`.
+    fn read_shared(&self, cmd_pos: &CommandPos, readers: &mut HandleReaders) -> Result<String> {
+        if let Some(value) = &cmd_pos.value {
+            return Ok(value.to_string());
+        }
+        if readers.generation != self.reader_generation {
+            readers.files.clear();
+            readers.generation = self.reader_generation;
+        }
+        let open = || {
+            let file = open_log(&self.files, cmd_pos.file_id)?;
+            BufReaderWithPos::new(file, self.options.buffer_capacity)
+        };
+        let reader = readers
+            .files
+            .get(cmd_pos.file_id, self.options.max_open_readers, open)?;
+        read_value_at(self.options.record_format, reader, cmd_pos)
+    }
+    // Count `gets` reads served without the exclusive lock.
+    fn count_gets(&self, gets: u64) {
+        self.ops.gets.fetch_add(gets, AtomicOrdering::Relaxed);
+        *self.last_op.lock().unwrap() = self.options.clock.now();
+    }
+    // The index entry of `key`, unless it is absent or has expired.
+    fn live_entry(&self, key: &str) -> Option<&CommandPos> {
+        self.index
+            .get(key)
+            .filter(|cmd_pos| !self.is_expired(cmd_pos))
+    }
+    fn read_at(&mut self, file_id: u64, pos: u64, len: u64) -> Result<Command> {
+        self.flush()?;
+        let reader = self.readers.get_mut(file_id)?;
+        let file_len = reader.reader.get_ref().metadata()?.len();
+        if len == 0 || pos.checked_add(len).is_none_or(|end| end > file_len) {
+            return Err(KvError::InvalidPosition { file_id, pos, len });
+        }
+        reader.seek(SeekFrom::Start(pos))?;
+        let record = match self.options.record_format.read(reader.take(len)) {
+            Some(decoded) => decoded?.0,
+            None => return Err(KvError::UnexpectedCommandType),
+        };
+        record.into_command()?.ok_or(KvError::UnexpectedCommandType)
+    }
+    fn digest(&self, readers: &mut HandleReaders) -> Result<[u8; 32]> {
+        self.full_keys()?;
+        let mut keys = self.live_keys(self.index.iter());
+        keys.sort_unstable();
+
+        let mut hasher = Sha256::new();
+        for key in keys {
+            let value = self.read_shared(&self.index[&key], readers)?;
+            for part in [key.as_bytes(), value.as_bytes()] {
+                hasher.update((part.len() as u64).to_le_bytes());
+                hasher.update(part);
+            }
+        }
+        Ok(hasher.finalize().into())
+    }
+    fn scan_range(
+        &self,
+        start: &str,
+        end: &str,
+        readers: &mut HandleReaders,
+    ) -> Result<Vec<(String, String)>> {
+        self.full_keys()?;
+        let order = match self.options.key_order {
+            Some(order) => order,
+            None => return self.range(start, end, readers),
+        };
+        let mut keys = self.live_keys(self.index.iter().filter(|(key, _)| {
+            order(start, key) != Ordering::Greater && order(key, end) == Ordering::Less
+        }));
+        keys.sort_unstable_by(|a, b| order(a, b));
+        self.read_pairs(keys, readers)
+    }
+    fn range(
+        &self,
+        start: &str,
+        end: &str,
+        readers: &mut HandleReaders,
+    ) -> Result<Vec<(String, String)>> {
+        self.full_keys()?;
+        // `BTreeMap::range` panics on a backwards range.
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let keys = self.live_keys(
+            self.index
+                .range::<str, _>((Bound::Included(start), Bound::Excluded(end))),
+        );
+        self.read_pairs(keys, readers)
+    }
+    fn scan_prefix(
+        &self,
+        prefix: &str,
+        readers: &mut HandleReaders,
+    ) -> Result<Vec<(String, String)>> {
+        self.full_keys()?;
+        let keys = self.live_keys(
+            self.index
+                .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix)),
+        );
+        self.read_pairs(keys, readers)
+    }
+    // The keys of `entries` whose TTL has not run out.
+    fn live_keys<'a>(
+        &self,
+        entries: impl Iterator<Item = (&'a String, &'a CommandPos)>,
+    ) -> Vec<String> {
+        entries
+            .filter(|(_, cmd_pos)| !self.is_expired(cmd_pos))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+    fn next_entry(&mut self, after: Option<&str>) -> Result<Option<(String, String)>> {
+        self.full_keys()?;
+        self.run_pending_compaction()?;
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let key = match self
+            .index
+            .range::<str, _>((start, Bound::Unbounded))
+            .find(|(_, cmd_pos)| !self.is_expired(cmd_pos))
+        {
+            Some((key, _)) => key.clone(),
+            None => return Ok(None),
+        };
+        let value = self.read_value(&key)?.ok_or(KvError::KeyNotFound)?;
+        Ok(Some((key, value)))
+    }
+    // Pair each of `keys` with its value, keeping their order.
+    fn read_pairs(
+        &self,
+        keys: Vec<String>,
+        readers: &mut HandleReaders,
+    ) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = self.read_shared(&self.index[&key], readers)?;
+            pairs.push((key, value));
+        }
+        Ok(pairs)
+    }
+    fn tombstoned_keys(&mut self) -> Result<Vec<String>> {
+        self.full_keys()?;
+        let mut removed = Vec::new();
+        self.for_each_record(|_, _, _, cmd| {
+            if let Command::Remove { key } = cmd {
+                removed.push(key);
+            }
+            Ok(())
+        })?;
+        removed.retain(|key| !self.index.contains_key(key));
+        removed.sort_unstable();
+        removed.dedup();
+        Ok(removed)
+    }
+    fn replay(&mut self) -> Result<Replay> {
+        self.flush()?;
+        let mut ids: Vec<u64> = self.readers.keys().cloned().collect();
+        ids.sort_unstable();
+        let pins = self.pins.pin(ids.clone());
+        let mut files = Vec::with_capacity(ids.len());
+        for id in ids {
+            files.push(open_log(&self.files, id)?);
+        }
+        Ok(Replay::new(
+            files,
+            self.options.scan_prefetch,
+            self.options.record_format,
+            pins,
+        ))
+    }
+    fn tail(&mut self) -> Result<Tail> {
+        self.flush()?;
+        match self.readers.keys().max() {
+            Some(&id) => {
+                let len = self.readers.file_len(id)?;
+                Tail::new(self.files.clone(), id, len, self.options.record_format)
+            }
+            None => Tail::new(self.files.clone(), 0, 0, self.options.record_format),
+        }
+    }
+    fn scan(&self) -> Result<Scan> {
+        self.full_keys()?;
+        let order = self
+            .options
+            .key_order
+            .unwrap_or(|a: &str, b: &str| a.cmp(b));
+        let mut entries: Vec<(String, u64, u64, u64)> = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| !self.is_expired(cmd_pos))
+            .map(|(key, cmd_pos)| (key.clone(), cmd_pos.file_id, cmd_pos.pos, cmd_pos.len))
+            .collect();
+        entries.sort_unstable_by(|a, b| order(&a.0, &b.0));
+
+        let ids: BTreeSet<u64> = entries.iter().map(|entry| entry.1).collect();
+        let pins = self.pins.pin(ids.iter().cloned().collect());
+        let mut files = HashMap::new();
+        for id in ids {
+            let file = open_log(&self.files, id)?;
+            files.insert(id, BufReader::new(file));
+        }
+        Ok(Scan::new(entries, files, self.options.record_format, pins))
+    }
+    fn stream_changes(&mut self, from_file_id: u64, out: &mut impl Write) -> Result<u64> {
+        self.flush()?;
+        let mut ids: Vec<u64> = self
+            .readers
+            .keys()
+            .cloned()
+            .filter(|&id| id >= from_file_id)
+            .collect();
+        ids.sort_unstable();
+        for id in ids {
+            let reader = self.readers.get_mut(id)?;
+            reader.seek(SeekFrom::Start(0))?;
+            std::io::copy(reader, out)?;
+        }
+        Ok(self.current_id)
+    }
+    fn import(&mut self, reader: impl Read) -> Result<usize> {
+        let imported = backup::import(reader, |key, value| self.set(key, value))?;
+        self.flush()?;
+        Ok(imported)
+    }
+    fn merge_from(
+        &mut self,
+        pairs: impl Iterator<Item = Result<(String, String)>>,
+    ) -> Result<usize> {
+        let mut merged = 0;
+        for pair in pairs {
+            let (key, value) = pair?;
+            self.set_inner(key, value, None, false)?;
+            merged += 1;
+        }
+        self.flush()?;
+        Ok(merged)
+    }
+    fn apply_changes(&mut self, mut changes: impl Read) -> Result<usize> {
+        let mut applied = 0;
+        // A transaction is only applied once its commit record arrives, so
+        // a stream cut off in the middle of one leaves none of it behind.
+        let mut frame = TxnFrame::default();
+        while let Some(decoded) = self.options.record_format.read(&mut changes) {
+            let (record, len) = decoded?;
+            frame.feed(0, len, record, |_, _, record| {
+                if self.apply_change(record)? {
+                    applied += 1;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(applied)
+    }
+    // Apply one streamed record, returning whether it counts as a command.
+    fn apply_change(&mut self, record: Record) -> Result<bool> {
+        if let Record::Clear = record {
+            self.full_keys()?;
+            let keys: Vec<_> = self.index.keys().cloned().collect();
+            for key in keys {
+                self.remove_entry(key)?;
+            }
+            return Ok(true);
+        }
+        match record.into_command()? {
+            Some(Command::Set { key, value }) => self.set(key, value)?,
+            Some(Command::SetEx {
+                key,
+                value,
+                expire_at_unix_secs,
+            }) => self.set_inner(
+                key,
+                value,
+                Some(expire_at_unix_secs),
+                self.options.sync_sets,
+            )?,
+            Some(Command::Remove { key }) => {
+                if self.indexed(&key)? {
+                    self.remove(key)?;
+                }
+            }
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+    // Decode every record of every log file in write order, passing the file
+    // id, position and length of each to `f`.
+    fn for_each_record(
+        &mut self,
+        mut f: impl FnMut(u64, u64, u64, Command) -> Result<()>,
+    ) -> Result<()> {
+        self.flush()?;
+        let mut ids: Vec<u64> = self.readers.keys().cloned().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let reader = self.readers.get_mut(id)?;
+            let mut pos = reader.seek(SeekFrom::Start(0))?;
+            while let Some(decoded) = self.options.record_format.read(&mut *reader) {
+                let (record, len) = decoded?;
+                if let Some(cmd) = record.into_command()? {
+                    f(id, pos, len, cmd)?;
+                }
+                pos += len;
+            }
+        }
+        Ok(())
+    }
+    fn compaction_advice(&mut self) -> Result<CompactionAdvice> {
+        let live: HashSet<(u64, u64)> = self
+            .positions()
+            .map(|cmd_pos| (cmd_pos.file_id, cmd_pos.pos))
+            .collect();
+        let mut files: BTreeMap<u64, FileFragmentation> = BTreeMap::new();
+        self.for_each_record(|file_id, pos, len, _| {
+            let file = files.entry(file_id).or_insert(FileFragmentation {
+                file_id,
+                ..FileFragmentation::default()
+            });
+            if live.contains(&(file_id, pos)) {
+                file.live_bytes += len;
+            } else {
+                file.stale_bytes += len;
+            }
+            Ok(())
+        })?;
+
+        let live_bytes: u64 = files.values().map(|file| file.live_bytes).sum();
+        let reclaimable_bytes: u64 = files.values().map(|file| file.stale_bytes).sum();
+        let mut worst_files: Vec<FileFragmentation> = files
+            .into_values()
+            .filter(|file| file.stale_bytes > 0)
+            .collect();
+        worst_files.sort_by(|a, b| {
+            b.stale_ratio()
+                .total_cmp(&a.stale_ratio())
+                .then(b.stale_bytes.cmp(&a.stale_bytes))
+        });
+        Ok(CompactionAdvice {
+            should_compact: reclaimable_bytes > 0
+                && (reclaimable_bytes > live_bytes
+                    || self
+                        .options
+                        .compaction_policy
+                        .triggered(reclaimable_bytes, live_bytes + reclaimable_bytes)),
+            reclaimable_bytes,
+            worst_files,
+        })
+    }
+    fn compaction_estimate(&self) -> CompactionEstimate {
+        let live_bytes = self.live_bytes();
+        let indexed_bytes: u64 = self.positions().map(|cmd_pos| cmd_pos.len).sum();
+        CompactionEstimate {
+            // Compaction drops the records of expired keys too.
+            reclaimable_bytes: self.uncompacted + (indexed_bytes - live_bytes),
+            live_bytes,
+            // Best effort, as in `stats`.
+            current_total_bytes: self.log_size().unwrap_or(0),
+        }
+    }
+    fn cleanup(&mut self) -> Result<usize> {
+        if self.filtered {
+            return Err(KvError::FilteredStore);
+        }
+        if self.options.audit_mode {
+            return Err(KvError::AuditMode);
+        }
+        if self.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        let live: HashSet<u64> = self.positions().map(|cmd_pos| cmd_pos.file_id).collect();
+        let mut files: BTreeMap<u64, FileKeys> = self
+            .readers
+            .keys()
+            .map(|&id| (id, FileKeys::default()))
+            .collect();
+        self.for_each_record(|file_id, _, len, cmd| {
+            let file = files
+                .get_mut(&file_id)
+                .ok_or(KvError::ReaderNotFound(file_id))?;
+            file.bytes += len;
+            match cmd {
+                Command::Set { key, .. } | Command::SetEx { key, .. } => {
+                    file.sets.insert(key);
+                }
+                Command::Remove { key } => file.removes.push(key),
+            }
+            Ok(())
+        })?;
+
+        // Keys set in the files kept so far, oldest first.
+        let mut kept_sets = HashSet::new();
+        let mut removed = 0;
+        for (id, file) in files {
+            let dead = id != self.current_id
+                && !live.contains(&id)
+                && !file.removes.iter().any(|key| kept_sets.contains(key));
+            if !dead {
+                kept_sets.extend(file.sets);
+                continue;
+            }
+            self.forget_saved_index()?;
+            self.readers.remove(id);
+            self.reader_generation += 1;
+            self.remove_log(id)?;
+            self.uncompacted = self.uncompacted.saturating_sub(file.bytes);
+            removed += 1;
+        }
+        Ok(removed)
+    }
+    fn options(&self) -> &KvStoreOptions {
+        &self.options
+    }
+    fn keys(&self) -> Vec<String> {
+        let mut keys = self.live_keys(self.index.iter());
+        if let Some(order) = self.options.key_order {
+            keys.sort_unstable_by(|a, b| order(a, b));
+        }
+        keys
+    }
+    fn len(&self) -> usize {
+        self.positions()
+            .filter(|cmd_pos| !self.is_expired(cmd_pos))
+            .count()
+    }
+    // Bytes of the records of keys that have not expired.
+    fn live_bytes(&self) -> u64 {
+        self.positions()
+            .filter(|cmd_pos| !self.is_expired(cmd_pos))
+            .map(|cmd_pos| cmd_pos.len)
+            .sum()
+    }
+    fn is_empty(&self) -> bool {
+        self.positions().all(|cmd_pos| self.is_expired(cmd_pos))
+    }
+    fn set_compaction_threshold(&mut self, bytes: u64) {
+        self.options.compaction_policy = CompactionPolicy::Bytes(bytes);
+    }
+    fn stats(&self) -> KvStoreStats {
+        KvStoreStats {
+            live_keys: self.len() as u64,
+            uncompacted_bytes: self.uncompacted,
+            num_log_files: self.readers.len() as u64,
+            current_file_id: self.current_id,
+            // Best effort: stats are informational and should not fail.
+            disk_bytes: self.log_size().unwrap_or(0),
+            live_bytes: self.live_bytes(),
+            bytes_written: self.ops.bytes_written,
+            compactions: self.ops.compactions,
+            gets: self.ops.gets.load(AtomicOrdering::Relaxed),
+            sets: self.ops.sets,
+            removes: self.ops.removes,
+            recovered_records: self.ops.recovered,
+            syncs: self.ops.syncs,
+        }
+    }
+    fn swap(&mut self, a: String, b: String) -> Result<()> {
+        self.full_keys()?;
+        self.expire_if_due(&a)?;
+        self.expire_if_due(&b)?;
+        let value_a = self.read_value(&a)?.ok_or(KvError::KeyNotFound)?;
+        let value_b = self.read_value(&b)?.ok_or(KvError::KeyNotFound)?;
+        if a == b {
+            return Ok(());
+        }
+        let expire_a = self.index[&a].expire_at;
+        let expire_b = self.index[&b].expire_at;
+        let commands = vec![
+            Command::set(a, value_b, expire_b),
+            Command::set(b, value_a, expire_a),
+        ];
+        self.transaction(Txn { commands })
+    }
+    fn rename_key(&mut self, from: String, to: String) -> Result<()> {
+        self.full_keys()?;
+        self.expire_if_due(&from)?;
+        let value = self.read_value(&from)?.ok_or(KvError::KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        let set = Command::set(to, value, self.index[&from].expire_at);
+        let commands = vec![set, Command::Remove { key: from }];
+        self.transaction(Txn { commands })
+    }
+    fn append(&mut self, key: String, suffix: &str) -> Result<()> {
+        self.expire_if_due(&key)?;
+        let mut value = self.read_value(&key)?.unwrap_or_default();
+        value.push_str(suffix);
+        self.set(key, value)
+    }
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: String,
+    ) -> Result<bool> {
+        self.expire_if_due(&key)?;
+        if self.read_value(&key)? != expected {
+            return Ok(false);
+        }
+        self.set(key, new)?;
+        Ok(true)
+    }
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        self.expire_if_due(&key)?;
+        if self.indexed(&key)? {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+    fn update(
+        &mut self,
+        key: String,
+        f: impl FnOnce(Option<String>) -> Option<String>,
+    ) -> Result<()> {
+        self.expire_if_due(&key)?;
+        match f(self.read_value(&key)?) {
+            Some(value) => self.set(key, value),
+            None if self.indexed(&key)? => {
+                self.remove_entry(key)?;
+                self.maybe_compact()
+            }
+            None => Ok(()),
+        }
+    }
+    fn set_and_get_old(&mut self, key: String, value: String) -> Result<Option<String>> {
+        self.expire_if_due(&key)?;
+        let old_value = self.read_value(&key)?;
+        self.set(key, value)?;
+        Ok(old_value)
+    }
+    fn get_or_insert_with(&mut self, key: String, f: impl FnOnce() -> String) -> Result<String> {
+        self.expire_if_due(&key)?;
+        if let Some(value) = self.read_value(&key)? {
+            return Ok(value);
+        }
+        let value = f();
+        self.set(key, value.clone())?;
+        Ok(value)
+    }
+    fn take(&mut self, key: String) -> Result<Option<String>> {
+        self.expire_if_due(&key)?;
+        let value = self.read_value(&key)?;
+        if value.is_some() {
+            self.remove(key)?;
+        }
+        Ok(value)
+    }
+    fn verify(&mut self) -> Result<VerifyReport> {
+        self.full_keys()?;
+        self.flush()?;
+        let format = self.options.record_format;
+        let mut report = VerifyReport::default();
+        for (key, cmd_pos) in &self.index {
+            report.checked += 1;
+            let result = match self.readers.get_mut(cmd_pos.file_id) {
+                Ok(reader) => verify_entry(format, reader, key, cmd_pos),
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(reason) = result {
+                report.failures.push(VerifyFailure {
+                    key: key.clone(),
+                    file_id: cmd_pos.file_id,
+                    pos: cmd_pos.pos,
+                    reason,
+                });
+            }
+        }
+        self.verify_order(&mut report)?;
+        Ok(report)
+    }
+    // Report every record numbered no higher than the write of its key
+    // before it, which replaying the logs in order would wrongly let win.
+    fn verify_order(&mut self, report: &mut VerifyReport) -> Result<()> {
+        let format = self.options.record_format;
+        let mut ids: Vec<u64> = self.readers.keys().cloned().collect();
+        ids.sort_unstable();
+        let mut last_seq: HashMap<String, u64> = HashMap::new();
+        for id in ids {
+            let reader = self.readers.get_mut(id)?;
+            let mut pos = reader.seek(SeekFrom::Start(0))?;
+            // Damage in live records is reported above; past any other,
+            // the rest of the file cannot be read.
+            while let Some(Ok((record, len))) = format.read(&mut *reader) {
+                let (key, seq) = match &record {
+                    Record::Clear => {
+                        last_seq.clear();
+                        (None, 0)
+                    }
+                    Record::Set { key, seq, .. }
+                    | Record::SetEx { key, seq, .. }
+                    | Record::Remove { key, seq, .. }
+                    | Record::SetDeflated { key, seq, .. }
+                    | Record::SetExDeflated { key, seq, .. } => (Some(key), *seq),
+                    _ => (None, 0),
+                };
+                if let Some(key) = key.filter(|_| seq != 0) {
+                    match last_seq.get(key) {
+                        Some(&before) if seq <= before => {
+                            report.failures.push(VerifyFailure {
+                                key: key.clone(),
+                                file_id: id,
+                                pos,
+                                reason: format!(
+                                    "record has sequence number {}, after {} for the same key",
+                                    seq, before
+                                ),
+                            });
+                        }
+                        _ => {}
+                    }
+                    last_seq.insert(key.clone(), seq);
+                }
+                pos += len;
+            }
+        }
+        Ok(())
+    }
+    fn check_files(&self) -> Result<()> {
+        let mut ids: Vec<u64> = self.readers.keys().cloned().collect();
+        ids.sort_unstable();
+        for id in ids {
+            match fs::metadata(log_path(&self.files, id)) {
+                Ok(metadata) if metadata.len() > 0 || id == self.current_id => {}
+                Ok(_) => return Err(KvError::MissingFile { id }),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(KvError::MissingFile { id })
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+    fn coalesce_files(&mut self, ids: &[u64]) -> Result<u64> {
+        let mut ids = ids.to_vec();
+        ids.sort_unstable();
+        ids.dedup();
+        let (first, last) = match (ids.first(), ids.last()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => return Err(KvError::InvalidCoalesce),
+        };
+        let in_range = self
+            .readers
+            .keys()
+            .filter(|&&id| id >= first && id <= last)
+            .count();
+        if last >= self.current_id
+            || in_range != ids.len()
+            || ids.iter().any(|id| !self.readers.contains_key(id))
+        {
+            return Err(KvError::InvalidCoalesce);
+        }
+
+        let tmp_path = tmp_log_path(&self.files, last);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let mut offsets = HashMap::new();
+        let mut offset = 0;
+        for &id in &ids {
+            let reader = self.readers.get_mut(id)?;
+            // A checkpoint only describes its own file, so leave it behind.
+            let data_len = match checkpoint::find(reader)? {
+                Some(data_len) => data_len,
+                None => reader.seek(SeekFrom::End(0))?,
+            };
+            reader.seek(SeekFrom::Start(0))?;
+            offsets.insert(id, offset);
+            offset += std::io::copy(&mut reader.take(data_len), &mut writer)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        self.forget_saved_index()?;
+        fs::rename(&tmp_path, log_path(&self.files, last))?;
+
+        let hashed = self.hashed.iter_mut().flat_map(HashedIndex::values_mut);
+        for cmd_pos in self.index.values_mut().chain(hashed) {
+            if let Some(offset) = offsets.get(&cmd_pos.file_id) {
+                cmd_pos.pos += offset;
+                cmd_pos.file_id = last;
+            }
+        }
+        self.reader_generation += 1;
+        for &id in &ids {
+            self.readers.remove(id);
+            KeyIndex::remove(self.files.dir(), id)?;
+            if id != last {
+                self.pins.remove_file(id, log_path(&self.files, id))?;
+            }
+        }
+        let reader =
+            BufReaderWithPos::new(open_log(&self.files, last)?, self.options.buffer_capacity)?;
+        self.readers.insert(last, reader);
+        Ok(last)
+    }
+    fn replace_all(&mut self, entries: impl Iterator<Item = (String, String)>) -> Result<()> {
+        if self.filtered {
+            return Err(KvError::FilteredStore);
+        }
+        if self.options.audit_mode {
+            return Err(KvError::AuditMode);
+        }
+        if self.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        self.flush()?;
+        create_dir(self.files.dir())?;
+        let new_id = self.current_id + 1;
+        let tmp_path = tmp_log_path(&self.files, new_id);
+        let mut next_seq = self.next_seq;
+        let replacement = self.write_replacement(&tmp_path, new_id, entries, &mut next_seq);
+        self.next_seq = next_seq;
+        let (index, uncompacted, written) = match replacement {
+            Ok(written) => written,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        };
+        self.forget_saved_index()?;
+        // Past this rename the old files are dead: should removing them
+        // fail, the `Clear` record hides them at the next open.
+        fs::rename(&tmp_path, log_path(&self.files, new_id))?;
+
+        let stale_files: Vec<_> = self.readers.keys().cloned().collect();
+        self.reader_generation += 1;
+        for stale_file in stale_files {
+            self.readers.remove(stale_file);
+            self.remove_log(stale_file)?;
+        }
+        let reader =
+            BufReaderWithPos::new(open_log(&self.files, new_id)?, self.options.buffer_capacity)?;
+        self.readers.insert(new_id, reader);
+        // A single file goes on with the replacement's records.
+        self.current_id = match self.files {
+            LogFiles::Dir(_) => new_id + 1,
+            LogFiles::File(_) => new_id,
+        };
+        self.curren_writer = Some(Self::new_log_file(
+            &self.files,
+            self.current_id,
+            self.options.buffer_capacity,
+            &mut self.readers,
+        )?);
+        self.install_index(index);
+        self.uncompacted = uncompacted;
+        self.ops.bytes_written += written;
+        if self.value_index.is_some() {
+            self.rebuild_value_index()?;
+        }
+        Ok(())
+    }
+    // Write `entries` after a `Clear` record to `path`, numbering them from
+    // `next_seq` on, and return the index of the new file `id`, its stale
+    // bytes and its length.
+    fn write_replacement(
+        &self,
+        path: &Path,
+        id: u64,
+        entries: impl Iterator<Item = (String, String)>,
+        next_seq: &mut u64,
+    ) -> Result<(BTreeMap<String, CommandPos>, u64, u64)> {
+        let mut writer = BufWriterWithPos::new(File::create(path)?, self.options.buffer_capacity)?;
+        let format = self.options.record_format;
+        format.write(&mut writer, &Record::Clear)?;
+        let mut uncompacted = writer.pos;
+        let mut index: BTreeMap<String, CommandPos> = BTreeMap::new();
+        let mut index_bytes = 0;
+        for (key, value) in entries {
+            if let Some(validator) = self.options.key_validator {
+                if !validator(&key) {
+                    return Err(KvError::InvalidKey { key });
+                }
+            }
+            self.check_sizes(&key, &value)?;
+            let cached = cached_value(&value, self.options.inline_value_size);
+            index_bytes -= index.get(&key).map_or(0, |cmd_pos| {
+                index_entry_size(&key, cmd_pos.value.as_deref())
+            });
+            index_bytes += index_entry_size(&key, cached);
+            self.check_memory_budget(index_bytes)?;
+            let pos = writer.pos;
+            let seq = *next_seq;
+            *next_seq += 1;
+            let cmd = CommandRef::Set {
+                key: &key,
+                value: &value,
+            };
+            write_command_record(format, &mut writer, cmd, seq, self.options.compress_values)?;
+            let cmd_pos = CommandPos {
+                file_id: id,
+                pos,
+                len: writer.pos - pos,
+                expire_at: None,
+                value: inline_value(&value, self.options.inline_value_size),
+                seq,
+            };
+            if let Some(old_cmd) = index.insert(key, cmd_pos) {
+                uncompacted += old_cmd.len;
+            }
+        }
+        let written = writer.pos;
+        writer
+            .writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok((index, uncompacted, written))
+    }
+    fn contains_key(&self, key: &str) -> bool {
+        self.index
+            .get(key)
+            .is_some_and(|cmd_pos| !self.is_expired(cmd_pos))
+    }
+    fn sequence(&self, key: &str) -> Option<u64> {
+        self.index
+            .get(key)
+            .filter(|cmd_pos| cmd_pos.seq != 0 && !self.is_expired(cmd_pos))
+            .map(|cmd_pos| cmd_pos.seq)
+    }
+    fn value_len(&self, key: &str, readers: &mut HandleReaders) -> Result<Option<usize>> {
+        self.count_gets(1);
+        match self.live_entry(key) {
+            Some(cmd_pos) => Ok(Some(self.read_shared(cmd_pos, readers)?.len())),
+            None => Ok(None),
+        }
+    }
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.run_pending_compaction()?;
+        *self.last_op.get_mut().unwrap() = self.options.clock.now();
+        if self.hashed.is_some() {
+            return self.remove_hashed_key(&key);
+        }
+        self.expire_if_due(&key)?;
+        if self.index.contains_key(&key) {
+            self.remove_entry(key)?;
+            self.ops.removes += 1;
+            self.maybe_compact()?;
+            Ok(())
+        } else if self.options.strict_removes {
+            Err(KvError::KeyNotFound)
+        } else {
+            Ok(())
+        }
+    }
+    // `remove` for a store with `hashed_keys`, looking for the entry of
+    // `key` only once.
+    fn remove_hashed_key(&mut self, key: &str) -> Result<()> {
+        let (hash, found) = self.find_hashed(key)?;
+        let slot = found.map(|(slot, _)| slot);
+        let live = slot.filter(|&slot| !self.is_expired(self.hashed_at(hash, slot)));
+        if let Some(slot) = slot {
+            self.remove_hashed(key, hash, slot)?;
+        }
+        if live.is_some() {
+            self.ops.removes += 1;
+            self.maybe_compact()
+        } else if self.options.strict_removes {
+            Err(KvError::KeyNotFound)
+        } else {
+            Ok(())
+        }
+    }
+    // Write a tombstone for `key`, whose entry is in `slot` of the bucket of
+    // `hash`, and drop the entry.
+    fn remove_hashed(&mut self, key: &str, hash: u64, slot: usize) -> Result<()> {
+        let cmd_pos = self.write_command(CommandRef::Remove { key }, self.options.sync_removes)?;
+        self.maybe_sync()?;
+        let old_cmd = self
+            .hashed
+            .as_mut()
+            .expect("index is hashed")
+            .remove(hash, slot);
+        self.index_bytes -= hashed_entry_size(old_cmd.value.as_deref());
+        self.uncompacted += old_cmd.len + cmd_pos.len;
+        Ok(())
+    }
+    // Write a tombstone for the live `key` and drop it from the index.
+    fn remove_entry(&mut self, key: String) -> Result<()> {
+        if self.hashed.is_some() {
+            let (hash, found) = self.find_hashed(&key)?;
+            let (slot, _) = found.ok_or(KvError::KeyNotFound)?;
+            return self.remove_hashed(&key, hash, slot);
+        }
+        let old_value = match self.value_index {
+            Some(_) => self.read_value(&key)?,
+            None => None,
+        };
+        let cmd_pos =
+            self.write_command(CommandRef::Remove { key: &key }, self.options.sync_removes)?;
+        self.maybe_sync()?;
+        self.index_remove(key, old_value, cmd_pos.len);
+        Ok(())
+    }
+    // Drop the live `key` after writing its tombstone of `len` bytes.
+    fn index_remove(&mut self, key: String, old_value: Option<String>, len: u64) {
+        if let (Some(value_index), Some(old_value)) = (self.value_index.as_mut(), old_value) {
+            value_index.remove(&old_value, &key);
+        }
+        self.index_bytes -= self.entry_size(&key);
+        let old_cmd = self.index.remove(&key).expect("key not found");
+        // The tombstone itself is stale as soon as it is written.
+        self.uncompacted += old_cmd.len + len;
+    }
+    // Whether the TTL of an entry has run out.
+    fn is_expired(&self, cmd_pos: &CommandPos) -> bool {
+        let now = self
+            .options
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        cmd_pos.expire_at.is_some_and(|expire_at| now >= expire_at)
+    }
+    // Drop `key` if its TTL has run out, returning whether it had. Handles
+    // that cannot write leave it in place and only hide it.
+    fn expire_if_due(&mut self, key: &str) -> Result<bool> {
+        if self.hashed.is_some() {
+            let (hash, found) = self.find_hashed(key)?;
+            let slot = match found {
+                Some((slot, _)) if self.is_expired(self.hashed_at(hash, slot)) => slot,
+                _ => return Ok(false),
+            };
+            if !self.read_only {
+                self.remove_hashed(key, hash, slot)?;
+            }
+            return Ok(true);
+        }
+        if !self
+            .index
+            .get(key)
+            .is_some_and(|cmd_pos| self.is_expired(cmd_pos))
+        {
+            return Ok(false);
+        }
+        if !self.read_only {
+            self.remove_entry(key.to_owned())?;
+        }
+        Ok(true)
+    }
+    // Sweep expired keys if `ttl_sweep_interval` has passed since the last
+    // sweep.
+    fn sweep_if_due(&mut self) -> Result<()> {
+        if let Some(interval) = self.options.ttl_sweep_interval {
+            if !self.read_only && self.elapsed_since(self.last_sweep) >= interval {
+                self.sweep_expired()?;
+            }
+        }
+        Ok(())
+    }
+    fn sweep_expired(&mut self) -> Result<usize> {
+        self.last_sweep = self.options.clock.now();
+        let mut expired: Vec<String> = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| self.is_expired(cmd_pos))
+            .map(|(key, _)| key.clone())
+            .collect();
+        if let Some(hashed) = &self.hashed {
+            // Only their records know the keys of hashed entries.
+            let records: Vec<(u64, u64, u64)> = hashed
+                .values()
+                .filter(|cmd_pos| self.is_expired(cmd_pos))
+                .map(|cmd_pos| (cmd_pos.file_id, cmd_pos.pos, cmd_pos.len))
+                .collect();
+            self.flush()?;
+            for (file_id, pos, len) in records {
+                let reader = self.readers.get_mut(file_id)?;
+                let (key, _) = read_pair_at(self.options.record_format, reader, pos, len)?;
+                expired.push(key);
+            }
+        }
+        for key in &expired {
+            self.remove_entry(key.clone())?;
+        }
+        self.maybe_compact()?;
+        Ok(expired.len())
+    }
+    // Drop the entries still pointing at files numbered below `id` from the
+    // index without writing tombstones, once a compaction that left their
+    // expired records behind is about to delete those files.
+    fn forget_uncopied(&mut self, id: u64) -> Result<()> {
+        if let Some(hashed) = self.hashed.as_mut() {
+            let mut forgotten = 0;
+            hashed.retain(|cmd_pos| {
+                if cmd_pos.file_id < id {
+                    forgotten += hashed_entry_size(cmd_pos.value.as_deref());
+                }
+                cmd_pos.file_id >= id
+            });
+            self.index_bytes -= forgotten;
+            return Ok(());
+        }
+        let uncopied: Vec<String> = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.file_id < id)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in uncopied {
+            if self.value_index.is_some() {
+                if let Some(old_value) = self.read_value(&key)? {
+                    let value_index = self.value_index.as_mut().expect("value index is built");
+                    value_index.remove(&old_value, &key);
+                }
+            }
+            self.index_bytes -= self.entry_size(&key);
+            self.index.remove(&key);
+        }
+        Ok(())
+    }
+    fn compact(&mut self) -> Result<CompactionResult> {
+        match self.plan_compaction(true)? {
+            Planned::Done(result) => Ok(result),
+            Planned::Copy(plan) => {
+                let segments = plan.copy();
+                self.install_compaction(plan, segments)
+            }
+        }
+    }
+    // Pick the live records to copy and move writes on to a file numbered
+    // above the copies. A store with nothing live is compacted right away.
+    fn plan_compaction(&mut self, allow_in_place: bool) -> Result<Planned> {
+        if self.filtered {
+            return Err(KvError::FilteredStore);
+        }
+        if self.options.audit_mode {
+            return Err(KvError::AuditMode);
+        }
+        self.writer()?;
+        self.flush()?;
+        let size_before = self.log_size()?;
+        debug!(
+            "Compacting {} live keys out of {} log files",
+            self.len(),
+            self.readers.len()
+        );
+        // Expired keys are left out of the copy, but stay in the index until
+        // it is installed in case it fails or is thrown away.
+        let mut entries: Vec<(String, u64, u64, u64)> = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| !self.is_expired(cmd_pos))
+            .map(|(key, cmd_pos)| (key.clone(), cmd_pos.file_id, cmd_pos.pos, cmd_pos.len))
+            .collect();
+        // Hashed entries go by the hashes of their keys instead, put in log
+        // order here so the sort below keeps them in step.
+        let mut hashes = Vec::new();
+        if let Some(hashed) = &self.hashed {
+            let mut records: Vec<(u64, u64, u64, u64)> = hashed
+                .iter()
+                .filter(|(_, cmd_pos)| !self.is_expired(cmd_pos))
+                .map(|(hash, cmd_pos)| (cmd_pos.file_id, cmd_pos.pos, cmd_pos.len, hash))
+                .collect();
+            records.sort_unstable();
+            hashes = records.iter().map(|&(.., hash)| hash).collect();
+            entries = records
+                .into_iter()
+                .map(|(file_id, pos, len, _)| (String::new(), file_id, pos, len))
+                .collect();
+        }
+        // A single file can only be replaced, which compacting it in place
+        // does even with nothing to copy.
+        if entries.is_empty() && matches!(self.files, LogFiles::Dir(_)) {
+            return self.compact_empty(size_before).map(Planned::Done);
+        }
+        // With only the active file on disk, rewrite it into new files the
+        // last of which also becomes the active one.
+        let in_place = allow_in_place && self.options.compact_in_place && self.readers.len() == 1;
+        let workers = match in_place {
+            true => 1,
+            false => self.options.compaction_workers.max(1),
+        };
+        // Copying in log order keeps each worker's reads sequential.
+        entries.sort_unstable_by_key(|&(_, file_id, pos, _)| (file_id, pos));
+        // Each worker copies its share of the live records into files of
+        // its own, as many as keep each within `max_file_size`, numbered
+        // from `compaction_id` up.
+        let per_worker = entries.len().div_ceil(workers).max(1);
+        let mut outputs: Vec<Range<usize>> = (0..entries.len())
+            .step_by(per_worker)
+            .flat_map(|start| {
+                let share = start..entries.len().min(start + per_worker);
+                split_by_size(&entries, share, self.options.max_file_size)
+            })
+            .collect();
+        if outputs.is_empty() {
+            outputs.push(0..0);
+        }
+        let compaction_id = self.current_id + 1;
+        let output_files = outputs.len() as u64;
+        if in_place {
+            self.current_id += output_files;
+        } else {
+            self.current_id += output_files + 1;
+            self.curren_writer = Some(Self::new_log_file(
+                &self.files,
+                self.current_id,
+                self.options.buffer_capacity,
+                &mut self.readers,
+            )?);
+        }
+        // Recovery replays files in id order, so writes made after this
+        // compaction must land in a file numbered above all of its output.
+        debug_assert!(in_place || compaction_id + output_files <= self.current_id);
+
+        Ok(Planned::Copy(CompactionPlan {
+            files: self.files.clone(),
+            compaction_id,
+            workers,
+            outputs,
+            in_place,
+            entries,
+            hashes,
+            size_before,
+            uncompacted: self.uncompacted,
+            generation: self.reader_generation,
+            chunk_size: self.options.compaction_chunk_size.max(1),
+            buffer_capacity: self.options.buffer_capacity,
+            progress: self.options.compaction_progress.clone(),
+        }))
+    }
+    // Put the copies made for `plan` in place of the files they came from.
+    fn install_compaction(
+        &mut self,
+        plan: CompactionPlan,
+        segments: Result<Vec<Segment>>,
+    ) -> Result<CompactionResult> {
+        // Only touch the index once every worker has succeeded.
+        let segments = segments?;
+        // Each output file only gets its real name once complete and on
+        // disk, so a crash leaves either nothing or whole copies of live
+        // records next to the files they came from. Recovery replays the
+        // copies last, and the next compaction drops the originals.
+        for id in plan.ids() {
+            fs::rename(tmp_log_path(&self.files, id), log_path(&self.files, id))?;
+        }
+
+        let mut entries = plan.entries.into_iter();
+        let mut hashes = plan.hashes.into_iter();
+        for (segment, id) in segments.into_iter().zip(plan.compaction_id..) {
+            let Segment {
+                writer: mut compaction_writer,
+                positions,
+            } = segment;
+            let reader =
+                BufReaderWithPos::new(open_log(&self.files, id)?, self.options.buffer_capacity)?;
+            self.readers.insert(id, reader);
+            let mut segment_entries = Vec::with_capacity(positions.len());
+            for (pos, len) in positions {
+                let (key, file_id, old_pos, _) =
+                    entries.next().expect("worker copied an unknown record");
+                // A key written to while a background compaction copied it
+                // keeps its newer record, leaving the copy stale.
+                let cmd_pos = match self.copied_entry(&key, hashes.next(), file_id, old_pos) {
+                    Some(cmd_pos) => cmd_pos,
+                    None => continue,
+                };
+                cmd_pos.file_id = id;
+                cmd_pos.pos = pos;
+                cmd_pos.len = len;
+                segment_entries.push((key, pos, len, cmd_pos.expire_at, cmd_pos.seq));
+            }
+            let new_pos = compaction_writer.pos;
+            self.ops.bytes_written += new_pos;
+            // The active file keeps growing, so only a finished one can end
+            // with a checkpoint.
+            if self.options.index_checkpoint
+                && self.options.record_format == RecordFormat::Json
+                && !plan.in_place
+            {
+                let entries = segment_entries.clone();
+                self.ops.bytes_written +=
+                    checkpoint::write(&mut compaction_writer, new_pos, entries)?;
+            }
+            compaction_writer.flush()?;
+            if self.options.key_index_file {
+                let key_index = KeyIndex {
+                    end: new_pos,
+                    entries: segment_entries,
+                };
+                key_index.write(self.files.dir(), id)?;
+            }
+            if plan.in_place {
+                self.curren_writer = Some(compaction_writer);
+            }
+        }
+
+        self.forget_uncopied(plan.compaction_id)?;
+        self.remove_files_before(plan.compaction_id)?;
+        self.finish_compaction(plan.size_before, plan.uncompacted)
+    }
+    // The index entry still pointing at the record at `pos` of `file_id`
+    // that a compaction copied, looked up by `key` or, with `hashed_keys`,
+    // by `hash`.
+    fn copied_entry(
+        &mut self,
+        key: &str,
+        hash: Option<u64>,
+        file_id: u64,
+        pos: u64,
+    ) -> Option<&mut CommandPos> {
+        let copied = |cmd_pos: &&mut CommandPos| cmd_pos.file_id == file_id && cmd_pos.pos == pos;
+        match (self.hashed.as_mut(), hash) {
+            (Some(hashed), Some(hash)) => hashed.bucket_mut(hash).iter_mut().find(copied),
+            _ => self.index.get_mut(key).filter(copied),
+        }
+    }
+    // Start the compaction `maybe_compact` signalled for, leaving the copy
+    // to be made without the lock held.
+    fn begin_background_compaction(&mut self) -> Result<Option<CompactionPlan>> {
+        let planned = self.plan_compaction(false);
+        if !matches!(planned, Ok(Planned::Copy(_))) {
+            self.compacting = false;
+        }
+        match planned? {
+            Planned::Copy(plan) => Ok(Some(plan)),
+            Planned::Done(_) => Ok(None),
+        }
+    }
+    // Swap in the copies made for `plan`, unless the files they came from
+    // were deleted or replaced in the meantime.
+    fn finish_background_compaction(
+        &mut self,
+        plan: CompactionPlan,
+        segments: Result<Vec<Segment>>,
+    ) -> Result<()> {
+        self.compacting = false;
+        if self.reader_generation != plan.generation {
+            debug!("Dropping a background compaction overtaken by other changes");
+            plan.discard();
+            return Ok(());
+        }
+        self.install_compaction(plan, segments).map(drop)
+    }
+    // Compact a store with no live keys: nothing needs copying, so the
+    // old files make way for a fresh, empty active file.
+    fn compact_empty(&mut self, size_before: u64) -> Result<CompactionResult> {
+        self.current_id += 1;
+        self.curren_writer = Some(Self::new_log_file(
+            &self.files,
+            self.current_id,
+            self.options.buffer_capacity,
+            &mut self.readers,
+        )?);
+        self.forget_uncopied(self.current_id)?;
+        self.remove_files_before(self.current_id)?;
+        self.finish_compaction(size_before, self.uncompacted)
+    }
+    // Delete every log file numbered below `id`, oldest first, so a crash
+    // part way through only ever cuts history off at its start.
+    fn remove_files_before(&mut self, id: u64) -> Result<()> {
+        self.forget_saved_index()?;
+        let mut stale_files: Vec<_> = self
+            .readers
+            .keys()
+            .filter(|&&file_id| file_id < id)
+            .cloned()
+            .collect();
+        stale_files.sort_unstable();
+        // Dropping the readers closes their handles before the files go, and
+        // the map gives back the room a long history made it grow to.
+        self.readers.retain(|file_id| file_id >= id);
+        self.readers.shrink_to_fit();
+        self.reader_generation += 1;
+        for stale_file in stale_files {
+            self.remove_log(stale_file)?;
+        }
+        Ok(())
+    }
+    // Write the index out for the next open, with `save_index`.
+    fn save_index(&mut self) -> Result<()> {
+        if !self.options.save_index || self.read_only || self.filtered {
+            return Ok(());
+        }
+        self.flush()?;
+        let mut files = BTreeMap::new();
+        for &id in self.readers.keys() {
+            files.insert(id, (self.readers.file_len(id)?, Vec::new()));
+        }
+        for (key, cmd_pos) in &self.index {
+            if let Some((_, entries)) = files.get_mut(&cmd_pos.file_id) {
+                let entry = (
+                    key.clone(),
+                    cmd_pos.pos,
+                    cmd_pos.len,
+                    cmd_pos.expire_at,
+                    cmd_pos.seq,
+                );
+                entries.push(entry);
+            }
+        }
+        let saved = SavedIndex {
+            files: files
+                .into_iter()
+                .map(|(id, (end, entries))| SavedFile { id, end, entries })
+                .collect(),
+            uncompacted: self.uncompacted,
+            max_seq: self.next_seq - 1,
+        };
+        saved.write(self.files.dir())
+    }
+    // Delete the saved index before the files it covers change other than
+    // by appending, whether or not this store saves one. A single file
+    // never has one.
+    fn forget_saved_index(&self) -> Result<()> {
+        match &self.files {
+            LogFiles::Dir(dir) => SavedIndex::remove(dir),
+            LogFiles::File(_) => Ok(()),
+        }
+    }
+    // Delete log file `id` and its key index, once no scan or replay is
+    // reading it. A single file has nothing to delete: the rename that put
+    // its replacement in place already let go of it.
+    fn remove_log(&self, id: u64) -> Result<()> {
+        if let LogFiles::Dir(dir) = &self.files {
+            self.pins.remove_file(id, log_path(&self.files, id))?;
+            KeyIndex::remove(dir, id)?;
+        }
+        Ok(())
+    }
+    // Reset the compaction bookkeeping and report the files left. Of the
+    // stale bytes, only the `compacted` ones there were when it began are
+    // gone.
+    fn finish_compaction(&mut self, size_before: u64, compacted: u64) -> Result<CompactionResult> {
+        self.uncompacted = self.uncompacted.saturating_sub(compacted);
+        self.compaction_pending = false;
+        self.last_compaction = self.options.clock.now();
+        self.ops.compactions += 1;
+
+        let mut files = Vec::with_capacity(self.readers.len());
+        for &id in self.readers.keys() {
+            files.push((id, self.readers.file_len(id)?));
+        }
+        files.sort_unstable();
+        self.save_index()?;
+        let size_after: u64 = files.iter().map(|&(_, size)| size).sum();
+        let reclaimed_bytes = size_before.saturating_sub(size_after);
+        info!(
+            "Compaction reclaimed {} bytes, leaving {} log files",
+            reclaimed_bytes,
+            files.len()
+        );
+        Ok(CompactionResult {
+            reclaimed_bytes,
+            files,
+        })
+    }
+    #[cfg(any(test, feature = "internals"))]
+    fn command_pos(&self, key: &str) -> Option<(u64, u64, u64)> {
+        self.index
+            .get(key)
+            .map(|cmd_pos| (cmd_pos.file_id, cmd_pos.pos, cmd_pos.len))
+    }
+    fn new_log_file(
+        files: &LogFiles,
+        key: u64,
+        buffer_capacity: usize,
+        readers: &mut LogReaders,
+    ) -> Result<BufWriterWithPos<File>> {
+        let writer = BufWriterWithPos::new(create_log(files, key)?, buffer_capacity)?;
+        readers.insert(
+            key,
+            BufReaderWithPos::new(open_log(files, key)?, buffer_capacity)?,
+        );
+        Ok(writer)
+    }
+}
+
+// Split the records `range` of `entries` into runs of them whose lengths
+// add up to at most `max` bytes, bar a record that is larger on its own.
+fn split_by_size(
+    entries: &[(String, u64, u64, u64)],
+    range: Range<usize>,
+    max: Option<u64>,
+) -> Vec<Range<usize>> {
+    let max = match max {
+        Some(max) => max,
+        None => return vec![range],
+    };
+    let mut runs = Vec::new();
+    let (mut start, mut size) = (range.start, 0);
+    for i in range.clone() {
+        let len = entries[i].3;
+        if i > start && size + len > max {
+            runs.push(start..i);
+            (start, size) = (i, 0);
+        }
+        size += len;
+    }
+    runs.push(start..range.end);
+    runs
+}
+// Copy the `(key, file_id, pos, len)` records in `entries` into the new log
+// file `id`.
+// Reads go through handles of its own, so several of these can run at once.
+fn copy_segment(
+    files: &LogFiles,
+    id: u64,
+    entries: &[(String, u64, u64, u64)],
+    chunk_size: usize,
+    buffer_capacity: usize,
+    progress: Option<&ProgressCallback>,
+) -> Result<Segment> {
+    let path = tmp_log_path(files, id);
+    let file = File::create(&path).map_err(KvError::file(FileOp::Open, path))?;
+    let mut writer = BufWriterWithPos::new(file, buffer_capacity)?;
+    let mut readers: HashMap<u64, BufReaderWithPos<File>> = HashMap::new();
+    let mut positions = Vec::with_capacity(entries.len());
+    // One reusable buffer bounds the memory used to copy each record,
+    // however large its value is.
+    let mut chunk = vec![0; chunk_size];
+    for &(_, file_id, pos, total) in entries {
+        let reader = match readers.entry(file_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = open_log(files, file_id)?;
+                entry.insert(BufReaderWithPos::new(file, buffer_capacity)?)
+            }
+        };
+        if reader.pos != pos {
+            reader.seek(SeekFrom::Start(pos))?;
+        }
+        let new_pos = writer.pos;
+        let len = copy_chunked(&mut reader.take(total), &mut writer, &mut chunk, |copied| {
+            // Only records spanning several chunks are worth reporting.
+            match progress {
+                Some(progress) if total > chunk_size as u64 => (progress.0)(copied, total),
+                _ => {}
+            }
+        })?;
+        positions.push((new_pos, len));
+    }
+    writer.flush()?;
+    Ok(Segment { writer, positions })
+}
+// What `plan_compaction` left to do.
+enum Planned {
+    // The compaction already happened.
+    Done(CompactionResult),
+    Copy(CompactionPlan),
+}
+// The live records a compaction copies and where the copies go, with the
+// settings for copying them away from the store.
+struct CompactionPlan {
+    files: LogFiles,
+    compaction_id: u64,
+    workers: usize,
+    // The records copied into each output file, in order.
+    outputs: Vec<Range<usize>>,
+    in_place: bool,
+    // The `(key, file_id, pos, len)` of each record, in log order.
+    entries: Vec<(String, u64, u64, u64)>,
+    // With `hashed_keys`, the hash of the key of each record, whose key is
+    // left empty.
+    hashes: Vec<u64>,
+    size_before: u64,
+    // The store's stale bytes and reader generation when the plan was made.
+    uncompacted: u64,
+    generation: u64,
+    chunk_size: usize,
+    buffer_capacity: usize,
+    progress: Option<ProgressCallback>,
+}
+impl CompactionPlan {
+    // The ids of the output files.
+    fn ids(&self) -> Range<u64> {
+        self.compaction_id..self.compaction_id + self.outputs.len() as u64
+    }
+    // Copy the records into temporary output files, synced to disk, and
+    // remove the files again if that fails.
+    fn copy(&self) -> Result<Vec<Segment>> {
+        let per_worker = self.outputs.len().div_ceil(self.workers).max(1);
+        // Copy the output files from `first` on, one after the other.
+        let copy_run = |outputs: &[Range<usize>], first: u64| -> Vec<Result<Segment>> {
+            outputs
+                .iter()
+                .zip(first..)
+                .map(|(records, id)| {
+                    copy_segment(
+                        &self.files,
+                        id,
+                        &self.entries[records.clone()],
+                        self.chunk_size,
+                        self.buffer_capacity,
+                        self.progress.as_ref(),
+                    )
+                })
+                .collect()
+        };
+        let segments = if self.outputs.len() <= per_worker {
+            copy_run(&self.outputs, self.compaction_id)
+        } else {
+            thread::scope(|scope| {
+                let handles: Vec<_> = self
+                    .outputs
+                    .chunks(per_worker)
+                    .zip(self.ids().step_by(per_worker))
+                    .map(|(outputs, first)| scope.spawn(move || copy_run(outputs, first)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().expect("compaction worker panicked"))
+                    .collect()
+            })
+        };
+        let segments = segments
+            .into_iter()
+            .collect::<Result<Vec<Segment>>>()
+            .and_then(|segments| {
+                for segment in &segments {
+                    segment.writer.writer.get_ref().sync_all()?;
+                }
+                Ok(segments)
+            });
+        if segments.is_err() {
+            self.discard();
+        }
+        segments
+    }
+    // Remove whatever output files are left.
+    fn discard(&self) {
+        for id in self.ids() {
+            let _ = fs::remove_file(tmp_log_path(&self.files, id));
+        }
+    }
+}
+// A compaction worker's output file, and the `(pos, len)` each record it
+// copied landed at.
+struct Segment {
+    writer: BufWriterWithPos<File>,
+    positions: Vec<(u64, u64)>,
+}
+// Copy everything from `reader` to `writer` through `chunk`, calling
+// `progress` with the running total after each chunk.
+fn copy_chunked(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    chunk: &mut [u8],
+    mut progress: impl FnMut(u64),
+) -> std::io::Result<u64> {
+    let mut copied = 0;
+    loop {
+        let len = match reader.read(chunk) {
+            Ok(0) => return Ok(copied),
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&chunk[..len])?;
+        copied += len as u64;
+        progress(copied);
+    }
+}
+// The part of `value` the index keeps, if it fits `inline_value_size`.
+fn cached_value(value: &str, max: Option<usize>) -> Option<&str> {
+    max.filter(|&max| value.len() <= max).map(|_| value)
+}
+// A copy of `value` for the index, if it fits `inline_value_size`.
+fn inline_value(value: &str, max: Option<usize>) -> Option<Box<str>> {
+    cached_value(value, max).map(Into::into)
+}
+// Estimated memory an index entry for `key` takes up, with a byte for its
+// share of the tree's node overhead and the `cached` value it keeps.
+fn index_entry_size(key: &str, cached: Option<&str>) -> usize {
+    key.len() + std::mem::size_of::<(String, CommandPos)>() + 1 + cached.map_or(0, str::len)
+}
+// Estimated memory the entry of a key in a `HashedIndex` takes up.
+fn hashed_entry_size(cached: Option<&str>) -> usize {
+    std::mem::size_of::<(u64, Vec<CommandPos>, CommandPos)>() + 1 + cached.map_or(0, str::len)
+}
+// Estimated memory all of `index` takes up.
+fn index_size(index: &BTreeMap<String, CommandPos>) -> usize {
+    index
+        .iter()
+        .map(|(key, cmd_pos)| index_entry_size(key, cmd_pos.value.as_deref()))
+        .sum()
+}
+// Read the value of the set record at `cmd_pos` through `reader`.
+fn read_value_at(
+    format: RecordFormat,
+    reader: &mut BufReaderWithPos<File>,
+    cmd_pos: &CommandPos,
+) -> Result<String> {
+    reader.seek_to(cmd_pos.pos)?;
+    match read_command(format, reader.take(cmd_pos.len))? {
+        Command::Set { value, .. } | Command::SetEx { value, .. } => Ok(value),
+        Command::Remove { .. } => Err(KvError::UnexpectedCommandType),
+    }
+}
+// Read the key and value of the set record of `len` bytes at `pos` through
+// `reader`.
+fn read_pair_at(
+    format: RecordFormat,
+    reader: &mut BufReaderWithPos<File>,
+    pos: u64,
+    len: u64,
+) -> Result<(String, String)> {
+    reader.seek_to(pos)?;
+    match read_command(format, reader.take(len))? {
+        Command::Set { key, value } | Command::SetEx { key, value, .. } => Ok((key, value)),
+        Command::Remove { .. } => Err(KvError::UnexpectedCommandType),
+    }
+}
+// Check that the record at `cmd_pos` is a set of `key` exactly `cmd_pos.len`
+// bytes long, describing what is wrong with it otherwise.
+fn verify_entry(
+    format: RecordFormat,
+    reader: &mut BufReaderWithPos<File>,
+    key: &str,
+    cmd_pos: &CommandPos,
+) -> std::result::Result<(), String> {
+    reader
+        .seek(SeekFrom::Start(cmd_pos.pos))
+        .map_err(|e| e.to_string())?;
+    let (record, len) = match format.read(reader.take(cmd_pos.len)) {
+        Some(Ok(decoded)) => decoded,
+        Some(Err(e)) => return Err(KvError::from(e).to_string()),
+        None => return Err("record is missing".to_owned()),
+    };
+    if len != cmd_pos.len {
+        return Err(format!(
+            "record takes {} bytes, the index says {}",
+            len, cmd_pos.len
+        ));
+    }
+    match record.into_command() {
+        Ok(Some(Command::Set { key: found, .. } | Command::SetEx { key: found, .. }))
+            if found == key =>
+        {
+            Ok(())
+        }
+        Ok(Some(Command::Set { key: found, .. } | Command::SetEx { key: found, .. })) => {
+            Err(format!("record is for key {:?}", found))
+        }
+        Ok(_) => Err(KvError::UnexpectedCommandType.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+// Decode a single record. One that ends early was truncated under us, so
+// report it as such and let the caller retry.
+pub(crate) fn read_command(format: RecordFormat, reader: impl Read) -> Result<Command> {
+    let record = match format.read(reader) {
+        Some(Ok((record, _))) => record,
+        Some(Err(e)) if e.truncated => return Err(KvError::ConcurrentModification),
+        Some(Err(e)) => return Err(e.into()),
+        None => return Err(KvError::ConcurrentModification),
+    };
+    record.into_command()?.ok_or(KvError::UnexpectedCommandType)
+}
+// Sorted ids of the log files in `path`. Only names of the form `{id}.log`
+// with a purely numeric id count; anything else that looks like a log is
+// left alone with a warning, since replaying it could corrupt the index.
+pub(crate) fn generate_id(path: &Path) -> Result<Vec<u64>> {
+    let mut id_list = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        match name.strip_suffix(".log") {
+            Some(stem) if !stem.is_empty() && stem.bytes().all(|b| b.is_ascii_digit()) => {
+                match stem.parse::<u64>() {
+                    Ok(id) => id_list.push(id),
+                    Err(_) => warn!("Ignoring {}: log id out of range", path.display()),
+                }
+            }
+            Some(_) => warn!("Ignoring {}: not a numbered log file", path.display()),
+            // Temporary logs are the leftovers of an interrupted compaction
+            // and get cleaned up on their own.
+            None if name.contains(".log.") && !name.ends_with(".log.tmp") => {
+                warn!("Ignoring {}: not a log file", path.display())
+            }
+            None => {}
+        }
+    }
+
+    id_list.sort_unstable();
+    // Names like `7.log` and `07.log` map to the same id; only one of
+    // them could ever be read, so refuse to guess.
+    if let Some(pair) = id_list.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(KvError::DuplicateFileId { id: pair[0] });
+    }
+    Ok(id_list)
+}
+
+// Where a store keeps its log files: numbered files in a directory, or,
+// for `open_file`, one file that stands for whichever log is current and
+// that compaction replaces with its copy.
+#[derive(Clone, Debug)]
+pub(crate) enum LogFiles {
+    Dir(PathBuf),
+    File(PathBuf),
+}
+impl LogFiles {
+    // The path the store was opened with.
+    pub(crate) fn path(&self) -> &Path {
+        match self {
+            LogFiles::Dir(path) | LogFiles::File(path) => path,
+        }
+    }
+    // The directory the log files are in.
+    fn dir(&self) -> &Path {
+        match self {
+            LogFiles::Dir(dir) => dir,
+            LogFiles::File(path) => match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            },
+        }
+    }
+    // The file next to a single file whose name adds `suffix` to its own.
+    fn beside(path: &Path, suffix: &str) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(suffix);
+        name.into()
+    }
+}
+// Sorted ids of the log files there are. A single file is always the log
+// numbered 1 when opened.
+pub(crate) fn log_ids(files: &LogFiles) -> Result<Vec<u64>> {
+    match files {
+        LogFiles::Dir(dir) => generate_id(dir),
+        LogFiles::File(path) if path.is_file() => Ok(vec![1]),
+        LogFiles::File(_) => Ok(Vec::new()),
+    }
+}
+// Generate log file by giving dirPath.
+pub(crate) fn log_path(files: &LogFiles, key: u64) -> PathBuf {
+    match files {
+        LogFiles::Dir(dir) => dir.join(format!("{}.log", key)),
+        LogFiles::File(path) => path.clone(),
+    }
+}
+// Where log file `id` is written before it is renamed into place.
+fn tmp_log_path(files: &LogFiles, id: u64) -> PathBuf {
+    match files {
+        LogFiles::Dir(dir) => dir.join(format!("{}.log.tmp", id)),
+        LogFiles::File(path) => LogFiles::beside(path, ".tmp"),
+    }
+}
+// Delete the partial log files a crash while writing one left behind.
+fn remove_tmp_logs(files: &LogFiles) -> Result<()> {
+    let dir = match files {
+        LogFiles::Dir(dir) => dir,
+        LogFiles::File(path) => {
+            return match fs::remove_file(LogFiles::beside(path, ".tmp")) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+    };
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        if name.ends_with(".log.tmp") && path.is_file() {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+// Create the store directory `dir` and any missing parents.
+pub(crate) fn create_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir).map_err(KvError::file(FileOp::Create, dir.to_owned()))
+}
+// Lock the store against other writable stores, in this process or
+// another, until the returned file is closed. A single file is replaced by
+// each compaction, so it is the lock file beside it that gets locked.
+fn lock_store(files: &LogFiles) -> Result<File> {
+    let path = match files {
+        LogFiles::Dir(dir) => dir.join(LOCK_FILE),
+        LogFiles::File(path) => LogFiles::beside(path, ".lock"),
+    };
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(KvError::file(FileOp::Open, path.clone()))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(KvError::Locked),
+        Err(TryLockError::Error(e)) => Err(KvError::file(FileOp::Lock, path)(e)),
+    }
+}
+// Open log file `id` for reading.
+pub(crate) fn open_log(files: &LogFiles, id: u64) -> Result<File> {
+    let path = log_path(files, id);
+    File::open(&path).map_err(KvError::file(FileOp::Open, path))
+}
+// Create log file `id` for appending. An existing file is an error rather
+// than something to append to: its records would sit in front of the new
+// ones while the writer counts positions from zero. A single file is the
+// exception, since it is the only log there is: writes go on at its end.
+fn create_log(files: &LogFiles, id: u64) -> Result<File> {
+    let path = log_path(files, id);
+    let single = matches!(files, LogFiles::File(_));
+    OpenOptions::new()
+        .create_new(!single)
+        .create(single)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.seek(SeekFrom::End(0)).map(|_| file))
+        .map_err(KvError::file(FileOp::Open, path))
 }
-impl KvStore {
-    /// Open a 'KvStore' with given path.
-    ///
-    /// This wiil create a new file if the given one is not exist.
-    ///
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let dir_path = path.into();
-        fs::create_dir_all(&dir_path)?;
-
-        let mut index = HashMap::new();
-        let mut readers = HashMap::new();
-
-        // generate id for every log file in given directory.
-        let id_list = Self::generate_id(&dir_path)?;
-        let mut uncompacted = 0;
-
-        for &id in &id_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&dir_path, id))?)?;
-            uncompacted += Self::recover(id, &mut reader, &mut index)?;
-            readers.insert(id, reader);
+// Cut log file `id` down to its first `len` bytes.
+fn truncate_log(files: &LogFiles, id: u64, len: u64) -> Result<()> {
+    let path = log_path(files, id);
+    OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_len(len))
+        .map_err(KvError::file(FileOp::Write, path))
+}
+/// A command as it is recorded in the log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Set `key` to `value`.
+    Set { key: String, value: String },
+    /// Set `key` to `value` until the given Unix time in seconds.
+    SetEx {
+        key: String,
+        value: String,
+        expire_at_unix_secs: u64,
+    },
+    /// Remove `key`.
+    Remove { key: String },
+}
+/// The `(key, pos, len, expire_at, seq)` of a live record, as saved by key
+/// index files and checkpoints.
+pub(crate) type IndexEntry = (String, u64, u64, Option<u64>, u64);
+impl Command {
+    // A `Set`, or a `SetEx` if the value expires at `expire_at`.
+    fn set(key: String, value: String, expire_at: Option<u64>) -> Command {
+        match expire_at {
+            Some(expire_at_unix_secs) => Command::SetEx {
+                key,
+                value,
+                expire_at_unix_secs,
+            },
+            None => Command::Set { key, value },
         }
-        let current_id = id_list.last().unwrap_or(&0) + 1;
-        let writer = Self::new_log_file(&dir_path, current_id, &mut readers)?;
-
-        Ok(KvStore {
-            dir_path: dir_path,
-            current_id: current_id,
-            index: index,
-            readers: readers,
-            curren_writer: writer,
-            uncompacted: uncompacted,
-        })
     }
-    fn recover(
-        id: u64,
-        reader: &mut BufReaderWithPos<File>,
-        index: &mut HashMap<String, CommandPos>,
-    ) -> Result<u64> {
-        // ready to read data
-        let mut pos = reader.seek(SeekFrom::Current(0))?;
-        let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-        let mut uncompacted = 0;
-
-        while let Some(cmd) = stream.next() {
-            let new_pos = stream.byte_offset() as u64;
-            match cmd? {
-                Command::Set { key, .. } => {
-                    if let Some(old_cmd) = index.insert(
-                        key,
-                        CommandPos {
-                            file_id: id,
-                            pos: pos,
-                            len: new_pos - pos,
-                        },
-                    ) {
-                        uncompacted += old_cmd.len;
-                    }
-                }
-                Command::Remove { key } => {
-                    if let Some(old_cmd) = index.remove(&key) {
-                        uncompacted += old_cmd.len;
-                    }
-                    uncompacted += new_pos - pos;
-                }
-            };
-            pos = new_pos;
+    fn as_ref(&self) -> CommandRef<'_> {
+        match self {
+            Command::Set { key, value } => CommandRef::Set { key, value },
+            Command::SetEx {
+                key,
+                value,
+                expire_at_unix_secs,
+            } => CommandRef::SetEx {
+                key,
+                value,
+                expire_at_unix_secs: *expire_at_unix_secs,
+            },
+            Command::Remove { key } => CommandRef::Remove { key },
         }
-        Ok(uncompacted)
-    }
-    fn generate_id(path: &Path) -> Result<Vec<u64>> {
-        // Get file key
-        let mut id_list: Vec<u64> = fs::read_dir(&path)?
-            .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-            .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-            .flat_map(|path| {
-                path.file_name()
-                    .and_then(OsStr::to_str)
-                    .map(|s| s.trim_end_matches(".log"))
-                    .map(str::parse::<u64>)
-            })
-            .flatten()
-            .collect();
-
-        id_list.sort_unstable();
-        Ok(id_list)
     }
-
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set {
-            key: key,
-            value: value,
+    fn checksum(&self, seq: u64) -> u32 {
+        self.as_ref().checksum(seq)
+    }
+}
+// A command borrowing its key and value, so that writers keep hold of them
+// to index once the record is written.
+#[derive(Clone, Copy)]
+enum CommandRef<'a> {
+    Set {
+        key: &'a str,
+        value: &'a str,
+    },
+    SetEx {
+        key: &'a str,
+        value: &'a str,
+        expire_at_unix_secs: u64,
+    },
+    Remove {
+        key: &'a str,
+    },
+}
+impl CommandRef<'_> {
+    // CRC32 over the command's fields, each prefixed by its length, after
+    // the name of the command, so that no two commands share the input. A
+    // sequence number of 0, as in records from before they were logged, is
+    // left out.
+    fn checksum(self, seq: u64) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        let expire_at;
+        let parts: &[&[u8]] = match self {
+            CommandRef::Set { key, value } => &[b"Set", key.as_bytes(), value.as_bytes()],
+            CommandRef::SetEx {
+                key,
+                value,
+                expire_at_unix_secs,
+            } => {
+                expire_at = expire_at_unix_secs.to_le_bytes();
+                &[b"SetEx", key.as_bytes(), value.as_bytes(), &expire_at]
+            }
+            CommandRef::Remove { key } => &[b"Remove", key.as_bytes()],
         };
-        let pos = self.curren_writer.pos;
-        serde_json::to_writer(&mut self.curren_writer, &cmd)?;
-        self.curren_writer.flush()?;
-
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.insert(
+        let seq_bytes = seq.to_le_bytes();
+        let seq_part = (seq != 0).then_some(&seq_bytes[..]);
+        for part in parts.iter().copied().chain(seq_part) {
+            hasher.update(&(part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        hasher.finalize()
+    }
+}
+/// Any record found in a log file: a command, a transaction marker, or the
+/// index checkpoint that closes a compacted file.
+///
+/// Commands carry the `crc` of their fields. Logs written before it was
+/// added have no such field and fail to parse rather than go unchecked.
+/// They also carry the `seq` of their write, which JSON logs from before
+/// it was added read as 0.
+///
+/// Sets whose value was deflated before logging have variants of their
+/// own, added last so that the others keep their bincode tags. Their `crc`
+/// covers the value as it was set, not the compressed bytes.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Record {
+    Set {
+        key: String,
+        value: String,
+        crc: u32,
+        #[serde(default)]
+        seq: u64,
+    },
+    SetEx {
+        key: String,
+        value: String,
+        expire_at_unix_secs: u64,
+        crc: u32,
+        #[serde(default)]
+        seq: u64,
+    },
+    Remove {
+        key: String,
+        crc: u32,
+        #[serde(default)]
+        seq: u64,
+    },
+    Checkpoint {
+        entries: Vec<IndexEntry>,
+    },
+    CheckpointAt(u64),
+    // Everything written before this record is discarded.
+    Clear,
+    // The records up to the next `TxnCommit` only count once it is there.
+    TxnBegin,
+    TxnCommit,
+    SetDeflated {
+        key: String,
+        value: Vec<u8>,
+        crc: u32,
+        seq: u64,
+    },
+    SetExDeflated {
+        key: String,
+        value: Vec<u8>,
+        expire_at_unix_secs: u64,
+        crc: u32,
+        seq: u64,
+    },
+}
+impl Record {
+    /// The command this record holds, if it is one, failing with
+    /// `KvError::CorruptRecord` if it does not match its checksum.
+    pub(crate) fn into_command(self) -> Result<Option<Command>> {
+        let (cmd, crc, seq) = match self {
+            Record::Set {
+                key,
+                value,
+                crc,
+                seq,
+            } => (Command::Set { key, value }, crc, seq),
+            Record::SetEx {
                 key,
-                CommandPos {
-                    file_id: self.current_id,
-                    pos: pos,
-                    len: self.curren_writer.pos - pos,
+                value,
+                expire_at_unix_secs,
+                crc,
+                seq,
+            } => (
+                Command::SetEx {
+                    key,
+                    value,
+                    expire_at_unix_secs,
                 },
-            ) {
-                self.uncompacted += old_cmd.len;
+                crc,
+                seq,
+            ),
+            Record::Remove { key, crc, seq } => (Command::Remove { key }, crc, seq),
+            Record::SetDeflated {
+                key,
+                value,
+                crc,
+                seq,
+            } => {
+                let value = inflate(&value)?;
+                (Command::Set { key, value }, crc, seq)
             }
+            Record::SetExDeflated {
+                key,
+                value,
+                expire_at_unix_secs,
+                crc,
+                seq,
+            } => (
+                Command::SetEx {
+                    key,
+                    value: inflate(&value)?,
+                    expire_at_unix_secs,
+                },
+                crc,
+                seq,
+            ),
+            Record::Checkpoint { .. }
+            | Record::CheckpointAt(_)
+            | Record::Clear
+            | Record::TxnBegin
+            | Record::TxnCommit => return Ok(None),
         };
-
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+        if cmd.checksum(seq) != crc {
+            return Err(KvError::CorruptRecord);
         }
-        Ok({})
+        Ok(Some(cmd))
     }
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.file_id)
-                .expect("cann't find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KvError::UnexpectedCommandType)
+    /// The sequence number of the command this record holds, or 0 if it
+    /// holds none or was logged without one.
+    pub(crate) fn seq(&self) -> u64 {
+        match self {
+            Record::Set { seq, .. }
+            | Record::SetEx { seq, .. }
+            | Record::Remove { seq, .. }
+            | Record::SetDeflated { seq, .. }
+            | Record::SetExDeflated { seq, .. } => *seq,
+            _ => 0,
+        }
+    }
+    // The log form of `cmd` with its value deflated, if it is a set and
+    // compressing makes the value smaller.
+    fn deflated(cmd: CommandRef, seq: u64) -> Option<Record> {
+        let (key, value) = match cmd {
+            CommandRef::Set { key, value } | CommandRef::SetEx { key, value, .. } => (key, value),
+            CommandRef::Remove { .. } => return None,
+        };
+        let compressed = miniz_oxide::deflate::compress_to_vec(value.as_bytes(), 6);
+        if compressed.len() >= value.len() {
+            return None;
+        }
+        let crc = cmd.checksum(seq);
+        let key = key.to_owned();
+        Some(match cmd {
+            CommandRef::SetEx {
+                expire_at_unix_secs,
+                ..
+            } => Record::SetExDeflated {
+                key,
+                value: compressed,
+                expire_at_unix_secs,
+                crc,
+                seq,
+            },
+            _ => Record::SetDeflated {
+                key,
+                value: compressed,
+                crc,
+                seq,
+            },
+        })
+    }
+}
+// The value deflated into `bytes`. Bytes that do not inflate to UTF-8 text
+// are as corrupt as a record failing its checksum.
+fn inflate(bytes: &[u8]) -> Result<String> {
+    let value =
+        miniz_oxide::inflate::decompress_to_vec(bytes).map_err(|_| KvError::CorruptRecord)?;
+    String::from_utf8(value).map_err(|_| KvError::CorruptRecord)
+}
+// Write `cmd` to `writer` as logged with `seq`, deflating its value first
+// if `compress` is set and that makes it smaller.
+fn write_command_record(
+    format: RecordFormat,
+    writer: impl Write,
+    cmd: CommandRef,
+    seq: u64,
+    compress: bool,
+) -> Result<()> {
+    if compress {
+        if let Some(record) = Record::deflated(cmd, seq) {
+            return format.write(writer, &record);
+        }
+    }
+    format.write(writer, &CommandRecord::new(cmd, seq))
+}
+/// The log form of a command borrowed for writing, serialized the same way
+/// as the matching `Record`.
+#[derive(Serialize)]
+pub(crate) enum CommandRecord<'a> {
+    Set {
+        key: &'a str,
+        value: &'a str,
+        crc: u32,
+        seq: u64,
+    },
+    SetEx {
+        key: &'a str,
+        value: &'a str,
+        expire_at_unix_secs: u64,
+        crc: u32,
+        seq: u64,
+    },
+    Remove {
+        key: &'a str,
+        crc: u32,
+        seq: u64,
+    },
+}
+impl<'a> CommandRecord<'a> {
+    fn new(cmd: CommandRef<'a>, seq: u64) -> CommandRecord<'a> {
+        let crc = cmd.checksum(seq);
+        match cmd {
+            CommandRef::Set { key, value } => CommandRecord::Set {
+                key,
+                value,
+                crc,
+                seq,
+            },
+            CommandRef::SetEx {
+                key,
+                value,
+                expire_at_unix_secs,
+            } => CommandRecord::SetEx {
+                key,
+                value,
+                expire_at_unix_secs,
+                crc,
+                seq,
+            },
+            CommandRef::Remove { key } => CommandRecord::Remove { key, crc, seq },
+        }
+    }
+}
+// How `open` should treat the directory and its contents.
+#[derive(Default)]
+struct OpenMode<'a> {
+    // Only index keys matching this predicate.
+    filter: Option<&'a dyn Fn(&str) -> bool>,
+    // Never modify the directory.
+    snapshot: bool,
+}
+// Bounds how long recovery may run before aborting.
+struct Deadline<'a> {
+    clock: &'a dyn Clock,
+    until: Option<SystemTime>,
+}
+impl<'a> Deadline<'a> {
+    fn new(clock: &'a dyn Clock, budget: Option<Duration>) -> Self {
+        Deadline {
+            clock,
+            until: budget.map(|budget| clock.now() + budget),
+        }
+    }
+    fn check(&self) -> Result<()> {
+        match self.until {
+            Some(until) if self.clock.now() > until => Err(KvError::RecoveryTimeout),
+            _ => Ok(()),
+        }
+    }
+}
+// Maps each live value to the set of keys holding it.
+#[derive(Default)]
+struct ValueIndex {
+    keys_by_value: HashMap<String, BTreeSet<String>>,
+}
+impl ValueIndex {
+    fn insert(&mut self, value: String, key: &str) {
+        self.keys_by_value
+            .entry(value)
+            .or_default()
+            .insert(key.to_owned());
+    }
+    fn remove(&mut self, value: &str, key: &str) {
+        if let Some(keys) = self.keys_by_value.get_mut(value) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.keys_by_value.remove(value);
             }
-        } else {
-            Ok(None)
         }
     }
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::Remove { key: key };
-            serde_json::to_writer(&mut self.curren_writer, &cmd)?;
-            self.curren_writer.flush()?;
-
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.len;
+}
+impl Drop for KvStoreInner {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!(
+                "Failed to flush {} on close: {}",
+                self.files.path().display(),
+                e
+            );
+        }
+        if let Err(e) = self.save_index() {
+            error!(
+                "Failed to save the index of {} on close: {}",
+                self.files.path().display(),
+                e
+            );
+        }
+    }
+}
+// The thread compacting a store opened with `background_compaction`,
+// shared by its handles.
+struct Compactor {
+    thread: Option<JoinHandle<()>>,
+}
+impl Drop for Compactor {
+    // Only runs once every handle has let go of the store, so the thread is
+    // done once it finishes any compaction it is in the middle of.
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+// Compact `store` each time a write signals it is due, until it is closed.
+//
+// The store is only locked to plan the compaction and to swap the copies
+// in, so writes carry on while the live records are copied.
+fn run_compactor(store: Weak<RwLock<KvStoreInner>>, signals: Receiver<()>) {
+    while signals.recv().is_ok() {
+        let plan = match store.upgrade() {
+            Some(store) => store.write().unwrap().begin_background_compaction(),
+            None => return,
+        };
+        let plan = match plan {
+            Ok(Some(plan)) => plan,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Background compaction failed: {}", e);
+                continue;
             }
-            Ok(())
-        } else {
-            Err(KvError::KeyNotFound)
+        };
+        let segments = plan.copy();
+        let store = match store.upgrade() {
+            Some(store) => store,
+            None => {
+                plan.discard();
+                return;
+            }
+        };
+        let result = store
+            .write()
+            .unwrap()
+            .finish_background_compaction(plan, segments);
+        if let Err(e) = result {
+            error!("Background compaction failed: {}", e);
         }
     }
-    pub fn compact(&mut self) -> Result<()> {
-        let compaction_id = self.current_id + 1;
-        self.current_id += 2;
-        self.curren_writer =
-            Self::new_log_file(&self.dir_path, self.current_id, &mut self.readers)?;
-
-        let mut compaction_writer =
-            Self::new_log_file(&self.dir_path, compaction_id, &mut self.readers)?;
-        let mut new_pos = 0;
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.file_id)
-                .expect("Cann't find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+}
+// The thread sweeping expired keys out of a store opened with
+// `ttl_sweep_interval`, shared by its handles.
+struct Sweeper {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+impl Drop for Sweeper {
+    // Hanging up wakes the thread, which then finishes any sweep it is in
+    // the middle of and exits.
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+// Sweep `store` each `interval` it is due, until it is closed.
+//
+// The store's clock decides whether a sweep is due, as it does for `tick`,
+// so the thread only waits `interval` between looks.
+fn run_sweeper(store: Weak<RwLock<KvStoreInner>>, interval: Duration, stop: Receiver<()>) {
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+        let store = match store.upgrade() {
+            Some(store) => store,
+            None => return,
+        };
+        let result = store.write().unwrap().sweep_if_due();
+        if let Err(e) = result {
+            error!("Sweeping expired keys failed: {}", e);
+        }
+    }
+}
+// A handle's own log file readers, valid while `generation` matches the
+// store's `reader_generation`.
+#[derive(Default)]
+struct HandleReaders {
+    generation: u64,
+    files: ReaderLru,
+}
+// Open log file readers, closing the least recently used one to make room
+// once a cap on their number is reached.
+#[derive(Default)]
+struct ReaderLru {
+    // Each reader with the use it was last read for.
+    readers: HashMap<u64, (BufReaderWithPos<File>, u64)>,
+    uses: u64,
+}
+impl ReaderLru {
+    // The reader of file `id`, opened with `open` if it is not open yet.
+    fn get(
+        &mut self,
+        id: u64,
+        max_open: Option<usize>,
+        open: impl FnOnce() -> Result<BufReaderWithPos<File>>,
+    ) -> Result<&mut BufReaderWithPos<File>> {
+        self.uses += 1;
+        if !self.readers.contains_key(&id) {
+            let reader = open()?;
+            self.insert(id, reader, max_open);
+        }
+        let (reader, last_used) = self.readers.get_mut(&id).unwrap();
+        *last_used = self.uses;
+        Ok(reader)
+    }
+    fn insert(&mut self, id: u64, reader: BufReaderWithPos<File>, max_open: Option<usize>) {
+        if let Some(max_open) = max_open {
+            while self.readers.len() >= max_open.max(1) && !self.readers.contains_key(&id) {
+                let oldest = self
+                    .readers
+                    .iter()
+                    .min_by_key(|(_, (_, last_used))| *last_used)
+                    .map(|(&id, _)| id);
+                match oldest {
+                    Some(oldest) => self.readers.remove(&oldest),
+                    None => break,
+                };
             }
-
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = std::io::copy(&mut entry_reader, &mut compaction_writer)?;
-            *cmd_pos = CommandPos {
-                file_id: compaction_id,
-                pos: new_pos,
-                len: len,
-            };
-            new_pos += len;
         }
-        compaction_writer.flush()?;
-
-        // remove stale log files.
-        let stale_files: Vec<_> = self
-            .readers
-            .keys()
-            .filter(|&&id| id < compaction_id)
-            .cloned()
-            .collect();
-        for stale_file in stale_files {
-            self.readers.remove(&stale_file);
-            std::fs::remove_file(log_path(&self.dir_path, stale_file))?;
+        self.uses += 1;
+        self.readers.insert(id, (reader, self.uses));
+    }
+    fn remove(&mut self, id: u64) -> Option<BufReaderWithPos<File>> {
+        self.readers.remove(&id).map(|(reader, _)| reader)
+    }
+    fn clear(&mut self) {
+        self.readers.clear();
+    }
+}
+// Every log file of a store, with readers for as many of them as
+// `max_open_readers` allows. A file whose reader was closed is opened again
+// the next time it is read.
+struct LogReaders {
+    log_files: LogFiles,
+    buffer_capacity: usize,
+    max_open: Option<usize>,
+    files: HashSet<u64>,
+    open: ReaderLru,
+}
+impl LogReaders {
+    fn new(log_files: LogFiles, buffer_capacity: usize, max_open: Option<usize>) -> LogReaders {
+        LogReaders {
+            log_files,
+            buffer_capacity,
+            max_open,
+            files: HashSet::new(),
+            open: ReaderLru::default(),
         }
-        self.uncompacted = 0;
-
-        Ok(())
     }
-    fn new_log_file(
-        path: &Path,
-        key: u64,
-        readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-    ) -> Result<BufWriterWithPos<File>> {
-        let path = log_path(path, key);
-        let writer = BufWriterWithPos::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .append(true)
-                .open(&path)?,
-        )?;
-        readers.insert(key, BufReaderWithPos::new(File::open(&path)?)?);
-        Ok(writer)
+    // Track the log file `id`, read through `reader`.
+    fn insert(&mut self, id: u64, reader: BufReaderWithPos<File>) {
+        self.files.insert(id);
+        self.open.insert(id, reader, self.max_open);
+    }
+    // Stop tracking log file `id`, closing its reader. Returns whether it
+    // was tracked.
+    fn remove(&mut self, id: u64) -> bool {
+        self.open.remove(id);
+        self.files.remove(&id)
+    }
+    // Stop tracking log file `id` and hand over its reader.
+    fn take(&mut self, id: u64) -> Result<BufReaderWithPos<File>> {
+        self.get_mut(id)?;
+        self.files.remove(&id);
+        Ok(self.open.remove(id).expect("reader was just opened"))
+    }
+    // The reader of log file `id`, failing if the store has no such file.
+    fn get_mut(&mut self, id: u64) -> Result<&mut BufReaderWithPos<File>> {
+        if !self.files.contains(&id) {
+            return Err(KvError::ReaderNotFound(id));
+        }
+        let (files, buffer_capacity) = (&self.log_files, self.buffer_capacity);
+        self.open.get(id, self.max_open, || {
+            BufReaderWithPos::new(open_log(files, id)?, buffer_capacity)
+        })
+    }
+    // The current size of log file `id`.
+    fn file_len(&self, id: u64) -> Result<u64> {
+        match self.open.readers.get(&id) {
+            Some((reader, _)) => Ok(reader.reader.get_ref().metadata()?.len()),
+            None => Ok(fs::metadata(log_path(&self.log_files, id))?.len()),
+        }
+    }
+    fn keys(&self) -> impl Iterator<Item = &u64> {
+        self.files.iter()
+    }
+    fn contains_key(&self, id: &u64) -> bool {
+        self.files.contains(id)
+    }
+    fn len(&self) -> usize {
+        self.files.len()
+    }
+    // Stop tracking the log files for which `keep` returns false.
+    fn retain(&mut self, mut keep: impl FnMut(u64) -> bool) {
+        let dropped: Vec<u64> = self.files.iter().cloned().filter(|&id| !keep(id)).collect();
+        for id in dropped {
+            self.remove(id);
+        }
+    }
+    fn shrink_to_fit(&mut self) {
+        self.files.shrink_to_fit();
+        self.open.readers.shrink_to_fit();
+    }
+    #[cfg(test)]
+    fn capacity(&self) -> usize {
+        self.files.capacity()
+    }
+    #[cfg(test)]
+    fn open_count(&self) -> usize {
+        self.open.readers.len()
     }
 }
-
-// Generate log file by giving dirPath.
-fn log_path(dir: &Path, key: u64) -> PathBuf {
-    dir.join(format!("{}.log", key))
+// Operations performed since the store was opened.
+#[derive(Default)]
+struct OpCounters {
+    gets: AtomicU64,
+    sets: u64,
+    removes: u64,
+    compactions: u64,
+    bytes_written: u64,
+    // Records replayed while opening.
+    recovered: u64,
+    syncs: u64,
 }
-// Struct representing a command.
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+// The keys a log file sets and removes, and its record bytes.
+#[derive(Default)]
+struct FileKeys {
+    sets: HashSet<String>,
+    removes: Vec<String>,
+    bytes: u64,
+}
+// What recovering a run of consecutive log files on its own found.
+struct RecoveredRun {
+    index: PartialIndex,
+    readers: Vec<(u64, BufReaderWithPos<File>)>,
+    uncompacted: u64,
+    records: u64,
+}
+// The index built from a run of log files, along with what it does to the
+// index of the files before them.
+struct PartialIndex {
+    entries: BTreeMap<String, CommandPos>,
+    // Keys removed and not set again since, if the earlier files matter.
+    removed: Option<HashSet<String>>,
+    // Whether the run discards everything written before it.
+    cleared: bool,
+    // Keep values up to this size in the entries, as `inline_value_size`.
+    inline_value_size: Option<usize>,
+    // The highest sequence number met, including in records since dropped.
+    max_seq: u64,
+}
+impl PartialIndex {
+    fn new(track_removes: bool, inline_value_size: Option<usize>) -> PartialIndex {
+        PartialIndex {
+            entries: BTreeMap::new(),
+            removed: track_removes.then(HashSet::new),
+            cleared: false,
+            inline_value_size,
+            max_seq: 0,
+        }
+    }
+    fn insert(&mut self, key: String, cmd_pos: CommandPos) -> Option<CommandPos> {
+        if let Some(removed) = self.removed.as_mut() {
+            removed.remove(&key);
+        }
+        self.entries.insert(key, cmd_pos)
+    }
+    fn remove(&mut self, key: String) -> Option<CommandPos> {
+        let old_cmd = self.entries.remove(&key);
+        if let Some(removed) = self.removed.as_mut() {
+            removed.insert(key);
+        }
+        old_cmd
+    }
+    // Drop every entry, returning the bytes they held.
+    fn clear(&mut self) -> u64 {
+        let stale = self.entries.values().map(|cmd_pos| cmd_pos.len).sum();
+        self.entries.clear();
+        if let Some(removed) = self.removed.as_mut() {
+            removed.clear();
+        }
+        self.cleared = true;
+        stale
+    }
+    // Apply the run to `index`, the index of the files before it, returning
+    // the bytes it makes stale there.
+    fn merge_into(self, index: &mut BTreeMap<String, CommandPos>) -> u64 {
+        if index.is_empty() {
+            *index = self.entries;
+            return 0;
+        }
+        let mut stale = 0;
+        if self.cleared {
+            stale += index.values().map(|cmd_pos| cmd_pos.len).sum::<u64>();
+            index.clear();
+        }
+        for key in self.removed.into_iter().flatten() {
+            if let Some(old_cmd) = index.remove(&key) {
+                stale += old_cmd.len;
+            }
+        }
+        for (key, cmd_pos) in self.entries {
+            if let Some(old_cmd) = index.insert(key, cmd_pos) {
+                stale += old_cmd.len;
+            }
+        }
+        stale
+    }
+}
+// The outcome of `recover_compacting`.
+struct Recovery {
+    index: BTreeMap<String, CommandPos>,
+    uncompacted: u64,
+    records: u64,
+    max_seq: u64,
+    // Whether the logs were replaced by the compaction file.
+    compacted: bool,
 }
 // Record <key,value> pair position in diffrent files.
 struct CommandPos {
     file_id: u64,
     pos: u64,
     len: u64,
+    // Unix time in seconds from which the entry reads as absent.
+    expire_at: Option<u64>,
+    // The value itself, if it is small enough to keep in memory.
+    value: Option<Box<str>>,
+    // Sequence number of the write, or 0 for records logged without one.
+    seq: u64,
 }
 // ReaderBufWithPos
 struct BufReaderWithPos<R: Read + Seek> {
@@ -248,13 +4602,24 @@ struct BufReaderWithPos<R: Read + Seek> {
     pos: u64,
 }
 impl<R: Read + Seek> BufReaderWithPos<R> {
-    fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+    fn new(mut inner: R, capacity: usize) -> Result<Self> {
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
-            reader: BufReader::new(inner),
-            pos: pos,
+            reader: BufReader::with_capacity(capacity, inner),
+            pos,
         })
     }
+    // Move to `pos`, keeping the read buffer if it already holds that
+    // offset, as it may when reads follow each other through a file. Log
+    // files only ever grow while a reader is open, so buffered bytes stay
+    // valid.
+    fn seek_to(&mut self, pos: u64) -> std::io::Result<()> {
+        if pos != self.pos {
+            self.reader.seek_relative(pos as i64 - self.pos as i64)?;
+            self.pos = pos;
+        }
+        Ok(())
+    }
 }
 impl<R: Read + Seek> Read for BufReaderWithPos<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
@@ -276,11 +4641,11 @@ struct BufWriterWithPos<W: Write + Seek> {
     pos: u64,
 }
 impl<W: Write + Seek> BufWriterWithPos<W> {
-    fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+    fn new(mut inner: W, capacity: usize) -> Result<Self> {
+        let pos = inner.stream_position()?;
         Ok(BufWriterWithPos {
-            writer: BufWriter::new(inner),
-            pos: pos,
+            writer: BufWriter::with_capacity(capacity, inner),
+            pos,
         })
     }
 }
@@ -300,3 +4665,327 @@ impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
         Ok(self.pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, SystemClock};
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    // Every live entry should be moved into the compaction file.
+    #[test]
+    fn compaction_moves_entries_to_compaction_file() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), "value".to_owned())?;
+            store.set(format!("key{}", key_id), format!("{}", key_id))?;
+        }
+        store.compact()?;
+
+        // The compaction file is the oldest one left.
+        let compaction_id = *store.lock().readers.keys().min().unwrap();
+        for key_id in 0..100 {
+            let (file_id, _, len) = store.command_pos(&format!("key{}", key_id)).unwrap();
+            assert_eq!(file_id, compaction_id);
+            assert!(len > 0);
+        }
+        assert_eq!(store.command_pos("missing"), None);
+        Ok(())
+    }
+
+    // An index entry pointing at a file the store has no reader for fails
+    // the read with an error instead of panicking.
+    #[test]
+    fn missing_reader_is_an_error() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let (file_id, ..) = store.command_pos("key1").unwrap();
+        store.lock().readers.remove(file_id);
+
+        assert!(matches!(
+            store.take("key1".to_owned()),
+            Err(KvError::ReaderNotFound(id)) if id == file_id
+        ));
+        assert!(matches!(
+            store.compare_and_swap("key1".to_owned(), None, "value2".to_owned()),
+            Err(KvError::ReaderNotFound(_))
+        ));
+        Ok(())
+    }
+
+    // Compaction keeps readers only for its output and the active file,
+    // however many files the store had opened before.
+    #[test]
+    fn compaction_drops_stale_readers() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        for round in 0..20 {
+            let store = KvStore::open(temp_dir.path())?;
+            store.set(format!("key{}", round % 5), "value".to_owned())?;
+        }
+        let store = KvStore::open(temp_dir.path())?;
+        assert!(store.lock().readers.len() > 20);
+
+        store.compact()?;
+        assert_eq!(store.lock().readers.len(), 2);
+        assert!(store.lock().readers.capacity() < 20);
+        assert_eq!(store.len(), 5);
+        Ok(())
+    }
+
+    // Expired keys only leave the index once the compaction that skipped
+    // them is installed, not when its copy fails or is thrown away.
+    #[test]
+    fn expired_keys_outlive_unfinished_compaction() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let clock = Arc::new(ManualClock::default());
+        let options = KvStoreOptions {
+            clock: clock.clone(),
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set_with_ttl(
+            "short".to_owned(),
+            "value1".to_owned(),
+            Duration::from_secs(10),
+        )?;
+        store.set("plain".to_owned(), "value2".to_owned())?;
+        clock.advance(Duration::from_secs(20));
+        let plan = |inner: &mut KvStoreInner| -> Result<CompactionPlan> {
+            match inner.plan_compaction(false)? {
+                Planned::Copy(plan) => Ok(plan),
+                Planned::Done(_) => panic!("a live key should need copying"),
+            }
+        };
+
+        let mut inner = store.lock();
+        let overtaken = plan(&mut inner)?;
+        assert_eq!(overtaken.entries.len(), 1);
+        let segments = overtaken.copy();
+        inner.reader_generation += 1;
+        inner.finish_background_compaction(overtaken, segments)?;
+        assert!(inner.index.contains_key("short"));
+
+        let failed = plan(&mut inner)?;
+        let segments = Err(KvError::Io(std::io::Error::other("copy failed")));
+        assert!(inner.install_compaction(failed, segments).is_err());
+        assert!(inner.index.contains_key("short"));
+
+        let installed = plan(&mut inner)?;
+        let segments = installed.copy();
+        inner.install_compaction(installed, segments)?;
+        assert!(!inner.index.contains_key("short"));
+        assert_eq!(inner.index.len(), 1);
+        drop(inner);
+        assert_eq!(store.get("plain".to_owned())?, Some("value2".to_owned()));
+        assert!(store.verify()?.failures.is_empty());
+        Ok(())
+    }
+
+    // With `max_open_readers`, reads across many files reopen the ones they
+    // need and never hold more readers open than the cap, in the store or
+    // in a handle.
+    #[test]
+    fn max_open_readers() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = || KvStoreOptions {
+            max_file_size: Some(256),
+            max_open_readers: Some(3),
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+            assert!(store.lock().readers.open_count() <= 3);
+        }
+        assert!(store.lock().readers.len() > 20);
+        let check = |store: &KvStore| -> Result<()> {
+            for key_id in (0..200).rev() {
+                let key = format!("key{}", key_id);
+                assert_eq!(store.get(key.clone())?, Some(format!("value{}", key_id)));
+                assert_eq!(store.lock().get(key)?, Some(format!("value{}", key_id)));
+                assert!(store.lock().readers.open_count() <= 3);
+                assert!(store.readers.lock().unwrap().files.readers.len() <= 3);
+            }
+            Ok(())
+        };
+        check(&store)?;
+        assert!(store.verify()?.failures.is_empty());
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        assert!(store.lock().readers.open_count() <= 3);
+        check(&store)?;
+        store.compact()?;
+        check(&store)?;
+        Ok(())
+    }
+
+    // The log bytes of `records`, written as the store would.
+    fn log_bytes(records: &[Record]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for record in records {
+            RecordFormat::Json.write(&mut bytes, record).unwrap();
+        }
+        bytes
+    }
+    fn set(key: &str, value: &str) -> Record {
+        let cmd = Command::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+        Record::Set {
+            crc: cmd.checksum(0),
+            key: key.to_owned(),
+            value: value.to_owned(),
+            seq: 0,
+        }
+    }
+    fn remove(key: &str) -> Record {
+        let cmd = Command::Remove {
+            key: key.to_owned(),
+        };
+        Record::Remove {
+            crc: cmd.checksum(0),
+            key: key.to_owned(),
+            seq: 0,
+        }
+    }
+    // Recover log file 1 from `bytes` in memory, returning the index, stale
+    // bytes and torn position.
+    fn recover_bytes(
+        bytes: Vec<u8>,
+        tail: bool,
+    ) -> Result<(BTreeMap<String, CommandPos>, u64, Option<u64>)> {
+        let mut reader = BufReaderWithPos::new(Cursor::new(bytes), 64)?;
+        let mut index = PartialIndex::new(false, None);
+        let clock = SystemClock;
+        let deadline = Deadline::new(&clock, None);
+        let (stale, _, torn_at) = KvStoreInner::recover(
+            1,
+            &mut reader,
+            &mut index,
+            &deadline,
+            None,
+            tail,
+            RecordFormat::Json,
+        )?;
+        Ok((index.entries, stale, torn_at))
+    }
+
+    // The last set of a key wins, and every earlier one counts as stale.
+    #[test]
+    fn recover_duplicate_keys() -> Result<()> {
+        let records = [set("a", "1"), set("b", "2"), set("a", "3")];
+        let first = log_bytes(&records[..1]).len() as u64;
+        let bytes = log_bytes(&records);
+        let last_pos = log_bytes(&records[..2]).len() as u64;
+        let (index, stale, torn_at) = recover_bytes(bytes.clone(), true)?;
+        assert_eq!(index.len(), 2);
+        assert_eq!(index["a"].pos, last_pos);
+        assert_eq!(index["a"].len, bytes.len() as u64 - last_pos);
+        assert_eq!(index["b"].pos, first);
+        assert_eq!(stale, first);
+        assert_eq!(torn_at, None);
+        Ok(())
+    }
+
+    // A remove drops the key set before it, both records turning stale,
+    // and a set after it brings the key back.
+    #[test]
+    fn recover_interleaved_removes() -> Result<()> {
+        let records = [
+            set("a", "1"),
+            remove("a"),
+            set("b", "2"),
+            remove("missing"),
+            remove("b"),
+            set("b", "3"),
+        ];
+        let bytes = log_bytes(&records);
+        let (index, stale, _) = recover_bytes(bytes.clone(), true)?;
+        assert_eq!(index.keys().collect::<Vec<_>>(), ["b"]);
+        let live = log_bytes(&records[5..]).len() as u64;
+        assert_eq!(index["b"].len, live);
+        assert_eq!(stale, bytes.len() as u64 - live);
+        Ok(())
+    }
+
+    // A record cut short ends the newest file where it starts, but is an
+    // error anywhere else.
+    #[test]
+    fn recover_truncated_record() -> Result<()> {
+        let whole = log_bytes(&[set("a", "1")]);
+        let mut bytes = log_bytes(&[set("a", "1"), set("b", "2")]);
+        bytes.truncate(bytes.len() - 3);
+        let (index, _, torn_at) = recover_bytes(bytes.clone(), true)?;
+        assert_eq!(index.keys().collect::<Vec<_>>(), ["a"]);
+        assert_eq!(torn_at, Some(whole.len() as u64));
+        assert!(recover_bytes(bytes, false).is_err());
+        Ok(())
+    }
+
+    // Records of a transaction only count once its commit record is read.
+    #[test]
+    fn recover_unfinished_transaction() -> Result<()> {
+        let committed = log_bytes(&[
+            set("a", "1"),
+            Record::TxnBegin,
+            set("b", "2"),
+            Record::TxnCommit,
+        ]);
+        let mut bytes = committed.clone();
+        bytes.extend(log_bytes(&[Record::TxnBegin, set("c", "3"), remove("a")]));
+        let (index, _, torn_at) = recover_bytes(bytes, true)?;
+        assert_eq!(index.keys().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(torn_at, Some(committed.len() as u64));
+        Ok(())
+    }
+
+    // A stream cut off before a transaction's commit record applies none
+    // of the transaction's writes on the replica.
+    #[test]
+    fn apply_changes_skips_unfinished_transaction() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        let replica = KvStore::open(replica_dir.path())?;
+        store.set("a".to_owned(), "1".to_owned())?;
+        store.transaction(|txn| {
+            txn.set("b".to_owned(), "2".to_owned());
+            txn.remove("a".to_owned());
+            Ok(())
+        })?;
+        let mut changes = Vec::new();
+        store.stream_changes(0, &mut changes)?;
+        let commit = log_bytes(&[Record::TxnCommit]);
+        assert!(changes.ends_with(&commit));
+
+        let truncated = &changes[..changes.len() - commit.len()];
+        assert_eq!(replica.apply_changes(truncated)?, 1);
+        assert_eq!(replica.get("a".to_owned())?, Some("1".to_owned()));
+        assert_eq!(replica.get("b".to_owned())?, None);
+
+        assert_eq!(replica.apply_changes(&changes[..])?, 3);
+        assert_eq!(replica.get("a".to_owned())?, None);
+        assert_eq!(replica.digest()?, store.digest()?);
+        Ok(())
+    }
+
+    // A corrupted checksum fails recovery instead of indexing bad data.
+    #[test]
+    fn recover_corrupt_record() {
+        let bytes = log_bytes(&[Record::Set {
+            key: "a".to_owned(),
+            value: "1".to_owned(),
+            crc: 0,
+            seq: 0,
+        }]);
+        assert!(matches!(
+            recover_bytes(bytes, true),
+            Err(KvError::CorruptRecord)
+        ));
+    }
+}
@@ -1,5 +1,55 @@
-pub use kv::KvStore;
-pub use error::{KvError,Result};
+pub use async_engine::{AsyncKvsEngine, SpawnBlocking};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use codec::RecordFormat;
+pub use engine::{recorded_engine, KvsEngine};
+pub use error::{FileError, FileOp, KvError, Result};
+pub use group_commit::GroupCommit;
+pub use hashed::hash_key;
+pub use iter::Iter;
+pub use kv::{Command, KvStore};
+pub use memory_engine::InMemoryKvsEngine;
+pub use options::{CompactionPolicy, FsyncPolicy, KvStoreOptions, ProgressCallback};
+pub use replay::Replay;
+pub use scan::Scan;
+pub use sharded::ShardedKvStore;
+pub use sled_engine::SledKvsEngine;
+pub use stats::{
+    CompactionAdvice, CompactionEstimate, CompactionResult, FileFragmentation, KvStoreStats,
+    VerifyFailure, VerifyReport,
+};
+pub use tail::Tail;
+pub use thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use txn::Txn;
+pub use typed::TypedKvStore;
+pub use value_reader::ValueReader;
 
+mod async_engine;
+mod backup;
+mod checkpoint;
+mod clock;
+mod codec;
+mod engine;
+mod error;
+mod group_commit;
+mod hashed;
+mod iter;
+mod key_index;
 mod kv;
-mod error;
\ No newline at end of file
+mod manifest;
+mod memory_engine;
+pub mod metrics;
+mod options;
+mod pin;
+pub mod protocol;
+mod replay;
+pub mod resp;
+mod saved_index;
+mod scan;
+mod sharded;
+mod sled_engine;
+mod stats;
+mod tail;
+mod thread_pool;
+mod txn;
+mod typed;
+mod value_reader;
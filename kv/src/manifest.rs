@@ -0,0 +1,79 @@
+//! Manifests listing several stores to open together, one per shard.
+
+use crate::{CompactionPolicy, KvStoreOptions, RecordFormat, Result};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+// One store of the manifest.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestEntry {
+    path: PathBuf,
+    #[serde(default)]
+    options: Settings,
+}
+
+// Declare `Settings` with an optional override for each listed option.
+macro_rules! settings {
+    ($($field:ident: $ty:ty,)*) => {
+        #[derive(Default, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Settings {
+            $($field: Option<$ty>,)*
+        }
+
+        impl Settings {
+            // The default options with these overrides applied.
+            fn into_options(self) -> KvStoreOptions {
+                let mut options = KvStoreOptions::default();
+                $(if let Some($field) = self.$field {
+                    options.$field = $field;
+                })*
+                options
+            }
+        }
+    };
+}
+
+settings! {
+    compaction_policy: CompactionPolicy,
+    compact_in_place: bool,
+    background_compaction: bool,
+    compact_on_open: bool,
+    compact_while_recovering: bool,
+    cleanup_on_open: bool,
+    build_value_index: bool,
+    sync_sets: bool,
+    sync_removes: bool,
+    strict_removes: bool,
+    buffer_capacity: usize,
+    max_open_readers: Option<usize>,
+    compaction_chunk_size: usize,
+    compaction_workers: usize,
+    recovery_workers: usize,
+    scan_prefetch: bool,
+    defer_compaction_one_op: bool,
+    memory_budget: Option<usize>,
+    max_key_size: Option<usize>,
+    max_value_size: Option<usize>,
+    inline_value_size: Option<usize>,
+    key_index_file: bool,
+    index_checkpoint: bool,
+    save_index: bool,
+    defer_active_file: bool,
+    audit_mode: bool,
+    record_format: RecordFormat,
+    compress_values: bool,
+}
+
+/// Read the manifest at `path`, resolving each store's directory.
+pub(crate) fn read(path: &Path) -> Result<Vec<(PathBuf, KvStoreOptions)>> {
+    let entries: Vec<ManifestEntry> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    Ok(entries
+        .into_iter()
+        .map(|entry| (base.join(entry.path), entry.options.into_options()))
+        .collect())
+}
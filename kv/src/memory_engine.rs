@@ -0,0 +1,44 @@
+use crate::{KvError, KvsEngine, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// A `KvsEngine` that keeps everything in memory and never touches the
+/// disk, for tests and caches that need not outlive the process.
+///
+/// Clones share the same map, and its contents are gone once the last
+/// one is dropped.
+#[derive(Clone, Default)]
+pub struct InMemoryKvsEngine {
+    map: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl InMemoryKvsEngine {
+    /// Create an empty engine.
+    pub fn new() -> InMemoryKvsEngine {
+        InMemoryKvsEngine::default()
+    }
+}
+
+impl KvsEngine for InMemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.map.write().unwrap().insert(key, value);
+        Ok(())
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.map.read().unwrap().get(&key).cloned())
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        self.map
+            .write()
+            .unwrap()
+            .remove(&key)
+            .map(drop)
+            .ok_or(KvError::KeyNotFound)
+    }
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+    fn clone_engine(&self) -> Box<dyn KvsEngine> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,139 @@
+//! Prometheus text exposition of store statistics and server counters.
+
+use crate::protocol::{Request, Response};
+use crate::{KvError, KvStore};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Render the store's statistics in the Prometheus text format, suitable
+/// for serving on a `/metrics` path.
+pub fn render_prometheus(store: &KvStore) -> String {
+    let stats = store.stats();
+    let metrics = [
+        (
+            "kv_live_keys",
+            "gauge",
+            "Number of live keys.",
+            stats.live_keys,
+        ),
+        (
+            "kv_uncompacted_bytes",
+            "gauge",
+            "Stale bytes reclaimable by compaction.",
+            stats.uncompacted_bytes,
+        ),
+        (
+            "kv_log_files",
+            "gauge",
+            "Number of log files on disk.",
+            stats.num_log_files,
+        ),
+        (
+            "kv_disk_bytes",
+            "gauge",
+            "Total size of all log files.",
+            stats.disk_bytes,
+        ),
+        (
+            "kv_bytes_written_total",
+            "counter",
+            "Bytes written to the logs, compaction copies included.",
+            stats.bytes_written,
+        ),
+        (
+            "kv_compactions_total",
+            "counter",
+            "Compactions run.",
+            stats.compactions,
+        ),
+        ("kv_gets_total", "counter", "Get operations.", stats.gets),
+        ("kv_sets_total", "counter", "Set operations.", stats.sets),
+        (
+            "kv_removes_total",
+            "counter",
+            "Remove operations.",
+            stats.removes,
+        ),
+    ];
+    let mut out = String::new();
+    write_metrics(&mut out, &metrics);
+    out
+}
+
+/// Request counters of a server, shared by the threads serving its
+/// connections.
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    removes: AtomicU64,
+    key_not_found: AtomicU64,
+}
+
+impl ServerMetrics {
+    /// Count `request`, which was answered with `response`.
+    pub fn record(&self, request: &Request, response: &Response) {
+        let counter = match request {
+            Request::Get { .. } => &self.gets,
+            Request::Set { .. } => &self.sets,
+            Request::Remove { .. } => &self.removes,
+            Request::Stats | Request::Compact | Request::Status => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let not_found = match response {
+            Response::Value(None) => true,
+            Response::Err(e) => *e == KvError::KeyNotFound.to_string(),
+            _ => false,
+        };
+        if not_found {
+            self.key_not_found.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Render the counters in the Prometheus text format, followed by the
+    /// statistics of `store` when the server has one.
+    pub fn render(&self, store: Option<&KvStore>) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let metrics = [
+            (
+                "kv_server_gets_total",
+                "counter",
+                "Get requests served.",
+                load(&self.gets),
+            ),
+            (
+                "kv_server_sets_total",
+                "counter",
+                "Set requests served.",
+                load(&self.sets),
+            ),
+            (
+                "kv_server_removes_total",
+                "counter",
+                "Remove requests served.",
+                load(&self.removes),
+            ),
+            (
+                "kv_server_key_not_found_total",
+                "counter",
+                "Gets and removes of a missing key.",
+                load(&self.key_not_found),
+            ),
+        ];
+        let mut out = String::new();
+        write_metrics(&mut out, &metrics);
+        if let Some(store) = store {
+            out.push_str(&render_prometheus(store));
+        }
+        out
+    }
+}
+
+// Append `(name, type, help, value)` metrics to `out`.
+fn write_metrics(out: &mut String, metrics: &[(&str, &str, &str, u64)]) {
+    for (name, kind, help, value) in metrics {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        writeln!(out, "{} {}", name, value).unwrap();
+    }
+}
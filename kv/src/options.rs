@@ -0,0 +1,258 @@
+use crate::clock::{Clock, SystemClock};
+use crate::RecordFormat;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Called during compaction with `(copied, total)` bytes as a record larger
+/// than `compaction_chunk_size` is copied.
+#[derive(Clone)]
+pub struct ProgressCallback(pub Arc<dyn Fn(u64, u64) + Send + Sync>);
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// When stale bytes have piled up enough for a write to compact.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompactionPolicy {
+    /// Compact once more than this many stale bytes have accumulated.
+    Bytes(u64),
+    /// Compact once stale bytes make up more than this fraction of the
+    /// log, so small and large stores alike keep the same share of waste.
+    Ratio(f64),
+}
+
+impl CompactionPolicy {
+    // Whether `stale` of a log `total` bytes long calls for compaction.
+    pub(crate) fn triggered(&self, stale: u64, total: u64) -> bool {
+        match *self {
+            CompactionPolicy::Bytes(bytes) => stale > bytes,
+            CompactionPolicy::Ratio(ratio) => stale > 0 && stale as f64 > total as f64 * ratio,
+        }
+    }
+}
+
+/// When writes are synced from the OS cache to disk. Flushing a write only
+/// hands it to the OS, which loses it if the machine goes down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// Sync the active file after every write before it returns.
+    Always,
+    /// Sync once this many writes have piled up, or on the first write or
+    /// `KvStore::tick` once `interval` has passed since the last sync.
+    Batch {
+        writes: u64,
+        interval: Option<Duration>,
+    },
+    /// Leave syncing to the OS.
+    #[default]
+    Never,
+}
+
+/// Options for opening a `KvStore`.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// When stale data has piled up enough to compact.
+    pub compaction_policy: CompactionPolicy,
+    /// Compact when this long has passed since the last compaction and
+    /// there is any stale data, regardless of the byte threshold.
+    pub compact_after: Option<Duration>,
+    /// Defer all compaction until no operation has happened for this long.
+    /// Compaction then only runs from `KvStore::tick`.
+    pub compact_when_idle: Option<Duration>,
+    /// Bound the total log size to roughly this many bytes by rolling the
+    /// active file into fixed-size segments and dropping the oldest ones.
+    pub ring_capacity: Option<u64>,
+    /// Size at which the active file is rolled over in ring mode.
+    pub ring_segment_size: u64,
+    /// Copy live entries out of a dropped segment instead of losing them.
+    pub ring_migrate_live: bool,
+    /// Start a new active file once a `set` leaves the current one at least
+    /// this large, so no single log file grows without bound between
+    /// compactions. Compaction likewise splits the records it copies into
+    /// files of at most this size.
+    pub max_file_size: Option<u64>,
+    /// When the active file is the only log file, compact it into a single
+    /// new file instead of a compaction file plus a new active file.
+    pub compact_in_place: bool,
+    /// Compact on a thread of the store's own rather than inside the write
+    /// that crosses the threshold. Writes go on to a new active file while
+    /// the live records are copied. Ignored in ring mode.
+    pub background_compaction: bool,
+    /// Abort `open` with `KvError::RecoveryTimeout` if replaying the logs
+    /// takes longer than this.
+    pub recovery_deadline: Option<Duration>,
+    /// Compact during `open` if the recovered logs hold more stale bytes
+    /// than live ones.
+    pub compact_on_open: bool,
+    /// With `compact_on_open`, write the compacted file while recovering
+    /// rather than in a second pass over the logs. The copy is dropped if
+    /// the logs turn out not to need compacting.
+    pub compact_while_recovering: bool,
+    /// Run `KvStore::cleanup` during `open`, dropping files a crash left
+    /// behind with nothing live in them.
+    pub cleanup_on_open: bool,
+    /// Maintain a reverse index from values to keys for `keys_with_value`.
+    /// This costs memory and a read of the old value on every write.
+    pub build_value_index: bool,
+    /// Flush each `set` out to the log file immediately. When false, sets
+    /// stay buffered in memory until `flush`, a read of the buffered data or
+    /// compaction, and are lost if the process dies before then. Bulk
+    /// loads gain throughput from turning it off and calling `flush` once
+    /// they are done; the default keeps every acknowledged set on disk.
+    pub sync_sets: bool,
+    /// Flush each `remove` out to the log file immediately, so a crash
+    /// cannot resurrect the removed key.
+    pub sync_removes: bool,
+    /// When writes are synced to disk, flushing any still buffered first.
+    /// A transaction counts as one write.
+    pub fsync: FsyncPolicy,
+    /// Reject keys for which this returns false with `KvError::InvalidKey`
+    /// before anything is written.
+    pub key_validator: Option<fn(&str) -> bool>,
+    /// Make `remove` of an absent key fail with `KvError::KeyNotFound`.
+    /// When false, such removes succeed without writing anything.
+    pub strict_removes: bool,
+    /// Capacity of the buffers log files are read and written through.
+    /// Larger buffers mean fewer system calls for large values.
+    pub buffer_capacity: usize,
+    /// Keep at most this many log files open for reading, closing the least
+    /// recently read one to open another. Each handle observes the cap on
+    /// its own, so a store with many files needs no more descriptors than
+    /// this per handle.
+    pub max_open_readers: Option<usize>,
+    /// Size of the buffer compaction copies records through. This bounds
+    /// compaction memory regardless of value sizes.
+    pub compaction_chunk_size: usize,
+    /// Progress reporting for large records during compaction.
+    pub compaction_progress: Option<ProgressCallback>,
+    /// Number of threads compaction copies live records with, each into a
+    /// log file of its own. Compacting in place always uses one.
+    pub compaction_workers: usize,
+    /// Number of threads `open` reads the log files with, each taking a run
+    /// of consecutive files. Opening with a key filter always uses one.
+    pub recovery_workers: usize,
+    /// Read the next log file on a background thread during replays.
+    pub scan_prefetch: bool,
+    /// When a write pushes the store past a compaction trigger, leave the
+    /// compaction to the next operation so no single call pays for both.
+    pub defer_compaction_one_op: bool,
+    /// Cap on the estimated memory held by the in-memory index, including
+    /// any values it caches. Opening a store whose index is already larger
+    /// fails, as does a `set` that would push it over.
+    pub memory_budget: Option<usize>,
+    /// Largest key, in bytes, a write may store. Larger ones fail with
+    /// `KvError::KeyTooLarge` before anything is written.
+    pub max_key_size: Option<usize>,
+    /// Largest value, in bytes, a write may store. Larger ones fail with
+    /// `KvError::ValueTooLarge` before anything is written.
+    pub max_value_size: Option<usize>,
+    /// Keep values of at most this many bytes in the in-memory index as
+    /// well as the log, so `get` returns them without reading the file.
+    /// Values are cached as they are written and as `open` replays the
+    /// logs. The cached values count against `memory_budget`.
+    pub inline_value_size: Option<usize>,
+    /// Order keys in sorted scans and range queries by this comparator
+    /// instead of byte-wise. Point operations are unaffected.
+    pub key_order: Option<fn(&str, &str) -> Ordering>,
+    /// Write a key index next to each compaction output, so `open` loads
+    /// the keys of compacted data without reading their values.
+    pub key_index_file: bool,
+    /// End each compaction file that is not also the active one with a
+    /// checkpoint of its index, which `open` loads instead of replaying it.
+    /// Only supported with `RecordFormat::Json`, and ignored otherwise.
+    pub index_checkpoint: bool,
+    /// Save the whole index to an `index` file on close and after each
+    /// compaction, so `open` loads it and only replays what was logged
+    /// after it. Anything else that deletes or rewrites log files removes
+    /// the saved index until the next save, with this set or not.
+    pub save_index: bool,
+    /// The encoding of records in the log files of a new store. An
+    /// existing directory keeps the format it notes it was created with.
+    pub record_format: RecordFormat,
+    /// Deflate values before logging them, for those it makes smaller.
+    /// Compressed and plain records read back the same whatever this is
+    /// set to, and compaction copies them as they are.
+    pub compress_values: bool,
+    /// Create the directory and active log file on the first write rather
+    /// than at open, so handles that only read never need write permission.
+    pub defer_active_file: bool,
+    /// How often keys whose TTL has run out are swept, by a background
+    /// thread the store starts and by `KvStore::tick`. Handles that cannot
+    /// write start no thread.
+    pub ttl_sweep_interval: Option<Duration>,
+    /// Index keys by this 64-bit hash of theirs, such as `kv::hash_key`,
+    /// instead of by the whole key, which saves memory on stores of many
+    /// keys. Point operations then read the key back from the log record
+    /// of each entry sharing its hash to tell the keys apart, under the
+    /// store's exclusive lock.
+    ///
+    /// Operations that need the keys themselves fail with
+    /// `KvError::HashedKeys`: scans, ranges, `iter`, `digest`, `export`,
+    /// `verify`, `tombstoned_keys`, transactions, `swap` and `rename_key`.
+    /// `keys` is always empty. Opening fails the same way with
+    /// `build_value_index`, `key_index_file`, `index_checkpoint`,
+    /// `save_index` or `ring_capacity`. Recovery at open still holds every
+    /// key until the index is built.
+    pub hashed_keys: Option<fn(&str) -> u64>,
+    /// Keep the full history on disk: removes hide keys but nothing is ever
+    /// garbage collected, so compaction and ring eviction are disabled.
+    pub audit_mode: bool,
+    /// Time source used by time-based behavior.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            compaction_policy: CompactionPolicy::Bytes(1024 * 1024),
+            compact_after: None,
+            compact_when_idle: None,
+            ring_capacity: None,
+            ring_segment_size: 64 * 1024,
+            ring_migrate_live: false,
+            max_file_size: None,
+            compact_in_place: true,
+            background_compaction: false,
+            recovery_deadline: None,
+            compact_on_open: false,
+            compact_while_recovering: false,
+            cleanup_on_open: false,
+            build_value_index: false,
+            sync_sets: true,
+            sync_removes: true,
+            fsync: FsyncPolicy::default(),
+            key_validator: None,
+            strict_removes: true,
+            buffer_capacity: 8 * 1024,
+            max_open_readers: None,
+            compaction_chunk_size: 64 * 1024,
+            compaction_progress: None,
+            compaction_workers: 1,
+            recovery_workers: 1,
+            scan_prefetch: false,
+            defer_compaction_one_op: false,
+            memory_budget: None,
+            max_key_size: None,
+            max_value_size: None,
+            inline_value_size: None,
+            key_order: None,
+            key_index_file: false,
+            index_checkpoint: false,
+            save_index: false,
+            record_format: RecordFormat::default(),
+            compress_values: false,
+            defer_active_file: false,
+            ttl_sweep_interval: None,
+            hashed_keys: None,
+            audit_mode: false,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Reference counts for log files that iterators are still reading.
+///
+/// Deleting a pinned file is deferred until its last pin is released, so
+/// compaction never pulls a file out from under a long scan.
+#[derive(Debug, Default)]
+pub(crate) struct FilePins {
+    state: Mutex<PinState>,
+}
+
+#[derive(Debug, Default)]
+struct PinState {
+    counts: HashMap<u64, usize>,
+    // Files whose deletion is waiting on their pins.
+    doomed: HashMap<u64, PathBuf>,
+}
+
+impl FilePins {
+    /// Pin `ids` until the returned guard is dropped.
+    pub(crate) fn pin(self: &Arc<Self>, ids: Vec<u64>) -> PinGuard {
+        let mut state = self.state.lock().unwrap();
+        for &id in &ids {
+            *state.counts.entry(id).or_insert(0) += 1;
+        }
+        PinGuard {
+            pins: Arc::clone(self),
+            ids,
+        }
+    }
+
+    /// Delete the log file `id` now, or once it is no longer pinned.
+    pub(crate) fn remove_file(&self, id: u64, path: PathBuf) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.counts.contains_key(&id) {
+            state.doomed.insert(id, path);
+            Ok(())
+        } else {
+            fs::remove_file(path)
+        }
+    }
+
+    fn unpin(&self, ids: &[u64]) {
+        let mut state = self.state.lock().unwrap();
+        for id in ids {
+            let count = state.counts.get_mut(id).expect("unbalanced file pin");
+            *count -= 1;
+            if *count == 0 {
+                state.counts.remove(id);
+                if let Some(path) = state.doomed.remove(id) {
+                    // Nobody is left to report a failure to.
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+    }
+}
+
+/// Keeps a set of log files pinned while it is alive.
+#[derive(Debug)]
+pub(crate) struct PinGuard {
+    pins: Arc<FilePins>,
+    ids: Vec<u64>,
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        self.pins.unpin(&self.ids);
+    }
+}
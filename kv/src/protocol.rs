@@ -0,0 +1,60 @@
+//! The wire protocol spoken between `kvs-client` and `kvs-server`.
+//!
+//! Each message is a single JSON value, written with `serde_json` just like
+//! the records of a log file. Values are not delimited: a reader decodes
+//! them back to back from the stream, as `Deserializer::into_iter` does.
+//! A client may wait for each `Response` before sending its next `Request`
+//! or, as `kvs-client batch` does, pipeline several requests ahead of their
+//! responses: the server answers them one by one, in the order they were
+//! sent, until the client closes the connection.
+
+use crate::KvStoreStats;
+use serde::{Deserialize, Serialize};
+
+/// A request from a client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+    /// Read the value of `key`.
+    Get { key: String },
+    /// Set `key` to `value`.
+    Set { key: String, value: String },
+    /// Remove `key`.
+    Remove { key: String },
+    /// Report the store's statistics.
+    Stats,
+    /// Compact the store's logs.
+    Compact,
+    /// Report what the server is serving, confirming that it is.
+    Status,
+}
+
+/// The server's answer to one `Request`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    /// The value of a `Get`, `None` for a missing key.
+    Value(Option<String>),
+    /// A `Set` or `Remove` succeeded.
+    Ok,
+    /// The store's statistics, answering `Stats`.
+    Stats(KvStoreStats),
+    /// The bytes a `Compact` freed.
+    Compacted(u64),
+    /// The request failed with this error message.
+    Err(String),
+    /// The server's state, answering `Status`.
+    Status(ServerStatus),
+}
+
+/// What a server reports about itself in answer to `Request::Status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStatus {
+    /// The storage engine being served, `kvs` or `sled`.
+    pub engine: String,
+    /// Seconds since the server started.
+    pub uptime_secs: u64,
+    /// Live keys in the store, known when the engine is `kvs`.
+    pub live_keys: Option<u64>,
+    /// Stale bytes the next compaction would reclaim, known when the engine
+    /// is `kvs`.
+    pub uncompacted_bytes: Option<u64>,
+}
@@ -0,0 +1,88 @@
+use crate::pin::PinGuard;
+use crate::{Command, RecordFormat, Result};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read};
+use std::thread::{self, JoinHandle};
+
+/// An iterator over every command in the logs, oldest first.
+///
+/// With prefetching enabled, the next log file is read on a background
+/// thread while the current one is being consumed. The files stay on disk
+/// until the replay is dropped, even if the store compacts meanwhile.
+pub struct Replay {
+    files: VecDeque<File>,
+    current: Option<Box<dyn Read + Send>>,
+    prefetched: Option<JoinHandle<io::Result<Vec<u8>>>>,
+    prefetch: bool,
+    format: RecordFormat,
+    _pins: PinGuard,
+}
+
+impl Replay {
+    pub(crate) fn new(
+        files: Vec<File>,
+        prefetch: bool,
+        format: RecordFormat,
+        pins: PinGuard,
+    ) -> Replay {
+        Replay {
+            files: files.into(),
+            current: None,
+            prefetched: None,
+            prefetch,
+            format,
+            _pins: pins,
+        }
+    }
+
+    // Move on to the next log file, returning false when none are left.
+    fn advance(&mut self) -> Result<bool> {
+        let reader: Box<dyn Read + Send> = match self.prefetched.take() {
+            Some(handle) => {
+                let bytes = handle.join().expect("prefetch thread panicked")?;
+                Box::new(Cursor::new(bytes))
+            }
+            None => match self.files.pop_front() {
+                Some(file) => Box::new(BufReader::new(file)),
+                None => return Ok(false),
+            },
+        };
+        if self.prefetch {
+            if let Some(mut next) = self.files.pop_front() {
+                self.prefetched = Some(thread::spawn(move || {
+                    let mut bytes = Vec::new();
+                    next.read_to_end(&mut bytes)?;
+                    Ok(bytes)
+                }));
+            }
+        }
+        self.current = Some(reader);
+        Ok(true)
+    }
+}
+
+impl Iterator for Replay {
+    type Item = Result<Command>;
+
+    fn next(&mut self) -> Option<Result<Command>> {
+        loop {
+            if let Some(reader) = self.current.as_mut() {
+                match self.format.read(reader) {
+                    Some(Ok((record, _))) => match record.into_command() {
+                        Ok(Some(cmd)) => return Some(Ok(cmd)),
+                        Ok(None) => continue,
+                        Err(e) => return Some(Err(e)),
+                    },
+                    Some(Err(e)) => return Some(Err(e.into())),
+                    None => self.current = None,
+                }
+            }
+            match self.advance() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
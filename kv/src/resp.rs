@@ -0,0 +1,126 @@
+//! A minimal subset of the Redis serialization protocol (RESP), so existing
+//! Redis clients can issue `GET`, `SET` and `DEL` against a store. `STATS`
+//! replies with the store's `KvStoreStats` as a JSON bulk string, so remote
+//! stores can be monitored.
+
+use crate::{KvError, KvStore, Result};
+use log::debug;
+use std::io::{BufRead, Write};
+
+/// Serve RESP commands read from `reader` until it is exhausted, writing a
+/// reply for each to `writer`.
+///
+/// Store errors are sent back as RESP errors and serving continues. A
+/// malformed request is answered with an error and ends the session, since
+/// the rest of the stream can no longer be framed.
+pub fn serve(store: &KvStore, mut reader: impl BufRead, mut writer: impl Write) -> Result<()> {
+    loop {
+        let args = match read_request(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e @ KvError::Protocol { .. }) => {
+                write!(writer, "-ERR {}\r\n", e)?;
+                writer.flush()?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        match execute(store, args) {
+            Ok(reply) => writer.write_all(reply.as_bytes())?,
+            Err(e) => write!(writer, "-ERR {}\r\n", e)?,
+        }
+        writer.flush()?;
+    }
+}
+
+// Run one command and encode its reply.
+fn execute(store: &KvStore, args: Vec<String>) -> Result<String> {
+    let mut args = args.into_iter();
+    let name = args.next().unwrap_or_default().to_ascii_uppercase();
+    let args: Vec<String> = args.collect();
+    debug!("{} with {} arguments", name, args.len());
+    match (name.as_str(), args.len()) {
+        ("GET", 1) => {
+            let key = args.into_iter().next().unwrap();
+            Ok(match store.get(key)? {
+                Some(value) => format!("${}\r\n{}\r\n", value.len(), value),
+                None => "$-1\r\n".to_owned(),
+            })
+        }
+        ("SET", 2) => {
+            let mut args = args.into_iter();
+            store.set(args.next().unwrap(), args.next().unwrap())?;
+            Ok("+OK\r\n".to_owned())
+        }
+        ("DEL", n) if n > 0 => {
+            let mut removed = 0;
+            for key in args {
+                if store.contains_key(&key) {
+                    store.remove(key)?;
+                    removed += 1;
+                }
+            }
+            Ok(format!(":{}\r\n", removed))
+        }
+        ("STATS", 0) => {
+            let stats = serde_json::to_string(&store.stats())?;
+            Ok(format!("${}\r\n{}\r\n", stats.len(), stats))
+        }
+        ("GET", _) | ("SET", _) | ("DEL", _) | ("STATS", _) => Ok(format!(
+            "-ERR wrong number of arguments for '{}' command\r\n",
+            name.to_ascii_lowercase()
+        )),
+        _ => Ok(format!("-ERR unknown command '{}'\r\n", name)),
+    }
+}
+
+// Read one request, an array of bulk strings, or `None` at end of input.
+fn read_request(reader: &mut impl BufRead) -> Result<Option<Vec<String>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = parse_header(&line, '*')?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| protocol("unexpected end of input"))?;
+        let len = parse_header(&line, '$')?;
+        let mut bulk = vec![0; len + 2];
+        reader.read_exact(&mut bulk)?;
+        if !bulk.ends_with(b"\r\n") {
+            return Err(protocol("bulk string is not terminated by CRLF"));
+        }
+        bulk.truncate(len);
+        let arg = String::from_utf8(bulk).map_err(|_| protocol("argument is not UTF-8"))?;
+        args.push(arg);
+    }
+    if args.is_empty() {
+        return Err(protocol("empty request"));
+    }
+    Ok(Some(args))
+}
+
+// Read a CRLF-terminated line without its terminator.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    match line.strip_suffix("\r\n") {
+        Some(line) => Ok(Some(line.to_owned())),
+        None => Err(protocol("line is not terminated by CRLF")),
+    }
+}
+
+// Parse a `<prefix><length>` header line.
+fn parse_header(line: &str, prefix: char) -> Result<usize> {
+    line.strip_prefix(prefix)
+        .and_then(|len| len.parse().ok())
+        .ok_or_else(|| protocol(&format!("expected '{}' followed by a length", prefix)))
+}
+
+fn protocol(reason: &str) -> KvError {
+    KvError::Protocol {
+        reason: reason.to_owned(),
+    }
+}
@@ -0,0 +1,75 @@
+use crate::kv::IndexEntry;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The whole index as of some point in the logs, written to `index` in the
+/// store directory so `open` only replays what was logged after it.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SavedIndex {
+    /// Every log file there was, oldest first.
+    pub files: Vec<SavedFile>,
+    /// Stale bytes in the logs up to the saved point.
+    pub uncompacted: u64,
+    /// The highest sequence number written up to the saved point.
+    pub max_seq: u64,
+}
+
+/// The part of one log file a `SavedIndex` covers.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SavedFile {
+    pub id: u64,
+    /// Offset up to which the file was indexed. Records after it were
+    /// appended later and must still be replayed.
+    pub end: u64,
+    /// The live records in the file.
+    pub entries: Vec<IndexEntry>,
+}
+
+fn saved_index_path(dir: &Path) -> PathBuf {
+    dir.join("index")
+}
+
+impl SavedIndex {
+    /// Write the saved index, replacing any old one.
+    pub(crate) fn write(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join("index.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        fs::rename(&tmp_path, saved_index_path(dir))?;
+        Ok(())
+    }
+
+    /// Load the saved index, if it still describes the log files `ids`: each
+    /// file it covers is there and at least as long as it was, and no file
+    /// it does not cover is older than the newest one it does. A missing or
+    /// unreadable index is not an error; the logs are simply replayed in
+    /// full.
+    pub(crate) fn read(
+        dir: &Path,
+        ids: &[u64],
+        file_len: impl Fn(u64) -> Result<u64>,
+    ) -> Option<SavedIndex> {
+        let file = File::open(saved_index_path(dir)).ok()?;
+        let saved: SavedIndex = serde_json::from_reader(BufReader::new(file)).ok()?;
+        let newest = saved.files.last().map_or(0, |file| file.id);
+        let older: Vec<u64> = ids.iter().copied().filter(|&id| id <= newest).collect();
+        let covered =
+            saved.files.len() == older.len()
+                && saved.files.iter().zip(older).all(|(file, id)| {
+                    file.id == id && file_len(id).is_ok_and(|len| len >= file.end)
+                });
+        covered.then_some(saved)
+    }
+
+    /// Delete the saved index, if there is one.
+    pub(crate) fn remove(dir: &Path) -> Result<()> {
+        match fs::remove_file(saved_index_path(dir)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use crate::kv::read_command;
+use crate::pin::PinGuard;
+use crate::{Command, KvError, RecordFormat, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::vec;
+
+/// An iterator over the live key/value pairs as of when it was created.
+///
+/// The log files it reads from stay on disk until the scan is dropped, so
+/// the store can keep writing and compacting in the meantime.
+pub struct Scan {
+    entries: vec::IntoIter<(String, u64, u64, u64)>,
+    files: HashMap<u64, BufReader<File>>,
+    format: RecordFormat,
+    _pins: PinGuard,
+}
+
+impl Scan {
+    pub(crate) fn new(
+        entries: Vec<(String, u64, u64, u64)>,
+        files: HashMap<u64, BufReader<File>>,
+        format: RecordFormat,
+        pins: PinGuard,
+    ) -> Scan {
+        Scan {
+            entries: entries.into_iter(),
+            files,
+            format,
+            _pins: pins,
+        }
+    }
+
+    fn read(&mut self, file_id: u64, pos: u64, len: u64) -> Result<String> {
+        let reader = self
+            .files
+            .get_mut(&file_id)
+            .ok_or(KvError::ReaderNotFound(file_id))?;
+        reader.seek(SeekFrom::Start(pos))?;
+        match read_command(self.format, reader.take(len))? {
+            Command::Set { value, .. } | Command::SetEx { value, .. } => Ok(value),
+            Command::Remove { .. } => Err(KvError::UnexpectedCommandType),
+        }
+    }
+}
+
+impl Iterator for Scan {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Result<(String, String)>> {
+        let (key, file_id, pos, len) = self.entries.next()?;
+        Some(self.read(file_id, pos, len).map(|value| (key, value)))
+    }
+}
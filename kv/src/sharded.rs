@@ -0,0 +1,109 @@
+use crate::engine;
+use crate::kv::create_dir;
+use crate::{KvError, KvStore, KvsEngine, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// The file recording how many shards a directory was created with.
+const SHARD_COUNT_FILE: &str = "shards";
+
+/// A store split into independent `KvStore` shards, one per subdirectory,
+/// so writes to different shards do not wait for each other.
+///
+/// Keys are routed to a shard by a hash that is stable across runs, so a
+/// directory must always be opened with the shard count it was created
+/// with.
+#[derive(Clone)]
+pub struct ShardedKvStore {
+    shards: Vec<KvStore>,
+}
+
+impl ShardedKvStore {
+    /// Open the sharded store at `path`, creating `shards` shards if it is
+    /// new.
+    ///
+    /// Fails with `KvError::ShardCountMismatch` if the directory was
+    /// created with a different number of shards.
+    pub fn open(path: impl Into<PathBuf>, shards: usize) -> Result<ShardedKvStore> {
+        let path = path.into();
+        let shards = shards.max(1);
+        create_dir(&path)?;
+        engine::claim_dir(&path, "kvs-sharded")?;
+        check_shard_count(&path, shards)?;
+        let shards = (0..shards)
+            .map(|shard| KvStore::open(path.join(format!("shard-{}", shard))))
+            .collect::<Result<_>>()?;
+        Ok(ShardedKvStore { shards })
+    }
+    /// Set `key` to `value` in its shard.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+    /// Get the value of `key` from its shard.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+    /// Remove `key` from its shard.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+    /// Write out the buffered records of every shard.
+    pub fn flush(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::flush)
+    }
+    /// Write out and sync the buffered records of every shard.
+    pub fn sync(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::sync)
+    }
+    /// The shards, in routing order.
+    pub fn shards(&self) -> &[KvStore] {
+        &self.shards
+    }
+    fn shard(&self, key: &str) -> &KvStore {
+        let hash = crc32fast::hash(key.as_bytes()) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+}
+
+impl KvsEngine for ShardedKvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        ShardedKvStore::set(self, key, value)
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        ShardedKvStore::get(self, key)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        ShardedKvStore::remove(self, key)
+    }
+    fn flush(&self) -> Result<()> {
+        ShardedKvStore::flush(self)
+    }
+    fn sync(&self) -> Result<()> {
+        ShardedKvStore::sync(self)
+    }
+    fn clone_engine(&self) -> Box<dyn KvsEngine> {
+        Box::new(self.clone())
+    }
+}
+
+// Record `shards` as the shard count of a new directory, or check it
+// against the recorded one.
+fn check_shard_count(dir: &Path, shards: usize) -> Result<()> {
+    let path = dir.join(SHARD_COUNT_FILE);
+    let found = match fs::read_to_string(&path) {
+        Ok(found) => found,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(fs::write(&path, shards.to_string())?);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    match found.trim().parse::<usize>() {
+        Ok(found) if found == shards => Ok(()),
+        Ok(found) => Err(KvError::ShardCountMismatch {
+            requested: shards,
+            found,
+        }),
+        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "unreadable shard count").into()),
+    }
+}
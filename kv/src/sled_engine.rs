@@ -0,0 +1,77 @@
+use crate::backup;
+use crate::engine;
+use crate::{KvError, KvsEngine, Result};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+/// A `KvsEngine` backed by the `sled` embedded database, mainly as a
+/// baseline to compare `KvStore` against.
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: sled::Db,
+}
+
+impl SledKvsEngine {
+    /// Open the sled database in the directory at `path`, creating it if
+    /// needed.
+    ///
+    /// Fails with `KvError::EngineMismatch` if the directory holds data of
+    /// another engine.
+    pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        engine::claim_dir(&path, "sled")?;
+        Ok(SledKvsEngine {
+            db: sled::open(path)?,
+        })
+    }
+    /// Write every key/value pair to `writer` in the format of
+    /// `KvStore::export`.
+    pub fn export(&self, writer: impl Write) -> Result<()> {
+        let pairs = self.db.iter().map(|pair| {
+            let (key, value) = pair?;
+            Ok((
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            ))
+        });
+        backup::export(pairs, writer)
+    }
+    /// Set every pair in an export read from `reader`, returning how many
+    /// were imported.
+    pub fn import(&self, reader: impl Read) -> Result<usize> {
+        let imported = backup::import(reader, |key, value| {
+            self.db.insert(key, value.into_bytes())?;
+            Ok(())
+        })?;
+        self.db.flush()?;
+        Ok(imported)
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.db.insert(key, value.into_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.db.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        self.db.remove(key)?.ok_or(KvError::KeyNotFound)?;
+        self.db.flush()?;
+        Ok(())
+    }
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+    fn clone_engine(&self) -> Box<dyn KvsEngine> {
+        Box::new(self.clone())
+    }
+}
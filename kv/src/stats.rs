@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+/// A point-in-time view of a store's internal state and activity.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KvStoreStats {
+    /// Number of live keys in the index.
+    pub live_keys: u64,
+    /// Stale bytes that the next compaction would reclaim.
+    pub uncompacted_bytes: u64,
+    /// Number of log files currently on disk.
+    pub num_log_files: u64,
+    /// Id of the active log file.
+    pub current_file_id: u64,
+    /// Total size of all log files.
+    pub disk_bytes: u64,
+    /// Bytes of live records in the logs.
+    pub live_bytes: u64,
+    /// Bytes written since the store was opened, including compaction
+    /// copies.
+    pub bytes_written: u64,
+    /// Compactions run since the store was opened.
+    pub compactions: u64,
+    /// `get` calls since the store was opened.
+    pub gets: u64,
+    /// `set` calls since the store was opened.
+    pub sets: u64,
+    /// `remove` calls since the store was opened.
+    pub removes: u64,
+    /// Log records replayed to rebuild the index at open.
+    pub recovered_records: u64,
+    /// Times the active file was synced to disk under the `fsync` policy
+    /// since the store was opened.
+    #[serde(default)]
+    pub syncs: u64,
+}
+
+/// The on-disk layout left behind by a compaction.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionResult {
+    /// Bytes of log files freed by the compaction.
+    pub reclaimed_bytes: u64,
+    /// `(file_id, size)` of every log file afterwards, in id order.
+    pub files: Vec<(u64, u64)>,
+}
+
+/// Live and stale record bytes in one log file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileFragmentation {
+    /// Id of the log file.
+    pub file_id: u64,
+    /// Bytes of records the index still refers to.
+    pub live_bytes: u64,
+    /// Bytes of overwritten values and removes.
+    pub stale_bytes: u64,
+}
+
+impl FileFragmentation {
+    /// Fraction of the file's record bytes that are stale.
+    pub fn stale_ratio(&self) -> f64 {
+        let total = self.live_bytes + self.stale_bytes;
+        if total == 0 {
+            0.0
+        } else {
+            self.stale_bytes as f64 / total as f64
+        }
+    }
+}
+
+/// The outcome of `KvStore::verify`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Index entries checked.
+    pub checked: u64,
+    /// Entries whose record did not hold up, in key order, then records
+    /// numbered out of order, in log order.
+    pub failures: Vec<VerifyFailure>,
+}
+
+/// An index entry that does not match the record it points at, or a
+/// record written out of order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyFailure {
+    /// The key in the index.
+    pub key: String,
+    /// Id of the log file the index points into.
+    pub file_id: u64,
+    /// Offset of the record in that file.
+    pub pos: u64,
+    /// What was wrong with the record.
+    pub reason: String,
+}
+
+/// A recommendation on whether compacting now is worthwhile.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactionAdvice {
+    /// Whether stale bytes outweigh live ones or exceed the compaction
+    /// threshold.
+    pub should_compact: bool,
+    /// Bytes a compaction would reclaim.
+    pub reclaimable_bytes: u64,
+    /// Files holding stale data, most fragmented first.
+    pub worst_files: Vec<FileFragmentation>,
+}
+
+/// What a compaction would reclaim, worked out from the store's counters
+/// without reading the logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// Stale bytes a compaction would drop.
+    pub reclaimable_bytes: u64,
+    /// Bytes of the records a compaction would copy.
+    pub live_bytes: u64,
+    /// Total size of the log files now.
+    pub current_total_bytes: u64,
+}
+
+impl KvStoreStats {
+    /// Bytes written per byte of live data. Zero when nothing is live.
+    pub fn write_amplification(&self) -> f64 {
+        if self.live_bytes == 0 {
+            0.0
+        } else {
+            self.bytes_written as f64 / self.live_bytes as f64
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::kv::{log_ids, open_log, LogFiles};
+use crate::{Command, RecordFormat, Result};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::thread;
+use std::time::Duration;
+
+// How long to wait before checking the logs again once caught up.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A blocking iterator over commands as they are appended to the logs.
+///
+/// It polls the newest log file for growth and moves on to the next file
+/// once one appears. A compaction starts new files, so the live records it
+/// rewrites are yielded again. The iterator never ends on its own.
+pub struct Tail {
+    files: LogFiles,
+    file_id: u64,
+    // `None` until the first log file exists.
+    file: Option<File>,
+    // Bytes read past the last complete command.
+    pending: Vec<u8>,
+    ready: VecDeque<Command>,
+    format: RecordFormat,
+}
+
+impl Tail {
+    pub(crate) fn new(
+        files: LogFiles,
+        file_id: u64,
+        pos: u64,
+        format: RecordFormat,
+    ) -> Result<Tail> {
+        let file = match file_id {
+            0 => None,
+            _ => {
+                let mut file = open_log(&files, file_id)?;
+                file.seek(SeekFrom::Start(pos))?;
+                Some(file)
+            }
+        };
+        Ok(Tail {
+            files,
+            file_id,
+            file,
+            pending: Vec::new(),
+            ready: VecDeque::new(),
+            format,
+        })
+    }
+
+    // Read whatever was appended since the last poll, returning whether
+    // anything new arrived.
+    fn poll(&mut self) -> Result<bool> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+        if file.read_to_end(&mut self.pending)? == 0 {
+            return Ok(false);
+        }
+        let mut consumed = 0;
+        while let Some(decoded) = self.format.read(&self.pending[consumed..]) {
+            match decoded {
+                Ok((record, len)) => {
+                    self.ready.extend(record.into_command()?);
+                    consumed += len as usize;
+                }
+                // The rest of the command has not been flushed yet.
+                Err(e) if e.truncated => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.pending.drain(..consumed);
+        Ok(true)
+    }
+
+    // Switch to the next log file if one was created, returning whether
+    // there was one.
+    fn advance(&mut self) -> Result<bool> {
+        let next = log_ids(&self.files)?
+            .into_iter()
+            .find(|&id| id > self.file_id);
+        let next = match next {
+            Some(next) => next,
+            None => return Ok(false),
+        };
+        // The writer is done with the current file once a newer one exists,
+        // but may have finished it since the last poll.
+        if self.poll()? {
+            return Ok(true);
+        }
+        self.file_id = next;
+        self.file = Some(open_log(&self.files, next)?);
+        self.pending.clear();
+        Ok(true)
+    }
+}
+
+impl Iterator for Tail {
+    type Item = Result<Command>;
+
+    fn next(&mut self) -> Option<Result<Command>> {
+        loop {
+            if let Some(cmd) = self.ready.pop_front() {
+                return Some(Ok(cmd));
+            }
+            match self.poll().and_then(|polled| Ok(polled || self.advance()?)) {
+                Ok(true) => {}
+                Ok(false) => thread::sleep(POLL_INTERVAL),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
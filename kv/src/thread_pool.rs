@@ -0,0 +1,95 @@
+use crate::Result;
+use log::error;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A pool of threads to run jobs on.
+pub trait ThreadPool {
+    /// Create a pool of `threads` threads, failing if one cannot be
+    /// spawned.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+    /// Run `job` on one of the pool's threads.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+/// A `ThreadPool` that spawns a new thread for every job, however many
+/// threads it was created with.
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A `ThreadPool` of a fixed set of threads taking jobs from one shared
+/// queue.
+///
+/// A thread whose job panics is replaced, so the pool keeps its size.
+/// Dropping the pool lets the threads finish the queued jobs and exit.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            spawn_worker(JobReceiver(receiver.clone()))?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // Workers only go away once the pool is dropped.
+        self.sender
+            .send(Box::new(job))
+            .expect("thread pool has no threads left");
+    }
+}
+
+// A worker's end of the queue. Dropping it while unwinding from a
+// panicking job starts a replacement worker.
+struct JobReceiver(Arc<Mutex<Receiver<Job>>>);
+
+impl Drop for JobReceiver {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            if let Err(e) = spawn_worker(JobReceiver(self.0.clone())) {
+                error!("Failed to replace a thread pool worker: {}", e);
+            }
+        }
+    }
+}
+
+fn spawn_worker(receiver: JobReceiver) -> Result<()> {
+    thread::Builder::new().spawn(move || loop {
+        // Only hold the lock while waiting, not while running the job.
+        let job = match receiver.0.lock() {
+            Ok(queue) => queue.recv(),
+            Err(_) => return,
+        };
+        match job {
+            Ok(job) => job(),
+            // The pool was dropped.
+            Err(_) => return,
+        }
+    })?;
+    Ok(())
+}
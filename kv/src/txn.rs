@@ -0,0 +1,74 @@
+use crate::kv::Record;
+use crate::{Command, Result};
+
+/// The writes of a transaction, buffered until
+/// [`KvStore::transaction`](crate::KvStore::transaction) commits them.
+#[derive(Debug, Default)]
+pub struct Txn {
+    pub(crate) commands: Vec<Command>,
+}
+
+impl Txn {
+    /// Set `key` to `value` once the transaction commits.
+    pub fn set(&mut self, key: String, value: String) {
+        self.commands.push(Command::Set { key, value });
+    }
+    /// Remove `key` once the transaction commits.
+    pub fn remove(&mut self, key: String) {
+        self.commands.push(Command::Remove { key });
+    }
+}
+
+// Holds back the records of a transaction read from a log file until its
+// commit marker turns up.
+#[derive(Default)]
+pub(crate) struct TxnFrame {
+    // Where the open transaction's begin marker starts.
+    begin: Option<u64>,
+    pending: Vec<(u64, u64, Record)>,
+    // Bytes of markers and of discarded transactions.
+    pub(crate) stale: u64,
+}
+
+impl TxnFrame {
+    // Pass the record at `pos` to `f` now, later once its transaction
+    // commits, or never if it is a marker.
+    pub(crate) fn feed(
+        &mut self,
+        pos: u64,
+        len: u64,
+        record: Record,
+        mut f: impl FnMut(u64, u64, Record) -> Result<()>,
+    ) -> Result<()> {
+        match record {
+            Record::TxnBegin => {
+                // Only a failed write leaves a transaction open behind
+                // another one.
+                self.discard();
+                self.begin = Some(pos);
+                self.stale += len;
+            }
+            Record::TxnCommit => {
+                self.begin = None;
+                self.stale += len;
+                for (pos, len, record) in self.pending.drain(..) {
+                    f(pos, len, record)?;
+                }
+            }
+            record if self.begin.is_some() => self.pending.push((pos, len, record)),
+            record => f(pos, len, record)?,
+        }
+        Ok(())
+    }
+    // Where a transaction left without its commit marker starts, dropping
+    // its records.
+    pub(crate) fn finish(&mut self) -> Option<u64> {
+        let begin = self.begin;
+        self.discard();
+        begin
+    }
+    fn discard(&mut self) {
+        self.stale += self.pending.drain(..).map(|(_, len, _)| len).sum::<u64>();
+        self.begin = None;
+    }
+}
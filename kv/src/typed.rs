@@ -0,0 +1,56 @@
+use crate::{KvStore, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+/// A `KvStore` holding values of type `V`, kept in the log as JSON.
+///
+/// A value that no longer deserializes as `V`, say one written under an
+/// older definition, reads back as a `KvError::Serde`.
+pub struct TypedKvStore<V> {
+    store: KvStore,
+    _values: PhantomData<fn() -> V>,
+}
+
+impl<V> Clone for TypedKvStore<V> {
+    fn clone(&self) -> Self {
+        TypedKvStore {
+            store: self.store.clone(),
+            _values: PhantomData,
+        }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> TypedKvStore<V> {
+    /// Open the store at `path` with default options.
+    pub fn open(path: impl Into<PathBuf>) -> Result<TypedKvStore<V>> {
+        Ok(TypedKvStore::new(KvStore::open(path)?))
+    }
+    /// Keep values of type `V` in `store`.
+    pub fn new(store: KvStore) -> TypedKvStore<V> {
+        TypedKvStore {
+            store,
+            _values: PhantomData,
+        }
+    }
+    /// Set `key` to `value`.
+    pub fn set(&self, key: String, value: &V) -> Result<()> {
+        self.store.set(key, serde_json::to_string(value)?)
+    }
+    /// Get the value of `key`.
+    pub fn get(&self, key: String) -> Result<Option<V>> {
+        match self.store.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+    /// Remove `key`.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(key)
+    }
+    /// The underlying store.
+    pub fn store(&self) -> &KvStore {
+        &self.store
+    }
+}
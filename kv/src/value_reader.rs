@@ -0,0 +1,243 @@
+use crate::{KvError, Result};
+use rmp::decode as msgpack;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Take};
+
+// Variant indices of `Record::Set` and `Record::SetEx`, as bincode numbers
+// them.
+const SET_TAG: u32 = 0;
+const SET_EX_TAG: u32 = 1;
+
+/// A reader over the value of one key, returned by
+/// [`KvStore::get_reader`](crate::KvStore::get_reader).
+///
+/// Uncompressed values in bincode and MessagePack logs are read from the
+/// log file as the reader is consumed, and checked against the record's
+/// checksum once it reaches the end, where a mismatch fails the read with
+/// `io::ErrorKind::InvalidData`. Any other value, including every value in
+/// a JSON log, is read and checked whole up front.
+pub struct ValueReader(Source);
+
+enum Source {
+    Memory(Cursor<Vec<u8>>),
+    Log(LogValue),
+}
+
+impl ValueReader {
+    pub(crate) fn from_value(value: String) -> ValueReader {
+        ValueReader(Source::Memory(Cursor::new(value.into_bytes())))
+    }
+
+    // Read the value of the bincode record for `key` at `pos` in `file`, or
+    // `None` if the record is not a plain set.
+    pub(crate) fn from_bincode_log(
+        file: File,
+        pos: u64,
+        key: &str,
+        buffer_capacity: usize,
+    ) -> Result<Option<ValueReader>> {
+        let mut reader = BufReader::with_capacity(buffer_capacity, file);
+        reader.seek(SeekFrom::Start(pos))?;
+        // The frame length, then the variant and the key.
+        let mut header = [0; 16];
+        reader.read_exact(&mut header)?;
+        let tag = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if tag != SET_TAG && tag != SET_EX_TAG {
+            return Ok(None);
+        }
+        let key_len = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        if key_len != key.len() as u64 {
+            return Err(KvError::CorruptRecord);
+        }
+        let mut record_key = vec![0; key.len()];
+        reader.read_exact(&mut record_key)?;
+        if record_key != key.as_bytes() {
+            return Err(KvError::CorruptRecord);
+        }
+        let value_len = read_u64(&mut reader)?;
+        let expires = tag == SET_EX_TAG;
+        Ok(Some(ValueReader(Source::Log(LogValue {
+            value: reader.take(value_len),
+            hasher: value_hasher(expires, key, value_len),
+            trailer: Trailer::Bincode { expires },
+            checked: false,
+        }))))
+    }
+
+    // Read the value of the MessagePack record for `key` at `pos` in
+    // `file`, or `None` if the record is not a plain set.
+    pub(crate) fn from_messagepack_log(
+        file: File,
+        pos: u64,
+        key: &str,
+        buffer_capacity: usize,
+    ) -> Result<Option<ValueReader>> {
+        let mut reader = BufReader::with_capacity(buffer_capacity, file);
+        reader.seek(SeekFrom::Start(pos))?;
+        // The frame length, then a map from the variant to a map of its
+        // fields, which start with the key and the value.
+        let mut frame_len = [0; 4];
+        reader.read_exact(&mut frame_len)?;
+        if msgpack::read_map_len(&mut reader).map_err(invalid)? != 1 {
+            return Ok(None);
+        }
+        let expires = match read_name(&mut reader)?.as_str() {
+            "Set" => false,
+            "SetEx" => true,
+            _ => return Ok(None),
+        };
+        let fields = msgpack::read_map_len(&mut reader).map_err(invalid)?;
+        if fields < 2 || read_name(&mut reader)? != "key" {
+            return Ok(None);
+        }
+        let key_len = msgpack::read_str_len(&mut reader).map_err(invalid)?;
+        if key_len as usize != key.len() {
+            return Err(KvError::CorruptRecord);
+        }
+        let mut record_key = vec![0; key.len()];
+        reader.read_exact(&mut record_key)?;
+        if record_key != key.as_bytes() {
+            return Err(KvError::CorruptRecord);
+        }
+        if read_name(&mut reader)? != "value" {
+            return Ok(None);
+        }
+        let value_len = msgpack::read_str_len(&mut reader).map_err(invalid)? as u64;
+        Ok(Some(ValueReader(Source::Log(LogValue {
+            value: reader.take(value_len),
+            hasher: value_hasher(expires, key, value_len),
+            trailer: Trailer::MessagePack { fields: fields - 2 },
+            checked: false,
+        }))))
+    }
+}
+
+// A hasher fed what `CommandRef::checksum` covers up to the value's bytes.
+fn value_hasher(expires: bool, key: &str, value_len: u64) -> crc32fast::Hasher {
+    let mut hasher = crc32fast::Hasher::new();
+    let name: &[u8] = match expires {
+        false => b"Set",
+        true => b"SetEx",
+    };
+    for part in [name, key.as_bytes()] {
+        hasher.update(&(part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.update(&value_len.to_le_bytes());
+    hasher
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            Source::Memory(value) => value.read(buf),
+            Source::Log(value) => value.read(buf),
+        }
+    }
+}
+
+// A value being read out of its log record.
+struct LogValue {
+    value: Take<BufReader<File>>,
+    hasher: crc32fast::Hasher,
+    trailer: Trailer,
+    checked: bool,
+}
+
+// What follows the value in its record.
+enum Trailer {
+    // The expiry if there is one, then the checksum and the sequence
+    // number.
+    Bincode { expires: bool },
+    // This many more named fields.
+    MessagePack { fields: u32 },
+}
+
+impl LogValue {
+    // Read the rest of the record and check it against its checksum.
+    fn check(&mut self) -> io::Result<()> {
+        self.checked = true;
+        let reader = self.value.get_mut();
+        let (expire_at, crc, seq) = match self.trailer {
+            Trailer::Bincode { expires } => {
+                let expire_at = match expires {
+                    true => Some(read_u64(reader)?),
+                    false => None,
+                };
+                let mut crc = [0; 4];
+                reader.read_exact(&mut crc)?;
+                (expire_at, u32::from_le_bytes(crc), read_u64(reader)?)
+            }
+            Trailer::MessagePack { fields } => {
+                let (mut expire_at, mut crc, mut seq) = (None, None, 0);
+                for _ in 0..fields {
+                    match read_name(reader)?.as_str() {
+                        "expire_at_unix_secs" => {
+                            expire_at = Some(msgpack::read_int(reader).map_err(invalid)?)
+                        }
+                        "crc" => crc = Some(msgpack::read_int(reader).map_err(invalid)?),
+                        "seq" => seq = msgpack::read_int(reader).map_err(invalid)?,
+                        _ => return Err(invalid("unexpected field")),
+                    }
+                }
+                (
+                    expire_at,
+                    crc.ok_or_else(|| invalid("missing checksum"))?,
+                    seq,
+                )
+            }
+        };
+        if let Some(expire_at) = expire_at {
+            let expire_at = expire_at.to_le_bytes();
+            self.hasher.update(&(expire_at.len() as u64).to_le_bytes());
+            self.hasher.update(&expire_at);
+        }
+        if seq != 0 {
+            self.hasher.update(&8u64.to_le_bytes());
+            self.hasher.update(&seq.to_le_bytes());
+        }
+        let hasher = std::mem::take(&mut self.hasher);
+        if hasher.finalize() != crc {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record does not match its checksum",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Read for LogValue {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.value.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        if n == 0 && !buf.is_empty() && !self.checked {
+            if self.value.limit() > 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.check()?;
+        }
+        Ok(n)
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// Read a MessagePack string short enough to be a variant or field name.
+fn read_name(reader: &mut impl Read) -> io::Result<String> {
+    let len = msgpack::read_str_len(reader).map_err(invalid)?;
+    if len > 32 {
+        return Err(invalid("name too long"));
+    }
+    let mut name = vec![0; len as usize];
+    reader.read_exact(&mut name)?;
+    String::from_utf8(name).map_err(invalid)
+}
+
+fn invalid(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
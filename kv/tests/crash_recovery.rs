@@ -0,0 +1,153 @@
+use kv::{KvStore, KvStoreOptions, RecordFormat, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+
+type State = BTreeMap<String, String>;
+
+// A write made against both the store and the model of what it holds.
+type Op = fn(&KvStore, &mut State) -> Result<()>;
+
+// The state after a write, keyed by the log length it ended at.
+type Checkpoint = (u64, State);
+
+// A history touching every kind of record: sets, overwrites, removes, a
+// set with a TTL, a large value and transactions, committed and not.
+const HISTORY: &[Op] = &[
+    |store, state| set(store, state, "key1", "value1"),
+    |store, state| set(store, state, "key2", "value2"),
+    |store, state| set(store, state, "key1", "overwritten"),
+    |store, state| {
+        store.remove("key2".to_owned())?;
+        state.remove("key2");
+        Ok(())
+    },
+    |store, state| {
+        store.set_with_ttl(
+            "ttl".to_owned(),
+            "lives".to_owned(),
+            Duration::from_secs(3600),
+        )?;
+        state.insert("ttl".to_owned(), "lives".to_owned());
+        Ok(())
+    },
+    |store, state| set(store, state, "large", &"x".repeat(300)),
+    |store, state| {
+        store.transaction(|txn| {
+            txn.set("txn1".to_owned(), "a".to_owned());
+            txn.set("txn2".to_owned(), "b".to_owned());
+            txn.remove("key1".to_owned());
+            Ok(())
+        })?;
+        state.insert("txn1".to_owned(), "a".to_owned());
+        state.insert("txn2".to_owned(), "b".to_owned());
+        state.remove("key1");
+        Ok(())
+    },
+    |store, state| set(store, state, "key2", "back"),
+    |store, state| {
+        store.remove("txn1".to_owned())?;
+        state.remove("txn1");
+        Ok(())
+    },
+];
+
+fn set(store: &KvStore, state: &mut State, key: &str, value: &str) -> Result<()> {
+    store.set(key.to_owned(), value.to_owned())?;
+    state.insert(key.to_owned(), value.to_owned());
+    Ok(())
+}
+
+fn contents(store: &KvStore) -> Result<State> {
+    store.scan()?.collect()
+}
+
+// Run `HISTORY` against a fresh store, returning the bytes of its log and
+// a checkpoint after each write.
+fn record_history(options: &KvStoreOptions) -> Result<(Vec<u8>, Vec<Checkpoint>)> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let mut state = State::new();
+    let mut checkpoints = vec![(0, State::new())];
+    for op in HISTORY {
+        op(&store, &mut state)?;
+        checkpoints.push((store.disk_size()?, state.clone()));
+    }
+    drop(store);
+    let log = std::fs::read(temp_dir.path().join("1.log"))?;
+    assert_eq!(log.len() as u64, checkpoints.last().unwrap().0);
+    Ok((log, checkpoints))
+}
+
+// Open a store whose only log is `log`, as a crash would have left it.
+fn open_crashed(dir: &Path, log: &[u8], options: &KvStoreOptions) -> Result<KvStore> {
+    std::fs::write(dir.join("1.log"), log)?;
+    KvStore::open_with_options(dir, options.clone())
+}
+
+// Cut the log short at every byte offset, as a crash part way through any
+// write would, and check that the store reopens holding exactly the writes
+// that were complete: no torn write is half applied, none is lost, and the
+// store takes new writes that survive another reopen.
+fn crash_at_every_offset(options: KvStoreOptions) -> Result<()> {
+    let (log, checkpoints) = record_history(&options)?;
+    for len in 0..=log.len() {
+        let (_, expected) = checkpoints
+            .iter()
+            .rev()
+            .find(|(end, _)| *end <= len as u64)
+            .unwrap();
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open_crashed(temp_dir.path(), &log[..len], &options)
+            .unwrap_or_else(|e| panic!("open failed with the log cut at {}: {}", len, e));
+        assert_eq!(&contents(&store)?, expected, "log cut at {}", len);
+        assert!(store.verify()?.failures.is_empty(), "log cut at {}", len);
+
+        store.set("after".to_owned(), "crash".to_owned())?;
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        let mut expected = expected.clone();
+        expected.insert("after".to_owned(), "crash".to_owned());
+        assert_eq!(contents(&store)?, expected, "log cut at {}, reopened", len);
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_recovery_json() -> Result<()> {
+    crash_at_every_offset(KvStoreOptions::default())
+}
+
+#[test]
+fn crash_recovery_bincode() -> Result<()> {
+    crash_at_every_offset(KvStoreOptions {
+        record_format: RecordFormat::Bincode,
+        ..KvStoreOptions::default()
+    })
+}
+
+#[test]
+fn crash_recovery_messagepack() -> Result<()> {
+    crash_at_every_offset(KvStoreOptions {
+        record_format: RecordFormat::MessagePack,
+        ..KvStoreOptions::default()
+    })
+}
+
+#[test]
+fn crash_recovery_compressed() -> Result<()> {
+    crash_at_every_offset(KvStoreOptions {
+        compress_values: true,
+        ..KvStoreOptions::default()
+    })
+}
+
+#[test]
+fn crash_recovery_while_compacting() -> Result<()> {
+    crash_at_every_offset(KvStoreOptions {
+        compact_on_open: true,
+        compact_while_recovering: true,
+        ..KvStoreOptions::default()
+    })
+}
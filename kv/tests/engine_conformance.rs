@@ -0,0 +1,174 @@
+use kv::{InMemoryKvsEngine, KvError, KvStore, KvsEngine, Result, SledKvsEngine};
+use std::path::Path;
+use tempfile::TempDir;
+
+// The contract every `KvsEngine` has to meet. Each engine runs the battery
+// through a thin wrapper test below, with `persistence` added for the ones
+// that keep their data on disk.
+fn battery(engine: &dyn KvsEngine) -> Result<()> {
+    set_then_get(engine)?;
+    overwrite(engine)?;
+    remove_then_get(engine)?;
+    remove_missing(engine)?;
+    large_value(engine)?;
+    shared_between_clones(engine)?;
+    engine.flush()
+}
+
+fn set_then_get(engine: &dyn KvsEngine) -> Result<()> {
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.set("".to_owned(), "".to_owned())?;
+    assert_eq!(engine.get("".to_owned())?, Some("".to_owned()));
+    engine.set("ключ".to_owned(), "值".to_owned())?;
+    assert_eq!(engine.get("ключ".to_owned())?, Some("值".to_owned()));
+    Ok(())
+}
+
+fn overwrite(engine: &dyn KvsEngine) -> Result<()> {
+    engine.set("key2".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn remove_then_get(engine: &dyn KvsEngine) -> Result<()> {
+    engine.set("key3".to_owned(), "value1".to_owned())?;
+    engine.remove("key3".to_owned())?;
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    // A removed key can be set again.
+    engine.set("key3".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key3".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn remove_missing(engine: &dyn KvsEngine) -> Result<()> {
+    assert!(matches!(
+        engine.remove("missing".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    engine.set("key4".to_owned(), "value1".to_owned())?;
+    engine.remove("key4".to_owned())?;
+    assert!(matches!(
+        engine.remove("key4".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+fn large_value(engine: &dyn KvsEngine) -> Result<()> {
+    let value: String = (0..1 << 20)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    engine.set("large".to_owned(), value.clone())?;
+    assert_eq!(engine.get("large".to_owned())?, Some(value));
+    Ok(())
+}
+
+fn shared_between_clones(engine: &dyn KvsEngine) -> Result<()> {
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let engine = engine.clone_engine();
+            std::thread::spawn(move || -> Result<()> {
+                for key_id in 0..25 {
+                    engine.set(
+                        format!("shared{}", thread_id * 25 + key_id),
+                        "value".to_owned(),
+                    )?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    for key_id in 0..100 {
+        assert_eq!(
+            engine.get(format!("shared{}", key_id))?,
+            Some("value".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Writes, overwrites and removes made before the engine is dropped should
+// all be there when `open` opens it again.
+fn persistence(open: impl Fn() -> Result<Box<dyn KvsEngine>>) -> Result<()> {
+    let engine = open()?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    engine.set("key3".to_owned(), "value4".to_owned())?;
+    engine.remove("key3".to_owned())?;
+    drop(engine);
+
+    let engine = open()?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key3".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+fn temp_dir() -> TempDir {
+    TempDir::new().expect("unable to create temporary working directory")
+}
+
+fn open_kvs(dir: &Path) -> Result<Box<dyn KvsEngine>> {
+    Ok(Box::new(KvStore::open(dir)?))
+}
+
+fn open_sled(dir: &Path) -> Result<Box<dyn KvsEngine>> {
+    Ok(Box::new(SledKvsEngine::open(dir)?))
+}
+
+fn open_sharded(dir: &Path) -> Result<Box<dyn KvsEngine>> {
+    Ok(Box::new(KvStore::open_sharded(dir, 4)?))
+}
+
+#[test]
+fn kvs_store() -> Result<()> {
+    let temp_dir = temp_dir();
+    battery(open_kvs(temp_dir.path())?.as_ref())
+}
+
+#[test]
+fn kvs_store_persistence() -> Result<()> {
+    let temp_dir = temp_dir();
+    persistence(|| open_kvs(temp_dir.path()))
+}
+
+#[test]
+fn sled_engine() -> Result<()> {
+    let temp_dir = temp_dir();
+    battery(open_sled(temp_dir.path())?.as_ref())
+}
+
+#[test]
+fn sled_engine_persistence() -> Result<()> {
+    let temp_dir = temp_dir();
+    persistence(|| open_sled(temp_dir.path()))
+}
+
+#[test]
+fn sharded_store() -> Result<()> {
+    let temp_dir = temp_dir();
+    let engine = KvStore::open_sharded(temp_dir.path(), 4)?;
+    battery(&engine)
+}
+
+#[test]
+fn sharded_store_persistence() -> Result<()> {
+    let temp_dir = temp_dir();
+    persistence(|| open_sharded(temp_dir.path()))
+}
+
+#[test]
+fn in_memory_engine() -> Result<()> {
+    battery(&InMemoryKvsEngine::new())
+}
@@ -1,15 +1,26 @@
 use assert_cmd::prelude::*;
-use kv::{KvStore, Result};
+use kv::{
+    AsyncKvsEngine, CompactionPolicy, FileOp, FsyncPolicy, GroupCommit, InMemoryKvsEngine, KvError,
+    KvStore, KvStoreOptions, KvStoreStats, KvsEngine, ManualClock, NaiveThreadPool, RecordFormat,
+    Result, ShardedKvStore, SharedQueueThreadPool, SledKvsEngine, SpawnBlocking, ThreadPool,
+    TypedKvStore,
+};
 use predicates::ord::eq;
+use predicates::prelude::PredicateBooleanExt;
 use predicates::str::{contains, is_empty, PredicateStrExt};
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
 // `kvs` with no args should exit with a non-zero code.
 #[test]
-fn cli_no_args() { 
+fn cli_no_args() {
     Command::cargo_bin("kvs").unwrap().assert().failure();
 }
 
@@ -18,7 +29,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -29,7 +40,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -42,20 +53,41 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
         .stdout(eq("Key not found").trim());
 }
 
+// `kvs rm --if-exists <KEY>` should print nothing and exit with zero for a
+// missing key, while plain `kvs rm` still exits with one.
+#[test]
+fn cli_rm_if_exists() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "--if-exists", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stdout(eq("Key not found").trim());
+}
+
 // `kvs set <KEY> <VALUE>` should print nothing and exit with zero.
 #[test]
 fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -66,14 +98,14 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -81,7 +113,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -90,18 +122,81 @@ fn cli_get_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs get <KEY> --format json` prints the key with its value, or with null
+// for a missing key.
+#[test]
+fn cli_get_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value \"1\"".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"key":"key1","value":"value \"1\""}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2", "--format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"key":"key2","value":null}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--format", "xml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// `kvs get <KEY>` on a log with a record cut short should fail with the
+// error instead of reporting the key as missing.
+#[test]
+fn cli_get_truncated_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Cut the tail off the first record, keeping the second one behind it.
+    let path = temp_dir.path().join("1.log");
+    let mut bytes = std::fs::read(&path)?;
+    bytes.drain(10..20);
+    std::fs::write(&path, bytes)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("Key not found").not())
+        .stderr(contains("Error"));
+
+    Ok(())
+}
+
 // `kvs rm <KEY>` should print nothing and exit with zero.
 #[test]
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -109,7 +204,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -118,17 +213,125 @@ fn cli_rm_stored() -> Result<()> {
     Ok(())
 }
 
+// `--log-dir` points `get`, `set` and `rm` at a directory other than the
+// current one, before or after the subcommand.
+#[test]
+fn cli_log_dir() -> Result<()> {
+    let data_dir = TempDir::new().expect("unable to create temporary working directory");
+    let work_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = data_dir.path().to_str().unwrap();
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&work_dir);
+        cmd
+    };
+
+    kvs(&["--log-dir", log_dir, "set", "key1", "value1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    kvs(&["set", "key2", "value2", "--log-dir", log_dir])
+        .assert()
+        .success();
+    kvs(&["get", "key1", "--log-dir", log_dir])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    kvs(&["--log-dir", log_dir, "rm", "key1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    kvs(&["--log-dir", log_dir, "get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    // Nothing landed in the current directory.
+    kvs(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    assert_eq!(
+        KvStore::open(data_dir.path())?.get("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+// `kvs list` prints every live pair, tab-separated, sorted by key.
+#[test]
+fn cli_list() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(args).current_dir(&temp_dir);
+        cmd
+    };
+    kvs(&["list"]).assert().success().stdout(is_empty());
+
+    kvs(&["set", "key2", "value2"]).assert().success();
+    kvs(&["set", "key3", "value3"]).assert().success();
+    kvs(&["set", "key1", "value1"]).assert().success();
+    kvs(&["set", "key4", "value4"]).assert().success();
+    kvs(&["rm", "key4"]).assert().success();
+    kvs(&["set", "key2", "value5"]).assert().success();
+    kvs(&["list"])
+        .assert()
+        .success()
+        .stdout(eq("key1\tvalue1\nkey2\tvalue5\nkey3\tvalue3\n"));
+    kvs(&["scan"])
+        .assert()
+        .success()
+        .stdout(eq("key1\tvalue1\nkey2\tvalue5\nkey3\tvalue3\n"));
+    Ok(())
+}
+
+// `kvs compact` should shrink a log full of overwrites and report the bytes
+// it freed.
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir_size = || {
+        let len: walkdir::Result<u64> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|res| res.and_then(|entry| entry.metadata()).map(|m| m.len()))
+            .sum();
+        len.expect("fail to get directory size")
+    };
+    {
+        let store = KvStore::open(temp_dir.path())?;
+        for i in 0..2000 {
+            store.set("key1".to_owned(), format!("value{}", i))?;
+        }
+    }
+    let size_before = dir_size();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Reclaimed").and(contains("bytes")));
+    assert!(dir_size() < size_before);
+    assert_eq!(
+        KvStore::open(temp_dir.path())?.get("key1".to_owned())?,
+        Some("value1999".to_owned())
+    );
+    Ok(())
+}
+
 #[test]
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -137,19 +340,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -158,13 +361,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -173,7 +376,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -182,7 +385,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -192,7 +395,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -203,7 +406,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -212,7 +415,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -224,14 +427,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -240,7 +443,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -248,7 +451,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -260,7 +463,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -290,7 +493,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -304,20 +507,17 @@ fn compaction() -> Result<()> {
 #[test]
 // basic impl using 14.96s
 fn bigdata_test() -> Result<()> {
-    #[warn(non_snake_case)]
-    let DATA_SIZE: u32 = 100000;
+    let data_size: u32 = 100000;
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    println!("{:?}",temp_dir);
-    
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     let mut rng = rand::thread_rng();
 
-    for i in 0..DATA_SIZE {
+    for i in 0..data_size {
         let key = format!("key_{}", i);
         let value = format!("value_{}", i);
         store.set(key, value)?;
 
-        let rand_num = rng.gen_range(0..DATA_SIZE);
+        let rand_num = rng.gen_range(0..data_size);
         let get_key = format!("key_{}", rand_num);
         if rand_num <= i {
             let get_value = format!("value_{}", rand_num);
@@ -328,3 +528,5195 @@ fn bigdata_test() -> Result<()> {
     }
     Ok(())
 }
+
+// `value_len` should agree with the length of the fetched value.
+#[test]
+fn value_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "a longer value".to_owned())?;
+    for key in &["key1", "key2", "key3"] {
+        assert_eq!(
+            store.value_len(key)?,
+            store.get(key.to_string())?.map(|v| v.len())
+        );
+    }
+    assert_eq!(store.value_len("key3")?, None);
+    Ok(())
+}
+
+// Stale data should be compacted once `compact_after` has elapsed.
+#[test]
+fn compact_after_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let options = KvStoreOptions {
+        compact_after: Some(Duration::from_secs(60)),
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let first_log = temp_dir.path().join("1.log");
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    clock.advance(Duration::from_secs(59));
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    assert!(first_log.exists());
+
+    clock.advance(Duration::from_secs(1));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(!first_log.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// `read_at` should decode the command at a known physical location.
+#[test]
+fn read_at_position() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let first = kv::Command::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    let second = kv::Command::Remove {
+        key: "key1".to_owned(),
+    };
+    let log_len = || std::fs::metadata(temp_dir.path().join("1.log")).map(|meta| meta.len());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first_len = log_len()?;
+    store.remove("key1".to_owned())?;
+    let second_len = log_len()? - first_len;
+
+    assert_eq!(store.read_at(1, 0, first_len)?, first);
+    assert_eq!(store.read_at(1, first_len, second_len)?, second);
+    assert!(matches!(
+        store.read_at(1, first_len, second_len + 1),
+        Err(KvError::InvalidPosition { .. })
+    ));
+    assert!(matches!(
+        store.read_at(42, 0, first_len),
+        Err(KvError::ReaderNotFound(42))
+    ));
+    Ok(())
+}
+
+// An empty value is stored and distinct from an absent key.
+#[test]
+fn empty_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.value_len("key1")?, Some(0));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    store.set("key1".to_owned(), "".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    Ok(())
+}
+
+// Digests depend only on logical contents, not on the physical layout.
+#[test]
+fn digest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let other = KvStore::open(other_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..100).rev() {
+        other.set(format!("key{}", key_id), "stale".to_owned())?;
+        other.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    other.set("extra".to_owned(), "value".to_owned())?;
+    other.remove("extra".to_owned())?;
+    other.compact()?;
+    assert_eq!(store.digest()?, other.digest()?);
+
+    other.set("key7".to_owned(), "changed".to_owned())?;
+    assert_ne!(store.digest()?, other.digest()?);
+    Ok(())
+}
+
+// Ring mode drops the oldest segments while recent keys stay readable.
+#[test]
+fn ring_capacity() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        ring_capacity: Some(4096),
+        ring_segment_size: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let log_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    assert!(log_count() <= 5);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+
+    // Migrated entries survive their segment being dropped.
+    store.set("pinned".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            ring_migrate_live: true,
+            ..options
+        },
+    )?;
+    for key_id in 0..1000 {
+        store.set(format!("other{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(store.get("pinned".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    Ok(())
+}
+
+// Removed keys are reported as tombstones until compaction drops them.
+#[test]
+fn tombstoned_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in &["key1", "key2", "key3"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.remove("key2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.tombstoned_keys()?, vec!["key1", "key2"]);
+
+    // A key set again after its removal is live, not tombstoned.
+    store.set("key1".to_owned(), "value".to_owned())?;
+    assert_eq!(store.tombstoned_keys()?, vec!["key2"]);
+
+    store.compact()?;
+    assert!(store.tombstoned_keys()?.is_empty());
+    Ok(())
+}
+
+// Compacting a single-file store leaves exactly one data file.
+#[test]
+fn compact_single_file_in_place() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let log_files = || -> Vec<_> {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().path().to_owned())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .collect()
+    };
+
+    for iter in 0..10 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert_eq!(log_files(), vec![temp_dir.path().join("2.log")]);
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("9".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Compacting a store with no live keys leaves a single empty log file,
+// however many files it started with.
+#[test]
+fn compact_empty_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        max_file_size: Some(4096),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    let log_files = || -> Vec<_> {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().path().to_owned())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .collect()
+    };
+
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{:050}", key_id))?;
+    }
+    for key_id in 0..200 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(log_files().len() > 1);
+    let result = store.compact()?;
+    assert_eq!(result.files.len(), 1);
+    assert_eq!(result.files[0].1, 0);
+    assert_eq!(log_files().len(), 1);
+
+    store.compact()?;
+    assert_eq!(log_files().len(), 1);
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+// The Prometheus rendering reports the store's statistics.
+#[test]
+fn render_prometheus_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in &["key1", "key2", "key3"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.get("key1".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.compact()?;
+
+    let text = kv::metrics::render_prometheus(&store);
+    let stats = store.stats();
+    for line in text.lines().filter(|line| !line.starts_with('#')) {
+        let mut parts = line.split(' ');
+        let name = parts.next().unwrap();
+        assert!(name.starts_with("kv_"));
+        parts.next().unwrap().parse::<u64>().unwrap();
+        assert_eq!(parts.next(), None);
+    }
+    assert!(text.contains("# TYPE kv_sets_total counter\n"));
+    assert!(text.contains("\nkv_live_keys 2\n"));
+    assert!(text.contains("\nkv_sets_total 3\n"));
+    assert!(text.contains("\nkv_gets_total 1\n"));
+    assert!(text.contains("\nkv_removes_total 1\n"));
+    assert!(text.contains("\nkv_compactions_total 1\n"));
+    assert!(text.contains(&format!("\nkv_disk_bytes {}\n", stats.disk_bytes)));
+    assert!(stats.disk_bytes > 0);
+    Ok(())
+}
+
+// A clock that moves forward by a second every time it is read, standing in
+// for a slow filesystem during recovery.
+#[derive(Debug, Default)]
+struct SteppingClock(std::sync::atomic::AtomicU64);
+
+impl kv::Clock for SteppingClock {
+    fn now(&self) -> std::time::SystemTime {
+        let secs = self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+        std::time::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+}
+
+// `open` gives up once recovery runs past `recovery_deadline`.
+#[test]
+fn recovery_deadline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    let options = |secs| KvStoreOptions {
+        recovery_deadline: Some(Duration::from_secs(secs)),
+        clock: Arc::new(SteppingClock::default()),
+        ..KvStoreOptions::default()
+    };
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), options(10)),
+        Err(KvError::RecoveryTimeout)
+    ));
+    let store = KvStore::open_with_options(temp_dir.path(), options(1000))?;
+    assert_eq!(store.get("key9999".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A filtered handle only sees keys matching its predicate.
+#[test]
+fn open_filtered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("order:1".to_owned(), "book".to_owned())?;
+    store.remove("user:2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_filtered(temp_dir.path(), |key| key.starts_with("user:"))?;
+    assert_eq!(store.get("user:1".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(store.get("user:2".to_owned())?, None);
+    assert_eq!(store.get("order:1".to_owned())?, None);
+    assert!(matches!(store.compact(), Err(KvError::FilteredStore)));
+    drop(store);
+
+    // The filtered-out data is still there for an unfiltered handle.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("order:1".to_owned())?, Some("book".to_owned()));
+    Ok(())
+}
+
+// `swap` exchanges two values and rejects missing keys.
+#[test]
+fn swap_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.swap("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    assert!(matches!(
+        store.swap("key1".to_owned(), "key3".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// `swap` moves each expiry with its value and treats an expired key as
+// missing.
+#[test]
+fn swap_with_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let options = || KvStoreOptions {
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set_with_ttl("a".to_owned(), "av".to_owned(), Duration::from_secs(10))?;
+    store.set("b".to_owned(), "bv".to_owned())?;
+    store.swap("a".to_owned(), "b".to_owned())?;
+    store.swap("a".to_owned(), "a".to_owned())?;
+    assert_eq!(store.get("a".to_owned())?, Some("bv".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("av".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(store.get("a".to_owned())?, Some("bv".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert!(matches!(
+        store.swap("a".to_owned(), "b".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    assert_eq!(store.get("a".to_owned())?, Some("bv".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    Ok(())
+}
+
+// `rename_key` moves a value, overwriting the target, and rejects a missing
+// source without touching the target.
+#[test]
+fn rename_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let options = || KvStoreOptions {
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.rename_key("key1".to_owned(), "key3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+
+    store.rename_key("key3".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    assert!(matches!(
+        store.rename_key("key1".to_owned(), "key2".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    store.rename_key("key2".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    store.set_with_ttl(
+        "ttl".to_owned(),
+        "lives".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    store.rename_key("ttl".to_owned(), "moved".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("ttl".to_owned())?, None);
+    assert_eq!(store.get("moved".to_owned())?, Some("lives".to_owned()));
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(store.get("moved".to_owned())?, None);
+    Ok(())
+}
+
+// Two file names parsing to the same id are reported instead of one being
+// silently ignored.
+#[test]
+fn duplicate_file_id() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    std::fs::copy(temp_dir.path().join("1.log"), temp_dir.path().join("7.log"))?;
+    std::fs::copy(
+        temp_dir.path().join("1.log"),
+        temp_dir.path().join("07.log"),
+    )?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::DuplicateFileId { id: 7 })
+    ));
+    Ok(())
+}
+
+// Files that merely look like logs are never replayed, compacted away or
+// reused for writing.
+#[test]
+fn junk_file_names() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let junk = [
+        "backup.log",
+        "10.log.bak",
+        "+2.log",
+        "3a.log",
+        ".log",
+        "log",
+        // Only the literal suffix is stripped, never its characters.
+        "log.log",
+        "1g.log",
+        "2.log.log",
+        "1.lo",
+    ];
+    for name in &junk {
+        std::fs::write(temp_dir.path().join(name), "not a log")?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.len(), 1);
+    for name in &junk {
+        assert_eq!(std::fs::read(temp_dir.path().join(name))?, b"not a log");
+    }
+    Ok(())
+}
+
+// With gaps in the log ids, writes go to a file past the newest one and
+// leave the existing files alone.
+#[test]
+fn log_id_gaps() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = |id: u64| temp_dir.path().join(format!("{}.log", id));
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    std::fs::rename(log(1), log(4))?;
+    std::fs::rename(log(2), log(9))?;
+    let (old, new) = (std::fs::read(log(4))?, std::fs::read(log(9))?);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    drop(store);
+    assert_eq!(std::fs::read(log(4))?, old);
+    assert_eq!(std::fs::read(log(9))?, new);
+    assert!(std::fs::metadata(log(10))?.len() > 0);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Replay yields every command in write order, with or without prefetching.
+#[test]
+fn replay_across_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        ring_capacity: Some(u64::MAX),
+        ring_segment_size: 256,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let mut expected = Vec::new();
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id);
+        store.set(key.clone(), "value".to_owned())?;
+        expected.push(kv::Command::Set {
+            key: key.clone(),
+            value: "value".to_owned(),
+        });
+        if key_id % 3 == 0 {
+            store.remove(key.clone())?;
+            expected.push(kv::Command::Remove { key });
+        }
+    }
+    assert!(store.stats().num_log_files > 2);
+    drop(store);
+
+    for scan_prefetch in [false, true] {
+        let store = KvStore::open_with_options(
+            temp_dir.path(),
+            KvStoreOptions {
+                scan_prefetch,
+                ..options.clone()
+            },
+        )?;
+        let replayed = store.replay()?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(replayed, expected);
+    }
+    Ok(())
+}
+
+// Deleting a log file behind the store's back is caught by `check_files`.
+#[test]
+fn check_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.check_files()?;
+    std::fs::remove_file(temp_dir.path().join("1.log"))?;
+    assert!(matches!(
+        store.check_files(),
+        Err(KvError::MissingFile { id: 1 })
+    ));
+    Ok(())
+}
+
+// A log dominated by hot-key overwrites is compacted at open when enabled.
+#[test]
+fn compact_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("cold".to_owned(), "value".to_owned())?;
+    for iter in 0..1000 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().compactions, 0);
+    drop(store);
+
+    let options = KvStoreOptions {
+        compact_on_open: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let stats = store.stats();
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert_eq!(store.get("hot".to_owned())?, Some("999".to_owned()));
+    assert_eq!(store.get("cold".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// The reverse index tracks which keys hold a value across writes and reopen.
+#[test]
+fn keys_with_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        build_value_index: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "red".to_owned())?;
+    store.set("key2".to_owned(), "red".to_owned())?;
+    store.set("key3".to_owned(), "red".to_owned())?;
+    store.set("key4".to_owned(), "blue".to_owned())?;
+    store.set("key3".to_owned(), "blue".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.keys_with_value("red"), vec!["key2"]);
+    assert_eq!(store.keys_with_value("blue"), vec!["key3", "key4"]);
+    assert!(store.keys_with_value("green").is_empty());
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.keys_with_value("blue"), vec!["key3", "key4"]);
+    store.compact()?;
+    assert_eq!(store.keys_with_value("red"), vec!["key2"]);
+    Ok(())
+}
+
+// Write amplification counts both logical writes and compaction copies.
+#[test]
+fn write_amplification() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let log_len = || {
+        std::fs::metadata(temp_dir.path().join("1.log"))
+            .unwrap()
+            .len()
+    };
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let stale = log_len();
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    let logical = log_len();
+    let live = logical - stale;
+    let stats = store.stats();
+    assert_eq!(stats.bytes_written, logical);
+    assert_eq!(stats.live_bytes, live);
+
+    // Compaction copies every live byte once more.
+    store.compact()?;
+    let stats = store.stats();
+    assert_eq!(stats.bytes_written, logical + live);
+    assert_eq!(stats.live_bytes, live);
+    assert_eq!(
+        stats.write_amplification(),
+        (logical + live) as f64 / live as f64
+    );
+    Ok(())
+}
+
+// A snapshot handle reads a read-only directory without touching it.
+#[cfg(unix)]
+#[test]
+fn open_snapshot_read_only_dir() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let listing = || -> Vec<(std::path::PathBuf, Vec<u8>)> {
+        let mut files: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let contents = std::fs::read(&path).unwrap();
+                (path, contents)
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let before = listing();
+    let set_mode =
+        |mode| std::fs::set_permissions(temp_dir.path(), std::fs::Permissions::from_mode(mode));
+    set_mode(0o555)?;
+
+    let result = (|| -> Result<()> {
+        let store = KvStore::open_snapshot(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert!(matches!(
+            store.set("key2".to_owned(), "value".to_owned()),
+            Err(KvError::ReadOnly)
+        ));
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(KvError::ReadOnly)
+        ));
+        assert!(matches!(store.compact(), Err(KvError::ReadOnly)));
+        Ok(())
+    })();
+    set_mode(0o755)?;
+    result?;
+    assert_eq!(listing(), before);
+    Ok(())
+}
+
+// Compaction streams a large value through a small buffer, reporting
+// progress chunk by chunk.
+#[test]
+fn compact_large_value_in_chunks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = reports.clone();
+    let options = KvStoreOptions {
+        compaction_chunk_size: 4096,
+        compaction_progress: Some(kv::ProgressCallback(Arc::new(move |copied, total| {
+            sink.lock().unwrap().push((copied, total));
+        }))),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let large = "x".repeat(1024 * 1024);
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("small".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+
+    let reports = reports.lock().unwrap();
+    let total = reports[0].1;
+    assert!(total > large.len() as u64);
+    assert!(reports.iter().all(|&(_, t)| t == total));
+    assert!(reports
+        .windows(2)
+        .all(|w| w[1].0 > w[0].0 && w[1].0 - w[0].0 <= 4096));
+    assert_eq!(reports.last().unwrap().0, total);
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    assert_eq!(store.get("small".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// `strict_removes` decides whether removing an absent key is an error.
+#[test]
+fn strict_removes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    drop(store);
+
+    let options = KvStoreOptions {
+        strict_removes: false,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let written = store.stats().bytes_written;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.stats().bytes_written, written);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Idle compaction waits for a quiet window and is postponed by activity.
+#[test]
+fn compact_when_idle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let options = KvStoreOptions {
+        compact_when_idle: Some(Duration::from_secs(10)),
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(!store.tick()?);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    clock.advance(Duration::from_secs(9));
+    assert!(!store.tick()?);
+
+    // Activity restarts the idle window.
+    store.get("key1".to_owned())?;
+    clock.advance(Duration::from_secs(9));
+    assert!(!store.tick()?);
+    assert!(store.stats().uncompacted_bytes > 0);
+
+    clock.advance(Duration::from_secs(1));
+    assert!(store.tick()?);
+    assert_eq!(store.stats().uncompacted_bytes, 0);
+    assert!(!store.tick()?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Streaming raw changes to a replica brings it to the same contents.
+#[test]
+fn stream_changes_to_replica() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let replica = KvStore::open(replica_dir.path())?;
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let mut changes = Vec::new();
+    let checkpoint = store.stream_changes(0, &mut changes)?;
+    assert_eq!(replica.apply_changes(&changes[..])?, 10);
+    assert_eq!(store.digest()?, replica.digest()?);
+
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key10".to_owned(), "value".to_owned())?;
+    let mut changes = Vec::new();
+    store.stream_changes(checkpoint, &mut changes)?;
+    replica.apply_changes(&changes[..])?;
+    assert_eq!(store.digest()?, replica.digest()?);
+    assert_eq!(replica.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A configured key validator rejects keys before they reach the log.
+#[test]
+fn key_validator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        key_validator: Some(|key| !key.contains('\n')),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set(
+        "key1".to_owned(),
+        "line\nbreaks are fine in values".to_owned(),
+    )?;
+
+    let written = store.stats().bytes_written;
+    match store.set("bad\nkey".to_owned(), "value".to_owned()) {
+        Err(KvError::InvalidKey { key }) => assert_eq!(key, "bad\nkey"),
+        other => panic!("expected InvalidKey, got {:?}", other),
+    }
+    assert_eq!(store.stats().bytes_written, written);
+    assert_eq!(store.get("bad\nkey".to_owned())?, None);
+    Ok(())
+}
+
+// Coalescing adjacent files keeps every record readable, before and after
+// reopening.
+#[test]
+fn coalesce_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        ring_capacity: Some(u64::MAX),
+        ring_segment_size: 256,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..40 {
+        store.set(format!("key{}", key_id % 25), format!("value{}", key_id))?;
+    }
+    let files_before = store.stats().num_log_files;
+    assert!(files_before > 4);
+
+    assert!(matches!(
+        store.coalesce_files(&[1, 3]),
+        Err(KvError::InvalidCoalesce)
+    ));
+    assert_eq!(store.coalesce_files(&[1, 2, 3])?, 3);
+    assert_eq!(store.stats().num_log_files, files_before - 2);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..40 {
+            if key_id >= 15 {
+                assert_eq!(
+                    store.get(format!("key{}", key_id % 25))?,
+                    Some(format!("value{}", key_id))
+                );
+            }
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&mut store)
+}
+
+// Removes should reach the log file immediately while sets stay buffered
+// until an explicit flush when `sync_sets` is off.
+#[test]
+fn per_operation_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync_sets: false,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let log_len = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(log_len(), 0);
+
+    store.remove("key2".to_owned())?;
+    let after_remove = log_len();
+    assert!(after_remove > 0);
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(log_len(), after_remove);
+    store.flush()?;
+    assert!(log_len() > after_remove);
+
+    // Buffered sets are still readable before they are flushed.
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Dropping the last handle flushes buffered sets; dropping an earlier clone
+// leaves them buffered.
+#[test]
+fn drop_flushes_buffered_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync_sets: false,
+        ..KvStoreOptions::default()
+    };
+    let log_len = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let clone = store.clone();
+    drop(store);
+    assert_eq!(log_len(), 0);
+    drop(clone);
+    assert!(log_len() > 0);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// `options` should report what the store was opened with, defaults included.
+#[test]
+fn options_reflect_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compact_after: Some(Duration::from_secs(60)),
+        sync_sets: false,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let options = store.options();
+    assert_eq!(options.compact_after, Some(Duration::from_secs(60)));
+    assert!(!options.sync_sets);
+    let defaults = KvStoreOptions::default();
+    assert_eq!(options.sync_removes, defaults.sync_removes);
+    assert_eq!(options.strict_removes, defaults.strict_removes);
+    assert_eq!(options.ring_capacity, defaults.ring_capacity);
+    assert_eq!(
+        options.compaction_chunk_size,
+        defaults.compaction_chunk_size
+    );
+    Ok(())
+}
+
+// In audit mode removed keys disappear from reads but their history stays
+// replayable, even past the automatic compaction threshold.
+#[test]
+fn audit_mode_keeps_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        audit_mode: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let value = "v".repeat(1024);
+    let mut expected = Vec::new();
+    for iter in 0..1200 {
+        let key = format!("key{}", iter % 10);
+        store.set(key.clone(), value.clone())?;
+        expected.push(kv::Command::Set {
+            key: key.clone(),
+            value: value.clone(),
+        });
+        if iter % 4 == 0 {
+            store.remove(key.clone())?;
+            expected.push(kv::Command::Remove { key });
+        }
+    }
+    assert_eq!(store.stats().compactions, 0);
+    assert_eq!(store.get("key6".to_owned())?, None);
+
+    let replayed = store.replay()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(replayed, expected);
+    match store.compact() {
+        Err(KvError::AuditMode) => (),
+        other => panic!("expected AuditMode, got {:?}", other),
+    }
+    Ok(())
+}
+
+// Compare numeric keys by value for ordered scans.
+fn numeric_order(a: &str, b: &str) -> std::cmp::Ordering {
+    a.parse::<u64>().unwrap().cmp(&b.parse::<u64>().unwrap())
+}
+
+// A custom `key_order` decides both which keys fall in a range and the
+// order they come back in.
+#[test]
+fn scan_range_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        key_order: Some(numeric_order),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key in ["1", "2", "10", "20", "100", "200"] {
+        store.set(key.to_owned(), format!("value{}", key))?;
+    }
+
+    let keys: Vec<String> = store
+        .scan_range("2", "100")?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, ["2", "10", "20"]);
+    assert_eq!(
+        store.scan_range("100", "101")?,
+        vec![("100".to_owned(), "value100".to_owned())]
+    );
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let keys: Vec<String> = store
+        .scan_range("1", "2")?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, ["1", "10", "100"]);
+    Ok(())
+}
+
+// `compare_and_swap` sets an absent key only when expecting `None`, and a
+// present one only when expecting its current value.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = || "key1".to_owned();
+
+    assert!(!store.compare_and_swap(key(), Some("value0".to_owned()), "value1".to_owned())?);
+    assert_eq!(store.get(key())?, None);
+    assert!(store.compare_and_swap(key(), None, "value1".to_owned())?);
+    assert_eq!(store.get(key())?, Some("value1".to_owned()));
+
+    assert!(!store.compare_and_swap(key(), None, "value2".to_owned())?);
+    assert!(!store.compare_and_swap(key(), Some("value0".to_owned()), "value2".to_owned())?);
+    assert_eq!(store.get(key())?, Some("value1".to_owned()));
+    assert!(store.compare_and_swap(key(), Some("value1".to_owned()), "value2".to_owned())?);
+    assert_eq!(store.get(key())?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(key())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// `set_nx` writes an absent key, removed or expired ones included, and
+// leaves a present one and the log untouched.
+#[test]
+fn set_nx() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let options = || KvStoreOptions {
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    let key = || "key1".to_owned();
+
+    assert!(store.set_nx(key(), "value1".to_owned())?);
+    assert_eq!(store.get(key())?, Some("value1".to_owned()));
+    let size = store.disk_size()?;
+    assert!(!store.set_nx(key(), "value2".to_owned())?);
+    assert_eq!(store.get(key())?, Some("value1".to_owned()));
+    assert_eq!(store.disk_size()?, size);
+    assert_eq!(store.stats().uncompacted_bytes, 0);
+
+    store.remove(key())?;
+    assert!(store.set_nx(key(), "value3".to_owned())?);
+    store.set_with_ttl("key2".to_owned(), "old".to_owned(), Duration::from_secs(10))?;
+    assert!(!store.set_nx("key2".to_owned(), "new".to_owned())?);
+    clock.advance(Duration::from_secs(10));
+    assert!(store.set_nx("key2".to_owned(), "new".to_owned())?);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get(key())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// `update` applies read-modify-write steps in order, and removes the key
+// once the closure returns `None`.
+#[test]
+fn update_counter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let increment = |value: Option<String>| {
+        let count: u64 = value.map_or(0, |value| value.parse().unwrap());
+        Some((count + 1).to_string())
+    };
+    for _ in 0..5 {
+        store.update("counter".to_owned(), increment)?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("5".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.update("counter".to_owned(), increment)?;
+    assert_eq!(store.get("counter".to_owned())?, Some("6".to_owned()));
+    store.update("counter".to_owned(), |_| None)?;
+    assert_eq!(store.get("counter".to_owned())?, None);
+    store.update("absent".to_owned(), |value| value)?;
+    assert_eq!(store.len(), 0);
+    Ok(())
+}
+
+// Writes refused before reaching the log and removes of absent keys leave
+// the operation counters alone.
+#[test]
+fn op_counters_skip_refused_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_value_size: Some(8),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        store.set("key2".to_owned(), "much too large".to_owned()),
+        Err(KvError::ValueTooLarge { .. })
+    ));
+    assert!(matches!(
+        store.remove("absent".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    store.remove("key1".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.sets, 1);
+    assert_eq!(stats.removes, 1);
+    Ok(())
+}
+
+// `set_and_get_old` and `take` hand back the value they replace or remove.
+#[test]
+fn previous_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = || "key1".to_owned();
+
+    assert_eq!(store.set_and_get_old(key(), "value1".to_owned())?, None);
+    assert_eq!(
+        store.set_and_get_old(key(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.set_and_get_old(key(), "value3".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get(key())?, Some("value3".to_owned()));
+
+    assert_eq!(store.take(key())?, Some("value3".to_owned()));
+    assert_eq!(store.get(key())?, None);
+    assert_eq!(store.take(key())?, None);
+    assert_eq!(store.take("absent".to_owned())?, None);
+    assert_eq!(store.stats().removes, 1);
+
+    store.set_and_get_old(key(), "value4".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.take(key())?, Some("value4".to_owned()));
+    drop(store);
+    assert_eq!(KvStore::open(temp_dir.path())?.get(key())?, None);
+    Ok(())
+}
+
+// `verify` reports exactly the entry whose record was damaged after the
+// index was built.
+#[test]
+fn verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 1..=3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let report = store.verify()?;
+    assert_eq!(report.checked, 3);
+    assert!(report.failures.is_empty());
+
+    // Drop the closing brace of key2's record, so it runs past the length
+    // the index has for it.
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = std::fs::read(&log)?;
+    let start = bytes.windows(6).position(|w| w == b"\"key2\"").unwrap();
+    let end = start + bytes[start..].windows(2).position(|w| w == b"}}").unwrap();
+    bytes[end + 1] = b' ';
+    std::fs::write(&log, bytes)?;
+
+    let report = store.verify()?;
+    assert_eq!(report.checked, 3);
+    let failed: Vec<_> = report.failures.iter().map(|f| f.key.as_str()).collect();
+    assert_eq!(failed, ["key2"]);
+    assert_eq!(report.failures[0].file_id, 1);
+    Ok(())
+}
+
+// Every write gets a higher sequence number than the one before it, kept
+// through compaction and counted on from after a reopen.
+#[test]
+fn sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let first = store.sequence("key1").unwrap();
+    let second = store.sequence("key2").unwrap();
+    assert!(second > first);
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.sequence("key2"), None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    // The remove took a number of its own.
+    assert!(store.sequence("key2").unwrap() > second + 1);
+    let last = store.sequence("key2").unwrap();
+
+    store.compact()?;
+    assert_eq!(store.sequence("key1"), Some(first));
+    assert_eq!(store.sequence("key2"), Some(last));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.sequence("key2"), Some(last));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(store.sequence("key3").unwrap() > last);
+    assert!(store.verify()?.failures.is_empty());
+    Ok(())
+}
+
+// `verify` reports a record numbered below an earlier write of its key, as
+// happens when a log file from another store is dropped in.
+#[test]
+fn verify_out_of_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 1..=3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    let other = KvStore::open(other_dir.path())?;
+    other.set("key3".to_owned(), "other".to_owned())?;
+    drop(other);
+    std::fs::copy(
+        other_dir.path().join("1.log"),
+        temp_dir.path().join("5.log"),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("other".to_owned()));
+    let report = store.verify()?;
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].key, "key3");
+    assert_eq!(report.failures[0].file_id, 5);
+    assert_eq!(report.failures[0].pos, 0);
+
+    // Writes go on from the highest number found, so new ones are in order.
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.verify()?.failures.len(), 1);
+    Ok(())
+}
+
+// `get_or_insert_with` only computes a value for a key that is absent.
+#[test]
+fn get_or_insert_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let calls = std::cell::Cell::new(0);
+    let compute = || {
+        calls.set(calls.get() + 1);
+        format!("value{}", calls.get())
+    };
+
+    assert_eq!(
+        store.get_or_insert_with("key1".to_owned(), compute)?,
+        "value1"
+    );
+    assert_eq!(calls.get(), 1);
+    assert_eq!(
+        store.get_or_insert_with("key1".to_owned(), compute)?,
+        "value1"
+    );
+    assert_eq!(calls.get(), 1);
+    store.set("key2".to_owned(), "existing".to_owned())?;
+    assert_eq!(
+        store.get_or_insert_with("key2".to_owned(), compute)?,
+        "existing"
+    );
+    assert_eq!(calls.get(), 1);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(
+        store.get_or_insert_with("key1".to_owned(), compute)?,
+        "value2"
+    );
+    assert_eq!(calls.get(), 2);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Appending to an absent key creates it.
+#[test]
+fn append_to_absent_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.append("key1".to_owned(), "abc")?;
+    assert_eq!(store.get("key1".to_owned())?, Some("abc".to_owned()));
+    Ok(())
+}
+
+// Appending to a key whose TTL has run out starts from an empty value.
+#[test]
+fn append_to_expired_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let options = KvStoreOptions {
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set_with_ttl("key1".to_owned(), "old".to_owned(), Duration::from_secs(10))?;
+    clock.advance(Duration::from_secs(10));
+    store.append("key1".to_owned(), "X")?;
+    assert_eq!(store.get("key1".to_owned())?, Some("X".to_owned()));
+    Ok(())
+}
+
+// Appending to an existing key concatenates, and repeated appends survive
+// reopening.
+#[test]
+fn append_to_existing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "log:".to_owned())?;
+    store.append("key1".to_owned(), "a")?;
+    assert_eq!(store.get("key1".to_owned())?, Some("log:a".to_owned()));
+
+    let mut expected = "log:a".to_owned();
+    for i in 0..20 {
+        let line = format!("{},", i);
+        store.append("key1".to_owned(), &line)?;
+        expected.push_str(&line);
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(expected));
+    Ok(())
+}
+
+// A deferred handle reads a read-only directory and only fails once it
+// first needs to write.
+#[cfg(unix)]
+#[test]
+fn defer_active_file_read_only_dir() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let file_count = || std::fs::read_dir(temp_dir.path()).unwrap().count();
+    let files_before = file_count();
+
+    let set_mode =
+        |mode| std::fs::set_permissions(temp_dir.path(), std::fs::Permissions::from_mode(mode));
+    set_mode(0o555)?;
+    // Permission bits do not stop a privileged user.
+    let enforced = std::fs::File::create(temp_dir.path().join("probe")).is_err();
+    let _ = std::fs::remove_file(temp_dir.path().join("probe"));
+
+    let options = KvStoreOptions {
+        defer_active_file: true,
+        ..KvStoreOptions::default()
+    };
+    let result = (|| -> Result<()> {
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(file_count(), files_before);
+        let set = store.set("key2".to_owned(), "value2".to_owned());
+        if enforced {
+            match set {
+                Err(KvError::File(err)) => {
+                    assert_eq!(err.source.kind(), std::io::ErrorKind::PermissionDenied)
+                }
+                other => panic!("expected a permission error, got {:?}", other),
+            }
+        } else {
+            set?;
+            assert_eq!(file_count(), files_before + 1);
+        }
+        Ok(())
+    })();
+    set_mode(0o755)?;
+    result?;
+
+    // Once writable the same handle type creates its active file on demand.
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            defer_active_file: true,
+            ..KvStoreOptions::default()
+        },
+    )?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A deferred handle claims its directory on the first write instead of at
+// open, and so still refuses another engine's directory.
+#[test]
+fn defer_active_file_claims_dir() -> Result<()> {
+    let options = || KvStoreOptions {
+        defer_active_file: true,
+        ..KvStoreOptions::default()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(kv::recorded_engine(temp_dir.path())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        kv::recorded_engine(temp_dir.path())?,
+        Some("kvs".to_owned())
+    );
+    drop(store);
+    assert!(matches!(
+        SledKvsEngine::open(temp_dir.path()),
+        Err(KvError::EngineMismatch { .. })
+    ));
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(SledKvsEngine::open(sled_dir.path())?);
+    let store = KvStore::open_with_options(sled_dir.path(), options())?;
+    assert!(matches!(
+        store.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvError::EngineMismatch { .. })
+    ));
+    assert!(!sled_dir.path().join("1.log").exists());
+    Ok(())
+}
+
+// A deferred handle notes its record format on the first write, so a later
+// open with the default format still reads its records.
+#[test]
+fn defer_active_file_records_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        defer_active_file: true,
+        record_format: RecordFormat::Bincode,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// The layout returned by `compact` matches the log files left on disk.
+#[test]
+fn compaction_result_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        ring_capacity: Some(u64::MAX),
+        ring_segment_size: 256,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..200 {
+        store.set(format!("key{}", iter % 10), format!("value{}", iter))?;
+    }
+    let disk_before = store.stats().disk_bytes;
+
+    let result = store.compact()?;
+    let mut on_disk: Vec<(u64, u64)> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().unwrap();
+            let id = name.strip_suffix(".log")?.parse().unwrap();
+            Some((id, entry.metadata().unwrap().len()))
+        })
+        .collect();
+    on_disk.sort_unstable();
+    assert_eq!(result.files, on_disk);
+    assert_eq!(result.files.len(), 2);
+    let disk_after: u64 = on_disk.iter().map(|&(_, size)| size).sum();
+    assert_eq!(result.reclaimed_bytes, disk_before - disk_after);
+    assert!(result.reclaimed_bytes > 0);
+    Ok(())
+}
+
+// Lowering the threshold at runtime makes the next write compact.
+#[test]
+fn set_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    assert_eq!(store.stats().compactions, 0);
+    assert!(store.stats().uncompacted_bytes > 1024);
+
+    store.set_compaction_threshold(1024);
+    assert_eq!(
+        store.options().compaction_policy,
+        CompactionPolicy::Bytes(1024)
+    );
+    store.set("key2".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats().compactions, 1);
+    assert_eq!(store.stats().uncompacted_bytes, 0);
+    Ok(())
+}
+
+// A scan started before a compaction finishes over the old files, which
+// are only deleted once the scan is dropped.
+#[test]
+fn scan_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        ring_capacity: Some(u64::MAX),
+        ring_segment_size: 256,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let mut expected = Vec::new();
+    for key_id in 0..50 {
+        let key = format!("key{:02}", key_id);
+        store.set(key.clone(), format!("value{}", key_id))?;
+        expected.push((key, format!("value{}", key_id)));
+    }
+    let first_log = temp_dir.path().join("1.log");
+    assert!(first_log.exists());
+
+    let mut scan = store.scan()?;
+    let mut scanned = scan.by_ref().take(10).collect::<Result<Vec<_>>>()?;
+    store.compact()?;
+    store.set("key00".to_owned(), "newer".to_owned())?;
+    assert!(first_log.exists());
+
+    scanned.extend(scan.by_ref().collect::<Result<Vec<_>>>()?);
+    assert_eq!(scanned, expected);
+    assert!(first_log.exists());
+    drop(scan);
+    assert!(!first_log.exists());
+    Ok(())
+}
+
+// `iter` walks every live pair in key order, reading values as it goes,
+// and sees writes made and compactions run while it is part way through.
+#[test]
+fn iter_live_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_file_size: Some(4096),
+        sync_sets: false,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let mut expected = Vec::new();
+    for key_id in 0..1000 {
+        let key = format!("key{:04}", key_id);
+        store.set(key.clone(), format!("value{}", key_id))?;
+        expected.push((key, format!("value{}", key_id)));
+    }
+    store.remove("key0500".to_owned())?;
+    expected.remove(500);
+    assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, expected);
+
+    // Dropping a cursor early leaves nothing locked or pinned.
+    let first = store.iter().take(10).collect::<Result<Vec<_>>>()?;
+    assert_eq!(first, expected[..10]);
+    store.set("key0000".to_owned(), "newer".to_owned())?;
+    store.compact()?;
+
+    let mut iter = store.iter();
+    let mut pairs = iter.by_ref().take(10).collect::<Result<Vec<_>>>()?;
+    store.set("key0001".to_owned(), "behind".to_owned())?;
+    store.set("key0999".to_owned(), "ahead".to_owned())?;
+    store.compact()?;
+    pairs.extend(iter.by_ref().collect::<Result<Vec<_>>>()?);
+    expected[0].1 = "newer".to_owned();
+    expected[998].1 = "ahead".to_owned();
+    assert_eq!(pairs, expected);
+    assert!(iter.next().is_none());
+    Ok(())
+}
+
+// A tail sees writes made from another thread, in order, across log file
+// rollovers.
+#[test]
+fn tail_follows_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        ring_capacity: Some(u64::MAX),
+        ring_segment_size: 256,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("before".to_owned(), "tail".to_owned())?;
+    let tail = store.tail()?;
+
+    let mut expected = Vec::new();
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id);
+        expected.push(kv::Command::Set {
+            key: key.clone(),
+            value: format!("value{}", key_id),
+        });
+        if key_id % 4 == 0 {
+            expected.push(kv::Command::Remove { key });
+        }
+    }
+    let to_write = expected.clone();
+    let writer = std::thread::spawn(move || -> Result<()> {
+        for cmd in to_write {
+            match cmd {
+                kv::Command::Set { key, value } => store.set(key, value)?,
+                kv::Command::Remove { key } => store.remove(key)?,
+                kv::Command::SetEx { .. } => unreachable!(),
+            }
+            std::thread::sleep(Duration::from_micros(200));
+        }
+        Ok(())
+    });
+
+    let seen = tail.take(expected.len()).collect::<Result<Vec<_>>>()?;
+    writer.join().unwrap()?;
+    assert_eq!(seen, expected);
+    Ok(())
+}
+
+// With a key index, `open` takes compacted keys from it without reading
+// the values: garbling every value still leaves `keys` intact.
+#[test]
+fn key_index_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        key_index_file: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..300 {
+        store.set(format!("key{}", iter % 30), "v".repeat(32))?;
+    }
+    store.compact()?;
+    let keys = store.keys();
+    assert_eq!(keys.len(), 30);
+    drop(store);
+
+    let log_files: Vec<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect();
+    assert!(log_files
+        .iter()
+        .any(|path| path.with_extension("keys").exists()));
+    for path in log_files {
+        let garbled = std::fs::read(&path)?
+            .into_iter()
+            .map(|byte| if byte == b'v' { 0x01 } else { byte })
+            .collect::<Vec<u8>>();
+        std::fs::write(&path, garbled)?;
+    }
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.keys(), keys);
+    store.set("key1".to_owned(), "fresh".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("fresh".to_owned()));
+    Ok(())
+}
+
+// A record truncated behind the store's back reads as a concurrent
+// modification rather than a parse error.
+#[test]
+fn get_truncated_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let log = std::fs::OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("1.log"))?;
+    let len = log.metadata()?.len();
+    log.set_len(len - 5)?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    match store.get("key2".to_owned()) {
+        Err(KvError::ConcurrentModification) => (),
+        other => panic!("expected ConcurrentModification, got {:?}", other),
+    }
+    Ok(())
+}
+
+// Random churn with compaction firing every few operations should leave the
+// store agreeing with a plain map, before and after a reopen.
+#[test]
+fn compaction_stress() -> Result<()> {
+    let configs = [(1, RecordFormat::Json), (4, RecordFormat::Bincode)];
+    for (seed, (workers, record_format)) in configs.into_iter().enumerate() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = || KvStoreOptions {
+            compaction_policy: CompactionPolicy::Bytes(1024),
+            compaction_workers: workers,
+            record_format,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        let mut model = HashMap::new();
+        let mut rng = StdRng::seed_from_u64(seed as u64);
+
+        for i in 0..5000 {
+            let key = format!("key{}", rng.gen_range(0..200));
+            match rng.gen_range(0..10) {
+                0..=4 => {
+                    let len = rng.gen_range(0..200);
+                    let value = format!("{}-{}", i, "v".repeat(len));
+                    store.set(key.clone(), value.clone())?;
+                    model.insert(key, value);
+                }
+                5..=6 => match (store.remove(key.clone()), model.remove(&key)) {
+                    (Ok(()), Some(_)) | (Err(KvError::KeyNotFound), None) => {}
+                    (result, expected) => {
+                        panic!("remove {}: got {:?}, expected {:?}", key, result, expected)
+                    }
+                },
+                _ => assert_eq!(store.get(key.clone())?, model.get(&key).cloned()),
+            }
+        }
+        assert!(store.stats().compactions > 10);
+
+        let check = |store: &KvStore| -> Result<()> {
+            assert_eq!(store.len(), model.len());
+            for key_id in 0..200 {
+                let key = format!("key{}", key_id);
+                assert_eq!(store.get(key.clone())?, model.get(&key).cloned());
+            }
+            Ok(())
+        };
+        check(&store)?;
+        drop(store);
+        check(&KvStore::open_with_options(temp_dir.path(), options())?)?;
+    }
+    Ok(())
+}
+
+// With `inline_value_size`, small values come from the index rather than
+// the log, both once written and once `open` has replayed them. Corrupting
+// the log behind the store's back shows which values it reads from disk.
+#[test]
+fn inline_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        inline_value_size: Some(8),
+        ..KvStoreOptions::default()
+    };
+    let log = temp_dir.path().join("1.log");
+    let corrupt = || -> Result<Vec<u8>> {
+        let contents = std::fs::read(&log)?;
+        std::fs::write(&log, vec![b'x'; contents.len()])?;
+        Ok(contents)
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("small".to_owned(), "tiny".to_owned())?;
+    store.set("large".to_owned(), "bigvalue".repeat(4))?;
+
+    let contents = corrupt()?;
+    assert_eq!(store.get("small".to_owned())?, Some("tiny".to_owned()));
+    assert!(store.get("large".to_owned()).is_err());
+    std::fs::write(&log, contents)?;
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    let contents = corrupt()?;
+    assert_eq!(store.get("small".to_owned())?, Some("tiny".to_owned()));
+    assert!(store.get("large".to_owned()).is_err());
+    std::fs::write(&log, contents)?;
+
+    store.set("small".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("small".to_owned())?, Some("new".to_owned()));
+    store.remove("small".to_owned())?;
+    assert_eq!(store.get("small".to_owned())?, None);
+    Ok(())
+}
+
+// With `compress_values`, compressible values take less room on disk and
+// read back unchanged, in both record formats and alongside plain records
+// from before it was turned on.
+#[test]
+fn compress_values() -> Result<()> {
+    let value = "lorem ipsum dolor sit amet ".repeat(400);
+    for record_format in [RecordFormat::Json, RecordFormat::Bincode] {
+        let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = |compress_values| KvStoreOptions {
+            compress_values,
+            record_format,
+            ..KvStoreOptions::default()
+        };
+        let plain = KvStore::open_with_options(plain_dir.path(), options(false))?;
+        plain.set("text".to_owned(), value.clone())?;
+        let store = KvStore::open_with_options(temp_dir.path(), options(true))?;
+        store.set("text".to_owned(), value.clone())?;
+        assert!(store.disk_size()? * 10 < plain.disk_size()?);
+        assert_eq!(store.get("text".to_owned())?, Some(value.clone()));
+
+        // Values compression would not shrink are logged as they are.
+        store.set("short".to_owned(), "abc".to_owned())?;
+        store.set_with_ttl("ttl".to_owned(), value.clone(), Duration::from_secs(3600))?;
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options(false))?;
+        store.set("later".to_owned(), value.clone())?;
+        let check = |store: &KvStore| -> Result<()> {
+            for key in ["text", "ttl", "later"] {
+                assert_eq!(store.get(key.to_owned())?, Some(value.clone()));
+            }
+            assert_eq!(store.get("short".to_owned())?, Some("abc".to_owned()));
+            Ok(())
+        };
+        check(&store)?;
+        drop(store);
+
+        // Compaction copies the compressed records rather than inflating
+        // them again.
+        let store = KvStore::open_with_options(temp_dir.path(), options(true))?;
+        store.remove("later".to_owned())?;
+        store.set("later".to_owned(), value.clone())?;
+        let before = store.disk_size()?;
+        store.compact()?;
+        assert!(store.disk_size()? < before);
+        assert!(store.disk_size()? * 10 < plain.disk_size()? * 3);
+        check(&store)?;
+        assert!(store.verify()?.failures.is_empty());
+        drop(store);
+        check(&KvStore::open_with_options(
+            temp_dir.path(),
+            options(false),
+        )?)?;
+    }
+    Ok(())
+}
+
+// With `max_file_size`, the active file rolls over once it is full, and
+// keys stay readable from every file before and after a reopen.
+#[test]
+fn max_file_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        max_file_size: Some(4096),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    let first_file = store.stats().current_file_id;
+    for key_id in 0..300 {
+        store.set(format!("key{}", key_id), format!("value{:050}", key_id))?;
+    }
+    let stats = store.stats();
+    assert!(stats.current_file_id >= first_file + 2);
+    assert!(stats.num_log_files >= 3);
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            // A file only rolls over after the set that filled it.
+            assert!(std::fs::metadata(&path)?.len() < 4096 + 128);
+        }
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..300 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{:050}", key_id))
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options())?)
+}
+
+// Stores work the same whatever size their file buffers are.
+#[test]
+fn buffer_capacity() -> Result<()> {
+    for capacity in [1, 256 * 1024] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = || KvStoreOptions {
+            buffer_capacity: capacity,
+            compaction_policy: CompactionPolicy::Bytes(64 * 1024),
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        for i in 0..20 {
+            store.set(
+                format!("key{}", i % 5),
+                format!("{}{}", i, "v".repeat(10_000)),
+            )?;
+        }
+        assert!(store.stats().compactions > 0);
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        for i in 15..20 {
+            assert_eq!(
+                store.get(format!("key{}", i % 5))?,
+                Some(format!("{}{}", i, "v".repeat(10_000)))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Gets running alongside repeated compactions always find their file and
+// never see a value older than one they already read.
+#[test]
+fn gets_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("key{}:0", key_id))?;
+    }
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let done = done.clone();
+            std::thread::spawn(move || -> Result<u64> {
+                let mut seen = vec![0; 50];
+                let mut gets = 0;
+                while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    for (key_id, seen) in seen.iter_mut().enumerate() {
+                        let key = format!("key{}", key_id);
+                        let value = store.get(key.clone())?.expect("key went missing");
+                        let (found, round) = value.split_once(':').unwrap();
+                        assert_eq!(found, key);
+                        let round: u64 = round.parse().unwrap();
+                        assert!(round >= *seen);
+                        *seen = round;
+                        gets += 1;
+                    }
+                }
+                Ok(gets)
+            })
+        })
+        .collect();
+
+    for round in 1..=20 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("key{}:{}", key_id, round))?;
+        }
+        store.compact()?;
+    }
+    done.store(true, std::sync::atomic::Ordering::SeqCst);
+    for reader in readers {
+        assert!(reader.join().unwrap()? > 0);
+    }
+    assert_eq!(store.stats().compactions, 20);
+    Ok(())
+}
+
+// With `defer_compaction_one_op`, the write that crosses the threshold
+// returns without compacting and the following operation compacts.
+#[test]
+fn defer_compaction_one_op() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_policy: CompactionPolicy::Bytes(1024),
+        defer_compaction_one_op: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    while store.stats().uncompacted_bytes <= 1024 {
+        store.set("key1".to_owned(), "value".repeat(10))?;
+    }
+    assert_eq!(store.stats().compactions, 0);
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value".repeat(10)));
+    assert_eq!(store.stats().compactions, 1);
+    assert_eq!(store.stats().uncompacted_bytes, 0);
+    Ok(())
+}
+
+// The file holding a hot key's overwrites tops the compaction advice.
+#[test]
+fn compaction_advice() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..300 {
+        store.set("hot".to_owned(), format!("value{}", iter))?;
+    }
+    drop(store);
+    for batch in 0..2 {
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..50 {
+            store.set(format!("key{}-{}", batch, key_id), "value".to_owned())?;
+        }
+    }
+    let store = KvStore::open(temp_dir.path())?;
+
+    let advice = store.compaction_advice()?;
+    assert!(advice.should_compact);
+    assert_eq!(advice.worst_files.len(), 1);
+    let worst = &advice.worst_files[0];
+    assert_eq!(worst.file_id, 1);
+    assert!(worst.stale_ratio() > 0.9);
+    assert_eq!(advice.reclaimable_bytes, worst.stale_bytes);
+    assert_eq!(advice.reclaimable_bytes, store.stats().uncompacted_bytes);
+
+    store.compact()?;
+    let advice = store.compaction_advice()?;
+    assert!(!advice.should_compact);
+    assert!(advice.worst_files.is_empty());
+    Ok(())
+}
+
+// New keys are refused once the index would outgrow the memory budget, and
+// a store whose index is already too large cannot be opened with it.
+#[test]
+fn memory_budget() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        memory_budget: Some(4096),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let mut stored = 0;
+    loop {
+        match store.set(format!("key{}", stored), "value".to_owned()) {
+            Ok(()) => stored += 1,
+            Err(KvError::MemoryBudgetExceeded { needed, budget }) => {
+                assert!(needed > budget);
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    assert!(stored > 0);
+    // Overwrites and removes never grow the index.
+    store.set("key0".to_owned(), "other".to_owned())?;
+    store.remove("key0".to_owned())?;
+    store.set("key0".to_owned(), "again".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("extra{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+    match KvStore::open_with_options(temp_dir.path(), options) {
+        Err(KvError::MemoryBudgetExceeded { budget: 4096, .. }) => (),
+        other => panic!("expected MemoryBudgetExceeded, got {:?}", other.err()),
+    }
+    Ok(())
+}
+
+// Values cached under `inline_value_size` count against the memory budget,
+// so fewer keys fit with them than without, and a cached value growing
+// is refused once it would not fit.
+#[test]
+fn memory_budget_counts_inline_values() -> Result<()> {
+    let fill = |store: &KvStore| -> Result<usize> {
+        let mut stored = 0;
+        loop {
+            match store.set(format!("key{}", stored), "v".repeat(64)) {
+                Ok(()) => stored += 1,
+                Err(KvError::MemoryBudgetExceeded { .. }) => return Ok(stored),
+                Err(e) => return Err(e),
+            }
+        }
+    };
+    let open = |dir: &TempDir| {
+        KvStore::open_with_options(
+            dir.path(),
+            KvStoreOptions {
+                memory_budget: Some(4096),
+                inline_value_size: Some(1024),
+                ..KvStoreOptions::default()
+            },
+        )
+    };
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let plain = KvStore::open_with_options(
+        plain_dir.path(),
+        KvStoreOptions {
+            memory_budget: Some(4096),
+            ..KvStoreOptions::default()
+        },
+    )?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir)?;
+    let stored = fill(&store)?;
+    assert!(stored < fill(&plain)?);
+
+    assert!(matches!(
+        store.set("key0".to_owned(), "v".repeat(1024)),
+        Err(KvError::MemoryBudgetExceeded { .. })
+    ));
+    store.set("key0".to_owned(), String::new())?;
+    store.set(format!("key{}", stored), "v".repeat(64))?;
+    drop(store);
+    open(&temp_dir)?;
+    Ok(())
+}
+
+// Raw RESP requests get Redis-compatible replies.
+#[test]
+fn resp_set_get_del() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let requests = concat!(
+        "*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n",
+        "*2\r\n$3\r\nget\r\n$4\r\nkey1\r\n",
+        "*2\r\n$3\r\nGET\r\n$4\r\nkey2\r\n",
+        "*3\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n",
+        "*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+        "*1\r\n$4\r\nPING\r\n",
+        "*2\r\n$3\r\nSET\r\n$4\r\nkey1\r\n",
+    );
+    let mut replies = Vec::new();
+    kv::resp::serve(&store, requests.as_bytes(), &mut replies)?;
+    assert_eq!(
+        String::from_utf8(replies).unwrap(),
+        concat!(
+            "+OK\r\n",
+            "$6\r\nvalue1\r\n",
+            "$-1\r\n",
+            ":1\r\n",
+            "$-1\r\n",
+            "-ERR unknown command 'PING'\r\n",
+            "-ERR wrong number of arguments for 'set' command\r\n",
+        )
+    );
+
+    let mut replies = Vec::new();
+    let result = kv::resp::serve(&store, "GET key1\r\n".as_bytes(), &mut replies);
+    assert!(matches!(result, Err(KvError::Protocol { .. })));
+    assert!(String::from_utf8(replies)
+        .unwrap()
+        .starts_with("-ERR Protocol error"));
+    Ok(())
+}
+
+// A compacted file ending in an index checkpoint is loaded from it rather
+// than replayed, and log readers skip the checkpoint.
+#[test]
+fn index_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        index_checkpoint: true,
+        compact_in_place: false,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..300 {
+        store.set(format!("key{}", iter % 100), format!("value{}", iter))?;
+    }
+    store.compact()?;
+    for key_id in 0..5 {
+        store.set(format!("new{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats().recovered_records, 5);
+    assert_eq!(store.stats().live_keys, 105);
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", 200 + key_id))
+        );
+    }
+    assert_eq!(store.get("new4".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.replay()?.collect::<Result<Vec<_>>>()?.len(), 105);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.stats().recovered_records > 105);
+    assert_eq!(store.stats().live_keys, 105);
+    Ok(())
+}
+
+// The store sweeps expired keys on its own once `ttl_sweep_interval` has
+// passed, without anyone calling `tick`, and closes promptly.
+#[test]
+fn ttl_sweep_in_background() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let options = KvStoreOptions {
+        ttl_sweep_interval: Some(Duration::from_millis(20)),
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("kept".to_owned(), "value".to_owned())?;
+    store.set_with_ttl(
+        "cold".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    clock.advance(Duration::from_secs(10));
+
+    let started = std::time::Instant::now();
+    while store.tombstoned_keys()?.is_empty() {
+        assert!(started.elapsed() < Duration::from_secs(10), "no sweep ran");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.tombstoned_keys()?, ["cold"]);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().live_keys, 1);
+    Ok(())
+}
+
+// Expired keys read as absent, and the periodic sweep reclaims the ones
+// nobody touches.
+#[test]
+fn ttl_sweep() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let options = KvStoreOptions {
+        ttl_sweep_interval: Some(Duration::from_secs(60)),
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("kept".to_owned(), "value".to_owned())?;
+    store.set_with_ttl(
+        "touched".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    for key_id in 0..10 {
+        store.set_with_ttl(
+            format!("cold{}", key_id),
+            "value".to_owned(),
+            Duration::from_secs(10),
+        )?;
+    }
+    assert_eq!(store.get("touched".to_owned())?, Some("value".to_owned()));
+
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(store.get("touched".to_owned())?, None);
+    assert!(!store.contains_key("cold0"));
+    store.tick()?;
+    // Only `touched` has been dropped so far, though stats skip them all.
+    assert_eq!(store.tombstoned_keys()?.len(), 1);
+    assert_eq!(store.stats().live_keys, 1);
+
+    clock.advance(Duration::from_secs(30));
+    store.tick()?;
+    assert_eq!(store.stats().live_keys, 1);
+    assert_eq!(store.tombstoned_keys()?.len(), 11);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats().live_keys, 1);
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// `replace_all` should swap the whole contents in at once, leaving the old
+// data untouched when interrupted.
+#[test]
+fn replace_all() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("old{}", key_id), "value".to_owned())?;
+    }
+
+    let interrupted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let entries = (0..10).map(|key_id| match key_id {
+            5 => panic!("interrupted"),
+            _ => (format!("new{}", key_id), "value".to_owned()),
+        });
+        store.replace_all(entries)
+    }));
+    assert!(interrupted.is_err());
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys().len(), 10);
+    assert_eq!(store.get("old0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("new0".to_owned())?, None);
+
+    // Keep copies of the old files to mimic a crash before their removal.
+    let old_files: Vec<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| {
+            let contents = std::fs::read(&path).unwrap();
+            (path, contents)
+        })
+        .collect();
+    store.replace_all((0..5).map(|key_id| (format!("new{}", key_id), format!("{}", key_id))))?;
+    assert_eq!(store.get("old0".to_owned())?, None);
+    assert_eq!(store.get("new3".to_owned())?, Some("3".to_owned()));
+    drop(store);
+
+    for (path, contents) in old_files {
+        std::fs::write(path, contents)?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    let mut keys = store.keys();
+    keys.sort();
+    assert_eq!(keys, vec!["new0", "new1", "new2", "new3", "new4"]);
+    assert_eq!(store.get("new3".to_owned())?, Some("3".to_owned()));
+    Ok(())
+}
+
+// `STATS` over RESP should report the store's statistics as JSON.
+#[test]
+fn resp_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let requests = concat!(
+        "*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n",
+        "*3\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$6\r\nvalue2\r\n",
+        "*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue3\r\n",
+        "*2\r\n$3\r\nDEL\r\n$4\r\nkey2\r\n",
+        "*1\r\n$5\r\nstats\r\n",
+    );
+    let mut replies = Vec::new();
+    kv::resp::serve(&store, requests.as_bytes(), &mut replies)?;
+    let replies = String::from_utf8(replies).unwrap();
+    let mut lines = replies.split("\r\n").skip(4);
+    let header = lines.next().unwrap();
+    let body = lines.next().unwrap();
+    assert_eq!(header, format!("${}", body.len()));
+
+    let stats: kv::KvStoreStats = serde_json::from_str(body).unwrap();
+    assert_eq!(stats, store.stats());
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.sets, 3);
+    assert_eq!(stats.removes, 1);
+    Ok(())
+}
+
+// Compacting with several workers should split the live records across
+// files without changing the store's contents.
+#[test]
+fn compaction_workers() -> Result<()> {
+    let mut digests = Vec::new();
+    for workers in [1, 4] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            compaction_workers: workers,
+            compact_in_place: false,
+            key_index_file: true,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for iter in 0..1000 {
+            store.set(format!("key{}", iter % 100), format!("value{}", iter))?;
+        }
+        store.remove("key7".to_owned())?;
+        let digest = store.digest()?;
+
+        let result = store.compact()?;
+        assert_eq!(result.files.len(), workers + 1);
+        assert_eq!(store.digest()?, digest);
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.digest()?, digest);
+        assert_eq!(store.get("key7".to_owned())?, None);
+        digests.push(digest);
+    }
+    assert_eq!(digests[0], digests[1]);
+    Ok(())
+}
+
+// `cleanup` should drop files left with nothing live by a crash, but keep
+// any whose removes still hide older values.
+#[test]
+fn cleanup_leftover_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = |id: u64| temp_dir.path().join(format!("{}.log", id));
+    let options = KvStoreOptions {
+        compact_in_place: false,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.set("gone".to_owned(), "value".to_owned())?;
+    let stale = std::fs::read(log(1))?;
+    store.compact()?;
+    store.remove("gone".to_owned())?;
+    drop(store);
+    // A crash before compaction removed its input leaves it behind.
+    std::fs::write(log(1), stale)?;
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let digest = store.digest()?;
+    assert_eq!(store.cleanup()?, 1);
+    assert!(!log(1).exists());
+    assert!(log(3).exists());
+    assert_eq!(store.get("gone".to_owned())?, None);
+    drop(store);
+
+    let options = KvStoreOptions {
+        cleanup_on_open: true,
+        ..options
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(!log(4).exists());
+    assert_eq!(store.digest()?, digest);
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// `kvs-server` should keep serving requests against one store across
+// connections.
+#[test]
+fn server_serves_connections() -> Result<()> {
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut server, addr, log) = spawn_server(&temp_dir, &["--threads", "2"])?;
+    assert!(log.contains("Storage engine: kvs"));
+    assert!(log.contains(&format!("Listening on {}", addr)));
+
+    let request = |requests: &str| -> Result<String> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(requests.as_bytes())?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut stream, &mut response)?;
+        Ok(response)
+    };
+    let response = request(r#"{"Set":{"key":"key1","value":"value1"}}{"Get":{"key":"key1"}}"#);
+    assert_eq!(response?, r#""Ok"{"Value":"value1"}"#);
+    let response = request(r#"{"Remove":{"key":"key2"}}{"Get":{"key":"key1"}}"#);
+    assert_eq!(response?, r#"{"Err":"Key not found"}{"Value":"value1"}"#);
+
+    // A message that is not a request is answered with an error and the
+    // connection carries on; JSON that does not parse ends it.
+    let response = request(r#"{"Drop":{"key":"key1"}}[1]{"Get":{"key":"key1"}}"#)?;
+    let responses: Vec<&str> = response.split_inclusive('}').collect();
+    assert_eq!(responses.len(), 3, "{}", response);
+    assert!(responses[0].starts_with(r#"{"Err":"Protocol error: invalid request"#));
+    assert!(responses[1].starts_with(r#"{"Err":"Protocol error: invalid request"#));
+    assert_eq!(responses[2], r#"{"Value":"value1"}"#);
+    let response = request(r#"{"Get":{"key":"key1"}}{"Get":}{"Get":{"key":"key1"}}"#)?;
+    assert!(response.starts_with(r#"{"Value":"value1"}{"Err":"Protocol error"#));
+    assert!(!response.ends_with(r#"{"Value":"value1"}"#));
+    assert_eq!(
+        request(r#"{"Get":{"key":"key1"}}"#)?,
+        r#"{"Value":"value1"}"#
+    );
+
+    // An idle connection holds one thread, not the whole server.
+    let idle = TcpStream::connect(addr)?;
+    let response = request(r#"{"Get":{"key":"key1"}}"#);
+    assert_eq!(response?, r#"{"Value":"value1"}"#);
+    drop(idle);
+
+    server.kill()?;
+    server.wait()?;
+    Ok(())
+}
+
+// SIGTERM stops the server once its connections are done, exiting cleanly
+// with the last write on disk.
+#[cfg(unix)]
+#[test]
+fn server_shuts_down_gracefully() -> Result<()> {
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut server, addr, _) = spawn_server(&temp_dir, &["--threads", "2"])?;
+    let idle = TcpStream::connect(addr)?;
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Set":{"key":"key1","value":"value1"}}"#)?;
+    let mut response = [0; 4];
+    std::io::Read::read_exact(&mut stream, &mut response)?;
+    assert_eq!(&response, br#""Ok""#);
+
+    Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .assert()
+        .success();
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = server.try_wait()? {
+            break status;
+        }
+        assert!(
+            std::time::Instant::now() < deadline,
+            "server did not shut down"
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    assert!(status.success());
+    drop((idle, stream));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Start `kvs-server` in `dir` on a free port with the extra `args`,
+// returning it once it listens along with its address and startup log.
+fn spawn_server(
+    dir: &TempDir,
+    args: &[&str],
+) -> Result<(std::process::Child, std::net::SocketAddr, String)> {
+    use std::io::{BufRead, BufReader};
+
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr.to_string()])
+        .args(args)
+        .current_dir(dir)
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    let mut stderr = BufReader::new(server.stderr.take().unwrap());
+    let mut log = String::new();
+    while !log.contains("Listening on") {
+        if stderr.read_line(&mut log)? == 0 {
+            break;
+        }
+    }
+    Ok((server, addr, log))
+}
+
+// `kvs-client` should behave like `kvs` against a running server.
+#[test]
+fn cli_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut server, addr, _) = spawn_server(&temp_dir, &[])?;
+    let addr = addr.to_string();
+    let client = |args: &[&str]| {
+        let mut client = Command::cargo_bin("kvs-client").unwrap();
+        client.args(args).args(["--addr", &addr]);
+        client
+    };
+
+    client(&["set", "key1", "value1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    client(&["rm", "key2"])
+        .assert()
+        .failure()
+        .code(1)
+        .stdout(eq("Key not found").trim());
+    client(&["rm", "key1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    client(&["stats"])
+        .assert()
+        .success()
+        .stdout(contains(r#""sets": 1"#).and(contains(r#""removes": 1"#)));
+    client(&["compact"])
+        .assert()
+        .success()
+        .stdout(contains("Reclaimed"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "extra"])
+        .assert()
+        .failure();
+
+    server.kill()?;
+    server.wait()?;
+    Ok(())
+}
+
+// `kvs-client --timeout` should give up on a server that never answers.
+#[test]
+fn cli_client_timeout() -> Result<()> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    // Accept the connection but hold the answer back.
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        std::thread::sleep(Duration::from_secs(2));
+        drop(stream);
+    });
+
+    let started = std::time::Instant::now();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr, "--timeout", "100"])
+        .assert()
+        .failure()
+        .code(1)
+        .stderr(eq("Request timed out").trim());
+    assert!(started.elapsed() < Duration::from_secs(2));
+    server.join().unwrap();
+    Ok(())
+}
+
+// Pointed at a port nobody listens on, `kvs-client` fails straight away,
+// in single and batch mode alike.
+#[test]
+fn cli_client_closed_port() -> Result<()> {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .to_string();
+
+    let started = std::time::Instant::now();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr, "--timeout", "100"])
+        .assert()
+        .failure()
+        .code(1)
+        .stdout(is_empty())
+        .stderr(is_empty().not());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", "--addr", &addr, "--timeout", "100"])
+        .with_stdin()
+        // Nothing to send: the client gives up before reading stdin, and
+        // writing to it then could fail the test on a broken pipe.
+        .buffer("")
+        .assert()
+        .failure()
+        .code(1);
+    assert!(started.elapsed() < Duration::from_secs(2));
+    Ok(())
+}
+
+// `kvs-client --retries` should keep trying to connect until a server
+// started after it comes up, and give up with the attempts it made.
+#[test]
+fn cli_client_retries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .to_string();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr, "--retries", "2"])
+        .assert()
+        .code(1)
+        .stderr(contains("after 3 attempts"));
+
+    let client = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr, "--retries", "8"])
+        .spawn()?;
+    std::thread::sleep(Duration::from_millis(300));
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr])
+        .current_dir(&temp_dir)
+        .stderr(std::process::Stdio::null())
+        .spawn()?;
+    let output = client.wait_with_output()?;
+    server.kill()?;
+    server.wait()?;
+    assert!(output.status.success());
+    assert_eq!(
+        KvStore::open(temp_dir.path())?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+// `open_many` should open each store of a manifest with its own options.
+#[test]
+fn open_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manifest = temp_dir.path().join("manifest.json");
+    let shard1 = temp_dir.path().join("elsewhere").join("shard1");
+    let contents = serde_json::json!([
+        { "path": "shard0" },
+        {
+            "path": shard1,
+            "options": { "compaction_policy": { "bytes": 4096 }, "strict_removes": false },
+        },
+    ]);
+    std::fs::write(&manifest, contents.to_string())?;
+
+    let stores = KvStore::open_many(&manifest)?;
+    assert_eq!(stores.len(), 2);
+    assert_eq!(
+        stores[0].options().compaction_policy,
+        CompactionPolicy::Bytes(1024 * 1024)
+    );
+    assert!(stores[0].options().strict_removes);
+    assert_eq!(
+        stores[1].options().compaction_policy,
+        CompactionPolicy::Bytes(4096)
+    );
+    assert!(!stores[1].options().strict_removes);
+    stores[0].set("key1".to_owned(), "value1".to_owned())?;
+    stores[1].remove("key1".to_owned())?;
+    drop(stores);
+    assert!(temp_dir.path().join("shard0").join("1.log").exists());
+    assert!(shard1.join("1.log").exists());
+
+    std::fs::write(
+        &manifest,
+        r#"[{ "path": "shard0", "options": { "clock": 1 } }]"#,
+    )?;
+    assert!(matches!(
+        KvStore::open_many(&manifest),
+        Err(KvError::Serde(_))
+    ));
+    Ok(())
+}
+
+// Compacting while recovering should leave a single compacted file with
+// the same contents as compacting after recovery.
+#[test]
+fn compact_while_recovering() -> Result<()> {
+    let two_pass = TempDir::new().expect("unable to create temporary working directory");
+    let fused = TempDir::new().expect("unable to create temporary working directory");
+    for dir in [&two_pass, &fused] {
+        for round in 0..3 {
+            let store = KvStore::open(dir.path())?;
+            for iter in 0..100 {
+                store.set(format!("key{}", iter % 10), format!("value{}", round))?;
+            }
+            store.remove(format!("key{}", round))?;
+        }
+    }
+    let options = KvStoreOptions {
+        compact_on_open: true,
+        ..KvStoreOptions::default()
+    };
+    let expected = KvStore::open_with_options(two_pass.path(), options.clone())?;
+    let options = KvStoreOptions {
+        compact_while_recovering: true,
+        ..options
+    };
+    let store = KvStore::open_with_options(fused.path(), options.clone())?;
+    assert_eq!(store.digest()?, expected.digest()?);
+    assert_eq!(store.stats().compactions, 1);
+    assert_eq!(store.stats().recovered_records, 303);
+    assert_eq!(store.stats().uncompacted_bytes, 0);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, Some("value2".to_owned()));
+    let log_files = || {
+        std::fs::read_dir(fused.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    assert_eq!(log_files(), 2);
+    drop(store);
+
+    // A compacted store has nothing left to compact, so the copy is dropped.
+    let store = KvStore::open_with_options(fused.path(), options)?;
+    assert_eq!(store.digest()?, expected.digest()?);
+    assert_eq!(store.stats().compactions, 0);
+    assert_eq!(log_files(), 3);
+    Ok(())
+}
+
+// A `KvStore` should work through a shared `Box<dyn KvsEngine>`, with every
+// clone seeing the same data.
+#[test]
+fn kvs_engine_shared_across_threads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine: Box<dyn KvsEngine> = Box::new(KvStore::open(temp_dir.path())?);
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for key_id in 0..25 {
+                    engine.set(
+                        format!("key{}", thread_id * 25 + key_id),
+                        "value".to_owned(),
+                    )?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    for key_id in 0..100 {
+        assert_eq!(
+            engine.get(format!("key{}", key_id))?,
+            Some("value".to_owned())
+        );
+    }
+    engine.remove("key0".to_owned())?;
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key0".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    drop(engine);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys().len(), 99);
+    Ok(())
+}
+
+// `SpawnBlocking` answers from a tokio runtime just as the engine it wraps
+// answers directly, for each engine and from many tasks at once.
+#[test]
+fn async_engine() -> Result<()> {
+    // The answers a sequence of operations gets, errors as their messages.
+    async fn run_ops(engine: &impl AsyncKvsEngine) -> Vec<String> {
+        vec![
+            format!(
+                "{:?}",
+                engine.set("key1".to_owned(), "value1".to_owned()).await
+            ),
+            format!(
+                "{:?}",
+                engine.set("key1".to_owned(), "value2".to_owned()).await
+            ),
+            format!("{:?}", engine.get("key1".to_owned()).await),
+            format!("{:?}", engine.get("key2".to_owned()).await),
+            format!("{:?}", engine.remove("key1".to_owned()).await),
+            format!("{:?}", engine.remove("key1".to_owned()).await),
+            format!("{:?}", engine.get("key1".to_owned()).await),
+            format!("{:?}", engine.flush().await),
+        ]
+    }
+    fn expected(engine: &impl KvsEngine) -> Vec<String> {
+        vec![
+            format!("{:?}", engine.set("key1".to_owned(), "value1".to_owned())),
+            format!("{:?}", engine.set("key1".to_owned(), "value2".to_owned())),
+            format!("{:?}", engine.get("key1".to_owned())),
+            format!("{:?}", engine.get("key2".to_owned())),
+            format!("{:?}", engine.remove("key1".to_owned())),
+            format!("{:?}", engine.remove("key1".to_owned())),
+            format!("{:?}", engine.get("key1".to_owned())),
+            format!("{:?}", engine.flush()),
+        ]
+    }
+    async fn concurrent_sets<E: KvsEngine + Sync>(engine: SpawnBlocking<E>) -> Result<()> {
+        let tasks: Vec<_> = (0..50)
+            .map(|key_id| {
+                let engine = engine.clone();
+                tokio::spawn(async move {
+                    engine
+                        .set(format!("key{}", key_id), format!("value{}", key_id))
+                        .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap()?;
+        }
+        for key_id in 0..50 {
+            let key = format!("key{}", key_id);
+            let value = Some(format!("value{}", key_id));
+            assert_eq!(engine.get(key.clone()).await?, value);
+            assert_eq!(engine.engine().get(key)?, value);
+        }
+        Ok(())
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    let dirs: Vec<TempDir> = (0..6)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let store = SpawnBlocking::new(KvStore::open(dirs[0].path())?);
+    let answers = runtime.block_on(run_ops(&store));
+    assert_eq!(answers, expected(&KvStore::open(dirs[1].path())?));
+    assert!(answers[5].contains("KeyNotFound"));
+    let sled = SpawnBlocking::new(SledKvsEngine::open(dirs[2].path())?);
+    let answers = runtime.block_on(run_ops(&sled));
+    assert_eq!(answers, expected(&SledKvsEngine::open(dirs[3].path())?));
+
+    runtime.block_on(concurrent_sets(SpawnBlocking::new(KvStore::open(
+        dirs[4].path(),
+    )?)))?;
+    runtime.block_on(concurrent_sets(SpawnBlocking::new(SledKvsEngine::open(
+        dirs[5].path(),
+    )?)))?;
+    Ok(())
+}
+
+// `SledKvsEngine` should follow the `KvStore` semantics, including
+// `KeyNotFound` on removing an absent key.
+#[test]
+fn sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.remove("key2".to_owned())?;
+    assert!(matches!(
+        engine.remove("key2".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    drop(engine);
+
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// `kvs-client batch` pipelines the commands on stdin over one connection
+// and prints the responses in order.
+#[test]
+fn cli_client_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut server, addr, _) = spawn_server(&temp_dir, &[])?;
+    let addr = addr.to_string();
+    let batch = |input: String| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["batch", "--addr", &addr])
+            .with_stdin()
+            .buffer(input)
+            .assert()
+    };
+
+    let mut input: String = (0..100).map(|i| format!("set key1 value{}\n", i)).collect();
+    input.push_str("get key1\n");
+    batch(input).success().stdout(eq("value99").trim());
+
+    batch("set key2 value2\n\nrm missing\nget key2\nrm key2\nget key2\n".to_owned())
+        .failure()
+        .code(1)
+        .stdout(eq("Key not found\nvalue2\nKey not found").trim());
+    batch("get key1\nbogus key1\n".to_owned())
+        .failure()
+        .stdout(eq("value99").trim())
+        .stderr(contains("line 2"));
+
+    server.kill()?;
+    server.wait()?;
+    Ok(())
+}
+
+// With `--metrics-addr`, the server's counters are scraped over HTTP and
+// go up as requests are served.
+#[test]
+fn server_metrics() -> Result<()> {
+    use std::io::{Read, Write};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let metrics_addr = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .to_string();
+    let (mut server, addr, _) = spawn_server(&temp_dir, &["--metrics-addr", &metrics_addr])?;
+    let addr = addr.to_string();
+    let client = |args: &[&str]| {
+        let mut client = Command::cargo_bin("kvs-client").unwrap();
+        client.args(args).args(["--addr", &addr]);
+        client
+    };
+    let scrape = |path: &str| -> Result<String> {
+        let mut stream = std::net::TcpStream::connect(&metrics_addr)?;
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    };
+
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["get", "key1"]).assert().success();
+    client(&["get", "key2"]).assert().success();
+    client(&["rm", "key2"]).assert().code(1);
+    let text = scrape("/metrics")?;
+    assert!(text.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(text.contains("\nkv_server_sets_total 1\n"));
+    assert!(text.contains("\nkv_server_gets_total 2\n"));
+    assert!(text.contains("\nkv_server_removes_total 1\n"));
+    assert!(text.contains("\nkv_server_key_not_found_total 2\n"));
+    assert!(text.contains("\nkv_compactions_total 0\n"));
+    assert!(!text.contains("\nkv_bytes_written_total 0\n"));
+
+    client(&["set", "key1", "value2"]).assert().success();
+    client(&["compact"]).assert().success();
+    let text = scrape("/metrics")?;
+    assert!(text.contains("\nkv_server_sets_total 2\n"));
+    assert!(text.contains("\nkv_compactions_total 1\n"));
+    assert!(scrape("/")?.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    server.kill()?;
+    server.wait()?;
+    Ok(())
+}
+
+// `kvs-client status` confirms the server is serving, naming its engine
+// and the state of its store.
+#[test]
+fn cli_client_status() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut server, addr, _) = spawn_server(&temp_dir, &[])?;
+    let addr = addr.to_string();
+    let client = |args: &[&str]| {
+        let mut client = Command::cargo_bin("kvs-client").unwrap();
+        client.args(args).args(["--addr", &addr]);
+        client
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["set", "key1", "value2"]).assert().success();
+    client(&["status"]).assert().success().stdout(
+        contains(r#""engine": "kvs""#)
+            .and(contains(r#""uptime_secs": "#))
+            .and(contains(r#""live_keys": 1"#))
+            .and(contains(r#""uncompacted_bytes": 0"#).not()),
+    );
+    server.kill()?;
+    server.wait()?;
+    Ok(())
+}
+
+// `kvs-server --engine sled` should serve requests from sled.
+#[test]
+fn server_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut server, addr, log) = spawn_server(&temp_dir, &["--engine", "sled"])?;
+    assert!(log.contains("Storage engine: sled"));
+    let addr = addr.to_string();
+    let client = |args: &[&str]| {
+        let mut client = Command::cargo_bin("kvs-client").unwrap();
+        client.args(args).args(["--addr", &addr]);
+        client
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    client(&["rm", "key2"])
+        .assert()
+        .code(1)
+        .stdout(eq("Key not found").trim());
+    client(&["stats"]).assert().failure();
+    client(&["status"])
+        .assert()
+        .success()
+        .stdout(contains(r#""engine": "sled""#).and(contains(r#""live_keys": null"#)));
+    server.kill()?;
+    server.wait()?;
+    assert!(!temp_dir.path().join("1.log").exists());
+    Ok(())
+}
+
+// A directory should stay tied to the engine that first opened it.
+#[test]
+fn engine_marker() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(kv::recorded_engine(temp_dir.path())?, None);
+    drop(SledKvsEngine::open(temp_dir.path())?);
+    assert_eq!(
+        kv::recorded_engine(temp_dir.path())?,
+        Some("sled".to_owned())
+    );
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::EngineMismatch { .. })
+    ));
+
+    // Logs written before the marker existed belong to `KvStore`.
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(kvs_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    std::fs::remove_file(kvs_dir.path().join("engine"))?;
+    assert!(matches!(
+        SledKvsEngine::open(kvs_dir.path()),
+        Err(KvError::EngineMismatch { .. })
+    ));
+
+    // The server defaults to the recorded engine and rejects another one.
+    let (mut server, _, log) = spawn_server(&temp_dir, &[])?;
+    assert!(log.contains("Storage engine: sled"));
+    server.kill()?;
+    server.wait()?;
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Directory holds data of the sled engine, not kvs"));
+    Ok(())
+}
+
+// After compactions interleaved with rewrites, `get` should return the
+// newest value, before and after reopening.
+#[test]
+fn get_newest_value_after_compactions() -> Result<()> {
+    for compact_in_place in [true, false] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            compact_in_place,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value0".to_owned())?;
+        for round in 1..=5 {
+            store.compact()?;
+            store.set("key1".to_owned(), format!("value{}", round))?;
+            store.set(format!("other{}", round), "value".to_owned())?;
+            assert_eq!(
+                store.get("key1".to_owned())?,
+                Some(format!("value{}", round))
+            );
+        }
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value5".to_owned()));
+        store.compact()?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value5".to_owned()));
+    }
+    Ok(())
+}
+
+// A small `CompactionPolicy::Bytes` given at open should make `set` compact as
+// soon as that many stale bytes pile up.
+#[test]
+fn compaction_policy_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_policy: CompactionPolicy::Bytes(256),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+        assert!(store.stats().uncompacted_bytes <= 256);
+    }
+    assert!(store.stats().compactions > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// With `CompactionPolicy::Ratio`, `set` should compact once stale bytes pass
+// that share of the log, however many bytes that takes.
+#[test]
+fn compaction_policy_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_policy: CompactionPolicy::Ratio(0.5),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".repeat(20))?;
+    }
+    let live_bytes = store.stats().live_bytes;
+
+    let mut stale_before = 0;
+    let mut last = String::new();
+    for iter in 0..200 {
+        last = format!("{:0100}", iter);
+        store.set("key0".to_owned(), last.clone())?;
+        let stats = store.stats();
+        if stats.compactions > 0 {
+            break;
+        }
+        assert!(stats.uncompacted_bytes * 2 <= store.disk_size()?);
+        stale_before = stats.uncompacted_bytes;
+    }
+    assert_eq!(store.stats().compactions, 1);
+    // Half the log is stale once the stale bytes match the live ones.
+    assert!(stale_before * 10 >= live_bytes * 9);
+    assert_eq!(store.get("key0".to_owned())?, Some(last));
+    Ok(())
+}
+
+// `contains_key` should answer from the index alone, and turn false once
+// the key is removed.
+#[test]
+fn contains_key_after_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value".repeat(1000))?;
+    assert!(store.contains_key("key1"));
+    assert!(!store.contains_key("key2"));
+
+    store.remove("key1".to_owned())?;
+    assert!(!store.contains_key("key1"));
+    assert_eq!(store.stats().gets, 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.contains_key("key1"));
+    Ok(())
+}
+
+// `merge_from` sets another store's live pairs, its values winning for
+// keys both hold, and leaves the other store as it was.
+#[test]
+fn merge_from() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "mine".to_owned())?;
+    store.set("key2".to_owned(), "mine".to_owned())?;
+    let other = KvStore::open(other_dir.path())?;
+    other.set("key2".to_owned(), "theirs".to_owned())?;
+    other.set("key3".to_owned(), "theirs".to_owned())?;
+    other.set("key4".to_owned(), "gone".to_owned())?;
+    other.remove("key4".to_owned())?;
+    drop(other);
+
+    assert_eq!(store.merge_from(other_dir.path())?, 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("mine".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("theirs".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("theirs".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("theirs".to_owned()));
+    let other = KvStore::open(other_dir.path())?;
+    assert_eq!(other.len(), 2);
+    Ok(())
+}
+
+// `get_many` answers in the order asked, with `None` for absent and
+// expired keys, whichever files the values live in.
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let options = KvStoreOptions {
+        max_file_size: Some(512),
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key50".to_owned())?;
+    store.set_with_ttl(
+        "key7".to_owned(),
+        "short".to_owned(),
+        Duration::from_secs(1),
+    )?;
+    clock.advance(Duration::from_secs(2));
+
+    let keys: Vec<String> = ["key99", "missing", "key0", "key50", "key7", "key0", "key42"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+    let expected: Vec<Option<String>> = keys
+        .iter()
+        .map(|key| match key.as_str() {
+            "missing" | "key50" | "key7" => None,
+            key => Some(key.replace("key", "value")),
+        })
+        .collect();
+    assert_eq!(store.get_many(&keys)?, expected);
+    assert_eq!(store.get_many(&[])?, Vec::<Option<String>>::new());
+    Ok(())
+}
+
+// `set_many` should index every pair and leave them all on disk once it
+// returns, even with per-write flushing on.
+#[test]
+fn set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    store.set_many((0..1000).map(|key_id| (format!("key{}", key_id), format!("{}", key_id))))?;
+    assert_eq!(store.stats().sets, 1001);
+    assert_eq!(store.get("key0".to_owned())?, Some("0".to_owned()));
+    assert_eq!(store.get("key999".to_owned())?, Some("999".to_owned()));
+
+    // Reopen from a copy so nothing could still be buffered in `store`.
+    let copy = TempDir::new().expect("unable to create temporary working directory");
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        std::fs::copy(&path, copy.path().join(path.file_name().unwrap()))?;
+    }
+    let reopened = KvStore::open(copy.path())?;
+    assert_eq!(reopened.keys().len(), 1000);
+    assert_eq!(reopened.digest()?, store.digest()?);
+    Ok(())
+}
+
+// `keys` lists only the live keys, straight from the index.
+#[test]
+fn keys_skip_removed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.keys(), vec!["key2".to_owned(), "key3".to_owned()]);
+    Ok(())
+}
+
+// `len` counts live keys only and `is_empty` holds for a fresh directory.
+#[test]
+fn len_and_is_empty() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    assert_eq!(store.len(), 0);
+
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    store.remove("key3".to_owned())?;
+    assert_eq!(store.len(), 3);
+    assert!(!store.is_empty());
+
+    store.compact()?;
+    assert_eq!(store.len(), 3);
+    drop(store);
+    assert_eq!(KvStore::open(temp_dir.path())?.len(), 3);
+    Ok(())
+}
+
+// Half a record left at the end of the newest log by a crash is cut off on
+// open instead of failing it.
+#[test]
+fn recover_torn_trailing_record() -> Result<()> {
+    for compact_while_recovering in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+
+        let other_dir = TempDir::new().expect("unable to create temporary working directory");
+        KvStore::open(other_dir.path())?.set("key2".to_owned(), "value2".to_owned())?;
+        let record = std::fs::read(other_dir.path().join("1.log"))?;
+        let mut log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(temp_dir.path().join("1.log"))?;
+        std::io::Write::write_all(&mut log, &record[..record.len() / 2])?;
+        drop(log);
+
+        let options = KvStoreOptions {
+            compact_on_open: compact_while_recovering,
+            compact_while_recovering,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+// Every job submitted to either pool runs.
+#[test]
+fn thread_pool_runs_jobs() -> Result<()> {
+    fn run_jobs<P: ThreadPool>() -> Result<()> {
+        let pool = P::new(4)?;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for job_id in 0..100 {
+            let sender = sender.clone();
+            pool.spawn(move || sender.send(job_id).unwrap());
+        }
+        let mut done: Vec<i32> = (0..100)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(10)).unwrap())
+            .collect();
+        done.sort_unstable();
+        assert_eq!(done, (0..100).collect::<Vec<_>>());
+        Ok(())
+    }
+    run_jobs::<NaiveThreadPool>()?;
+    run_jobs::<SharedQueueThreadPool>()
+}
+
+// Panicking jobs leave the shared-queue pool with threads to run the rest.
+#[test]
+fn shared_queue_thread_pool_survives_panics() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    for _ in 0..4 {
+        pool.spawn(|| panic!("job failed"));
+    }
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for job_id in 0..10 {
+        let sender = sender.clone();
+        pool.spawn(move || sender.send(job_id).unwrap());
+    }
+    for _ in 0..10 {
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+    Ok(())
+}
+
+// Clones read shared keys from several threads at once, and keep reading
+// the right values while another clone overwrites and compacts.
+#[test]
+fn concurrent_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for round in 0..20 {
+                    for key_id in 0..100 {
+                        assert_eq!(
+                            store.get(format!("key{}", key_id))?,
+                            Some(format!("value{}", key_id))
+                        );
+                    }
+                    assert_eq!(store.get(format!("missing{}", round))?, None);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    // Rewrite the same values so readers always see them, moving them
+    // between files.
+    for _ in 0..5 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.compact()?;
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert_eq!(store.stats().gets, 8 * 20 * 101);
+    Ok(())
+}
+
+// Whole-store reads run under the shared lock too, and see the same pairs
+// from every thread while another clone overwrites and compacts.
+#[test]
+fn concurrent_range_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pairs: Vec<(String, String)> = (0..50)
+        .map(|key_id| (format!("key{:02}", key_id), format!("value{}", key_id)))
+        .collect();
+    store.set_many(pairs.clone())?;
+    let keys: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    let values: Vec<Option<String>> = pairs.iter().map(|(_, value)| Some(value.clone())).collect();
+    let digest = store.digest()?;
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let (pairs, keys, values) = (pairs.clone(), keys.clone(), values.clone());
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..20 {
+                    assert_eq!(store.range("key", "kez")?, pairs);
+                    assert_eq!(store.scan_prefix("key")?, pairs);
+                    assert_eq!(store.get_many(&keys)?, values);
+                    assert_eq!(store.digest()?, digest);
+                    assert_eq!(store.value_len("key07")?, Some("value7".len()));
+                    assert_eq!(store.len(), 50);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for _ in 0..5 {
+        store.set_many(pairs.clone())?;
+        store.compact()?;
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// A value changed on disk without breaking the JSON fails its checksum,
+// both when read and when the log is recovered.
+#[test]
+fn corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let path = temp_dir.path().join("1.log");
+    let bytes = std::fs::read(&path)?;
+    let text = String::from_utf8(bytes).unwrap();
+    std::fs::write(&path, text.replace("value1", "valueX"))?;
+
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvError::CorruptRecord)
+    ));
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::CorruptRecord)
+    ));
+    Ok(())
+}
+
+// A log file that cannot be opened is named in the error.
+#[cfg(unix)]
+#[test]
+fn file_error_names_path() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let path = temp_dir.path().join("1.log");
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o000))?;
+    // Permission bits do not stop a privileged user.
+    let enforced = std::fs::File::open(&path).is_err();
+    let result = KvStore::open(temp_dir.path());
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+    if !enforced {
+        result?;
+        return Ok(());
+    }
+    match result {
+        Err(KvError::File(err)) => {
+            assert_eq!(err.op, FileOp::Open);
+            assert_eq!(err.path, path);
+            assert!(err.to_string().contains(&path.display().to_string()));
+        }
+        other => panic!("expected a file error, got {:?}", other.map(|_| ())),
+    }
+    Ok(())
+}
+
+// A store directory that cannot be created is named in the error, whether
+// its parent is read-only or not a directory at all.
+#[cfg(unix)]
+#[test]
+fn open_error_names_directory() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let assert_create_failed = |result: Result<KvStore>, dir: &std::path::Path| match result {
+        Err(KvError::File(err)) => {
+            assert_eq!(err.op, FileOp::Create);
+            assert_eq!(err.path, dir);
+            assert!(err.to_string().contains(&dir.display().to_string()));
+        }
+        other => panic!("expected a file error, got {:?}", other.map(|_| ())),
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let parent = temp_dir.path().join("parent");
+    std::fs::create_dir(&parent)?;
+    std::fs::set_permissions(&parent, std::fs::Permissions::from_mode(0o555))?;
+    let dir = parent.join("store");
+    // Permission bits do not stop a privileged user.
+    let enforced = std::fs::create_dir(parent.join("probe")).is_err();
+    let result = KvStore::open(&dir);
+    std::fs::set_permissions(&parent, std::fs::Permissions::from_mode(0o755))?;
+    if enforced {
+        assert_create_failed(result, &dir);
+    }
+
+    let file = temp_dir.path().join("file");
+    std::fs::write(&file, "not a directory")?;
+    let dir = file.join("store");
+    assert_create_failed(KvStore::open(&dir), &dir);
+    Ok(())
+}
+
+// With `sync_sets` off, buffered sets reach other readers of the directory
+// once flushed.
+#[test]
+fn buffered_sets_visible_after_flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync_sets: false,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(KvStore::open_snapshot(temp_dir.path())?.len(), 0);
+
+    store.flush()?;
+    let snapshot = KvStore::open_snapshot(temp_dir.path())?;
+    assert_eq!(snapshot.len(), 100);
+    assert_eq!(
+        snapshot.get("key42".to_owned())?,
+        Some("value42".to_owned())
+    );
+    Ok(())
+}
+
+// A store written with bincode records reads back large values, survives
+// compaction and reopens with the same format.
+#[test]
+fn bincode_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        record_format: RecordFormat::Bincode,
+        ..KvStoreOptions::default()
+    };
+    let large = "x".repeat(1 << 20);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("large".to_owned(), large.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.replay()?.count(), 4);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    Ok(())
+}
+
+// `range` includes its start, excludes its end and returns nothing for an
+// empty or backwards range.
+#[test]
+fn range_boundaries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["a", "b", "ba", "c", "d"] {
+        store.set(key.to_owned(), format!("value_{}", key))?;
+    }
+    store.remove("c".to_owned())?;
+
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(keys(store.range("b", "d")?), vec!["b", "ba"]);
+    assert_eq!(keys(store.range("", "b")?), vec!["a"]);
+    assert_eq!(keys(store.range("bb", "zz")?), vec!["d"]);
+    assert_eq!(
+        store.range("a", "b")?,
+        vec![("a".to_owned(), "value_a".to_owned())]
+    );
+    assert!(store.range("b", "b")?.is_empty());
+    assert!(store.range("d", "a")?.is_empty());
+    assert!(store.range("e", "f")?.is_empty());
+    Ok(())
+}
+
+// `scan_prefix` returns exactly the keys starting with the prefix, values
+// included, and everything for an empty prefix.
+#[test]
+fn scan_prefix_overlapping() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["aa", "ab", "abc", "abd", "ac", "b"] {
+        store.set(key.to_owned(), format!("value_{}", key))?;
+    }
+    // Move some values into an older file so the scan reads several logs.
+    store.compact()?;
+    store.set("abc".to_owned(), "new_abc".to_owned())?;
+    store.remove("abd".to_owned())?;
+
+    assert_eq!(
+        store.scan_prefix("ab")?,
+        vec![
+            ("ab".to_owned(), "value_ab".to_owned()),
+            ("abc".to_owned(), "new_abc".to_owned()),
+        ]
+    );
+    assert_eq!(
+        store.scan_prefix("abc")?,
+        vec![("abc".to_owned(), "new_abc".to_owned())]
+    );
+    assert!(store.scan_prefix("abd")?.is_empty());
+    assert!(store.scan_prefix("c")?.is_empty());
+    let all: Vec<String> = store
+        .scan_prefix("")?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(all, vec!["aa", "ab", "abc", "ac", "b"]);
+    Ok(())
+}
+
+// Compaction leaves expired entries out entirely, writing no tombstone for
+// them, while keys with time left survive it.
+#[test]
+fn compaction_drops_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let options = KvStoreOptions {
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(100),
+    )?;
+    store.set("plain".to_owned(), "value3".to_owned())?;
+    clock.advance(Duration::from_secs(20));
+
+    store.compact()?;
+    let keys: Vec<String> = store
+        .replay()?
+        .map(|cmd| match cmd? {
+            kv::Command::Set { key, .. }
+            | kv::Command::SetEx { key, .. }
+            | kv::Command::Remove { key } => Ok(key),
+        })
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["long", "plain"]);
+    assert_eq!(store.get("short".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.len(), 2);
+    Ok(())
+}
+
+// An overwrite makes exactly the record it replaces stale, whichever kind of
+// set wrote either one, and a remove makes the last value and the tombstone
+// stale.
+#[test]
+fn overwrite_accounting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut last_len = 0;
+    let mut stale = 0;
+    let mut write = |write: &dyn Fn(&KvStore) -> Result<()>| -> Result<()> {
+        let before = store.disk_size()?;
+        write(&store)?;
+        stale += last_len;
+        last_len = store.disk_size()? - before;
+        assert_eq!(store.stats().uncompacted_bytes, stale);
+        Ok(())
+    };
+    write(&|store| store.set("key".to_owned(), "value1".to_owned()))?;
+    write(&|store| store.set("key".to_owned(), "a longer value2".to_owned()))?;
+    write(&|store| store.set_with_ttl("key".to_owned(), "v3".to_owned(), Duration::from_secs(60)))?;
+    write(&|store| store.set("key".to_owned(), "value4".to_owned()))?;
+
+    let before = store.disk_size()?;
+    store.remove("key".to_owned())?;
+    let tombstone = store.disk_size()? - before;
+    assert_eq!(
+        store.stats().uncompacted_bytes,
+        stale + last_len + tombstone
+    );
+    assert_eq!(store.stats().uncompacted_bytes, store.disk_size()?);
+    Ok(())
+}
+
+// `uncompacted_bytes` grows with every overwrite and removal, agrees with
+// what reopening recovers, and drops to zero once compacted.
+#[test]
+fn stats_uncompacted_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value0".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats().uncompacted_bytes, 0);
+
+    let mut last = 0;
+    for iter in 1..5 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+        let uncompacted = store.stats().uncompacted_bytes;
+        assert!(uncompacted > last);
+        last = uncompacted;
+    }
+    store.remove("other".to_owned())?;
+    let stats = store.stats();
+    assert!(stats.uncompacted_bytes > last);
+    assert_eq!(stats.live_keys, 1);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let reopened = store.stats();
+    assert_eq!(reopened.uncompacted_bytes, stats.uncompacted_bytes);
+    store.compact()?;
+    let stats = store.stats();
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert_eq!(stats.num_log_files, 2);
+    assert!(stats.current_file_id > reopened.current_file_id);
+    Ok(())
+}
+
+// An export imports into a fresh `KvStore` or a sled engine with every
+// live key intact, and a cut-off export fails to import.
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key7".to_owned(), "new value".to_owned())?;
+    store.remove("key42".to_owned())?;
+    store.set("unicode".to_owned(), "värde ✓".to_owned())?;
+    let mut export = Vec::new();
+    store.export(&mut export)?;
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let imported = KvStore::open(other_dir.path())?;
+    assert_eq!(imported.import(&export[..])?, 100);
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open(sled_dir.path())?;
+    assert_eq!(sled.import(&export[..])?, 100);
+    for key in store.keys() {
+        let value = store.get(key.clone())?;
+        assert_eq!(imported.get(key.clone())?, value);
+        assert_eq!(KvsEngine::get(&sled, key)?, value);
+    }
+    assert_eq!(imported.len(), 100);
+    assert_eq!(imported.get("key42".to_owned())?, None);
+
+    let mut sled_export = Vec::new();
+    sled.export(&mut sled_export)?;
+    assert_eq!(sled_export, export);
+
+    let truncated_dir = TempDir::new().expect("unable to create temporary working directory");
+    let truncated = KvStore::open(truncated_dir.path())?;
+    assert!(truncated.import(&export[..export.len() - 1]).is_err());
+    Ok(())
+}
+
+// Keys whose TTL has run out are left out of an export, so importing it
+// cannot bring them back.
+#[test]
+fn export_skips_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let options = KvStoreOptions {
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("b".to_owned(), "bv".to_owned())?;
+    store.set_with_ttl("c".to_owned(), "cv".to_owned(), Duration::from_secs(10))?;
+    clock.advance(Duration::from_secs(10));
+    let mut export = Vec::new();
+    store.export(&mut export)?;
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let imported = KvStore::open(other_dir.path())?;
+    assert_eq!(imported.import(&export[..])?, 1);
+    assert_eq!(imported.get("b".to_owned())?, Some("bv".to_owned()));
+    assert_eq!(imported.get("c".to_owned())?, None);
+    Ok(())
+}
+
+// A crash during compaction leaves either a partial `.log.tmp` file, which
+// the next open deletes, or finished copies next to the files they came
+// from, which recovery prefers.
+#[test]
+fn recover_interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let first_log = temp_dir.path().join("1.log");
+    let original = std::fs::read(&first_log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    drop(store);
+    assert!(!first_log.exists());
+    // Put the compacted file back as if the crash came before its removal,
+    // and leave half of an unfinished output file.
+    std::fs::write(&first_log, &original)?;
+    let partial = temp_dir.path().join("100.log.tmp");
+    std::fs::write(&partial, &original[..original.len() / 2])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!partial.exists());
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    assert!(store.stats().uncompacted_bytes >= original.len() as u64);
+    store.compact()?;
+    assert!(!first_log.exists());
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// A sled value that is not UTF-8 fails the get with `KvError::Utf8`, and
+// the export with it, instead of panicking.
+#[test]
+fn sled_non_utf8_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    db.insert("key1", &[0xff, 0xfe][..])?;
+    db.flush()?;
+    drop(db);
+
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    match KvsEngine::get(&engine, "key1".to_owned()) {
+        Err(KvError::Utf8(_)) => {}
+        other => panic!("expected a UTF-8 error, got {:?}", other),
+    }
+    assert!(matches!(engine.export(Vec::new()), Err(KvError::Utf8(_))));
+    Ok(())
+}
+
+// Threads writing through a sharded store at once each see their own
+// writes, the keys spread over every shard, and the store reopens only
+// with its shard count.
+#[test]
+fn sharded_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_sharded(temp_dir.path(), 4)?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for key_id in 0..100 {
+                    let key = format!("thread{}key{}", thread_id, key_id);
+                    store.set(key.clone(), "old".to_owned())?;
+                    store.set(key.clone(), format!("value{}", key_id))?;
+                    if key_id % 10 == 0 {
+                        store.remove(key)?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let check = |store: &ShardedKvStore| -> Result<()> {
+        for thread_id in 0..8 {
+            for key_id in 0..100 {
+                let expected = match key_id % 10 {
+                    0 => None,
+                    _ => Some(format!("value{}", key_id)),
+                };
+                let key = format!("thread{}key{}", thread_id, key_id);
+                assert_eq!(store.get(key)?, expected);
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    assert!(store.shards().iter().all(|shard| !shard.is_empty()));
+    let total: usize = store.shards().iter().map(KvStore::len).sum();
+    assert_eq!(total, 720);
+    drop(store);
+
+    check(&ShardedKvStore::open(temp_dir.path(), 4)?)?;
+    assert!(matches!(
+        ShardedKvStore::open(temp_dir.path(), 2),
+        Err(KvError::ShardCountMismatch {
+            requested: 2,
+            found: 4
+        })
+    ));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::EngineMismatch { .. })
+    ));
+    Ok(())
+}
+
+// A committed transaction should apply all of its writes and survive a
+// reopen, while one whose closure fails should leave the store untouched.
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    store.transaction(|txn| {
+        txn.set("key2".to_owned(), "value2".to_owned());
+        txn.remove("key1".to_owned());
+        Ok(())
+    })?;
+    let aborted = store.transaction(|txn| {
+        txn.set("key3".to_owned(), "value3".to_owned());
+        txn.remove("key2".to_owned());
+        Err(KvError::KeyNotFound)
+    });
+    assert!(matches!(aborted, Err(KvError::KeyNotFound)));
+
+    for _ in 0..2 {
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// A transaction whose commit record never reached the file should be
+// dropped whole on the next open.
+#[test]
+fn transaction_without_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.transaction(|txn| {
+        txn.set("key1".to_owned(), "value2".to_owned());
+        txn.set("key2".to_owned(), "value2".to_owned());
+        Ok(())
+    })?;
+    drop(store);
+
+    let path = temp_dir.path().join("1.log");
+    let bytes = std::fs::read(&path)?;
+    let commit = br#""TxnCommit""#;
+    assert!(bytes.ends_with(commit));
+    std::fs::write(&path, &bytes[..bytes.len() - commit.len()])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    // Later writes must not be swallowed by the unfinished transaction.
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Account {
+    owner: String,
+    balance: i64,
+    tags: Vec<String>,
+}
+
+// A typed store should hand back the struct it was given, including after
+// a reopen, and report a value of the wrong shape as an error.
+#[test]
+fn typed_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let account = Account {
+        owner: "alice".to_owned(),
+        balance: -42,
+        tags: vec!["savings".to_owned()],
+    };
+    let store = TypedKvStore::<Account>::open(temp_dir.path())?;
+    store.set("key1".to_owned(), &account)?;
+    assert_eq!(store.get("key1".to_owned())?, Some(account));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store
+        .store()
+        .set("key2".to_owned(), "not json".to_owned())?;
+    assert!(matches!(
+        store.get("key2".to_owned()),
+        Err(KvError::Serde(_))
+    ));
+    drop(store);
+
+    let store = TypedKvStore::<Account>::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key1".to_owned())?.map(|account| account.balance),
+        Some(-42)
+    );
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Recovering the log files on several threads should rebuild exactly the
+// index a one-by-one replay does, with removes, clears and overwrites
+// spread over many files.
+#[test]
+fn parallel_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_file_size: Some(2048),
+        compaction_policy: CompactionPolicy::Bytes(u64::MAX),
+        strict_removes: false,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let mut model = HashMap::new();
+    let mut rng = StdRng::seed_from_u64(0);
+    for i in 0..3000 {
+        if i == 1000 {
+            let entries: Vec<_> = (0..10)
+                .map(|n| (format!("key{}", n), format!("{}-cleared", i)))
+                .collect();
+            store.replace_all(entries.clone().into_iter())?;
+            model = entries.into_iter().collect();
+            continue;
+        }
+        let key = format!("key{}", rng.gen_range(0..100));
+        if rng.gen_range(0..3) == 0 {
+            store.remove(key.clone())?;
+            model.remove(&key);
+        } else {
+            let value = format!("{}-{}", i, "v".repeat(rng.gen_range(0..50)));
+            store.set(key.clone(), value.clone())?;
+            model.insert(key, value);
+        }
+    }
+    assert!(store.stats().num_log_files > 20);
+    drop(store);
+
+    let open = |recovery_workers| -> Result<(KvStoreStats, Vec<(String, String)>)> {
+        let options = KvStoreOptions {
+            recovery_workers,
+            defer_active_file: true,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        let pairs = store.scan()?.collect::<Result<Vec<_>>>()?;
+        Ok((store.stats(), pairs))
+    };
+    let (stats, pairs) = open(1)?;
+    assert!(stats.uncompacted_bytes > 0);
+    let mut expected: Vec<_> = model.into_iter().collect();
+    expected.sort();
+    assert_eq!(pairs, expected);
+    for workers in [2, 3, 8, 100] {
+        assert_eq!(open(workers)?, (stats.clone(), pairs.clone()));
+    }
+    Ok(())
+}
+
+// Keys and values over their size limits should be refused without a byte
+// reaching the log, leaving earlier values in place.
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        max_key_size: Some(8),
+        max_value_size: Some(16),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "v".repeat(16))?;
+    let disk_bytes = store.stats().disk_bytes;
+
+    assert!(matches!(
+        store.set("key1".to_owned(), "v".repeat(17)),
+        Err(KvError::ValueTooLarge { size: 17, max: 16 })
+    ));
+    assert!(matches!(
+        store.set("k".repeat(9), "value".to_owned()),
+        Err(KvError::KeyTooLarge { size: 9, max: 8 })
+    ));
+    assert!(matches!(
+        store.transaction(|txn| {
+            txn.set("key2".to_owned(), "value2".to_owned());
+            txn.set("key1".to_owned(), "v".repeat(17));
+            Ok(())
+        }),
+        Err(KvError::ValueTooLarge { .. })
+    ));
+    assert_eq!(store.stats().disk_bytes, disk_bytes);
+    assert_eq!(store.get("key1".to_owned())?, Some("v".repeat(16)));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("v".repeat(16)));
+    assert_eq!(store.len(), 1);
+    Ok(())
+}
+
+// `path` should return the directory the store was opened at, from every
+// handle.
+#[test]
+fn store_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("store");
+    let store = KvStore::open(&dir)?;
+    assert_eq!(store.path(), dir);
+    assert_eq!(store.clone().path(), dir);
+    assert_eq!(KvStore::open_snapshot(&dir)?.path(), dir);
+    Ok(())
+}
+
+// `disk_size` should grow with every write and drop once compaction throws
+// the stale records away.
+#[test]
+fn disk_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.disk_size()?, 0);
+
+    let value = "v".repeat(1000);
+    let mut size = 0;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), value.clone())?;
+        let new_size = store.disk_size()?;
+        assert!(new_size > size);
+        size = new_size;
+    }
+    assert!(size > 100 * 1000);
+    assert_eq!(size, store.stats().disk_bytes);
+
+    store.compact()?;
+    let compacted = store.disk_size()?;
+    assert!(compacted < size / 5);
+    assert!(compacted > 10 * 1000);
+    Ok(())
+}
+
+// A read-only handle should serve reads from a writable directory while
+// refusing writes, and leave no new file behind.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let files = || -> Vec<std::path::PathBuf> {
+        let mut files: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    };
+    let before = files();
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.keys(), vec!["key1".to_owned()]);
+    assert!(matches!(
+        store.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvError::ReadOnly)
+    ));
+    assert!(matches!(store.compact(), Err(KvError::ReadOnly)));
+    drop(store);
+    assert_eq!(files(), before);
+    Ok(())
+}
+
+// An engine counting its flushes, failing them if `fail` is set.
+#[derive(Clone)]
+struct FlushCounter {
+    engine: InMemoryKvsEngine,
+    flushes: Arc<std::sync::atomic::AtomicUsize>,
+    fail: bool,
+}
+
+impl KvsEngine for FlushCounter {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.engine.set(key, value)
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        self.engine.remove(key)
+    }
+    fn flush(&self) -> Result<()> {
+        self.flushes
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        match self.fail {
+            true => Err(KvError::Io(std::io::Error::other("disk full"))),
+            false => Ok(()),
+        }
+    }
+    fn clone_engine(&self) -> Box<dyn KvsEngine> {
+        Box::new(self.clone())
+    }
+}
+
+// Each `fsync` policy keeps the store correct, and syncs as often as it
+// says: after every write, every few writes or once its interval passes,
+// or never.
+#[test]
+fn fsync_policy() -> Result<()> {
+    let clock = Arc::new(ManualClock::default());
+    let policies = [
+        (FsyncPolicy::Always, 11),
+        (
+            FsyncPolicy::Batch {
+                writes: 4,
+                interval: None,
+            },
+            2,
+        ),
+        (FsyncPolicy::Never, 0),
+    ];
+    for (fsync, syncs) in policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = || KvStoreOptions {
+            fsync,
+            sync_sets: false,
+            clock: clock.clone(),
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        for key_id in 0..9 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key0".to_owned())?;
+        store.transaction(|txn| {
+            txn.set("key9".to_owned(), "value9".to_owned());
+            txn.remove("key1".to_owned());
+            Ok(())
+        })?;
+        assert_eq!(store.stats().syncs, syncs, "{:?}", fsync);
+        assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        assert_eq!(store.len(), 8);
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key8".to_owned())?, Some("value8".to_owned()));
+    }
+
+    // A batch interval syncs on the next write or tick once it has passed.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        fsync: FsyncPolicy::Batch {
+            writes: 100,
+            interval: Some(Duration::from_secs(1)),
+        },
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.tick()?;
+    assert_eq!(store.stats().syncs, 0);
+    clock.advance(Duration::from_secs(1));
+    store.tick()?;
+    assert_eq!(store.stats().syncs, 1);
+    store.tick()?;
+    assert_eq!(store.stats().syncs, 1);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    clock.advance(Duration::from_secs(1));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.stats().syncs, 2);
+    Ok(())
+}
+
+// Writers committing together should share syncs, and all of them should
+// hear about a sync that failed.
+#[test]
+fn group_commit() -> Result<()> {
+    for fail in [false, true] {
+        let engine = FlushCounter {
+            engine: InMemoryKvsEngine::new(),
+            flushes: Arc::default(),
+            fail,
+        };
+        let group_commit = GroupCommit::new(Duration::from_millis(20), 8);
+        let failures = std::sync::atomic::AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for writer in 0..8 {
+                let (engine, group_commit, failures) = (&engine, &group_commit, &failures);
+                scope.spawn(move || {
+                    for i in 0..10 {
+                        engine.set(format!("key{}-{}", writer, i), "value".to_owned())?;
+                        if group_commit.commit(engine).is_err() {
+                            failures.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        }
+                    }
+                    Ok::<_, KvError>(())
+                });
+            }
+        });
+        let flushes = engine.flushes.load(std::sync::atomic::Ordering::SeqCst);
+        assert!(flushes < 80, "{} flushes for 80 writes", flushes);
+        let failures = failures.into_inner();
+        assert_eq!(failures, if fail { 80 } else { 0 });
+    }
+
+    // A `KvStore` syncs its log to disk once per batch.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync_sets: false,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let group_commit = GroupCommit::new(Duration::from_millis(20), 8);
+    std::thread::scope(|scope| {
+        for writer in 0..8 {
+            let (store, group_commit) = (&store, &group_commit);
+            scope.spawn(move || {
+                for i in 0..10 {
+                    store.set(format!("key{}-{}", writer, i), "value".to_owned())?;
+                    group_commit.commit(store)?;
+                }
+                Ok::<_, KvError>(())
+            });
+        }
+    });
+    let syncs = store.stats().syncs;
+    assert!(syncs > 0 && syncs < 80, "{} syncs for 80 writes", syncs);
+    assert_eq!(store.len(), 80);
+    Ok(())
+}
+
+// Under `--group-commit` every write a client saw acknowledged should be
+// on disk, even if the server is killed straight after.
+#[test]
+fn server_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (mut server, addr, _) = spawn_server(&temp_dir, &["--group-commit", "20"])?;
+    let addr = addr.to_string();
+    std::thread::scope(|scope| {
+        for client in 0..4 {
+            let addr = &addr;
+            scope.spawn(move || {
+                for i in 0..5 {
+                    let key = format!("key{}-{}", client, i);
+                    Command::cargo_bin("kvs-client")
+                        .unwrap()
+                        .args(["set", &key, "value", "--addr", addr])
+                        .assert()
+                        .success();
+                }
+            });
+        }
+    });
+    server.kill()?;
+    server.wait()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 20);
+    Ok(())
+}
+
+// `clear` should drop every key and log file there was, for this handle
+// and after a reopen, and leave the store ready for writes.
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        max_file_size: Some(256),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(store.stats().num_log_files > 5);
+    let size = store.disk_size()?;
+
+    store.clear()?;
+    assert_eq!(store.len(), 0);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.stats().num_log_files <= 2);
+    assert!(store.disk_size()? < size / 10);
+    store.set("key2".to_owned(), "new".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// With `background_compaction`, a write crossing the threshold leaves the
+// compaction to the store's own thread. Writers carry on while it is held
+// up in the middle of copying, and once let go it reclaims the stale bytes
+// without losing any of the writes made meanwhile.
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (copying, copy_started) = std::sync::mpsc::channel();
+    let (resume, resumed) = std::sync::mpsc::channel::<()>();
+    let resumed = std::sync::Mutex::new(resumed);
+    let paused = std::sync::atomic::AtomicBool::new(false);
+    let options = KvStoreOptions {
+        background_compaction: true,
+        compaction_policy: CompactionPolicy::Bytes(4 * 1024),
+        compaction_chunk_size: 16,
+        compaction_progress: Some(kv::ProgressCallback(Arc::new(move |_, _| {
+            if !paused.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                copying.send(()).unwrap();
+                let _ = resumed.lock().unwrap().recv();
+            }
+        }))),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let value = |t: usize, i: usize| format!("{}-{}-{:060}", t, i, 0);
+
+    let (done, writer_done) = std::sync::mpsc::channel();
+    for t in 0..4 {
+        let store = store.clone();
+        let done = done.clone();
+        std::thread::spawn(move || {
+            for i in 0..400 {
+                store
+                    .set(format!("key{}-{}", t, i % 10), value(t, i))
+                    .unwrap();
+            }
+            done.send(()).unwrap();
+        });
+    }
+    copy_started
+        .recv_timeout(Duration::from_secs(10))
+        .expect("compaction never started");
+    for _ in 0..4 {
+        writer_done
+            .recv_timeout(Duration::from_secs(10))
+            .expect("writers were held up by the compaction");
+    }
+    assert_eq!(store.stats().compactions, 0);
+
+    let size = store.disk_size()?;
+    resume.send(()).unwrap();
+    for _ in 0..1000 {
+        if store.stats().compactions > 0 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.stats().compactions, 1);
+    assert!(store.disk_size()? < size);
+    let check = |store: &KvStore| -> Result<()> {
+        for t in 0..4 {
+            for i in 390..400 {
+                let key = format!("key{}-{}", t, i % 10);
+                assert_eq!(store.get(key)?, Some(value(t, i)));
+            }
+        }
+        assert_eq!(store.len(), 40);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)
+}
+
+// Only one writable store at a time may have a directory open, whether in
+// this process or another; read-only opens are let in regardless.
+#[test]
+fn open_locks_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::Locked)
+    ));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Locked"));
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// `get_reader` streams a value out in pieces, bit for bit, from bincode and
+// MessagePack logs and from the in-memory fallback alike. A streamed value
+// that does not match its checksum fails the read once it reaches the end,
+// while one in a JSON log is read whole and fails `get_reader` itself.
+#[test]
+fn get_reader() -> Result<()> {
+    use std::io::Read;
+
+    let value: String = (0..1 << 20)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+    let read_all = |store: &KvStore, key: &str| -> Result<Option<Vec<u8>>> {
+        let mut bytes = Vec::new();
+        match store.get_reader(key)? {
+            Some(mut reader) => {
+                let mut chunk = [0; 4096];
+                loop {
+                    match reader.read(&mut chunk)? {
+                        0 => return Ok(Some(bytes)),
+                        n => bytes.extend_from_slice(&chunk[..n]),
+                    }
+                }
+            }
+            None => Ok(None),
+        }
+    };
+    let formats = [
+        RecordFormat::Bincode,
+        RecordFormat::MessagePack,
+        RecordFormat::Json,
+    ];
+    for record_format in formats {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            record_format,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set("large".to_owned(), value.clone())?;
+        store.set_with_ttl(
+            "ttl".to_owned(),
+            "lives".to_owned(),
+            Duration::from_secs(3600),
+        )?;
+        assert_eq!(read_all(&store, "large")?, Some(value.clone().into_bytes()));
+        assert_eq!(read_all(&store, "ttl")?, Some(b"lives".to_vec()));
+        assert_eq!(read_all(&store, "missing")?, None);
+    }
+
+    for record_format in formats {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            record_format,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set("large".to_owned(), value.clone())?;
+        let path = temp_dir.path().join("1.log");
+        let mut log = std::fs::read(&path)?;
+        let middle = log.len() / 2;
+        log[middle] = b'#';
+        std::fs::write(&path, log)?;
+        if record_format == RecordFormat::Json {
+            assert!(store.get_reader("large").is_err());
+            continue;
+        }
+        let mut bytes = Vec::new();
+        let err = store
+            .get_reader("large")?
+            .unwrap()
+            .read_to_end(&mut bytes)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+    Ok(())
+}
+
+// With `save_index`, `open` loads the index saved on close or by the last
+// compaction and replays only what was logged after it, ending up with the
+// same index as a full replay.
+#[test]
+fn save_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        save_index: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..300 {
+        store.set(format!("key{}", iter % 50), format!("value{}", iter))?;
+    }
+    store.set_with_ttl(
+        "ttl".to_owned(),
+        "lives".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.compact()?;
+    for iter in 0..20 {
+        store.set(format!("key{}", iter), format!("late{}", iter))?;
+        store.remove(format!("key{}", iter + 20))?;
+    }
+    // A copy of the directory as a crash would leave it, with only the
+    // index saved by the compaction.
+    let crashed = TempDir::new().expect("unable to create temporary working directory");
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        std::fs::copy(&path, crashed.path().join(path.file_name().unwrap()))?;
+    }
+    drop(store);
+
+    let contents = |store: &KvStore| -> Result<HashMap<String, String>> { store.scan()?.collect() };
+    for dir in [temp_dir.path(), crashed.path()] {
+        let saved = KvStore::open_with_options(dir, options.clone())?;
+        let replayed_records = saved.stats().recovered_records;
+        let saved_contents = contents(&saved)?;
+        let saved_stats = saved.stats();
+        drop(saved);
+        std::fs::remove_file(dir.join("index"))?;
+
+        let replayed = KvStore::open(dir)?;
+        assert!(replayed_records < replayed.stats().recovered_records);
+        assert_eq!(saved_contents, contents(&replayed)?);
+        assert_eq!(saved_contents.len(), 31);
+        assert_eq!(
+            saved_stats.uncompacted_bytes,
+            replayed.stats().uncompacted_bytes
+        );
+    }
+    Ok(())
+}
+
+// A store opened with `open_file` lives in that one file: sets, removes
+// and compaction all survive a reopen, and compaction shrinks the file
+// without leaving anything but the lock file beside it.
+#[test]
+fn open_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store.db");
+    let store = KvStore::open_file(&path)?;
+    assert_eq!(store.path(), path);
+    for iter in 0..100 {
+        store.set(format!("key{}", iter % 10), format!("value{}", iter))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_file(&path)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value93".to_owned()));
+    let before = std::fs::metadata(&path)?.len();
+    store.compact()?;
+    assert!(std::fs::metadata(&path)?.len() < before);
+    store.set("after".to_owned(), "compaction".to_owned())?;
+    store.compact()?;
+    store.set("key9".to_owned(), "last".to_owned())?;
+    drop(store);
+
+    let mut names: Vec<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["store.db", "store.db.lock"]);
+    let store = KvStore::open_file(&path)?;
+    let contents: HashMap<String, String> = store.scan()?.collect::<Result<_>>()?;
+    assert_eq!(contents.len(), 10);
+    assert_eq!(contents["after"], "compaction");
+    assert_eq!(contents["key5"], "value95");
+    assert_eq!(contents["key9"], "last");
+    assert!(matches!(KvStore::open_file(&path), Err(KvError::Locked)));
+
+    // Compacting away every key leaves an empty file that still takes
+    // writes.
+    for key in contents.keys() {
+        store.remove(key.clone())?;
+    }
+    store.compact()?;
+    assert_eq!(std::fs::metadata(&path)?.len(), 0);
+    store.set("key1".to_owned(), "again".to_owned())?;
+    drop(store);
+    let store = KvStore::open_file(&path)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("again".to_owned()));
+    assert_eq!(store.keys().len(), 1);
+
+    // So does replacing the whole contents.
+    store.replace_all((0..3).map(|iter| (format!("new{}", iter), "value".to_owned())))?;
+    store.set("new3".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::open_file(&path)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.keys().len(), 4);
+    Ok(())
+}
+
+// With `max_file_size`, compaction splits live data larger than that
+// across several files, none over the limit, and every key reads back the
+// same before and after a reopen.
+#[test]
+fn compaction_max_file_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        max_file_size: Some(1024),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for iter in 0..3 {
+        for key_id in 0..200 {
+            let value = format!("value{}-{:040}", iter, key_id);
+            store.set(format!("key{}", key_id), value)?;
+        }
+    }
+    let result = store.compact()?;
+    let active = store.stats().current_file_id;
+    let compacted: Vec<_> = result
+        .files
+        .iter()
+        .filter(|&&(id, _)| id != active)
+        .collect();
+    assert!(compacted.len() >= 10);
+    assert!(compacted.iter().all(|&&(_, size)| size <= 1024));
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..200 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value2-{:040}", key_id))
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options())?)
+}
+
+// `compaction_estimate` reports the stale bytes the store counts, which
+// together with the live ones make up the logs, and which are what a
+// compaction then reclaims.
+#[test]
+fn compaction_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..5 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let estimate = store.compaction_estimate();
+    assert_eq!(estimate.reclaimable_bytes, store.stats().uncompacted_bytes);
+    assert!(estimate.reclaimable_bytes > estimate.live_bytes);
+    assert_eq!(
+        estimate.reclaimable_bytes + estimate.live_bytes,
+        estimate.current_total_bytes
+    );
+    assert_eq!(estimate.current_total_bytes, store.disk_size()?);
+
+    let result = store.compact()?;
+    assert_eq!(result.reclaimed_bytes, estimate.reclaimable_bytes);
+    let estimate = store.compaction_estimate();
+    assert_eq!(estimate.reclaimable_bytes, 0);
+    assert_eq!(estimate.live_bytes, estimate.current_total_bytes);
+    Ok(())
+}
+
+// Every record format round-trips sets, expiring sets, removes and
+// transactions through recovery and compaction, and a directory goes on
+// being read in the format it was created with.
+#[test]
+fn record_formats() -> Result<()> {
+    for format in [
+        RecordFormat::Json,
+        RecordFormat::Bincode,
+        RecordFormat::MessagePack,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            record_format: format,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for iter in 0..50 {
+            store.set(format!("key{}", iter % 10), format!("value{}", iter))?;
+        }
+        store.set_with_ttl(
+            "ttl".to_owned(),
+            "lives".to_owned(),
+            Duration::from_secs(3600),
+        )?;
+        store.remove("key0".to_owned())?;
+        store.transaction(|txn| {
+            txn.set("txn".to_owned(), "committed".to_owned());
+            txn.remove("key1".to_owned());
+            Ok(())
+        })?;
+        drop(store);
+
+        let check = |store: &KvStore| -> Result<()> {
+            assert_eq!(store.get("key0".to_owned())?, None);
+            assert_eq!(store.get("key1".to_owned())?, None);
+            assert_eq!(store.get("key5".to_owned())?, Some("value45".to_owned()));
+            assert_eq!(store.get("ttl".to_owned())?, Some("lives".to_owned()));
+            assert_eq!(store.get("txn".to_owned())?, Some("committed".to_owned()));
+            assert_eq!(store.len(), 10);
+            Ok(())
+        };
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.options().record_format, format);
+        check(&store)?;
+        store.compact()?;
+        drop(store);
+        check(&KvStore::open(temp_dir.path())?)?;
+    }
+    Ok(())
+}
+
+// Once its TTL runs out a key is gone from every read, not only from
+// `get`: key lists, counts, ranges, prefix scans, full scans and digests
+// all leave it out, as if it had been removed.
+#[test]
+fn expired_keys_hidden_from_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let options = KvStoreOptions {
+        clock: clock.clone(),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("b".to_owned(), "bv".to_owned())?;
+    store.set_with_ttl("c".to_owned(), "cv".to_owned(), Duration::from_secs(10))?;
+    // The same store without `c`, to compare digests with.
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let without_c = KvStore::open(other_dir.path())?;
+    without_c.set("b".to_owned(), "bv".to_owned())?;
+    assert_eq!(store.len(), 2);
+    clock.advance(Duration::from_secs(10));
+
+    let only_b = vec![("b".to_owned(), "bv".to_owned())];
+    assert!(!store.contains_key("c"));
+    assert_eq!(store.keys(), ["b"]);
+    assert_eq!(store.len(), 1);
+    assert!(!store.is_empty());
+    assert_eq!(store.range("a", "z")?, only_b);
+    assert_eq!(store.scan_range("a", "z")?, only_b);
+    assert_eq!(store.scan_prefix("c")?, []);
+    assert_eq!(store.scan()?.collect::<Result<Vec<_>>>()?, only_b);
+    assert_eq!(store.digest()?, without_c.digest()?);
+    assert_eq!(store.stats().live_keys, 1);
+    assert_eq!(store.stats().live_bytes, without_c.stats().live_bytes);
+    let estimate = store.compaction_estimate();
+    assert_eq!(estimate.live_bytes, without_c.stats().live_bytes);
+    assert_eq!(
+        estimate.reclaimable_bytes + estimate.live_bytes,
+        estimate.current_total_bytes
+    );
+
+    store.remove("b".to_owned())?;
+    assert!(store.is_empty());
+    Ok(())
+}
+
+// A store indexing key hashes serves point operations like any other and
+// survives compaction and reopening, while scans fail. Long keys take up
+// far less of the memory budget than in a store indexing whole keys.
+#[test]
+fn hashed_keys() -> Result<()> {
+    let options = || KvStoreOptions {
+        hashed_keys: Some(kv::hash_key),
+        ..KvStoreOptions::default()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.value_len("key1")?, Some(6));
+    assert!(store.contains_key("key1"));
+    assert!(!store.contains_key("key2"));
+    assert_eq!(store.len(), 1);
+    assert!(store.keys().is_empty());
+    assert!(matches!(store.scan(), Err(KvError::HashedKeys)));
+    assert!(matches!(store.digest(), Err(KvError::HashedKeys)));
+    assert!(matches!(
+        store.remove("key2".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.len(), 1);
+    drop(store);
+    assert!(matches!(
+        KvStore::open_with_options(
+            temp_dir.path(),
+            KvStoreOptions {
+                build_value_index: true,
+                ..options()
+            }
+        ),
+        Err(KvError::HashedKeys)
+    ));
+
+    let fill = |options: KvStoreOptions| -> Result<usize> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            memory_budget: Some(16 * 1024),
+            ..options
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..100 {
+            match store.set(format!("{:0>1000}", key_id), "value".to_owned()) {
+                Ok(()) => (),
+                Err(KvError::MemoryBudgetExceeded { .. }) => return Ok(key_id),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(100)
+    };
+    assert!(fill(KvStoreOptions::default())? < 100);
+    assert_eq!(fill(options())?, 100);
+    Ok(())
+}
+
+// Keys that share a hash are told apart by the keys in their log records,
+// here with every key hashing the same.
+#[test]
+fn hashed_keys_collisions() -> Result<()> {
+    let options = || KvStoreOptions {
+        hashed_keys: Some(|_| 0),
+        ..KvStoreOptions::default()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key3".to_owned(), "other".to_owned())?;
+    store.remove("key5".to_owned())?;
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..10 {
+            let expected = match key_id {
+                3 => Some("other".to_owned()),
+                5 => None,
+                _ => Some(format!("value{}", key_id)),
+            };
+            assert_eq!(
+                store.contains_key(&format!("key{}", key_id)),
+                expected.is_some()
+            );
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        assert_eq!(store.get("missing".to_owned())?, None);
+        assert_eq!(store.len(), 9);
+        Ok(())
+    };
+    check(&store)?;
+    assert!(matches!(
+        store.remove("key5".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    check(&store)?;
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.len(), 8);
+    Ok(())
+}